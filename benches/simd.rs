@@ -0,0 +1,128 @@
+//! Timings of the SIMD code paths against the scalar code they replace.
+//!
+//! $ cargo bench --bench simd
+use raymart::{
+    hit::{Interval, Triangle, Triangle4},
+    material::{Conductor, CLAY},
+    Color, Ray, P3, V3,
+};
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+const RUNS: usize = 10;
+
+/// The fastest of several runs of f, which is less noisy than the mean.
+fn time(mut f: impl FnMut()) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, scalar: Duration, simd: Duration) {
+    println!(
+        "{name:<24} scalar = {scalar:>10.2?}  simd = {simd:>10.2?}  speedup = {:.2}x",
+        scalar.as_secs_f64() / simd.as_secs_f64()
+    );
+}
+
+fn triangles() {
+    let tris: Vec<Triangle> = (0..4 * 1024)
+        .map(|_| {
+            let a = P3::ORIGIN + V3::random(-1.0, 1.0);
+            let (b, c) = (a + V3::random(-0.5, 0.5), a + V3::random(-0.5, 0.5));
+            Triangle::new(a, b, c, &CLAY)
+        })
+        .collect();
+    let packed: Vec<Triangle4> = tris.chunks(4).map(Triangle4::new).collect();
+    let rays: Vec<Ray> = (0..1024)
+        .map(|_| Ray::new(P3::ORIGIN + V3::random(-3.0, 3.0), V3::random(-1.0, 1.0)))
+        .collect();
+    let ray_t = Interval::new(0.001, f32::INFINITY);
+
+    let hits = |n: usize, hit: bool| n + hit as usize;
+    let (mut n_scalar, mut n_simd) = (0, 0);
+    let scalar = time(|| {
+        n_scalar = (rays.iter())
+            .flat_map(|r| {
+                tris.iter()
+                    .map(move |t| black_box(t.hits(r, ray_t)).is_some())
+            })
+            .fold(0, hits);
+    });
+    let simd = time(|| {
+        n_simd = (rays.iter())
+            .flat_map(|r| {
+                packed
+                    .iter()
+                    .map(move |t| black_box(t.hits(r, ray_t)).is_some())
+            })
+            .fold(0, hits);
+    });
+
+    assert!(n_simd <= n_scalar, "{n_simd} > {n_scalar}");
+    report("triangle intersection", scalar, simd);
+}
+
+/// The per channel conductor Fresnel term as it was before being vectorized.
+fn scalar_reflectance(c: &Conductor, cos_theta: f32) -> Color {
+    let cos2 = cos_theta.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let channel = |eta: f32, k: f32| {
+        let t0 = eta * eta - k * k - sin2;
+        let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+        let t1 = a2b2 + cos2;
+        let t2 = 2.0 * cos2.sqrt() * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+
+        0.5 * (rs + rp)
+    };
+
+    Color::new(
+        channel(c.eta.r, c.k.r),
+        channel(c.eta.g, c.k.g),
+        channel(c.eta.b, c.k.b),
+    )
+}
+
+fn conductor_fresnel() {
+    let metals = [Conductor::GOLD, Conductor::COPPER, Conductor::ALUMINIUM];
+    let cosines: Vec<f32> = (0..1_000_000).map(|i| i as f32 / 1_000_000.0).collect();
+
+    for c in metals.iter() {
+        for &cos in cosines.iter().step_by(1000) {
+            let (a, b) = (scalar_reflectance(c, cos), c.reflectance(cos));
+            let diff = [a.r - b.r, a.g - b.g, a.b - b.b];
+            assert!(diff.iter().all(|d| d.abs() < 1e-5), "{a:?} != {b:?}");
+        }
+    }
+
+    let sum = |f: &dyn Fn(&Conductor, f32) -> Color| {
+        (metals.iter())
+            .flat_map(|c| cosines.iter().map(move |&cos| black_box(f(c, cos))))
+            .fold(Color::BLACK, |acc, c| acc + c)
+    };
+    let scalar = time(|| {
+        black_box(sum(&scalar_reflectance));
+    });
+    let simd = time(|| {
+        black_box(sum(&|c, cos| c.reflectance(cos)));
+    });
+
+    report("conductor fresnel", scalar, simd);
+}
+
+fn main() {
+    triangles();
+    conductor_fresnel();
+}
@@ -0,0 +1,111 @@
+//! Exposure analysis of the final HDR pixel buffer: a per-stop luminance histogram and a
+//! false-color heatmap image so that exposure and clipping can be judged objectively.
+use crate::{output::Output, Color};
+use image::{ImageResult, RgbImage};
+use std::fs;
+
+/// Luminance of 18% grey which is used as the reference point (0 EV) for the histogram.
+pub const MID_GREY: f32 = 0.18;
+/// Lowest stop tracked by the histogram relative to mid grey.
+pub const MIN_EV: i32 = -10;
+/// Highest stop tracked by the histogram relative to mid grey.
+pub const MAX_EV: i32 = 6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// Pixel counts per stop from MIN_EV to MAX_EV (values outside the range are clamped in)
+    pub bins: Vec<usize>,
+    /// Pixels with zero luminance
+    pub black: usize,
+    /// Pixels with at least one channel at or above 1.0 (clipped in LDR output)
+    pub clipped: usize,
+    pub total: usize,
+}
+
+impl Histogram {
+    pub fn new(pixels: &[Color]) -> Self {
+        let mut bins = vec![0; (MAX_EV - MIN_EV + 1) as usize];
+        let (mut black, mut clipped) = (0, 0);
+
+        for p in pixels.iter() {
+            let l = p.luminance();
+            if l <= 0.0 {
+                black += 1;
+                continue;
+            }
+            if p.r >= 1.0 || p.g >= 1.0 || p.b >= 1.0 {
+                clipped += 1;
+            }
+            let ev = ev(l).floor().clamp(MIN_EV as f32, MAX_EV as f32) as i32;
+            bins[(ev - MIN_EV) as usize] += 1;
+        }
+
+        Self {
+            bins,
+            black,
+            clipped,
+            total: pixels.len(),
+        }
+    }
+
+    pub fn percent(&self, n: usize) -> f32 {
+        100.0 * n as f32 / self.total.max(1) as f32
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut s = String::from("ev,count,percent\n");
+        for (i, &n) in self.bins.iter().enumerate() {
+            let ev = MIN_EV + i as i32;
+            s.push_str(&format!("{ev},{n},{:.3}\n", self.percent(n)));
+        }
+
+        s
+    }
+}
+
+/// Exposure value of a luminance relative to mid grey.
+fn ev(l: f32) -> f32 {
+    (l / MID_GREY).log2()
+}
+
+/// Map a pixel onto a false-color scale by stops relative to mid grey:
+///   black -> purple -> blue -> cyan -> green (mid grey) -> yellow -> orange -> red (clipped)
+pub fn false_color(p: Color) -> [u8; 3] {
+    let l = p.luminance();
+    if l <= 0.0 {
+        return [0, 0, 0];
+    }
+    if p.r >= 1.0 || p.g >= 1.0 || p.b >= 1.0 {
+        return [255, 0, 0];
+    }
+
+    match ev(l) {
+        e if e < -5.0 => [80, 0, 120],
+        e if e < -3.0 => [0, 40, 220],
+        e if e < -1.0 => [0, 190, 220],
+        e if e < 1.0 => [40, 200, 40],
+        e if e < 2.0 => [240, 230, 0],
+        _ => [255, 140, 0],
+    }
+}
+
+/// Write the histogram as CSV and the false-color image as PNG next to the main output.
+pub fn write_analysis(
+    output: &Output,
+    width: u16,
+    height: u16,
+    pixels: &[Color],
+) -> ImageResult<()> {
+    let hist = Histogram::new(pixels);
+    eprintln!(
+        "\nLuminance: {:.2}% black, {:.2}% clipped",
+        hist.percent(hist.black),
+        hist.percent(hist.clipped)
+    );
+    fs::write(output.aux_path("histogram", "csv"), hist.to_csv())?;
+
+    let raw = pixels.iter().flat_map(|&p| false_color(p)).collect();
+    let img = RgbImage::from_raw(width as u32, height as u32, raw).unwrap();
+
+    img.save(output.aux_path("falsecolor", "png"))
+}
@@ -0,0 +1,180 @@
+//! Angles with an explicit unit so that degrees and radians can not be mixed up.
+//!
+//! Scene files give angles in degrees as bare numbers or with a unit suffix as a string, for
+//! example `fov = 40.0`, `fov = "40deg"` or `fov = "0.7rad"`.
+use serde::Deserialize;
+use std::{
+    fmt,
+    ops::{Add, Div, Mul, Neg, Sub},
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+#[serde(try_from = "RawAngle")]
+pub struct Angle {
+    rad: f32,
+}
+
+impl Angle {
+    pub const ZERO: Angle = Angle { rad: 0.0 };
+
+    pub fn deg(deg: f32) -> Self {
+        Self {
+            rad: deg.to_radians(),
+        }
+    }
+
+    pub const fn rad(rad: f32) -> Self {
+        Self { rad }
+    }
+
+    pub const fn radians(&self) -> f32 {
+        self.rad
+    }
+
+    pub fn degrees(&self) -> f32 {
+        self.rad.to_degrees()
+    }
+
+    pub fn sin(&self) -> f32 {
+        self.rad.sin()
+    }
+
+    pub fn cos(&self) -> f32 {
+        self.rad.cos()
+    }
+
+    pub fn tan(&self) -> f32 {
+        self.rad.tan()
+    }
+
+    pub fn sin_cos(&self) -> (f32, f32) {
+        self.rad.sin_cos()
+    }
+}
+
+impl fmt::Display for Angle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}°", self.degrees())
+    }
+}
+
+impl Add for Angle {
+    type Output = Angle;
+
+    fn add(self, rhs: Angle) -> Angle {
+        Angle::rad(self.rad + rhs.rad)
+    }
+}
+
+impl Sub for Angle {
+    type Output = Angle;
+
+    fn sub(self, rhs: Angle) -> Angle {
+        Angle::rad(self.rad - rhs.rad)
+    }
+}
+
+impl Neg for Angle {
+    type Output = Angle;
+
+    fn neg(self) -> Angle {
+        Angle::rad(-self.rad)
+    }
+}
+
+impl Mul<f32> for Angle {
+    type Output = Angle;
+
+    fn mul(self, rhs: f32) -> Angle {
+        Angle::rad(self.rad * rhs)
+    }
+}
+
+impl Mul<Angle> for f32 {
+    type Output = Angle;
+
+    fn mul(self, rhs: Angle) -> Angle {
+        rhs * self
+    }
+}
+
+impl Div<f32> for Angle {
+    type Output = Angle;
+
+    fn div(self, rhs: f32) -> Angle {
+        Angle::rad(self.rad / rhs)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawAngle {
+    Degrees(f32),
+    Units(String),
+}
+
+impl TryFrom<RawAngle> for Angle {
+    type Error = String;
+
+    fn try_from(raw: RawAngle) -> Result<Self, Self::Error> {
+        match raw {
+            RawAngle::Degrees(deg) => Ok(Angle::deg(deg)),
+            RawAngle::Units(s) => parse_angle(&s).ok_or_else(|| {
+                format!("invalid angle '{s}': expected a number of degrees or a value ending in deg or rad")
+            }),
+        }
+    }
+}
+
+fn parse_angle(s: &str) -> Option<Angle> {
+    let s = s.trim();
+    let (n, to_angle): (&str, fn(f32) -> Angle) = if let Some(n) = s.strip_suffix("rad") {
+        (n, Angle::rad)
+    } else if let Some(n) = s.strip_suffix("deg").or_else(|| s.strip_suffix('°')) {
+        (n, Angle::deg)
+    } else {
+        return None;
+    };
+
+    n.trim().parse().ok().map(to_angle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+    use std::f32::consts::FRAC_PI_2;
+
+    #[derive(Deserialize)]
+    struct Spec {
+        angle: Angle,
+    }
+
+    #[test_case("angle = 90.0", Some(FRAC_PI_2); "bare number is degrees")]
+    #[test_case("angle = 90", Some(FRAC_PI_2); "integer degrees")]
+    #[test_case("angle = \"90deg\"", Some(FRAC_PI_2); "degrees suffix")]
+    #[test_case("angle = \"90°\"", Some(FRAC_PI_2); "degree sign")]
+    #[test_case("angle = \"1.5708 rad\"", Some(FRAC_PI_2); "radians suffix")]
+    #[test_case("angle = \"90\"", None; "string without unit")]
+    #[test_case("angle = \"1.5 turns\"", None; "unknown unit")]
+    #[test]
+    fn angles_are_parsed_with_units(s: &str, expected: Option<f32>) {
+        let parsed = toml::from_str::<Spec>(s).map(|spec| spec.angle.radians());
+
+        match (parsed, expected) {
+            (Ok(rad), Some(expected)) => assert!((rad - expected).abs() < 1e-4, "{rad}"),
+            (Err(_), None) => (),
+            (parsed, _) => panic!("unexpected result for {s}: {parsed:?}"),
+        }
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        let a = Angle::deg(30.0);
+
+        assert!((a.radians() - 30f32.to_radians()).abs() < 1e-6);
+        assert!((Angle::rad(a.radians()).degrees() - 30.0).abs() < 1e-4);
+        assert!(((a / 2.0).degrees() - 15.0).abs() < 1e-4);
+        assert!((a.sin() - 0.5).abs() < 1e-6);
+    }
+}
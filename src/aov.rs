@@ -0,0 +1,641 @@
+//! Arbitrary output variables (AOVs): additional per-pixel data written alongside the main
+//! render for use in compositing.
+//!
+//! ID mattes are written in the style of Cryptomatte: each pixel stores the IDs covering it along
+//! with the fraction of samples that hit each ID, ranked by coverage.
+//!   https://github.com/Psyop/Cryptomatte
+//!
+//! Motion vectors give the movement of the surface seen through each pixel since the previous
+//! frame for temporal denoisers and motion blur applied in post, while positions allow for
+//! relighting and fog to be added in compositing. Depth maps are written along with the camera
+//! parameters in the conventions of computer vision (x right, y down and z forward) so that
+//! renders can be used as training data for depth estimation, as are segmentation images that
+//! give each tag of the scene objects and materials a flat color.
+use crate::{
+    bvh::{Bvh, MAX_BVH_DEPTH},
+    hit::Interval,
+    mat::M4,
+    output::Output,
+    ray::Camera,
+    Color, P3,
+};
+use image::{ImageResult, Rgb32FImage, RgbImage, Rgba32FImage};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, io};
+
+/// Settings for writing the light in the render split into diffuse, specular and emission passes
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Passes {
+    /// Glossy bounces from lobes rougher than this are blurry enough to go in the diffuse pass
+    #[serde(default = "default_specular_roughness")]
+    pub specular_roughness: f32,
+}
+
+fn default_specular_roughness() -> f32 {
+    0.5
+}
+
+impl Default for Passes {
+    fn default() -> Self {
+        Self {
+            specular_roughness: default_specular_roughness(),
+        }
+    }
+}
+
+/// (id, coverage) pairs ranked by decreasing coverage with ID 0 being the background.
+type Ranked = [(u32, f32); 2];
+
+/// Rank the IDs in a set of samples by coverage, keeping the top two.
+fn rank_ids(ids: &[u32]) -> Ranked {
+    let mut counts: Vec<(u32, usize)> = Vec::new();
+    for &id in ids {
+        match counts.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((id, 1)),
+        }
+    }
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let total = ids.len().max(1) as f32;
+    let mut ranked = [(0, 0.0); 2];
+    for (r, (id, n)) in ranked.iter_mut().zip(counts) {
+        *r = (id, n as f32 / total);
+    }
+
+    ranked
+}
+
+/// The Cryptomatte ID of a name: its 32-bit MurmurHash3 read as a float, with the exponent nudged
+/// away from all zeros or all ones so that it is never denormal, infinite or NaN.
+pub fn name_hash(name: &str) -> f32 {
+    let mut hash = murmur3_32(name.as_bytes(), 0);
+    let exponent = (hash >> 23) & 0xff;
+    if exponent == 0 || exponent == 0xff {
+        hash ^= 1 << 23;
+    }
+
+    f32::from_bits(hash)
+}
+
+/// MurmurHash3 x86 32-bit
+///   https://github.com/aappleby/smhasher/blob/master/src/MurmurHash3.cpp
+fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut h = seed;
+    let blocks = data.chunks_exact(4);
+    let tail = blocks.remainder();
+    for b in blocks {
+        h ^= mix(u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    if !tail.is_empty() {
+        let k = tail.iter().rev().fold(0, |k, &b| k << 8 | b as u32);
+        h ^= mix(k);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+/// Write the ranked IDs of each pixel as their name hashes (hashes[id - 1]) and coverage.
+fn write_matte(
+    output: &Output,
+    name: &str,
+    w: u16,
+    h: u16,
+    ranked: &[Ranked],
+    hashes: &[f32],
+) -> ImageResult<()> {
+    let (w, h) = (w as u32, h as u32);
+    let hash = |id: u32| id.checked_sub(1).map_or(0.0, |i| hashes[i as usize]);
+
+    let raw = ranked
+        .iter()
+        .flat_map(|[(id0, c0), (id1, c1)]| [hash(*id0), *c0, hash(*id1), *c1])
+        .collect();
+    Rgba32FImage::from_raw(w, h, raw)
+        .unwrap()
+        .save(output.aux_path(name, "exr"))?;
+
+    // A coverage weighted preview with each ID shown as a random color (background is black)
+    let raw = ranked
+        .iter()
+        .flat_map(|rs| {
+            let c = rs
+                .iter()
+                .filter(|(id, _)| *id != 0)
+                .fold(Color::BLACK, |acc, (id, cov)| {
+                    acc + Color::from_id(*id) * *cov
+                });
+            [c.r, c.g, c.b]
+        })
+        .collect();
+    let preview = Rgb32FImage::from_raw(w, h, raw).unwrap();
+    image::DynamicImage::ImageRgb32F(preview)
+        .into_rgb8()
+        .save(output.aux_path(name, "png"))
+}
+
+/// Write a half resolution buffer of (mean luminance, variance of the mean) as an EXR with the
+/// relative standard error of each block in the third channel.
+pub fn write_variance(output: &Output, w: u32, h: u32, variance: &[[f32; 2]]) -> ImageResult<()> {
+    let raw = variance
+        .iter()
+        .flat_map(|&[lum, var]| [lum, var, var.sqrt() / lum.max(1e-6)])
+        .collect();
+
+    Rgb32FImage::from_raw(w, h, raw)
+        .unwrap()
+        .save(output.aux_path("variance", "exr"))
+}
+
+/// The motion of the surface seen through the center of each pixel from the previous frame to
+/// this one in pixels, with x to the right and y down. Object motion over the shutter interval is
+/// taken as its motion over a frame and the camera is taken to be still when there is no
+/// previous frame. Only perspective projections are supported, with zero motion otherwise.
+pub fn motion_vectors(camera: &Camera, prev: Option<&Camera>, bvh: &Bvh) -> Vec<[f32; 2]> {
+    let prev = prev.unwrap_or(camera);
+
+    camera.map_pixels(|i, j| {
+        let r = camera.ray_through(i as f32, j as f32);
+        let ray_t = Interval::new(r.epsilon, f32::INFINITY);
+        let before = match bvh.hits(&r, ray_t, &mut [0; MAX_BVH_DEPTH]) {
+            Some(hr) => hr.p - hr.motion,
+            // the background only moves with the rotation of the camera
+            None => prev.center() + r.dir,
+        };
+
+        match prev.project(before) {
+            Some((x, y)) => [i as f32 - x, j as f32 - y],
+            None => [0.0; 2],
+        }
+    })
+}
+
+/// Write motion vectors as an EXR with the x and y motion in pixels in the first two channels.
+pub fn write_motion_vectors(
+    output: &Output,
+    w: u16,
+    h: u16,
+    motion: &[[f32; 2]],
+) -> ImageResult<()> {
+    let raw = motion.iter().flat_map(|&[x, y]| [x, y, 0.0]).collect();
+
+    Rgb32FImage::from_raw(w as u32, h as u32, raw)
+        .unwrap()
+        .save(output.aux_path("motion", "exr"))
+}
+
+/// The position and object ID of the surface seen through the center of each pixel in scene
+/// space, or None for pixels that miss the scene.
+pub fn positions(camera: &Camera, bvh: &Bvh) -> Vec<Option<(P3, u32)>> {
+    camera.map_pixels(|i, j| {
+        let r = camera.ray_through(i as f32, j as f32);
+        let ray_t = Interval::new(r.epsilon, f32::INFINITY);
+
+        bvh.hits(&r, ray_t, &mut [0; MAX_BVH_DEPTH])
+            .map(|hr| (hr.p, hr.obj_id))
+    })
+}
+
+/// Write the scene space position of the surface seen through each pixel if output.position is
+/// set and its position in the space of the object that was hit if output.object_position is set,
+/// using to_object to move from the scene into the space of each object by object ID. Positions
+/// are written as EXRs with alpha marking the pixels that hit the scene.
+pub fn write_positions(
+    camera: &Camera,
+    bvh: &Bvh,
+    output: &Output,
+    to_object: &[M4],
+) -> ImageResult<()> {
+    let (w, h) = camera.dimensions();
+    let positions = positions(camera, bvh);
+    let save = |name: &str, f: &dyn Fn(P3, u32) -> P3| {
+        let raw = positions
+            .iter()
+            .flat_map(|hit| match *hit {
+                Some((p, id)) => {
+                    let p = f(p, id);
+                    [p.x, p.y, p.z, 1.0]
+                }
+                None => [0.0; 4],
+            })
+            .collect();
+
+        Rgba32FImage::from_raw(w as u32, h as u32, raw)
+            .unwrap()
+            .save(output.aux_path(name, "exr"))
+    };
+
+    if output.position {
+        save("position", &|p, _| p)?;
+    }
+    if output.object_position {
+        // object ID 0 is for hittables that are not scene objects
+        save("object_position", &|p, id| match id
+            .checked_sub(1)
+            .and_then(|k| to_object.get(k as usize))
+        {
+            Some(m) => m.transform_point(p),
+            None => p,
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Write the light that scattered from a diffuse or specular lobe at the first hit as separate
+/// EXRs, along with the remaining light emitted at or seen directly through the first hit so
+/// that the three passes sum to the render.
+pub fn write_passes(
+    output: &Output,
+    w: u16,
+    h: u16,
+    pixels: &[Color],
+    diffuse: &[Color],
+    specular: &[Color],
+) -> ImageResult<()> {
+    let save = |name: &str, colors: &mut dyn Iterator<Item = Color>| {
+        let raw = colors.flat_map(|c| [c.r, c.g, c.b]).collect();
+        Rgb32FImage::from_raw(w as u32, h as u32, raw)
+            .unwrap()
+            .save(output.aux_path(name, "exr"))
+    };
+
+    save("diffuse", &mut diffuse.iter().copied())?;
+    save("specular", &mut specular.iter().copied())?;
+    save(
+        "emission",
+        &mut (pixels.iter().zip(diffuse).zip(specular)).map(|((&c, &d), &s)| c + (d + s) * -1.0),
+    )
+}
+
+/// The depth along the view direction and the distance from the camera of the surface seen
+/// through the center of each pixel, or None for pixels that miss the scene. Panoramas have no
+/// single view direction so their depth is also the distance.
+pub fn depths(camera: &Camera, bvh: &Bvh) -> Vec<Option<(f32, f32)>> {
+    let forward = -camera.basis()[2];
+    let planar = camera.project(camera.center() + forward).is_some();
+
+    positions(camera, bvh)
+        .into_iter()
+        .map(|hit| {
+            let d = hit?.0 - camera.center();
+            let dist = d.length();
+            Some((if planar { d.dot(&forward) } else { dist }, dist))
+        })
+        .collect()
+}
+
+/// The focal lengths and principal point (fx, fy, cx, cy) of the camera in pixels, where pixel
+/// centers lie at whole numbers, or None for projections other than perspective.
+pub fn intrinsics(camera: &Camera) -> Option<[f32; 4]> {
+    let [u, v, w] = camera.basis();
+    let ahead = camera.center() - w;
+    let (cx, cy) = camera.project(ahead)?;
+    let (x, _) = camera.project(ahead + u)?;
+    let (_, y) = camera.project(ahead - v)?;
+
+    Some([x - cx, y - cy, cx, cy])
+}
+
+/// Write depth.exr with the depth along the view direction, the distance from the camera and
+/// whether the scene was hit in its channels, along with camera.json giving the intrinsics and
+/// the extrinsics of the camera with x right, y down and z forward.
+pub fn write_depth_map(camera: &Camera, bvh: &Bvh, output: &Output) -> io::Result<()> {
+    let (w, h) = camera.dimensions();
+    let raw = depths(camera, bvh)
+        .into_iter()
+        .flat_map(|d| match d {
+            Some((depth, dist)) => [depth, dist, 1.0],
+            None => [0.0; 3],
+        })
+        .collect();
+    Rgb32FImage::from_raw(w as u32, h as u32, raw)
+        .unwrap()
+        .save(output.aux_path("depth", "exr"))
+        .map_err(io::Error::other)?;
+
+    // rows of the rotation from scene to camera space
+    let [u, v, back] = camera.basis();
+    let rows = [u, -v, -back];
+    let c = camera.center() - P3::ORIGIN;
+    let matrix = |rows: [[f32; 4]; 3]| {
+        let rows: Vec<String> = (rows.iter().chain(&[[0.0, 0.0, 0.0, 1.0]]))
+            // adding zero turns negative zeros positive
+            .map(|r| r.map(|x| x + 0.0))
+            .map(|r| format!("[{}, {}, {}, {}]", r[0], r[1], r[2], r[3]))
+            .collect();
+        format!("[\n    {}\n  ]", rows.join(",\n    "))
+    };
+    let world_to_camera = rows.map(|r| [r.x, r.y, r.z, -r.dot(&c)]);
+    let camera_to_world =
+        [0, 1, 2].map(|k| [rows[0][k], rows[1][k], rows[2][k], camera.center()[k]]);
+    let intrinsics = match intrinsics(camera) {
+        Some([fx, fy, cx, cy]) => {
+            format!("{{ \"fx\": {fx}, \"fy\": {fy}, \"cx\": {cx}, \"cy\": {cy} }}")
+        }
+        None => "null".to_string(),
+    };
+
+    let fields = [
+        format!("  \"width\": {w}"),
+        format!("  \"height\": {h}"),
+        format!("  \"intrinsics\": {intrinsics}"),
+        format!("  \"world_to_camera\": {}", matrix(world_to_camera)),
+        format!("  \"camera_to_world\": {}", matrix(camera_to_world)),
+    ];
+    let json = format!("{{\n{}\n}}\n", fields.join(",\n"));
+    fs::write(output.aux_path("camera", "json"), json)
+}
+
+/// The index into the sorted and deduplicated tags of the object or, failing that, the material
+/// seen through the center of each pixel (0 for untagged pixels and 1 for the first tag), along
+/// with the tags.
+pub fn segment(
+    camera: &Camera,
+    bvh: &Bvh,
+    object_tags: &[Option<String>],
+    material_tags: &[Option<String>],
+) -> (Vec<u32>, Vec<String>) {
+    // every tag in the scene is listed so that labels are the same in each frame of an animation
+    let mut tags: Vec<String> = object_tags
+        .iter()
+        .chain(material_tags)
+        .flatten()
+        .cloned()
+        .collect();
+    tags.sort();
+    tags.dedup();
+
+    let tag = |tags: &[Option<String>], id: u32| -> Option<String> {
+        tags.get(id.checked_sub(1)? as usize)?.clone()
+    };
+    let labels = camera.map_pixels(|i, j| {
+        let r = camera.ray_through(i as f32, j as f32);
+        let ray_t = Interval::new(r.epsilon, f32::INFINITY);
+        let Some(hr) = bvh.hits(&r, ray_t, &mut [0; MAX_BVH_DEPTH]) else {
+            return 0;
+        };
+        let t = tag(object_tags, hr.obj_id).or_else(|| tag(material_tags, hr.mat_id));
+
+        t.map_or(0, |t| tags.binary_search(&t).unwrap() as u32 + 1)
+    });
+
+    (labels, tags)
+}
+
+/// Write segmentation.png giving each tag of the objects and materials in the scene a flat color,
+/// with untagged pixels black, along with a JSON legend of the label and color of each tag.
+pub fn write_segmentation(
+    camera: &Camera,
+    bvh: &Bvh,
+    output: &Output,
+    object_tags: &[Option<String>],
+    material_tags: &[Option<String>],
+) -> io::Result<()> {
+    let (w, h) = camera.dimensions();
+    let (labels, tags) = segment(camera, bvh, object_tags, material_tags);
+    let color = |label: u32| match label {
+        0 => [0; 3],
+        _ => Color::from_id(label).to_rgb8(),
+    };
+
+    let raw = labels.iter().flat_map(|&l| color(l)).collect();
+    RgbImage::from_raw(w as u32, h as u32, raw)
+        .unwrap()
+        .save(output.aux_path("segmentation", "png"))
+        .map_err(io::Error::other)?;
+
+    let entries: Vec<String> = (tags.iter().enumerate())
+        .map(|(i, tag)| {
+            let [r, g, b] = color(i as u32 + 1);
+            format!(
+                "    {}: {{ \"label\": {}, \"color\": [{r}, {g}, {b}] }}",
+                json_str(tag),
+                i + 1
+            )
+        })
+        .collect();
+    let legend = format!(
+        "{{\n  \"untagged\": {{ \"label\": 0, \"color\": [0, 0, 0] }},\n  \"tags\": {{\n{}\n  }}\n}}\n",
+        entries.join(",\n")
+    );
+    fs::write(output.aux_path("segmentation", "json"), legend)
+}
+
+pub fn json_str(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn manifest(names: &[String]) -> BTreeMap<&str, String> {
+    (names.iter())
+        .map(|n| (n.as_str(), format!("{:08x}", name_hash(n).to_bits())))
+        .collect()
+}
+
+/// Trace output.aov_samples primary rays per pixel and write object and material ID mattes
+/// along with a JSON manifest mapping names to the hex of their hashes as Cryptomatte does.
+pub fn write_id_mattes(
+    camera: &Camera,
+    bvh: &Bvh,
+    output: &Output,
+    object_names: &[String],
+    material_names: &[String],
+) -> ImageResult<()> {
+    let (w, h) = camera.dimensions();
+    let n = output.aov_samples.max(1) as usize;
+
+    let (objects, materials): (Vec<Ranked>, Vec<Ranked>) = camera
+        .map_pixels(|i, j| {
+            let (mut obj_ids, mut mat_ids) = (Vec::with_capacity(n), Vec::with_capacity(n));
+            for _ in 0..n {
+                let (_, hr) = camera.primary_hit(i, j, bvh);
+                obj_ids.push(hr.as_ref().map(|hr| hr.obj_id).unwrap_or(0));
+                mat_ids.push(hr.as_ref().map(|hr| hr.mat_id).unwrap_or(0));
+            }
+
+            (rank_ids(&obj_ids), rank_ids(&mat_ids))
+        })
+        .into_iter()
+        .unzip();
+
+    let hashes = |names: &[String]| -> Vec<f32> { names.iter().map(|n| name_hash(n)).collect() };
+    write_matte(output, "object_id", w, h, &objects, &hashes(object_names))?;
+    write_matte(
+        output,
+        "material_id",
+        w,
+        h,
+        &materials,
+        &hashes(material_names),
+    )?;
+
+    let manifest = serde_json::json!({
+        "objects": manifest(object_names),
+        "materials": manifest(material_names),
+    });
+    let json = serde_json::to_string_pretty(&manifest).map_err(io::Error::other)?;
+    fs::write(output.aux_path("ids", "json"), json + "\n")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        angle::Angle,
+        hit::{Hittable, Quad},
+        material::CLAY,
+        p,
+        ray::{Background, RenderMode},
+        v, P3, V3,
+    };
+    use simple_test_case::test_case;
+
+    #[test_case(&[], [(0, 0.0), (0, 0.0)]; "empty")]
+    #[test_case(&[3, 3, 3, 3], [(3, 1.0), (0, 0.0)]; "single id")]
+    #[test_case(&[1, 2, 2, 2], [(2, 0.75), (1, 0.25)]; "two ids")]
+    #[test_case(&[1, 2, 3, 3], [(3, 0.5), (1, 0.25)]; "ties broken by id")]
+    #[test]
+    fn rank_ids_works(ids: &[u32], expected: Ranked) {
+        assert_eq!(rank_ids(ids), expected);
+    }
+
+    #[test_case("", 0, 0; "empty")]
+    #[test_case("", 1, 0x514e_28b7; "empty with seed")]
+    #[test_case("test", 0, 0xba6b_d213; "one block")]
+    #[test_case("Hello, world!", 0, 0xc036_3e43; "with tail")]
+    #[test_case("The quick brown fox jumps over the lazy dog", 0, 0x2e4f_f723; "sentence")]
+    #[test]
+    fn murmur3_matches_reference_values(data: &str, seed: u32, expected: u32) {
+        assert_eq!(murmur3_32(data.as_bytes(), seed), expected);
+    }
+
+    #[test]
+    fn name_hashes_are_normal_floats() {
+        let names = (0..10_000).map(|i| format!("object{i}"));
+
+        assert!(names.map(|n| name_hash(&n)).all(f32::is_normal));
+    }
+
+    fn camera(from: P3) -> Camera {
+        Camera::new(
+            1.0,
+            4,
+            1,
+            0,
+            1,
+            Background::Solid(Color::BLACK),
+            Angle::deg(90.0),
+            from,
+            from + v!(0, 0, -1),
+            v!(0, 1, 0),
+            Angle::ZERO,
+            1.0,
+            RenderMode::Beauty,
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn id_mattes_store_the_name_hashes_in_the_manifest() {
+        let wall = Quad::new(p!(-10, -10, -2), v!(20, 0, 0), v!(0, 20, 0), &CLAY);
+        let bvh = Bvh::new(vec![Hittable::from(wall).with_id(1, 1)]);
+        let path = std::env::temp_dir().join("raymart_ids.png");
+        let output = Output {
+            path: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let names = |n: &str| vec![n.to_string()];
+        write_id_mattes(
+            &camera(p!(0, 0, 0)),
+            &bvh,
+            &output,
+            &names("wall"),
+            &names("clay"),
+        )
+        .unwrap();
+
+        let json = fs::read_to_string(output.aux_path("ids", "json")).unwrap();
+        let matte = image::open(output.aux_path("object_id", "exr"))
+            .unwrap()
+            .into_rgba32f();
+        fs::remove_file(output.aux_path("ids", "json")).unwrap();
+        for name in ["object_id", "material_id"] {
+            fs::remove_file(output.aux_path(name, "exr")).unwrap();
+            fs::remove_file(output.aux_path(name, "png")).unwrap();
+        }
+
+        let manifest: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let hex = manifest["objects"]["wall"].as_str().unwrap();
+        let [id, coverage, ..] = matte.get_pixel(0, 0).0;
+        assert_eq!(u32::from_str_radix(hex, 16).unwrap(), id.to_bits());
+        assert_eq!(coverage, 1.0);
+        assert_eq!(
+            manifest["materials"]["clay"],
+            format!("{:08x}", name_hash("clay").to_bits())
+        );
+    }
+
+    #[test]
+    fn pixels_are_labelled_by_object_tags_then_material_tags() {
+        let quad = |x: f32| Quad::new(p!(x, -10, -2), v!(1, 0, 0), v!(0, 20, 0), &CLAY);
+        // the image spans x in [-2, 2] on the wall with one column per unit
+        let bvh = Bvh::new(vec![
+            Hittable::from(quad(-2.0)).with_id(1, 1),
+            Hittable::from(quad(-1.0)).with_id(2, 1),
+            Hittable::from(quad(0.0)).with_id(3, 2),
+        ]);
+        let object_tags = [None, Some("car".to_string()), None];
+        let material_tags = [Some("road".to_string()), None];
+
+        let (labels, tags) = segment(&camera(p!(0, 0, 0)), &bvh, &object_tags, &material_tags);
+
+        assert_eq!(tags, ["car", "road"]);
+        assert_eq!(&labels[..4], [2, 1, 0, 0]);
+    }
+
+    #[test]
+    fn depth_is_along_the_view_direction() {
+        let wall = Quad::new(p!(-10, -10, -2), v!(20, 0, 0), v!(0, 20, 0), &CLAY);
+        let bvh = Bvh::new(vec![wall.into()]);
+        let c = camera(p!(0, 0, 0));
+
+        let depths = depths(&c, &bvh);
+
+        assert_eq!(intrinsics(&c), Some([2.0, 2.0, 1.5, 1.5]));
+        for (depth, dist) in depths.into_iter().map(Option::unwrap) {
+            assert!((depth - 2.0).abs() < 1e-5 && dist > depth, "{depth} {dist}");
+        }
+    }
+
+    #[test]
+    fn motion_vectors_follow_the_camera() {
+        // pixels are 1 unit apart on the wall so a step to the right moves it one pixel left
+        let wall = Quad::new(p!(-10, -10, -2), v!(20, 0, 0), v!(0, 20, 0), &CLAY);
+        let bvh = Bvh::new(vec![wall.into()]);
+        let (prev, cur) = (camera(p!(0, 0, 0)), camera(p!(1, 0, 0)));
+
+        let still = motion_vectors(&cur, None, &bvh);
+        let moved = motion_vectors(&cur, Some(&prev), &bvh);
+
+        assert!(
+            still.iter().all(|&[x, y]| x == 0.0 && y == 0.0),
+            "{still:?}"
+        );
+        for [x, y] in moved {
+            assert!((x + 1.0).abs() < 1e-4 && y.abs() < 1e-4, "{x} {y}");
+        }
+    }
+}
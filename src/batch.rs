@@ -0,0 +1,317 @@
+//! Rendering a batch of jobs listed in a manifest, each one a scene file with optional overrides
+//! of its settings, so that comparison grids and test suites don't need a shell loop.
+//!
+//! ```toml
+//! parallel = 2         # number of jobs rendered at once
+//! scene = "scene.toml" # used by jobs that don't give their own scene
+//!
+//! [[jobs]]
+//! name = "shallow"
+//! set = { max_bounces = 4 }
+//!
+//! [[jobs]]
+//! scene = "scenes/dragon.toml"
+//! set = { samples_per_pixel = 64, output = { path = "dragon.png" } }
+//! ```
+//!
+//! Named jobs have their name added to the output path of the scene (in the same way as looks)
+//! unless they set the output path themselves.
+use crate::scene::Scene;
+use serde::Deserialize;
+use std::{
+    fs,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    #[serde(default = "default_parallel")]
+    pub parallel: usize,
+    #[serde(default)]
+    pub scene: Option<String>,
+    pub jobs: Vec<Job>,
+}
+
+fn default_parallel() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Job {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub scene: Option<String>,
+    /// Settings merged over those given in the scene file
+    #[serde(default)]
+    pub set: toml::Table,
+}
+
+/// The outcome of a single job of a batch
+#[derive(Debug)]
+pub struct JobResult {
+    pub name: String,
+    pub scene: String,
+    pub output: Option<String>,
+    pub time: Duration,
+    pub error: Option<String>,
+}
+
+impl Manifest {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let s = fs::read_to_string(path).map_err(|e| format!("unable to read {path}: {e}"))?;
+
+        Self::parse(&s)
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        toml::from_str(s).map_err(|e| format!("invalid manifest: {e}"))
+    }
+
+    /// The scene file and the scene to render for each job, or the reason it could not be
+    /// loaded.
+    pub fn scenes(&self) -> Vec<(String, Result<Scene, String>)> {
+        self.jobs
+            .iter()
+            .map(|job| match job.scene.as_ref().or(self.scene.as_ref()) {
+                Some(path) => (path.clone(), job.load(path)),
+                None => (String::new(), Err("no scene given".to_string())),
+            })
+            .collect()
+    }
+
+    /// Render each job that can be loaded with render, running up to parallel jobs at once.
+    pub fn run(&self, render: impl Fn(&Scene, &str) + Sync) -> Vec<JobResult> {
+        let jobs = (self.jobs.iter().enumerate())
+            .zip(self.scenes())
+            .map(|((i, job), (path, scene))| (job.label(i), path, scene))
+            .collect();
+
+        run_scenes(self.parallel, jobs, render)
+    }
+}
+
+/// Render each of the named scenes (given with the path they were loaded from) that could be
+/// loaded with render, running up to parallel jobs at once.
+pub fn run_scenes(
+    parallel: usize,
+    jobs: Vec<(String, String, Result<Scene, String>)>,
+    render: impl Fn(&Scene, &str) + Sync,
+) -> Vec<JobResult> {
+    let parallel = parallel.clamp(1, jobs.len().max(1));
+
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, JobResult)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..parallel)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some((name, path, scene)) = jobs.get(i) else {
+                            return done;
+                        };
+                        done.push((i, run_job(name, path, scene, &render)));
+                    }
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
+fn run_job(
+    name: &str,
+    path: &str,
+    scene: &Result<Scene, String>,
+    render: &impl Fn(&Scene, &str),
+) -> JobResult {
+    let start = Instant::now();
+    let (output, error) = match scene {
+        Ok(s) => {
+            eprintln!("\njob = {name}");
+            render(s, path);
+            (Some(s.output.path()), None)
+        }
+        Err(e) => (None, Some(e.clone())),
+    };
+
+    JobResult {
+        name: name.to_string(),
+        scene: path.to_string(),
+        output,
+        time: start.elapsed(),
+        error,
+    }
+}
+
+impl Job {
+    /// The name shown for the job in the summary.
+    fn label(&self, i: usize) -> String {
+        self.name.clone().unwrap_or_else(|| format!("#{}", i + 1))
+    }
+
+    /// Load the scene at path with the settings of this job merged over it.
+    pub fn load(&self, path: &str) -> Result<Scene, String> {
+        let s = fs::read_to_string(path).map_err(|e| format!("unable to read {path}: {e}"))?;
+        // errors are kept to a single line for the summary
+        let invalid = |e: toml::de::Error| format!("invalid scene: {}", e.message());
+        let mut table: toml::Table = s.parse().map_err(invalid)?;
+        merge(&mut table, &self.set);
+        let merged = toml::to_string(&table).map_err(|e| format!("invalid settings: {e}"))?;
+        let mut scene = Scene::try_from_toml(&merged).map_err(invalid)?;
+        scene
+            .validate()
+            .map_err(|e| format!("invalid scene: {e}"))?;
+
+        let sets_path = self.set.get("output").and_then(|o| o.get("path")).is_some();
+        if let (Some(name), false) = (&self.name, sets_path) {
+            let ext = scene.output.format.extension();
+            scene.output.path = Some(scene.output.aux_path(name, ext));
+        }
+
+        Ok(scene)
+    }
+}
+
+/// Merge the values of over into base, merging nested tables rather than replacing them.
+fn merge(base: &mut toml::Table, over: &toml::Table) {
+    for (k, v) in over {
+        match (base.get_mut(k), v) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => merge(b, o),
+            _ => {
+                base.insert(k.clone(), v.clone());
+            }
+        }
+    }
+}
+
+/// Print a table of the outcome of each job.
+pub fn print_summary(results: &[JobResult]) {
+    let width = |f: fn(&JobResult) -> usize, title: &str| {
+        results.iter().map(f).max().unwrap_or(0).max(title.len())
+    };
+    let name_w = width(|r| r.name.len(), "job");
+    let scene_w = width(|r| r.scene.len(), "scene");
+
+    println!(
+        "{:name_w$}  {:scene_w$}  {:>8}  result",
+        "job", "scene", "time"
+    );
+    for r in results {
+        let result = match (&r.error, &r.output) {
+            (Some(e), _) => format!("failed: {e}"),
+            (None, Some(output)) => output.clone(),
+            (None, None) => String::new(),
+        };
+        println!(
+            "{:name_w$}  {:scene_w$}  {:>7.1}s  {result}",
+            r.name,
+            r.scene,
+            r.time.as_secs_f32()
+        );
+    }
+
+    let failed = results.iter().filter(|r| r.error.is_some()).count();
+    println!(
+        "\n{} jobs rendered, {failed} failed",
+        results.len() - failed
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    const SCENE: &str = "
+samples_per_pixel = 10
+max_bounces = 5
+fov = 40.0
+image_width = 100
+aspect_ratio = 1.0
+from = [0.0, 0.0, 5.0]
+at = [0.0, 0.0, 0.0]
+v_up = [0.0, 1.0, 0.0]
+as_points = false
+point_radius = 0.0
+bg = 0.5
+
+[materials.white]
+kind = \"solid\"
+color = 0.5
+
+[output]
+path = \"out.png\"
+format = \"png\"
+";
+
+    #[test]
+    fn settings_are_merged_over_the_scene() {
+        let mut base: toml::Table = SCENE.parse().unwrap();
+        let over: toml::Table = "max_bounces = 2\noutput = { format = \"ppm\" }"
+            .parse()
+            .unwrap();
+        merge(&mut base, &over);
+
+        assert_eq!(base["max_bounces"].as_integer(), Some(2));
+        assert_eq!(base["output"]["format"].as_str(), Some("ppm"));
+        assert_eq!(base["output"]["path"].as_str(), Some("out.png"));
+        assert_eq!(base["samples_per_pixel"].as_integer(), Some(10));
+    }
+
+    #[test]
+    fn jobs_are_rendered_in_order_with_failures_reported() {
+        let path = std::env::temp_dir().join("raymart-batch-test.toml");
+        fs::write(&path, SCENE).unwrap();
+        let manifest = Manifest::parse(&format!(
+            "parallel = 2
+scene = {path:?}
+
+[[jobs]]
+name = \"shallow\"
+set = {{ max_bounces = 1 }}
+
+[[jobs]]
+set = {{ output = {{ path = \"deep.png\" }} }}
+
+[[jobs]]
+scene = \"does/not/exist.toml\"
+
+[[jobs]]
+name = \"broken\"
+set = {{ max_bounces = \"lots\" }}
+"
+        ))
+        .unwrap();
+
+        let rendered = Mutex::new(Vec::new());
+        let results = manifest.run(|s, _| rendered.lock().unwrap().push(s.max_bounces));
+        let mut rendered = rendered.into_inner().unwrap();
+        rendered.sort();
+
+        assert_eq!(rendered, vec![1, 5]);
+        let summary: Vec<_> = results
+            .iter()
+            .map(|r| (r.name.as_str(), r.output.as_deref(), r.error.is_some()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("shallow", Some("out.shallow.png"), false),
+                ("#2", Some("deep.png"), false),
+                ("#3", None, true),
+                ("broken", None, true),
+            ]
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Burn-in of render metadata as a block of text in the corner of an image using a tiny 3x5
+//! pixel font. Lower case letters are drawn as upper case and unsupported characters as '?'.
+use crate::Color;
+
+const GLYPH_W: usize = 3;
+const GLYPH_H: usize = 5;
+
+/// Rows of the glyph for c from top to bottom with the leftmost pixel in the highest bit.
+fn glyph(c: char) -> [u8; GLYPH_H] {
+    match c.to_ascii_uppercase() {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        '[' => [0b110, 0b100, 0b100, 0b100, 0b110],
+        ']' => [0b011, 0b001, 0b001, 0b001, 0b011],
+        _ => [0b110, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+/// Draw the lines of text in white over a darkened block in the bottom left corner of the
+/// encoded image. The font is scaled up with the image height and anything that does not fit
+/// is cut off.
+pub fn burn_in(pixels: &mut [Color], width: usize, height: usize, lines: &[String]) {
+    let scale = (height / 360).max(1);
+    let (advance, line_height, pad) = ((GLYPH_W + 1) * scale, (GLYPH_H + 2) * scale, 2 * scale);
+    let cols = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    let block_w = (cols * advance + 2 * pad).min(width);
+    let block_h = (lines.len() * line_height + 2 * pad).min(height);
+    let top = height - block_h;
+
+    for y in top..height {
+        for p in pixels[y * width..y * width + block_w].iter_mut() {
+            *p *= 0.3;
+        }
+    }
+
+    for (row, line) in lines.iter().enumerate() {
+        let y0 = top + pad + row * line_height;
+        for (col, c) in line.chars().enumerate() {
+            let x0 = pad + col * advance;
+            for (gy, bits) in glyph(c).iter().enumerate() {
+                for gx in 0..GLYPH_W {
+                    if bits & (1 << (GLYPH_W - 1 - gx)) == 0 {
+                        continue;
+                    }
+                    for (dx, dy) in (0..scale).flat_map(|dx| (0..scale).map(move |dy| (dx, dy))) {
+                        let (x, y) = (x0 + gx * scale + dx, y0 + gy * scale + dy);
+                        if x < width && y < height {
+                            pixels[y * width + x] = Color::WHITE;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burn_in_is_drawn_in_the_bottom_left() {
+        let (w, h) = (40, 20);
+        let mut pixels = vec![Color::grey(0.5); w * h];
+        burn_in(&mut pixels, w, h, &["T".to_string()]);
+        let at = |x: usize, y: usize| pixels[y * w + x];
+        let dark = Color::grey(0.5) * 0.3;
+
+        // a single character with a 2px border
+        assert_eq!(at(0, 8), Color::grey(0.5));
+        assert_eq!(at(8, 10), Color::grey(0.5));
+        assert_eq!(at(0, 9), dark);
+        assert_eq!(at(7, 19), dark);
+        // the top bar of the T then its stem
+        assert_eq!(
+            (at(2, 11), at(3, 11), at(4, 11)),
+            (Color::WHITE, Color::WHITE, Color::WHITE)
+        );
+        assert_eq!((at(2, 12), at(3, 12)), (dark, Color::WHITE));
+    }
+}
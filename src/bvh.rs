@@ -2,12 +2,71 @@
 //! See Section 3 of https://raytracing.github.io/books/RayTracingTheNextWeek.html for the details
 
 use crate::{
-    hit::{HitRecord, Hittable, Interval},
+    counters::{self, Counter},
+    hit::{HitRecord, Hittable, Interval, RAY_EPSILON},
+    lights::Lights,
     Ray, P3, V3,
 };
+use serde::Deserialize;
 use std::ops::Add;
 
 pub const MAX_BVH_DEPTH: usize = 32;
+/// Minimum size of each axis of a bounding box at a scene scale of 1
+const MIN_BBOX_SIZE: f32 = 0.0001;
+
+/// Tuning for how trees are built and traversed
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Accel {
+    /// Largest number of hittables kept together in a leaf
+    #[serde(default = "default_max_leaf_size")]
+    pub max_leaf_size: usize,
+    #[serde(default)]
+    pub split: Split,
+    #[serde(default)]
+    pub traversal: Traversal,
+}
+
+impl Accel {
+    pub const DEFAULT: Accel = Accel {
+        max_leaf_size: 1,
+        split: Split::Median,
+        traversal: Traversal::Nearest,
+    };
+}
+
+impl Default for Accel {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+fn default_max_leaf_size() -> usize {
+    Accel::DEFAULT.max_leaf_size
+}
+
+/// Where each node is split along its longest axis
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Split {
+    /// Equal numbers of hittables on each side: fast to build and always balanced
+    #[default]
+    Median,
+    /// Minimize the surface area heuristic: slower to build but usually faster to trace for
+    /// scenes with unevenly distributed geometry
+    Sah,
+}
+
+/// The order that the children of a node are visited in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Traversal {
+    /// The child whose bounds the ray enters first
+    #[default]
+    Nearest,
+    /// The child on the side of the split that the ray is heading from, without comparing
+    /// distances
+    Axis,
+}
 
 #[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct AABBox {
@@ -19,24 +78,29 @@ pub struct AABBox {
 }
 
 impl AABBox {
-    pub const EMPTY: AABBox = AABBox::new(Interval::EMPTY, Interval::EMPTY, Interval::EMPTY);
+    pub const EMPTY: AABBox =
+        AABBox::new_unpadded(Interval::EMPTY, Interval::EMPTY, Interval::EMPTY);
     pub const UNIVERSE: AABBox =
-        AABBox::new(Interval::UNIVERSE, Interval::UNIVERSE, Interval::UNIVERSE);
+        AABBox::new_unpadded(Interval::UNIVERSE, Interval::UNIVERSE, Interval::UNIVERSE);
 
-    pub const fn new(x: Interval, y: Interval, z: Interval) -> AABBox {
-        let mut bbox = AABBox {
-            x,
-            y,
-            z,
-            min: wide::f32x4::ZERO,
-            max: wide::f32x4::ZERO,
-        };
+    pub fn new(x: Interval, y: Interval, z: Interval) -> AABBox {
+        let mut bbox = AABBox::new_unpadded(x, y, z);
         bbox.pad_to_minimum();
 
         bbox
     }
 
-    pub const fn new_enclosing(a: AABBox, b: AABBox) -> AABBox {
+    const fn new_unpadded(x: Interval, y: Interval, z: Interval) -> AABBox {
+        AABBox {
+            x,
+            y,
+            z,
+            min: wide::f32x4::new([x.min, y.min, z.min, 0.0]),
+            max: wide::f32x4::new([x.max, y.max, z.max, 0.0]),
+        }
+    }
+
+    pub fn new_enclosing(a: AABBox, b: AABBox) -> AABBox {
         let mut bbox = AABBox {
             x: Interval::new_enclosing(a.x, b.x),
             y: Interval::new_enclosing(a.y, b.y),
@@ -49,18 +113,18 @@ impl AABBox {
         bbox
     }
 
-    fn new_containing(hittables: &[Hittable]) -> Self {
-        let mut bbox = AABBox::EMPTY;
-        for obj in hittables.iter() {
-            bbox = AABBox::new_enclosing(bbox, obj.bounding_box());
-        }
+    /// The box containing all of the hittables with each of their boxes padded to min_size.
+    fn new_containing(hittables: &[Hittable], min_size: f32) -> Self {
+        Self::new_containing_all(hittables.iter().map(|h| h.bounding_box().padded(min_size)))
+    }
 
-        bbox
+    fn new_containing_all(bboxes: impl Iterator<Item = AABBox>) -> Self {
+        bboxes.fold(AABBox::EMPTY, AABBox::new_enclosing)
     }
 
     /// Treat the two points a and b as extrema for the bounding box, so we don't require a
     /// particular minimum/maximum coordinate order.
-    pub const fn new_from_points(a: P3, b: P3) -> AABBox {
+    pub fn new_from_points(a: P3, b: P3) -> AABBox {
         let (x1, x2) = if a.x <= b.x { (a.x, b.x) } else { (b.x, a.x) };
         let (y1, y2) = if a.y <= b.y { (a.y, b.y) } else { (b.y, a.y) };
         let (z1, z2) = if a.z <= b.z { (a.z, b.z) } else { (b.z, a.z) };
@@ -120,8 +184,12 @@ impl AABBox {
         }
     }
 
-    const fn pad_to_minimum(&mut self) {
-        let delta = 0.0001;
+    fn pad_to_minimum(&mut self) {
+        self.pad_to(MIN_BBOX_SIZE);
+    }
+
+    /// Expand any axis smaller than delta so that flat boxes can still be hit.
+    fn pad_to(&mut self, delta: f32) {
         if self.x.size() < delta {
             self.x = self.x.expand(delta);
         }
@@ -136,7 +204,18 @@ impl AABBox {
         self.max = wide::f32x4::new([self.x.max, self.y.max, self.z.max, 0.0]);
     }
 
-    pub const fn expand(&self, delta: f32) -> AABBox {
+    fn surface_area(&self) -> f32 {
+        let (x, y, z) = (self.x.size(), self.y.size(), self.z.size());
+
+        2.0 * (x * y + y * z + z * x)
+    }
+
+    fn padded(mut self, delta: f32) -> AABBox {
+        self.pad_to(delta);
+        self
+    }
+
+    pub fn expand(&self, delta: f32) -> AABBox {
         AABBox::new(
             self.x.expand(delta),
             self.y.expand(delta),
@@ -145,6 +224,47 @@ impl AABBox {
     }
 }
 
+/// A convex region bounded by planes, used to find the parts of a tree that camera rays can
+/// reach.
+#[derive(Debug, Clone)]
+pub struct Frustum {
+    /// Inward facing normals n and offsets d of each plane: p is inside when n.p + d >= 0
+    planes: Vec<(V3, f32)>,
+}
+
+impl Frustum {
+    /// The region in front of apex covered by rays through the quad with the given corners
+    /// (given in order around its edge).
+    pub fn new(apex: P3, corners: [P3; 4]) -> Self {
+        let mid = corners.iter().fold(V3::ZERO, |acc, &c| acc + (c - apex)) / 4.0;
+        let forward = mid.unit_vector();
+        let mut planes = vec![(forward, -forward.dot(&(apex - P3::ORIGIN)))];
+
+        for i in 0..4 {
+            let (a, b) = (corners[i] - apex, corners[(i + 1) % 4] - apex);
+            let mut n = a.cross(&b).unit_vector();
+            if n.dot(&mid) < 0.0 {
+                n = -n;
+            }
+            planes.push((n, -n.dot(&(apex - P3::ORIGIN))));
+        }
+
+        Self { planes }
+    }
+
+    /// Whether any part of the box from min to max may be inside. Only the corner furthest along
+    /// the normal of each plane needs to be checked to know the box is entirely outside of it.
+    fn may_contain(&self, min: [f32; 3], max: [f32; 3]) -> bool {
+        self.planes.iter().all(|(n, d)| {
+            let corner = |i: usize, n: f32| if n >= 0.0 { max[i] } else { min[i] };
+            let p = V3::new(corner(0, n.x), corner(1, n.y), corner(2, n.z));
+            // NaN from infinite bounds counts as inside
+            let dist = n.dot(&p) + d;
+            dist >= 0.0 || dist.is_nan()
+        })
+    }
+}
+
 impl Add<V3> for AABBox {
     type Output = AABBox;
 
@@ -166,6 +286,7 @@ pub struct FatNode {
     bbox: AABBox,
     start: usize, // start of children if n is None, else start of hittables
     n: Option<usize>,
+    axis: u8, // axis the children were split along
 }
 
 impl FatNode {
@@ -174,19 +295,24 @@ impl FatNode {
             bbox,
             start,
             n: None,
+            axis: 0,
         }
     }
 }
 
+/// Split the hittables (given as indices into bboxes) of a node between two children.
+#[allow(clippy::too_many_arguments)]
 fn split(
     parent_idx: usize,
     start: usize,
     n: usize,
     depth: usize,
     nodes: &mut Vec<FatNode>,
-    hittables: &mut [Hittable],
+    ix: &mut [usize],
+    bboxes: &[AABBox],
+    accel: &Accel,
 ) {
-    if n == 1 || depth >= MAX_BVH_DEPTH {
+    if n <= accel.max_leaf_size.max(1) || depth >= MAX_BVH_DEPTH {
         // remaining hittables sit in this node
         let parent = &mut nodes[parent_idx];
         parent.start = start;
@@ -194,28 +320,109 @@ fn split(
         return;
     }
 
-    // Split into two halves and recursively split the children
+    // Split in two along the longest axis and recursively split the children
     let axis = nodes[parent_idx].bbox.longest_axis();
-    hittables[start..(start + n)].sort_by(|a, b| {
-        let a_axis_interval = a.bounding_box().axis_interval(axis);
-        let b_axis_interval = b.bounding_box().axis_interval(axis);
-        a_axis_interval.min.total_cmp(&b_axis_interval.min)
-    });
-
-    let nleft = n / 2;
+    nodes[parent_idx].axis = axis as u8;
+    let nleft = match accel.split {
+        Split::Median => {
+            ix[start..(start + n)].sort_by(|&a, &b| {
+                let a_axis_interval = bboxes[a].axis_interval(axis);
+                let b_axis_interval = bboxes[b].axis_interval(axis);
+                a_axis_interval.min.total_cmp(&b_axis_interval.min)
+            });
+            n / 2
+        }
+        Split::Sah => {
+            let centroid = |i: usize| {
+                let interval = bboxes[i].axis_interval(axis);
+                interval.min + interval.max
+            };
+            ix[start..(start + n)].sort_by(|&a, &b| centroid(a).total_cmp(&centroid(b)));
+            sah_split(&ix[start..(start + n)], bboxes).unwrap_or(n / 2)
+        }
+    };
     let nright = n - nleft;
 
-    let lbbox = AABBox::new_containing(&hittables[start..start + nleft]);
-    nodes.push(FatNode::new(lbbox, start));
-    let rbbox = AABBox::new_containing(&hittables[start + nleft..start + n]);
-    nodes.push(FatNode::new(rbbox, start + nleft));
+    let containing = |ix: &[usize]| AABBox::new_containing_all(ix.iter().map(|&i| bboxes[i]));
+    nodes.push(FatNode::new(containing(&ix[start..start + nleft]), start));
+    nodes.push(FatNode::new(
+        containing(&ix[start + nleft..start + n]),
+        start + nleft,
+    ));
 
     let lidx = nodes.len() - 2;
     let ridx = nodes.len() - 1;
     nodes[parent_idx].start = lidx;
 
-    split(lidx, start, nleft, depth + 1, nodes, hittables);
-    split(ridx, start + nleft, nright, depth + 1, nodes, hittables);
+    split(lidx, start, nleft, depth + 1, nodes, ix, bboxes, accel);
+    split(
+        ridx,
+        start + nleft,
+        nright,
+        depth + 1,
+        nodes,
+        ix,
+        bboxes,
+        accel,
+    );
+}
+
+/// The number of the (sorted) hittables to place in the left child that minimizes the summed
+/// surface area of each child weighted by the number of hittables inside of it.
+fn sah_split(ix: &[usize], bboxes: &[AABBox]) -> Option<usize> {
+    let n = ix.len();
+    let mut right_area = vec![0.0; n];
+    let mut right = AABBox::EMPTY;
+    for i in (1..n).rev() {
+        right = AABBox::new_enclosing(right, bboxes[ix[i]]);
+        right_area[i] = right.surface_area();
+    }
+
+    let mut left = AABBox::EMPTY;
+    let mut best = None;
+    let mut best_cost = f32::INFINITY;
+    for i in 1..n {
+        left = AABBox::new_enclosing(left, bboxes[ix[i - 1]]);
+        let cost = left.surface_area() * i as f32 + right_area[i] * (n - i) as f32;
+        if cost < best_cost {
+            best_cost = cost;
+            best = Some(i);
+        }
+    }
+
+    best
+}
+
+/// Reorder items so that item i is moved to the position of i in order.
+fn permute<T>(items: Vec<T>, order: &[usize]) -> Vec<T> {
+    let mut slots: Vec<Option<T>> = items.into_iter().map(Some).collect();
+
+    order.iter().map(|&i| slots[i].take().unwrap()).collect()
+}
+
+/// Reorder items so that runs of `leaf_size` neighbouring items are close together in space,
+/// using the same median split as the BVH construction. Used to group primitives for packed
+/// (SIMD) intersection tests before the BVH is built over the groups.
+pub fn sort_spatially<T>(items: &mut [T], leaf_size: usize, bbox: impl Fn(&T) -> AABBox + Copy) {
+    if items.len() <= leaf_size {
+        return;
+    }
+
+    let axis = items
+        .iter()
+        .fold(AABBox::EMPTY, |acc, t| AABBox::new_enclosing(acc, bbox(t)))
+        .longest_axis();
+    items.sort_by(|a, b| {
+        let a_axis_interval = bbox(a).axis_interval(axis);
+        let b_axis_interval = bbox(b).axis_interval(axis);
+        a_axis_interval.min.total_cmp(&b_axis_interval.min)
+    });
+
+    // Keep the left half a multiple of leaf_size so groups never straddle the split
+    let nleft = (items.len() / 2).div_ceil(leaf_size) * leaf_size;
+    let (left, right) = items.split_at_mut(nleft);
+    sort_spatially(left, leaf_size, bbox);
+    sort_spatially(right, leaf_size, bbox);
 }
 
 #[derive(Debug, Clone)]
@@ -224,11 +431,13 @@ pub struct Node {
     max: wide::f32x4,
     start: usize, // start of children if n is None, else start of hittables
     n: Option<usize>,
+    axis: u8,
 }
 
 impl Node {
     #[inline]
     pub fn hit_dist(&self, r: &Ray, ray_t: Interval) -> f32 {
+        counters::add(Counter::BboxTests, 1);
         let tmin = (self.min - r.ro) * r.inv_dir;
         let tmax = (self.max - r.ro) * r.inv_dir;
         let t1 = tmin.fast_min(tmax);
@@ -252,16 +461,43 @@ impl Node {
 #[derive(Debug, Default, Clone)]
 pub struct Bvh {
     hittables: Vec<Hittable>,
+    lights: Lights,
+    order: Vec<usize>, // index of each hittable in the order that they were given
     nodes: Vec<Node>,
+    traversal: Traversal,
+    scene_scale: f32, // scales the padding of flat boxes and the ray epsilon
     pub bbox: AABBox,
 }
 
 impl Bvh {
-    pub fn new(mut hittables: Vec<Hittable>) -> Self {
-        let bbox = AABBox::new_containing(&hittables);
-        let mut fat_nodes = vec![FatNode::new(bbox, 0)];
+    /// Build a tree using the default [Accel] settings for a scene scale of 1.
+    pub fn new(hittables: Vec<Hittable>) -> Self {
+        Self::with_accel(hittables, &Accel::DEFAULT, 1.0)
+    }
 
-        split(0, 0, hittables.len(), 1, &mut fat_nodes, &mut hittables);
+    /// Build a tree using the given [Accel] settings, with the bounding boxes of flat hittables
+    /// padded in proportion to the scene scale.
+    pub fn with_accel(hittables: Vec<Hittable>, accel: &Accel, scene_scale: f32) -> Self {
+        let min_size = MIN_BBOX_SIZE * scene_scale;
+        let bboxes: Vec<AABBox> = hittables
+            .iter()
+            .map(|h| h.bounding_box().padded(min_size))
+            .collect();
+        let bbox = AABBox::new_containing_all(bboxes.iter().copied());
+        let mut fat_nodes = vec![FatNode::new(bbox, 0)];
+        let mut order: Vec<usize> = (0..hittables.len()).collect();
+
+        split(
+            0,
+            0,
+            hittables.len(),
+            1,
+            &mut fat_nodes,
+            &mut order,
+            &bboxes,
+            accel,
+        );
+        let hittables = permute(hittables, &order);
         let nodes = fat_nodes
             .into_iter()
             .map(|n| Node {
@@ -269,22 +505,115 @@ impl Bvh {
                 max: n.bbox.max,
                 start: n.start,
                 n: n.n,
+                axis: n.axis,
             })
             .collect();
 
         Self {
+            lights: Lights::new(&hittables),
             hittables,
+            order,
             nodes,
+            traversal: accel.traversal,
+            scene_scale,
             bbox,
         }
     }
 
+    pub fn hittables(&self) -> &[Hittable] {
+        &self.hittables
+    }
+
+    /// Minimum distance along rays traced through the tree for a hit to count, in proportion to
+    /// the scene scale it was built for.
+    pub fn ray_epsilon(&self) -> f32 {
+        RAY_EPSILON * self.scene_scale
+    }
+
+    /// The hittables of the tree that can be sampled as lights
+    pub fn lights(&self) -> &Lights {
+        &self.lights
+    }
+
+    /// Replace the hittables with new versions given in the same order as when the tree was
+    /// built (e.g. the same scene at the next frame of an animation) and refit the tree to them.
+    pub fn update(&mut self, hittables: Vec<Hittable>) {
+        assert_eq!(
+            hittables.len(),
+            self.hittables.len(),
+            "bvh updates need the same number of hittables"
+        );
+        self.hittables = permute(hittables, &self.order);
+        self.lights = Lights::new(&self.hittables);
+        self.refit();
+    }
+
+    /// Recompute the bounds of each node bottom-up without re-sorting the hittables. This is
+    /// much faster than a rebuild but the tree becomes less efficient the further the hittables
+    /// move from where they were when it was built.
+    pub fn refit(&mut self) {
+        // children are always stored after their parent
+        for i in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[i];
+            let (min, max) = match node.n {
+                Some(n) => {
+                    let hittables = &self.hittables[node.start..node.start + n];
+                    let b = AABBox::new_containing(hittables, MIN_BBOX_SIZE * self.scene_scale);
+                    (b.min, b.max)
+                }
+                None => {
+                    let (l, r) = (&self.nodes[node.start], &self.nodes[node.start + 1]);
+                    (l.min.fast_min(r.min), l.max.fast_max(r.max))
+                }
+            };
+            self.nodes[i].min = min;
+            self.nodes[i].max = max;
+        }
+
+        let ([x1, y1, z1, _], [x2, y2, z2, _]) =
+            (self.nodes[0].min.to_array(), self.nodes[0].max.to_array());
+        self.bbox = AABBox::new(
+            Interval::new(x1, x2),
+            Interval::new(y1, y2),
+            Interval::new(z1, z2),
+        );
+    }
+
+    /// Which nodes may be reached by rays inside of frustum, indexed in the same way as the
+    /// nodes of the tree. Nodes outside of it can be skipped when tracing camera rays.
+    pub fn visible_nodes(&self, frustum: &Frustum) -> Vec<bool> {
+        self.nodes
+            .iter()
+            .map(|n| {
+                let ([x1, y1, z1, _], [x2, y2, z2, _]) = (n.min.to_array(), n.max.to_array());
+                frustum.may_contain([x1, y1, z1], [x2, y2, z2])
+            })
+            .collect()
+    }
+
     pub fn hits(
+        &self,
+        r: &Ray,
+        ray_t: Interval,
+        stack: &mut [usize; MAX_BVH_DEPTH],
+    ) -> Option<HitRecord> {
+        self.hits_visible(r, ray_t, stack, None)
+    }
+
+    /// Find the closest hit skipping any nodes that are not marked as visible (see
+    /// [Bvh::visible_nodes]).
+    pub fn hits_visible(
         &self,
         r: &Ray,
         mut ray_t: Interval,
         stack: &mut [usize; MAX_BVH_DEPTH],
+        visible: Option<&[bool]>,
     ) -> Option<HitRecord> {
+        let is_visible = |i: usize| visible.is_none_or(|v| v[i]);
+        if !is_visible(0) {
+            return None;
+        }
+
         let mut hr = None;
         let mut i = 1;
         stack[0] = 0;
@@ -308,19 +637,24 @@ impl Bvh {
                 let right = &self.nodes[node.start + 1];
                 let ldist = left.hit_dist(r, ray_t);
                 let rdist = right.hit_dist(r, ray_t);
+                let left_first = match self.traversal {
+                    Traversal::Nearest => ldist < rdist,
+                    Traversal::Axis => r.dir[node.axis as usize] >= 0.0,
+                };
 
-                let ((a, adist), (b, bdist)) = if ldist < rdist {
+                let ((near, near_dist), (far, far_dist)) = if left_first {
                     ((node.start, ldist), (node.start + 1, rdist))
                 } else {
                     ((node.start + 1, rdist), (node.start, ldist))
                 };
 
-                if adist < ray_t.max {
-                    stack[i] = a;
+                // the near child is pushed last so that it is visited first
+                if far_dist < ray_t.max && is_visible(far) {
+                    stack[i] = far;
                     i += 1;
                 }
-                if bdist < ray_t.max {
-                    stack[i] = b;
+                if near_dist < ray_t.max && is_visible(near) {
+                    stack[i] = near;
                     i += 1;
                 }
             }
@@ -333,6 +667,7 @@ impl Bvh {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{hit::Sphere, material::Material, p, v, Color};
     use simple_test_case::test_case;
 
     fn bbox(x1: f32, x2: f32, y1: f32, y2: f32, z1: f32, z2: f32) -> AABBox {
@@ -367,4 +702,93 @@ mod tests {
 
         assert_eq!(res, expected);
     }
+
+    #[test]
+    fn updated_trees_match_rebuilt_ones() {
+        let mat: &'static Material = Box::leak(Box::new(Material::solid_color(Color::WHITE)));
+        let spheres = |dx: f32| -> Vec<Hittable> {
+            (0..20)
+                .map(|i| {
+                    let x = i as f32 * 3.0 + if i % 2 == 0 { dx } else { 0.0 };
+                    Sphere::new(p!(x, 0, 0), 1.0, mat).into()
+                })
+                .collect()
+        };
+        let mut refit = Bvh::new(spheres(0.0));
+        refit.update(spheres(10.0));
+        let rebuilt = Bvh::new(spheres(10.0));
+
+        assert_eq!(refit.bbox, rebuilt.bbox);
+        for x in 0..70 {
+            let r = Ray::new(p!(x as f32 * 0.9, 0.5, -5), v!(0, 0, 1));
+            let t = |bvh: &Bvh| {
+                bvh.hits(
+                    &r,
+                    Interval::new(0.001, f32::INFINITY),
+                    &mut [0; MAX_BVH_DEPTH],
+                )
+                .map(|hr| hr.t)
+            };
+            assert_eq!(t(&refit), t(&rebuilt), "x = {x}");
+        }
+    }
+
+    #[test_case(Accel::DEFAULT; "default")]
+    #[test_case(Accel { max_leaf_size: 4, ..Accel::DEFAULT }; "large leaves")]
+    #[test_case(Accel { split: Split::Sah, ..Accel::DEFAULT }; "sah")]
+    #[test_case(Accel { traversal: Traversal::Axis, ..Accel::DEFAULT }; "axis traversal")]
+    #[test_case(
+        Accel { max_leaf_size: 3, split: Split::Sah, traversal: Traversal::Axis };
+        "everything"
+    )]
+    #[test]
+    fn accel_settings_find_the_closest_hit(accel: Accel) {
+        let mat: &'static Material = Box::leak(Box::new(Material::solid_color(Color::WHITE)));
+        // a dense cluster alongside sparse outliers
+        let spheres: Vec<Hittable> = (0..60)
+            .map(|i| {
+                let c = if i < 50 {
+                    p!(i % 5, (i / 5) % 5, i / 25)
+                } else {
+                    p!(20 * (i - 50), 3, 7)
+                };
+                Sphere::new(c, 0.4, mat).into()
+            })
+            .collect();
+        let bvh = Bvh::with_accel(spheres.clone(), &accel, 1.0);
+
+        assert!(bvh
+            .nodes
+            .iter()
+            .all(|n| n.n.is_none_or(|n| n <= accel.max_leaf_size)));
+        let mut n_hits = 0;
+        for k in 0..400 {
+            // aim across the cluster and along the row of outliers from different directions
+            let target = p!((k % 20) as f32 * 0.3 - 1.0, (k / 20) as f32 * 0.3, 0.5);
+            let target = if k % 4 == 0 { p!(k, 3, 7) } else { target };
+            let orig = p!(10 * (k % 3) - 10, 5, -10);
+            let r = Ray::new(orig, target - orig);
+            let ray_t = Interval::new(0.001, f32::INFINITY);
+            let closest = spheres
+                .iter()
+                .filter_map(|h| h.hits(&r, ray_t))
+                .map(|hr| hr.t)
+                .min_by(f32::total_cmp);
+            let hit = bvh.hits(&r, ray_t, &mut [0; MAX_BVH_DEPTH]).map(|hr| hr.t);
+
+            assert_eq!(hit, closest, "ray {k}");
+            n_hits += hit.is_some() as usize;
+        }
+        assert!(n_hits > 150, "{n_hits}");
+    }
+
+    #[test]
+    fn sort_spatially_groups_neighbours() {
+        // Two clusters of four points along x, interleaved in the input
+        let mut xs = vec![0.0, 10.0, 1.0, 11.0, 2.0, 12.0, 3.0, 13.0];
+        sort_spatially(&mut xs, 4, |&x| bbox(x, x, 0.0, 0.0, 0.0, 0.0));
+
+        assert!(xs[..4].iter().all(|&x| x < 5.0), "{xs:?}");
+        assert!(xs[4..].iter().all(|&x| x > 5.0), "{xs:?}");
+    }
 }
@@ -0,0 +1,185 @@
+//! Command line arguments of the render, validate and bench commands: an optional scene path
+//! followed by flags overriding settings of the scene, so that quick previews don't need the
+//! scene file to be edited.
+//!
+//! ```sh
+//! $ raymart render scene.toml --samples 16 --width 400 --bounces 4 --output preview.png
+//! $ raymart validate scene.toml
+//! $ raymart bench scene.toml -s 64
+//! ```
+use crate::{
+    output::OutputFormat,
+    scene::{Scene, SceneError},
+    SCENE_PATH,
+};
+use std::path::Path;
+
+pub const USAGE: &str = "\
+usage: raymart [render|validate|bench] [scene] [options]
+       raymart batch <manifest>
+       raymart dataset <dataset>
+       raymart diff <image_a> <image_b> [out.png]
+       raymart envmap <map> [cubemap_size]
+       raymart paths <scene> <x> <y> [n_paths] [out.obj|out.ply]
+       raymart info [scene]
+       raymart furnace [scene]
+
+options:
+  -s, --samples <n>    samples per pixel
+  -w, --width <n>      image width in pixels
+  -b, --bounces <n>    maximum number of bounces
+  -o, --output <path>  output path, also setting the format from its extension";
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Args {
+    /// Defaults to scene.toml
+    pub scene: Option<String>,
+    pub overrides: Overrides,
+}
+
+/// Settings given on the command line in place of those in the scene file
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Overrides {
+    pub samples_per_pixel: Option<u16>,
+    pub image_width: Option<u16>,
+    pub max_bounces: Option<u8>,
+    pub output: Option<String>,
+}
+
+impl Args {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| {
+                args.next()
+                    .ok_or_else(|| format!("{flag} requires a value"))
+            };
+            let o = &mut parsed.overrides;
+            match arg.as_str() {
+                "-s" | "--samples" => o.samples_per_pixel = Some(number(arg, value(arg)?)?),
+                "-w" | "--width" => o.image_width = Some(number(arg, value(arg)?)?),
+                "-b" | "--bounces" => o.max_bounces = Some(number(arg, value(arg)?)?),
+                "-o" | "--output" => o.output = Some(value(arg)?.clone()),
+                flag if flag.starts_with('-') => return Err(format!("unknown option {flag}")),
+                path if parsed.scene.is_none() => parsed.scene = Some(path.to_string()),
+                extra => return Err(format!("unexpected argument {extra}")),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// The path of the scene and the scene with the overrides applied, checking that everything
+    /// it refers to is defined. Without a scene file the default scene is used, unless a path
+    /// was given.
+    pub fn load(&self) -> Result<(String, Scene), String> {
+        let path = self.scene.as_deref().unwrap_or(SCENE_PATH);
+        let mut scene = match Scene::try_from_file(path) {
+            Ok(s) => s,
+            Err(SceneError::File { .. }) if self.scene.is_none() => Scene::default(),
+            Err(e) => return Err(e.to_string()),
+        };
+        self.overrides.apply(&mut scene);
+        scene.validate().map_err(|e| format!("{path}: {e}"))?;
+
+        Ok((path.to_string(), scene))
+    }
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {flag}: {value}"))
+}
+
+impl Overrides {
+    pub fn apply(&self, s: &mut Scene) {
+        if let Some(n) = self.samples_per_pixel {
+            s.samples_per_pixel = n;
+        }
+        if let Some(w) = self.image_width {
+            s.image_width = w;
+        }
+        if let Some(n) = self.max_bounces {
+            s.max_bounces = n;
+        }
+        if let Some(path) = &self.output {
+            let ext = Path::new(path).extension().and_then(|e| e.to_str());
+            match ext.and_then(OutputFormat::from_extension) {
+                // keep 16-bit PNGs when writing to another .png
+                Some(f) if f.extension() != s.output.format.extension() => s.output.format = f,
+                _ => (),
+            }
+            s.output.path = Some(path.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn flags_can_come_before_or_after_the_scene() {
+        let expected = Args {
+            scene: Some("dragon.toml".to_string()),
+            overrides: Overrides {
+                samples_per_pixel: Some(16),
+                image_width: Some(400),
+                max_bounces: Some(4),
+                output: Some("preview.png".to_string()),
+            },
+        };
+
+        for s in [
+            "dragon.toml -s 16 --width 400 -b 4 -o preview.png",
+            "--samples 16 -w 400 dragon.toml --bounces 4 --output preview.png",
+        ] {
+            assert_eq!(Args::parse(&args(s)), Ok(expected.clone()));
+        }
+    }
+
+    #[test_case("-s", "-s requires a value"; "missing value")]
+    #[test_case("-b 300", "invalid value for -b: 300"; "out of range")]
+    #[test_case("--fast", "unknown option --fast"; "unknown option")]
+    #[test_case("a.toml b.toml", "unexpected argument b.toml"; "two scenes")]
+    #[test]
+    fn invalid_arguments_are_errors(s: &str, expected: &str) {
+        assert_eq!(Args::parse(&args(s)), Err(expected.to_string()));
+    }
+
+    #[test_case(OutputFormat::Ppm, "out.exr", OutputFormat::Exr; "from the extension")]
+    #[test_case(OutputFormat::Png16, "out.png", OutputFormat::Png16; "same extension")]
+    #[test_case(OutputFormat::Tiff, "out", OutputFormat::Tiff; "no extension")]
+    #[test]
+    fn output_paths_set_the_format(format: OutputFormat, path: &str, expected: OutputFormat) {
+        let mut s = Scene::default();
+        s.output.format = format;
+        let overrides = Overrides {
+            output: Some(path.to_string()),
+            ..Default::default()
+        };
+
+        overrides.apply(&mut s);
+
+        assert_eq!(s.output.path(), path);
+        assert_eq!(s.output.format, expected);
+    }
+
+    #[test]
+    fn missing_scene_files_are_errors_when_given() {
+        let args = Args {
+            scene: Some("no/such/scene.toml".to_string()),
+            ..Default::default()
+        };
+
+        assert!(args.load().unwrap_err().starts_with("unable to read"));
+    }
+}
@@ -1,4 +1,10 @@
-use crate::{hit::Interval, v3::V3};
+use crate::hit::Interval;
+use rand::random_range;
+use serde::Deserialize;
+use std::{
+    iter::Sum,
+    ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign},
+};
 
 /// Apply a linear to gamma transform for gamma 2
 fn linear_to_gamma(linear_component: f32) -> f32 {
@@ -9,23 +15,400 @@ fn linear_to_gamma(linear_component: f32) -> f32 {
     }
 }
 
-pub type Color = V3;
+/// A linear RGB color.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Color {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+}
 
 impl Color {
     pub const WHITE: Color = Color::new(1.0, 1.0, 1.0);
     pub const BLACK: Color = Color::new(0.0, 0.0, 0.0);
 
+    pub const fn new(r: f32, g: f32, b: f32) -> Color {
+        Self { r, g, b }
+    }
+
+    pub fn random(min: f32, max: f32) -> Color {
+        Color::new(
+            random_range(min..max),
+            random_range(min..max),
+            random_range(min..max),
+        )
+    }
+
     pub const fn grey(v: f32) -> Color {
         Color::new(v, v, v)
     }
 
-    pub fn ppm_string(&self) -> String {
-        // Translate the [0,1] component values to the byte range [0,255].
+    /// A stable pseudo-random color for the given ID so that neighbouring IDs are easy to tell
+    /// apart visually.
+    pub fn from_id(id: u32) -> Color {
+        // lowbias32 integer hash: https://nullprogram.com/blog/2018/07/31/
+        let mut h = id;
+        h ^= h >> 16;
+        h = h.wrapping_mul(0x7feb352d);
+        h ^= h >> 15;
+        h = h.wrapping_mul(0x846ca68b);
+        h ^= h >> 16;
+
+        let c = |shift: u32| 0.15 + 0.85 * ((h >> shift) & 0xff) as f32 / 255.0;
+
+        Color::new(c(0), c(8), c(16))
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.r.is_finite() && self.g.is_finite() && self.b.is_finite()
+    }
+
+    /// Relative luminance using the Rec.709 / sRGB primaries.
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    /// Gamma corrected component values clamped to [0,1).
+    pub fn gamma_encoded(&self) -> Color {
+        let intensity = Interval::new(0.0, 0.999);
+
+        Color::new(
+            intensity.clamp(linear_to_gamma(self.r)),
+            intensity.clamp(linear_to_gamma(self.g)),
+            intensity.clamp(linear_to_gamma(self.b)),
+        )
+    }
+
+    /// Clamped components of an already gamma encoded color.
+    fn encoded_components(&self) -> [f32; 3] {
         let intensity = Interval::new(0.0, 0.999);
-        let ir = (256.0 * intensity.clamp(linear_to_gamma(self.x))) as i64;
-        let ig = (256.0 * intensity.clamp(linear_to_gamma(self.y))) as i64;
-        let ib = (256.0 * intensity.clamp(linear_to_gamma(self.z))) as i64;
 
-        format!("{ir} {ig} {ib}\n")
+        [self.r, self.g, self.b].map(|c| intensity.clamp(c))
+    }
+
+    /// Translate the [0,1] component values to the byte range [0,255].
+    pub fn to_rgb8(&self) -> [u8; 3] {
+        self.gamma_encoded().encoded_to_rgb8()
+    }
+
+    /// Translate the [0,1] component values to the range [0,65535].
+    pub fn to_rgb16(&self) -> [u16; 3] {
+        self.gamma_encoded().encoded_to_rgb16()
+    }
+
+    /// As [Color::to_rgb8] for a color that has already been gamma encoded.
+    pub fn encoded_to_rgb8(&self) -> [u8; 3] {
+        self.encoded_components().map(|c| (256.0 * c) as u8)
+    }
+
+    /// As [Color::to_rgb16] for a color that has already been gamma encoded.
+    pub fn encoded_to_rgb16(&self) -> [u16; 3] {
+        [self.r, self.g, self.b].map(|c| (c.clamp(0.0, 1.0) * 65535.0).round() as u16)
+    }
+
+    fn transform(&self, m: &[[f32; 3]; 3]) -> Color {
+        let row = |r: [f32; 3]| r[0] * self.r + r[1] * self.g + r[2] * self.b;
+
+        Color::new(row(m[0]), row(m[1]), row(m[2]))
+    }
+}
+
+/// Linear sRGB to ACEScg including Bradford adaptation from the D65 to the D60 white point.
+const SRGB_TO_ACESCG: [[f32; 3]; 3] = [
+    [0.613_097, 0.339_523, 0.047_379],
+    [0.070_194, 0.916_354, 0.013_452],
+    [0.020_616, 0.109_570, 0.869_815],
+];
+
+/// ACEScg to linear sRGB including Bradford adaptation from the D60 to the D65 white point.
+const ACESCG_TO_SRGB: [[f32; 3]; 3] = [
+    [1.704_859, -0.621_715, -0.083_299],
+    [-0.130_078, 1.140_734, -0.010_560],
+    [-0.023_964, -0.128_975, 1.153_013],
+];
+
+/// CIE XYZ to linear sRGB
+const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+    [3.240_454, -1.537_139, -0.498_531],
+    [-0.969_266, 1.876_011, 0.041_556],
+    [0.055_643, -0.204_026, 1.057_225],
+];
+
+/// Temperature of the Planckian radiator closest to the D65 white point
+const D65_KELVIN: f32 = 6504.0;
+
+/// The linear sRGB color with unit luminance of a black body radiator at the given temperature
+/// (clamped to [1667, 25000] K) using a cubic spline approximation of the Planckian locus.
+///   https://en.wikipedia.org/wiki/Planckian_locus#Approximation
+pub fn blackbody(kelvin: f32) -> Color {
+    let t = kelvin.clamp(1667.0, 25000.0);
+    let (t2, t3) = (t * t, t * t * t);
+
+    let x = if t <= 4000.0 {
+        -0.266_123_9e9 / t3 - 0.234_358_9e6 / t2 + 0.877_695_6e3 / t + 0.179_910
+    } else {
+        -3.025_846_9e9 / t3 + 2.107_038e6 / t2 + 0.222_634_7e3 / t + 0.240_390
+    };
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t <= 2222.0 {
+        -1.106_381_4 * x3 - 1.348_110_2 * x2 + 2.185_558_3 * x - 0.202_196_83
+    } else if t <= 4000.0 {
+        -0.954_947_6 * x3 - 1.374_185_9 * x2 + 2.091_37 * x - 0.167_488_67
+    } else {
+        3.081_758 * x3 - 5.873_387 * x2 + 3.751_13 * x - 0.370_014_83
+    };
+
+    Color::new(x / y, 1.0, (1.0 - x - y) / y).transform(&XYZ_TO_SRGB)
+}
+
+/// Camera white balance: the color temperature of the light that should appear neutral along
+/// with a green / magenta tint correction.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct WhiteBalance {
+    /// Color temperature in Kelvin: lower values make the image cooler
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    /// Tint correction in [-1, 1]: positive values shift towards magenta and negative towards
+    /// green
+    #[serde(default)]
+    pub tint: f32,
+}
+
+fn default_temperature() -> f32 {
+    D65_KELVIN
+}
+
+impl WhiteBalance {
+    /// Per channel gains mapping the given white to D65, normalized to preserve luminance.
+    pub fn gains(&self) -> Color {
+        let (white, target) = (blackbody(self.temperature), blackbody(D65_KELVIN));
+        let mut gains = Color::new(target.r / white.r, target.g / white.g, target.b / white.b);
+        gains.g *= 1.0 - 0.5 * self.tint.clamp(-1.0, 1.0);
+
+        gains / gains.luminance()
+    }
+}
+
+/// RGB color spaces for rendering and output. sRGB and Rec.709 share their primaries and white
+/// point but differ in their transfer functions. ACEScg has wider primaries and is linear.
+///   https://en.wikipedia.org/wiki/SRGB
+///   https://en.wikipedia.org/wiki/Rec._709
+///   https://docs.acescentral.com/specifications/acescg/
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    #[default]
+    Srgb,
+    Rec709,
+    AcesCg,
+}
+
+impl ColorSpace {
+    const fn is_aces(&self) -> bool {
+        matches!(self, Self::AcesCg)
+    }
+
+    /// Convert a linear color in this color space to the given color space.
+    pub fn convert(&self, c: Color, to: ColorSpace) -> Color {
+        match (self.is_aces(), to.is_aces()) {
+            (false, true) => c.transform(&SRGB_TO_ACESCG),
+            (true, false) => c.transform(&ACESCG_TO_SRGB),
+            _ => c,
+        }
+    }
+
+    /// Apply the transfer function (OETF) of this color space to a linear component value.
+    pub fn encode(&self, v: f32) -> f32 {
+        let v = v.max(0.0);
+
+        match self {
+            Self::Srgb if v <= 0.003_130_8 => 12.92 * v,
+            Self::Srgb => 1.055 * v.powf(1.0 / 2.4) - 0.055,
+            Self::Rec709 if v < 0.018 => 4.5 * v,
+            Self::Rec709 => 1.099 * v.powf(0.45) - 0.099,
+            Self::AcesCg => v,
+        }
+    }
+
+    /// CIE xy chromaticities of the white point followed by the red, green and blue primaries.
+    pub const fn chromaticities(&self) -> [[f32; 2]; 4] {
+        match self {
+            Self::Srgb | Self::Rec709 => {
+                [[0.3127, 0.3290], [0.64, 0.33], [0.30, 0.60], [0.15, 0.06]]
+            }
+            Self::AcesCg => [
+                [0.32168, 0.33767],
+                [0.713, 0.293],
+                [0.165, 0.830],
+                [0.128, 0.044],
+            ],
+        }
+    }
+}
+
+impl From<[f32; 3]> for Color {
+    fn from(c: [f32; 3]) -> Self {
+        Color::new(c[0], c[1], c[2])
+    }
+}
+
+impl Add<Color> for Color {
+    type Output = Color;
+
+    fn add(self, rhs: Color) -> Self::Output {
+        Self::new(self.r + rhs.r, self.g + rhs.g, self.b + rhs.b)
+    }
+}
+
+impl AddAssign<Color> for Color {
+    fn add_assign(&mut self, rhs: Color) {
+        self.r += rhs.r;
+        self.g += rhs.g;
+        self.b += rhs.b;
+    }
+}
+
+impl Sum for Color {
+    fn sum<I: Iterator<Item = Color>>(iter: I) -> Self {
+        iter.fold(Color::BLACK, |acc, c| acc + c)
+    }
+}
+
+/// Attenuation of one color by another (component-wise product).
+impl Mul<Color> for Color {
+    type Output = Color;
+
+    fn mul(self, rhs: Color) -> Self::Output {
+        Self::new(self.r * rhs.r, self.g * rhs.g, self.b * rhs.b)
+    }
+}
+
+impl MulAssign<Color> for Color {
+    fn mul_assign(&mut self, rhs: Color) {
+        self.r *= rhs.r;
+        self.g *= rhs.g;
+        self.b *= rhs.b;
+    }
+}
+
+impl Mul<f32> for Color {
+    type Output = Color;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        Self::new(self.r * rhs, self.g * rhs, self.b * rhs)
+    }
+}
+
+impl Mul<Color> for f32 {
+    type Output = Color;
+
+    fn mul(self, rhs: Color) -> Self::Output {
+        rhs * self
+    }
+}
+
+impl MulAssign<f32> for Color {
+    fn mul_assign(&mut self, rhs: f32) {
+        self.r *= rhs;
+        self.g *= rhs;
+        self.b *= rhs;
+    }
+}
+
+impl Div<f32> for Color {
+    type Output = Color;
+
+    fn div(self, rhs: f32) -> Self::Output {
+        self * (1.0 / rhs)
+    }
+}
+
+impl DivAssign<f32> for Color {
+    fn div_assign(&mut self, rhs: f32) {
+        *self *= 1.0 / rhs;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    fn assert_close(a: Color, b: Color) {
+        let d = (a.r - b.r).abs() + (a.g - b.g).abs() + (a.b - b.b).abs();
+        assert!(d < 1e-3, "{a:?} != {b:?}");
+    }
+
+    #[test_case(Color::WHITE; "white")]
+    #[test_case(Color::new(0.8, 0.2, 0.1); "red")]
+    #[test_case(Color::new(0.05, 0.3, 0.9); "blue")]
+    #[test]
+    fn acescg_round_trip_works(c: Color) {
+        let aces = ColorSpace::Srgb.convert(c, ColorSpace::AcesCg);
+        let back = ColorSpace::AcesCg.convert(aces, ColorSpace::Srgb);
+
+        assert_close(back, c);
+    }
+
+    #[test]
+    fn white_is_preserved_between_spaces() {
+        assert_close(
+            ColorSpace::Srgb.convert(Color::WHITE, ColorSpace::AcesCg),
+            Color::WHITE,
+        );
+    }
+
+    #[test_case(2700.0, true; "tungsten")]
+    #[test_case(10000.0, false; "shade")]
+    #[test]
+    fn blackbody_color_follows_temperature(kelvin: f32, warm: bool) {
+        let c = blackbody(kelvin);
+
+        assert_eq!(c.r > c.b, warm, "{c:?}");
+    }
+
+    #[test]
+    fn white_balance_at_d65_is_neutral() {
+        let wb = WhiteBalance {
+            temperature: D65_KELVIN,
+            tint: 0.0,
+        };
+
+        assert_close(wb.gains(), Color::WHITE);
+    }
+
+    #[test_case(3200.0, true; "warm light is cooled")]
+    #[test_case(9000.0, false; "cool light is warmed")]
+    #[test]
+    fn white_balance_compensates_for_temperature(temperature: f32, cooled: bool) {
+        let wb = WhiteBalance {
+            temperature,
+            tint: 0.0,
+        };
+        let gains = wb.gains();
+
+        assert_eq!(gains.b > gains.r, cooled, "{gains:?}");
+        assert!((gains.luminance() - 1.0).abs() < 1e-4);
+    }
+
+    #[test_case(1.0, 65535; "white")]
+    #[test_case(2.0, 65535; "over range")]
+    #[test_case(0.5, 32768; "mid")]
+    #[test_case(1.0 / 65535.0, 1; "smallest step")]
+    #[test_case(0.0, 0; "black")]
+    #[test_case(-1.0, 0; "under range")]
+    #[test]
+    fn rgb16_uses_the_full_range(v: f32, expected: u16) {
+        assert_eq!(Color::grey(v).encoded_to_rgb16(), [expected; 3]);
+    }
+
+    #[test_case(ColorSpace::Srgb, 0.003_130_8; "srgb")]
+    #[test_case(ColorSpace::Rec709, 0.018; "rec709")]
+    #[test]
+    fn transfer_functions_are_continuous(cs: ColorSpace, knee: f32) {
+        let (below, above) = (cs.encode(knee - 1e-6), cs.encode(knee + 1e-6));
+
+        assert!((below - above).abs() < 1e-3, "{below} != {above}");
+        assert!((cs.encode(1.0) - 1.0).abs() < 1e-3);
     }
 }
@@ -0,0 +1,125 @@
+//! Counts of the work done while rendering (rays traced, bounding box and triangle tests) for
+//! evaluating algorithmic changes without the noise of wall clock timings. The counters are only
+//! compiled in with the `stats` feature as updating them slows down the innermost loops of the
+//! renderer:
+//!
+//! ```sh
+//! $ cargo run --release --features stats -- scene.toml
+//! ```
+//!
+//! Counts are shared by every render in the process so are only meaningful for batches rendered
+//! one job at a time.
+#[cfg(feature = "stats")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    /// Camera and bounce rays traced along paths
+    Rays,
+    /// Rays only checking whether something lies in the way
+    ShadowRays,
+    /// Ray / bounding box intersection tests while traversing a BVH
+    BboxTests,
+    /// Ray / triangle intersection tests (counting each lane of packed triangles)
+    TriangleTests,
+}
+
+impl Counter {
+    const ALL: [Counter; 4] = [
+        Counter::Rays,
+        Counter::ShadowRays,
+        Counter::BboxTests,
+        Counter::TriangleTests,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Rays => "Rays traced",
+            Self::ShadowRays => "Shadow rays",
+            Self::BboxTests => "BBox tests",
+            Self::TriangleTests => "Triangle tests",
+        }
+    }
+}
+
+/// Each counter is kept on its own cache line so that threads updating different counters do
+/// not contend with one another.
+#[cfg(feature = "stats")]
+#[repr(align(64))]
+struct Padded(AtomicU64);
+
+#[cfg(feature = "stats")]
+static COUNTS: [Padded; 4] = [const { Padded(AtomicU64::new(0)) }; 4];
+
+/// Add n to the counter. This compiles to nothing without the stats feature.
+#[inline(always)]
+pub fn add(counter: Counter, n: u64) {
+    #[cfg(feature = "stats")]
+    COUNTS[counter as usize].0.fetch_add(n, Ordering::Relaxed);
+    #[cfg(not(feature = "stats"))]
+    let _ = (counter, n);
+}
+
+/// The current value of the counter (always 0 without the stats feature).
+pub fn get(counter: Counter) -> u64 {
+    #[cfg(feature = "stats")]
+    return COUNTS[counter as usize].0.load(Ordering::Relaxed);
+    #[cfg(not(feature = "stats"))]
+    {
+        let _ = counter;
+        0
+    }
+}
+
+pub fn reset() {
+    #[cfg(feature = "stats")]
+    for c in COUNTS.iter() {
+        c.0.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Print the counters along with the tests per ray when the stats feature is enabled.
+pub fn report() {
+    if !cfg!(feature = "stats") {
+        return;
+    }
+
+    let rays = (get(Counter::Rays) + get(Counter::ShadowRays)).max(1) as f64;
+    eprintln!();
+    for c in Counter::ALL {
+        let n = get(c);
+        match c {
+            Counter::Rays | Counter::ShadowRays => eprintln!("{:<16}{n}", c.label()),
+            _ => eprintln!("{:<16}{n} ({:.1} / ray)", c.label(), n as f64 / rays),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod tests {
+    use super::*;
+    use crate::{
+        bvh::{Bvh, MAX_BVH_DEPTH},
+        hit::{Interval, Triangle},
+        material::CLAY,
+        p, v, Ray, P3, V3,
+    };
+
+    #[test]
+    fn intersection_tests_are_counted() {
+        let tri = |x: f32| Triangle::new(p!(x, 0, 0), p!(x + 1.0, 0, 0), p!(x, 1, 0), &CLAY);
+        let bvh = Bvh::new((0..8).map(|x| tri(x as f32).into()).collect());
+        let r = Ray::new(p!(0.1, 0.1, 1), v!(0, 0, -1));
+        // other tests render in parallel so only an increase can be checked for
+        let before = Counter::ALL.map(get);
+
+        bvh.hits(
+            &r,
+            Interval::new(0.001, f32::INFINITY),
+            &mut [0; MAX_BVH_DEPTH],
+        );
+
+        assert!(get(Counter::BboxTests) > before[Counter::BboxTests as usize]);
+        assert!(get(Counter::TriangleTests) > before[Counter::TriangleTests as usize]);
+    }
+}
@@ -0,0 +1,317 @@
+//! Generating synthetic datasets: a scene is rendered many times with its camera, lights,
+//! material colors and object placement drawn at random from the given ranges. Each variant is
+//! written with the ground truth AOVs enabled in its output settings (depth maps, segmentation,
+//! ID mattes, ...) and a JSON record of the parameters drawn for it.
+//!
+//! ```toml
+//! scene = "scene.toml"
+//! count = 100
+//! seed = 7
+//! parallel = 2
+//! set = { samples_per_pixel = 64, output = { depth_map = true, segmentation = true } }
+//!
+//! [randomize]
+//! from = [50.0, 20.0, 50.0]      # largest offset of the camera position along each axis
+//! at = [10.0, 10.0, 10.0]        # and of the point it looks at
+//! fov = [30.0, 50.0]
+//! light_intensity = [0.5, 2.0]   # factor scaling the emission of every light
+//! colors = { red = [[0.4, 0.0, 0.0], [1.0, 0.2, 0.2]] }
+//! objects = { box = [100.0, 0.0, 100.0], "*" = [5.0, 0.0, 5.0] }
+//! ```
+//!
+//! Variants have their index added to the output path of the scene (e.g. test.0003.png) along
+//! with their AOVs and parameters (test.0003.params.json).
+use crate::{
+    angle::Angle,
+    aov::json_str,
+    batch::{self, Job, JobResult},
+    scene::{ColorSpec, Scene},
+    Color, V3,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs};
+
+#[derive(Debug, Deserialize)]
+pub struct Dataset {
+    pub scene: String,
+    /// Number of variants to render
+    pub count: u32,
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default = "default_parallel")]
+    pub parallel: usize,
+    /// Settings merged over those given in the scene file for every variant
+    #[serde(default)]
+    pub set: toml::Table,
+    #[serde(default)]
+    pub randomize: Randomize,
+}
+
+fn default_parallel() -> usize {
+    1
+}
+
+/// The ranges that the parameters of each variant are drawn from
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Randomize {
+    /// Largest offset of the camera position along each axis
+    #[serde(default)]
+    pub from: Option<[f32; 3]>,
+    /// Largest offset of the point the camera looks at along each axis
+    #[serde(default)]
+    pub at: Option<[f32; 3]>,
+    #[serde(default)]
+    pub fov: Option<[Angle; 2]>,
+    /// Range of the factor scaling the emission of every light
+    #[serde(default)]
+    pub light_intensity: Option<[f32; 2]>,
+    /// Range of the color of each named material, drawn for each channel
+    #[serde(default)]
+    pub colors: BTreeMap<String, [ColorSpec; 2]>,
+    /// Largest offset along each axis of the meshes and objects with each name, or every one of
+    /// them for "*", each moved independently
+    #[serde(default)]
+    pub objects: BTreeMap<String, [f32; 3]>,
+}
+
+impl Dataset {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let s = fs::read_to_string(path).map_err(|e| format!("unable to read {path}: {e}"))?;
+
+        Self::parse(&s)
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        toml::from_str(s).map_err(|e| format!("invalid dataset: {e}"))
+    }
+
+    /// Each variant of the scene along with a JSON record of the parameters drawn for it. The
+    /// variants are the same for a given seed.
+    pub fn variants(&self) -> Result<Vec<(Scene, String)>, String> {
+        let job = Job {
+            name: None,
+            scene: None,
+            set: self.set.clone(),
+        };
+        let base = job.load(&self.scene)?;
+        // ranges may use the names of colors in the [colors] table of the scene
+        let mut randomize = self.randomize.clone();
+        for (name, range) in randomize.colors.iter_mut() {
+            match base.materials.get(name) {
+                Some(spec) if spec.kind.clone().color_mut().is_some() => (),
+                Some(_) => return Err(format!("material {name} has no color to randomize")),
+                None => return Err(format!("unknown material {name}")),
+            }
+            for c in range {
+                c.resolve(&base.colors)
+                    .map_err(|color| format!("material {name}: unknown color: {color}"))?;
+            }
+        }
+
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let variants = (0..self.count)
+            .map(|i| {
+                let (mut s, params) = randomize.apply(&base, &mut rng);
+                let ext = s.output.format.extension();
+                s.output.path = Some(base.output.aux_path(&format!("{i:04}"), ext));
+
+                (s, format!("{{\n  \"variant\": {i},\n{params}\n}}\n"))
+            })
+            .collect();
+
+        Ok(variants)
+    }
+
+    /// Render each variant with render, running up to parallel variants at once, after writing
+    /// the parameters drawn for it.
+    pub fn run(&self, render: impl Fn(&Scene, &str) + Sync) -> Result<Vec<JobResult>, String> {
+        let mut jobs = Vec::with_capacity(self.count as usize);
+        for (i, (s, params)) in self.variants()?.into_iter().enumerate() {
+            let path = s.output.aux_path("params", "json");
+            fs::write(&path, params).map_err(|e| format!("unable to write {path}: {e}"))?;
+            jobs.push((format!("{i:04}"), self.scene.clone(), Ok(s)));
+        }
+
+        Ok(batch::run_scenes(self.parallel, jobs, render))
+    }
+}
+
+impl Randomize {
+    /// The scene with parameters drawn from these ranges along with the JSON fields recording
+    /// them.
+    pub fn apply(&self, scene: &Scene, rng: &mut StdRng) -> (Scene, String) {
+        let mut s = scene.clone();
+        let mut offset = |max: [f32; 3]| {
+            let [x, y, z] = max.map(|m| m * rng.random_range(-1.0..=1.0));
+            V3::new(x, y, z)
+        };
+
+        let add = |p: [f32; 3], d: V3| [p[0] + d.x, p[1] + d.y, p[2] + d.z];
+        if let Some(max) = self.from {
+            s.from = add(s.from, offset(max));
+        }
+        if let Some(max) = self.at {
+            s.at = add(s.at, offset(max));
+        }
+        let mut objects = Vec::new();
+        for (name, max) in self.objects.iter() {
+            let mut moved = Vec::new();
+            s.offset_objects(name, || {
+                let d = offset(*max);
+                moved.push(json_vec([d.x, d.y, d.z]));
+                d
+            });
+            objects.push(format!("    {}: [{}]", json_str(name), moved.join(", ")));
+        }
+
+        if let Some([lo, hi]) = self.fov {
+            s.fov = Angle::deg(rng.random_range(lo.degrees()..=hi.degrees()));
+        }
+        let mut colors = Vec::new();
+        for (name, [lo, hi]) in self.colors.iter() {
+            let (lo, hi) = (Color::from(lo), Color::from(hi));
+            let mut channel = |lo: f32, hi: f32| lo + (hi - lo) * rng.random_range(0.0..=1.0);
+            let rgb = [
+                channel(lo.r, hi.r),
+                channel(lo.g, hi.g),
+                channel(lo.b, hi.b),
+            ];
+            if let Some(color) = s.materials.get_mut(name).and_then(|m| m.kind.color_mut()) {
+                *color = ColorSpec::RGB(rgb);
+            }
+            colors.push(format!("    {}: {}", json_str(name), json_vec(rgb)));
+        }
+
+        // after the colors so that lights given a color are scaled too
+        let light_intensity = match self.light_intensity {
+            Some([lo, hi]) => rng.random_range(lo..=hi),
+            None => 1.0,
+        };
+        if self.light_intensity.is_some() {
+            for spec in s.materials.values_mut() {
+                spec.kind.scale_emission(light_intensity);
+            }
+        }
+
+        let params = [
+            format!("  \"from\": {}", json_vec(s.from)),
+            format!("  \"at\": {}", json_vec(s.at)),
+            format!("  \"fov\": {}", s.fov.degrees()),
+            format!("  \"light_intensity\": {light_intensity}"),
+            format!("  \"colors\": {}", json_object(&colors)),
+            format!("  \"objects\": {}", json_object(&objects)),
+        ];
+
+        (s, params.join(",\n"))
+    }
+}
+
+fn json_vec(v: [f32; 3]) -> String {
+    // adding zero turns -0 into 0
+    let [x, y, z] = v.map(|c| c + 0.0);
+    format!("[{x}, {y}, {z}]")
+}
+
+fn json_object(entries: &[String]) -> String {
+    match entries {
+        [] => "{}".to_string(),
+        _ => format!("{{\n{}\n  }}", entries.join(",\n")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = "
+samples_per_pixel = 10
+max_bounces = 5
+fov = 40.0
+image_width = 100
+aspect_ratio = 1.0
+from = [0.0, 0.0, 5.0]
+at = [0.0, 0.0, 0.0]
+v_up = [0.0, 1.0, 0.0]
+as_points = false
+point_radius = 0.0
+bg = 0.5
+
+[materials.white]
+kind = \"solid\"
+color = 0.5
+
+[materials.light]
+kind = \"light\"
+color = [2.0, 4.0, 4.0]
+
+[[objects]]
+kind = \"sphere\"
+name = \"ball\"
+center = [0.0, 0.0, 0.0]
+r = 1.0
+material = \"white\"
+
+[[objects]]
+kind = \"sphere\"
+center = [0.0, 5.0, 0.0]
+r = 1.0
+material = \"light\"
+
+[output]
+path = \"out.png\"
+format = \"png\"
+";
+
+    fn dataset(randomize: &str) -> Dataset {
+        let path = std::env::temp_dir().join("raymart-dataset-test.toml");
+        fs::write(&path, SCENE).unwrap();
+
+        Dataset::parse(&format!(
+            "scene = {path:?}\ncount = 3\nseed = 1\nset = {{ max_bounces = 2 }}\n\n[randomize]\n{randomize}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn variants_are_drawn_from_the_ranges_and_reproducible() {
+        let d = dataset(
+            "from = [1.0, 0.0, 0.0]
+fov = [30.0, 50.0]
+light_intensity = [0.5, 2.0]
+colors = { white = [[0.0, 0.5, 1.0], [0.2, 0.5, 1.0]] }
+objects = { ball = [0.0, 2.0, 0.0] }",
+        );
+        let variants = d.variants().unwrap();
+
+        assert_eq!(variants.len(), 3);
+        for (i, (s, params)) in variants.iter().enumerate() {
+            assert_eq!(s.output.path(), format!("out.{i:04}.png"));
+            assert_eq!(s.max_bounces, 2);
+            assert!((s.from[0] - 0.0).abs() <= 1.0 && s.from[1..] == [0.0, 5.0]);
+            assert!((30.0..=50.0).contains(&s.fov.degrees()));
+
+            let color =
+                |name: &str| Color::from(&*s.materials[name].kind.clone().color_mut().unwrap());
+            let white = color("white");
+            assert!((0.0..=0.2).contains(&white.r) && white.g == 0.5 && white.b == 1.0);
+            let light = color("light");
+            assert!((light.g - 2.0 * light.r).abs() < 1e-5 && (1.0..=4.0).contains(&light.r));
+
+            let ball = s.object_spaces()[0].transform_point(crate::P3::new(0.0, 0.0, 0.0));
+            assert!(ball.x == 0.0 && ball.y.abs() <= 2.0, "{ball:?}");
+            assert!(params.contains(&format!("\"variant\": {i}")), "{params}");
+        }
+
+        let again = d.variants().unwrap();
+        assert_eq!(variants[2].1, again[2].1);
+        assert_ne!(variants[1].1, variants[2].1);
+    }
+
+    #[test]
+    fn unknown_materials_are_errors() {
+        let d = dataset("colors = { chrome = [0.0, 1.0] }");
+
+        assert_eq!(d.variants().unwrap_err(), "unknown material chrome");
+    }
+}
@@ -0,0 +1,274 @@
+//! Deep compositing output: multiple depth samples per pixel written to a deep scanline OpenEXR
+//! file so that renders (particularly of volumetrics) can be merged with other elements after
+//! rendering.
+//!   https://openexr.com/en/latest/InterpretingDeepPixels.html
+//!   https://openexr.com/en/latest/OpenEXRFileLayout.html
+//!
+//! Each pixel is sampled with full paths and the radiance of each path is binned by the distance
+//! to its first hit. Surfaces produce a single tight bin while media spread their samples over
+//! their depth. Bin alphas are chosen so that compositing the bins front to back reproduces the
+//! pixel value, with paths that escape the scene leaving the pixel transparent.
+use crate::{bvh::Bvh, output::Output, ray::Camera, Color};
+use std::{fs, io};
+
+/// Relative depth range covered by a single bin
+const DEPTH_TOLERANCE: f32 = 0.01;
+
+/// A single deep sample covering the depth range [z, z_back] with premultiplied color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeepSample {
+    pub z: f32,
+    pub z_back: f32,
+    pub alpha: f32,
+    pub color: Color,
+}
+
+/// Bin (depth, radiance) samples for a pixel into deep samples. Escaped paths should be
+/// included with infinite depth so that they contribute to the transparency of the pixel.
+pub fn deep_pixel(samples: &mut [(f32, Color)]) -> Vec<DeepSample> {
+    let total = samples.len() as f32;
+    samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut deep: Vec<DeepSample> = Vec::new();
+    let mut remaining = total;
+    let mut i = 0;
+
+    while i < samples.len() && samples[i].0.is_finite() {
+        let z = samples[i].0;
+        let mut sum = Color::BLACK;
+        let mut n = 0;
+        while i < samples.len() && samples[i].0 <= z * (1.0 + DEPTH_TOLERANCE) {
+            sum += samples[i].1;
+            n += 1;
+            i += 1;
+        }
+
+        // the bin covers n of the remaining samples and everything in front of it has already
+        // removed total - remaining of them from view
+        deep.push(DeepSample {
+            z,
+            z_back: samples[i - 1].0,
+            alpha: n as f32 / remaining,
+            color: sum / remaining,
+        });
+        remaining -= n as f32;
+    }
+
+    deep
+}
+
+/// Trace output.aov_samples paths per pixel and write the binned deep samples to a deep EXR.
+pub fn write_deep(camera: &Camera, bvh: &Bvh, output: &Output) -> io::Result<()> {
+    let (w, h) = camera.dimensions();
+    let n = output.aov_samples.max(1);
+
+    let pixels = camera.map_pixels(|i, j| {
+        let mut samples: Vec<(f32, Color)> = (0..n)
+            .map(|_| {
+                let (c, dist) = camera.sample_with_depth(i, j, bvh);
+                (dist, c)
+            })
+            .collect();
+
+        deep_pixel(&mut samples)
+    });
+
+    fs::write(
+        output.aux_path("deep", "exr"),
+        deep_exr(w as usize, h as usize, &pixels, &output.metadata),
+    )
+}
+
+/// Append an EXR header attribute: its name, type, size in bytes and value.
+pub fn attribute(buf: &mut Vec<u8>, name: &str, ty: &str, value: &[u8]) {
+    buf.extend(name.as_bytes());
+    buf.push(0);
+    buf.extend(ty.as_bytes());
+    buf.push(0);
+    buf.extend((value.len() as i32).to_le_bytes());
+    buf.extend(value);
+}
+
+/// Encode an uncompressed single part deep scanline EXR with A, B, G, R, Z and ZBack float
+/// channels and one scanline per chunk. Metadata is written as string header attributes.
+fn deep_exr(
+    width: usize,
+    height: usize,
+    pixels: &[Vec<DeepSample>],
+    metadata: &[(String, String)],
+) -> Vec<u8> {
+    const FLOAT: i32 = 2;
+    const CHANNELS: [&str; 6] = ["A", "B", "G", "R", "Z", "ZBack"]; // must be sorted
+
+    let mut buf = Vec::new();
+    buf.extend(20000630i32.to_le_bytes()); // magic number
+    buf.extend((2i32 | 0x800).to_le_bytes()); // version 2 with the deep data flag
+
+    let mut chlist = Vec::new();
+    for name in CHANNELS {
+        chlist.extend(name.as_bytes());
+        chlist.push(0);
+        chlist.extend(FLOAT.to_le_bytes());
+        chlist.extend([0, 0, 0, 0]); // pLinear and reserved
+        chlist.extend(1i32.to_le_bytes()); // x sampling
+        chlist.extend(1i32.to_le_bytes()); // y sampling
+    }
+    chlist.push(0);
+
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+    let max_samples = pixels.iter().map(|p| p.len()).max().unwrap_or(0) as i32;
+
+    attribute(&mut buf, "channels", "chlist", &chlist);
+    attribute(
+        &mut buf,
+        "chunkCount",
+        "int",
+        &(height as i32).to_le_bytes(),
+    );
+    attribute(&mut buf, "compression", "compression", &[0]);
+    attribute(&mut buf, "dataWindow", "box2i", &window);
+    attribute(&mut buf, "displayWindow", "box2i", &window);
+    attribute(&mut buf, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut buf,
+        "maxSamplesPerPixel",
+        "int",
+        &max_samples.to_le_bytes(),
+    );
+    attribute(&mut buf, "name", "string", b"deep");
+    attribute(&mut buf, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute(&mut buf, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut buf,
+        "screenWindowWidth",
+        "float",
+        &1.0f32.to_le_bytes(),
+    );
+    attribute(&mut buf, "type", "string", b"deepscanline");
+    attribute(&mut buf, "version", "int", &1i32.to_le_bytes());
+    for (k, v) in metadata {
+        attribute(&mut buf, k, "string", v.as_bytes());
+    }
+    buf.push(0); // end of header
+
+    let chunks: Vec<Vec<u8>> = pixels
+        .chunks(width)
+        .enumerate()
+        .map(|(y, row)| {
+            let mut offsets = Vec::with_capacity(width * 4);
+            let mut count = 0i32;
+            for p in row {
+                count += p.len() as i32;
+                offsets.extend(count.to_le_bytes());
+            }
+
+            let channel = |f: fn(&DeepSample) -> f32| -> Vec<u8> {
+                row.iter()
+                    .flatten()
+                    .flat_map(|s| f(s).to_le_bytes())
+                    .collect()
+            };
+            let data = [
+                channel(|s| s.alpha),
+                channel(|s| s.color.b),
+                channel(|s| s.color.g),
+                channel(|s| s.color.r),
+                channel(|s| s.z),
+                channel(|s| s.z_back),
+            ]
+            .concat();
+
+            let mut chunk = (y as i32).to_le_bytes().to_vec();
+            chunk.extend((offsets.len() as u64).to_le_bytes());
+            chunk.extend((data.len() as u64).to_le_bytes()); // packed size
+            chunk.extend((data.len() as u64).to_le_bytes()); // unpacked size
+            chunk.extend(offsets);
+            chunk.extend(data);
+
+            chunk
+        })
+        .collect();
+
+    // offset table of the absolute file position of each chunk
+    let mut pos = (buf.len() + 8 * chunks.len()) as u64;
+    for c in chunks.iter() {
+        buf.extend(pos.to_le_bytes());
+        pos += c.len() as u64;
+    }
+    for c in chunks {
+        buf.extend(c);
+    }
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Composite deep samples front to back using "over".
+    fn flatten(deep: &[DeepSample]) -> (Color, f32) {
+        deep.iter().fold((Color::BLACK, 0.0), |(c, a), s| {
+            (c + s.color * (1.0 - a), a + s.alpha * (1.0 - a))
+        })
+    }
+
+    #[test]
+    fn flattening_deep_pixels_gives_the_mean() {
+        let mut samples = vec![
+            (5.0, Color::grey(1.0)),
+            (2.0, Color::grey(0.5)),
+            (2.01, Color::grey(0.3)),
+            (9.0, Color::grey(0.2)),
+            (f32::INFINITY, Color::grey(0.6)),
+        ];
+        let mean = samples.iter().fold(Color::BLACK, |acc, s| acc + s.1) / 5.0;
+        let deep = deep_pixel(&mut samples);
+        let (c, a) = flatten(&deep);
+
+        assert_eq!(deep.len(), 3, "{deep:?}");
+        assert_eq!((deep[0].z, deep[0].z_back), (2.0, 2.01));
+        // escaped paths are transparent so the background is added back in comp
+        assert!((a - 0.8).abs() < 1e-5, "{a}");
+        assert!((c.r - (mean.r - 0.6 / 5.0)).abs() < 1e-5, "{c:?} {mean:?}");
+    }
+
+    #[test]
+    fn deep_exr_offsets_point_at_chunks() {
+        let s = DeepSample {
+            z: 1.0,
+            z_back: 1.0,
+            alpha: 1.0,
+            color: Color::WHITE,
+        };
+        let pixels = vec![vec![s], vec![], vec![s, s], vec![s]];
+        let bytes = deep_exr(
+            2,
+            2,
+            &pixels,
+            &[("Software".to_string(), "raymart".to_string())],
+        );
+
+        let read_u64 = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let read_i32 = |i: usize| i32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        // the file ends with the offset table followed by the two chunks
+        let table = bytes.len() - chunk_len(1) - chunk_len(3) - 16;
+
+        assert_eq!(read_i32(0), 20000630);
+        assert_eq!(read_u64(table) as usize, table + 16);
+        assert_eq!(read_u64(table + 8) as usize, table + 16 + chunk_len(1));
+        assert_eq!(read_i32(table + 16), 0);
+        assert_eq!(read_i32(table + 16 + chunk_len(1)), 1);
+        // cumulative sample counts for the second row
+        let offsets = table + 16 + chunk_len(1) + 4 + 24;
+        assert_eq!((read_i32(offsets), read_i32(offsets + 4)), (2, 3));
+    }
+
+    /// y, sizes, pixel offsets for two pixels and six float channels
+    fn chunk_len(samples: usize) -> usize {
+        4 + 3 * 8 + 2 * 4 + 6 * 4 * samples
+    }
+}
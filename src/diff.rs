@@ -0,0 +1,471 @@
+//! Comparison of two rendered images for checking that changes to sampling strategies or
+//! refactors are noise-neutral.
+//!   https://en.wikipedia.org/wiki/Root_mean_square_deviation
+//!   https://en.wikipedia.org/wiki/Color_difference#CIE76
+//!   Andersson et al, "FLIP: A Difference Evaluator for Alternating Images" (HPG 2020)
+use crate::{Color, V3};
+use image::{
+    error::{ParameterError, ParameterErrorKind},
+    open, ImageError, ImageResult, Rgb32FImage, RgbImage,
+};
+use std::f32::consts::PI;
+
+/// Pixels per degree of visual angle that FLIP assumes the images are viewed at: a 0.7m wide
+/// 4K monitor seen from 0.7m away
+const FLIP_PPD: f32 = 67.0;
+/// Weights a and widths b of the two Gaussians making up the contrast sensitivity of each of the
+/// achromatic, red-green and blue-yellow channels.
+const FLIP_CSF: [[(f32, f32); 2]; 3] = [
+    [(1.0, 0.0047), (0.0, 1e-5)],
+    [(1.0, 0.0053), (0.0, 1e-5)],
+    [(34.1, 0.04), (13.5, 0.025)],
+];
+/// Exponents compressing the color and feature differences
+const FLIP_QC: f32 = 0.7;
+const FLIP_QF: f32 = 0.5;
+/// Fraction pc of the largest color difference that is mapped to the fraction pt of the error
+/// range, leaving the rest for the much rarer larger differences
+const FLIP_PC: f32 = 0.4;
+const FLIP_PT: f32 = 0.95;
+/// Standard deviation of the feature detectors in degrees of visual angle
+const FLIP_FEATURE_SD: f32 = 0.5 * 0.082;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiffStats {
+    /// Root mean squared error of the encoded [0,1] channel values
+    pub rmse: f32,
+    /// Peak signal to noise ratio in dB
+    pub psnr: f32,
+    /// Mean perceptual color difference (CIE76 ΔE in L*a*b*)
+    pub mean_delta_e: f32,
+    /// Largest perceptual color difference found in any pixel
+    pub max_delta_e: f32,
+    /// Mean FLIP error in [0,1], which accounts for how differences are seen at a normal viewing
+    /// distance: fine noise is blurred away while shifted edges and points stand out
+    pub mean_flip: f32,
+}
+
+/// Decode an sRGB encoded channel value back to linear.
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Convert a linear color to CIE XYZ relative to the D65 white point, so that white is (1, 1, 1).
+fn linear_to_xyz(c: Color) -> V3 {
+    V3::new(
+        (0.4124 * c.r + 0.3576 * c.g + 0.1805 * c.b) / 0.95047,
+        0.2126 * c.r + 0.7152 * c.g + 0.0722 * c.b,
+        (0.0193 * c.r + 0.1192 * c.g + 0.9505 * c.b) / 1.08883,
+    )
+}
+
+/// The inverse of [linear_to_xyz].
+fn xyz_to_linear(v: V3) -> Color {
+    let (x, y, z) = (v.x * 0.95047, v.y, v.z * 1.08883);
+
+    Color::new(
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    )
+}
+
+/// Convert relative XYZ to CIE L*a*b* as (L, a, b) coordinates.
+fn xyz_to_lab(v: V3) -> V3 {
+    let f = |t: f32| {
+        if t > 0.008856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(v.x), f(v.y), f(v.z));
+
+    V3::new(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+/// Convert an sRGB encoded color to CIE L*a*b* (D65 white point) as (L, a, b) coordinates.
+fn to_lab(c: Color) -> V3 {
+    let linear = Color::new(
+        srgb_to_linear(c.r),
+        srgb_to_linear(c.g),
+        srgb_to_linear(c.b),
+    );
+
+    xyz_to_lab(linear_to_xyz(linear))
+}
+
+/// The linearized opponent space (Yy, Cx, Cz) of L*a*b* that FLIP filters in, where the
+/// channels are a linear transform of XYZ.
+fn xyz_to_ycxcz(v: V3) -> V3 {
+    V3::new(116.0 * v.y - 16.0, 500.0 * (v.x - v.y), 200.0 * (v.y - v.z))
+}
+
+fn ycxcz_to_xyz(v: V3) -> V3 {
+    let y = (v.x + 16.0) / 116.0;
+
+    V3::new(y + v.y / 500.0, y, y - v.z / 200.0)
+}
+
+/// L*a*b* with the chroma scaled by lightness (the Hunt effect: colors look less saturated as
+/// they get darker).
+fn hunt_lab(c: Color) -> V3 {
+    let lab = xyz_to_lab(linear_to_xyz(c));
+
+    V3::new(lab.x, 0.01 * lab.x * lab.y, 0.01 * lab.x * lab.z)
+}
+
+/// The HyAB distance between two colors: city block in lightness and euclidean in chroma, which
+/// tracks large color differences better than the euclidean distance.
+fn hyab(a: V3, b: V3) -> f32 {
+    (a.x - b.x).abs() + ((a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt()
+}
+
+/// A single channel image
+struct Plane {
+    w: usize,
+    h: usize,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    fn new(w: usize, h: usize, f: impl Fn(usize, usize) -> f32) -> Self {
+        let data = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .map(|(x, y)| f(x, y));
+
+        Self {
+            w,
+            h,
+            data: data.collect(),
+        }
+    }
+
+    fn at(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.w + x]
+    }
+
+    /// Convolve with the separable kernel kx(x)ky(y), both of odd length and centered, clamping
+    /// lookups to the edges of the image.
+    fn convolve(&self, kx: &[f32], ky: &[f32]) -> Self {
+        let pass = |src: &Plane, k: &[f32], horizontal: bool| {
+            let r = (k.len() / 2) as isize;
+            Plane::new(src.w, src.h, |x, y| {
+                k.iter()
+                    .enumerate()
+                    .map(|(i, w)| {
+                        let d = i as isize - r;
+                        let (sx, sy) = if horizontal {
+                            ((x as isize + d).clamp(0, src.w as isize - 1) as usize, y)
+                        } else {
+                            (x, (y as isize + d).clamp(0, src.h as isize - 1) as usize)
+                        };
+                        w * src.at(sx, sy)
+                    })
+                    .sum()
+            })
+        };
+
+        pass(&pass(self, kx, true), ky, false)
+    }
+}
+
+/// The contrast sensitivity of each opponent channel as a pair of separable Gaussian kernels
+/// with their 2D weights, normalized so that the full 2D filter sums to 1.
+fn csf_filters() -> [Vec<(Vec<f32>, f32)>; 3] {
+    let max_b = FLIP_CSF
+        .iter()
+        .flatten()
+        .map(|(_, b)| *b)
+        .fold(0.0, f32::max);
+    let radius = (3.0 * (max_b / (2.0 * PI * PI)).sqrt() * FLIP_PPD).ceil() as i32;
+
+    FLIP_CSF.map(|terms| {
+        let terms: Vec<(Vec<f32>, f32)> = terms
+            .iter()
+            .filter(|(a, _)| *a > 0.0)
+            .map(|&(a, b)| {
+                let k: Vec<f32> = (-radius..=radius)
+                    .map(|i| (-PI * PI * (i as f32 / FLIP_PPD).powi(2) / b).exp())
+                    .collect();
+                (k, a * (PI / b).sqrt())
+            })
+            .collect();
+        let total: f32 = terms
+            .iter()
+            .map(|(k, a)| a * k.iter().sum::<f32>().powi(2))
+            .sum();
+
+        terms.into_iter().map(|(k, a)| (k, a / total)).collect()
+    })
+}
+
+/// First (edge) and second (point) derivative of Gaussian kernels along one axis, each with its
+/// positive and negative weights summing to 1 and -1, and the Gaussian they are paired with
+/// along the other axis.
+fn feature_filters() -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+    let sd = FLIP_FEATURE_SD * FLIP_PPD;
+    let radius = (3.0 * sd).ceil() as i32;
+    let xs: Vec<f32> = (-radius..=radius).map(|i| i as f32).collect();
+    let g: Vec<f32> = xs
+        .iter()
+        .map(|x| (-x * x / (2.0 * sd * sd)).exp())
+        .collect();
+    let normalize = |k: Vec<f32>| {
+        let pos: f32 = k.iter().filter(|&&w| w > 0.0).sum();
+        let neg: f32 = -k.iter().filter(|&&w| w < 0.0).sum::<f32>();
+        k.into_iter()
+            .map(|w| if w > 0.0 { w / pos } else { w / neg })
+            .collect()
+    };
+
+    let edge = normalize(xs.iter().zip(&g).map(|(x, g)| -x * g).collect());
+    let point = normalize(
+        xs.iter()
+            .zip(&g)
+            .map(|(x, g)| (x * x / (sd * sd) - 1.0) * g)
+            .collect(),
+    );
+    let sum: f32 = g.iter().sum();
+
+    (edge, point, g.into_iter().map(|w| w / sum).collect())
+}
+
+/// The FLIP error of each pixel of b against the reference a, both sRGB encoded. Colors are
+/// compared after filtering by the contrast sensitivity of the eye, and the error is increased
+/// where edges and points in the lightness differ.
+pub fn flip(a: &Rgb32FImage, b: &Rgb32FImage) -> Vec<f32> {
+    let (w, h) = (a.width() as usize, a.height() as usize);
+    let opponent = |img: &Rgb32FImage| -> [Plane; 3] {
+        let ycxcz: Vec<V3> = img
+            .pixels()
+            .map(|p| {
+                let [r, g, b] = p.0.map(srgb_to_linear);
+                xyz_to_ycxcz(linear_to_xyz(Color::new(r, g, b)))
+            })
+            .collect();
+        [0, 1, 2].map(|c| Plane::new(w, h, |x, y| ycxcz[y * w + x][c]))
+    };
+    let (oa, ob) = (opponent(a), opponent(b));
+
+    // color pipeline
+    let filters = csf_filters();
+    let filtered = |o: &[Plane; 3]| -> Vec<V3> {
+        let channels: Vec<Plane> = o
+            .iter()
+            .zip(&filters)
+            .map(|(p, terms)| {
+                let mut sum = Plane::new(w, h, |_, _| 0.0);
+                for (k, weight) in terms {
+                    let f = p.convolve(k, k);
+                    sum.data
+                        .iter_mut()
+                        .zip(f.data)
+                        .for_each(|(s, v)| *s += weight * v);
+                }
+                sum
+            })
+            .collect();
+
+        (0..w * h)
+            .map(|i| {
+                let v = V3::new(
+                    channels[0].data[i],
+                    channels[1].data[i],
+                    channels[2].data[i],
+                );
+                let c = xyz_to_linear(ycxcz_to_xyz(v));
+                hunt_lab(Color::new(
+                    c.r.clamp(0.0, 1.0),
+                    c.g.clamp(0.0, 1.0),
+                    c.b.clamp(0.0, 1.0),
+                ))
+            })
+            .collect()
+    };
+    let (fa, fb) = (filtered(&oa), filtered(&ob));
+    let cmax = hyab(
+        hunt_lab(Color::new(0.0, 1.0, 0.0)),
+        hunt_lab(Color::new(0.0, 0.0, 1.0)),
+    )
+    .powf(FLIP_QC);
+
+    // feature pipeline on the normalized achromatic channel
+    let (edge, point, g) = feature_filters();
+    let features = |o: &[Plane; 3]| -> (Plane, Plane) {
+        let y = Plane::new(w, h, |x, y| (o[0].at(x, y) + 16.0) / 116.0);
+        let magnitude = |k: &[f32]| {
+            let (dx, dy) = (y.convolve(k, &g), y.convolve(&g, k));
+            Plane::new(w, h, |x, y| dx.at(x, y).hypot(dy.at(x, y)))
+        };
+        (magnitude(&edge), magnitude(&point))
+    };
+    let ((ea, pa), (eb, pb)) = (features(&oa), features(&ob));
+
+    (0..w * h)
+        .map(|i| {
+            let de = hyab(fa[i], fb[i]).powf(FLIP_QC);
+            let dc = if de < FLIP_PC * cmax {
+                FLIP_PT / (FLIP_PC * cmax) * de
+            } else {
+                FLIP_PT + (de - FLIP_PC * cmax) / (cmax - FLIP_PC * cmax) * (1.0 - FLIP_PT)
+            };
+            let edges = (ea.data[i] - eb.data[i]).abs();
+            let points = (pa.data[i] - pb.data[i]).abs();
+            let df = (edges.max(points) / 2f32.sqrt()).powf(FLIP_QF);
+
+            dc.powf(1.0 - df)
+        })
+        .collect()
+}
+
+fn pixel(img: &Rgb32FImage, x: u32, y: u32) -> Color {
+    let [r, g, b] = img.get_pixel(x, y).0;
+    Color::new(r, g, b)
+}
+
+/// Compare two images of the same dimensions, returning summary statistics and the per-pixel
+/// ΔE values.
+pub fn compare(a: &Rgb32FImage, b: &Rgb32FImage) -> (DiffStats, Vec<f32>) {
+    let mut sq_err = 0.0f64;
+    let mut delta_es = Vec::with_capacity((a.width() * a.height()) as usize);
+
+    for y in 0..a.height() {
+        for x in 0..a.width() {
+            let (pa, pb) = (pixel(a, x, y), pixel(b, x, y));
+            let (dr, dg, db) = (pa.r - pb.r, pa.g - pb.g, pa.b - pb.b);
+            sq_err += (dr * dr + dg * dg + db * db) as f64;
+            delta_es.push((to_lab(pa) - to_lab(pb)).length());
+        }
+    }
+
+    let n = delta_es.len().max(1);
+    let rmse = (sq_err / (3 * n) as f64).sqrt() as f32;
+    let stats = DiffStats {
+        rmse,
+        psnr: -20.0 * rmse.log10(),
+        mean_delta_e: delta_es.iter().sum::<f32>() / n as f32,
+        max_delta_e: delta_es.iter().copied().fold(0.0, f32::max),
+        mean_flip: flip(a, b).iter().sum::<f32>() / n as f32,
+    };
+
+    (stats, delta_es)
+}
+
+/// Map a normalised error in [0,1] onto a black -> red -> yellow -> white heatmap.
+fn heat(t: f32) -> [u8; 3] {
+    let t = t.clamp(0.0, 1.0) * 3.0;
+    let r = t.min(1.0);
+    let g = (t - 1.0).clamp(0.0, 1.0);
+    let b = (t - 2.0).clamp(0.0, 1.0);
+
+    [(255.0 * r) as u8, (255.0 * g) as u8, (255.0 * b) as u8]
+}
+
+/// Load the two images at path_a and path_b, print their difference metrics and write a heatmap
+/// of the per-pixel differences to out_path.
+pub fn diff_images(path_a: &str, path_b: &str, out_path: &str) -> ImageResult<DiffStats> {
+    let a = open(path_a)?.into_rgb32f();
+    let b = open(path_b)?.into_rgb32f();
+    if a.dimensions() != b.dimensions() {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::Generic(format!(
+                "image dimensions differ: {:?} != {:?}",
+                a.dimensions(),
+                b.dimensions()
+            )),
+        )));
+    }
+
+    let (stats, delta_es) = compare(&a, &b);
+    let scale = if stats.max_delta_e > 0.0 {
+        1.0 / stats.max_delta_e
+    } else {
+        0.0
+    };
+    let raw = delta_es.iter().flat_map(|&d| heat(d * scale)).collect();
+    RgbImage::from_raw(a.width(), a.height(), raw)
+        .unwrap()
+        .save(out_path)?;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    fn img(c: f32) -> Rgb32FImage {
+        Rgb32FImage::from_pixel(4, 4, image::Rgb([c, c, c]))
+    }
+
+    #[test_case(0.5, 0.5, 0.0; "identical")]
+    #[test_case(0.5, 0.6, 0.1; "constant offset")]
+    #[test_case(0.0, 1.0, 1.0; "black and white")]
+    #[test]
+    fn rmse_is_correct(a: f32, b: f32, expected: f32) {
+        let (stats, _) = compare(&img(a), &img(b));
+
+        assert!((stats.rmse - expected).abs() < 1e-5, "{}", stats.rmse);
+    }
+
+    #[test_case(0.5, 0.5, 0.0, 1e-6; "identical")]
+    #[test_case(0.5, 0.51, 0.0, 0.1; "barely visible offset")]
+    #[test_case(0.0, 1.0, 1.0, 0.05; "black and white")]
+    #[test]
+    fn flip_is_correct(a: f32, b: f32, expected: f32, tolerance: f32) {
+        let (stats, _) = compare(&img(a), &img(b));
+
+        assert!(
+            (stats.mean_flip - expected).abs() < tolerance,
+            "{}",
+            stats.mean_flip
+        );
+    }
+
+    #[test]
+    fn flip_finds_shifted_edges() {
+        let edge = |at: u32| {
+            Rgb32FImage::from_fn(32, 32, |x, _| {
+                let c = if x < at { 0.2 } else { 0.25 };
+                image::Rgb([c, c, c])
+            })
+        };
+        let err = flip(&edge(16), &edge(18));
+
+        assert!(err[16 * 32 + 17] > 10.0 * err[16 * 32 + 2], "{err:?}");
+    }
+
+    #[test]
+    fn different_dimensions_are_an_error() {
+        let dir = std::env::temp_dir();
+        let (a, b) = (
+            dir.join("raymart_diff_a.png"),
+            dir.join("raymart_diff_b.png"),
+        );
+        RgbImage::new(4, 4).save(&a).unwrap();
+        RgbImage::new(4, 2).save(&b).unwrap();
+        let out = dir.join("raymart_diff_out.png");
+
+        let res = diff_images(
+            &a.to_string_lossy(),
+            &b.to_string_lossy(),
+            &out.to_string_lossy(),
+        );
+
+        assert!(res.is_err(), "{res:?}");
+    }
+
+    #[test]
+    fn white_is_l_100() {
+        let lab = to_lab(Color::WHITE);
+
+        assert!((lab.x - 100.0).abs() < 0.01, "{lab:?}");
+        assert!(lab.y.abs() < 0.1 && lab.z.abs() < 0.1, "{lab:?}");
+    }
+}
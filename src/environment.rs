@@ -0,0 +1,552 @@
+//! Image based lighting from an equirectangular environment map: rays that escape the scene
+//! look up the light arriving from their direction in a (usually HDR) panorama, with the center
+//! of the image straight ahead along -z and the top row straight up.
+//!
+//! Bright areas of the map like the sun or a window can be importance sampled so that diffuse
+//! bounces find them directly rather than waiting for a bounce to hit them by chance. Pixels
+//! are chosen in proportion to their luminance weighted by the solid angle they cover, by
+//! picking a row from the marginal distribution over rows and then a pixel from the
+//! distribution within that row.
+//!
+//! Decoding a large map and building its distributions can take a noticeable part of the start
+//! up time of a render, so `raymart envmap` precomputes them along with a mip chain of the map
+//! and writes them to a cache file next to it ("sky.hdr" is cached in "sky.hdr.envcache"). Maps
+//! with an up to date cache file are read from it rather than decoded. Given a size, it also
+//! writes the map out as the six faces of a cube map for use in other tools.
+use crate::{
+    angle::Angle,
+    sampler::random_range,
+    texture_cache::{read_u64, source_stamp},
+    Color, V3,
+};
+use image::{DynamicImage, Rgb32FImage};
+use std::{
+    f32::consts::PI,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+/// Start of every cache file, changed whenever the layout changes
+const MAGIC: &[u8; 8] = b"RMENV001";
+
+/// The name of a face of a cube map and the direction through each texel for (s, t) in [-1, 1]
+/// across and down the face
+type Face = (&'static str, fn(f32, f32) -> V3);
+
+/// The faces of a cube map in the usual +x, -x, +y, -y, +z, -z order
+const CUBE_FACES: [Face; 6] = [
+    ("px", |s, t| V3::new(1.0, -t, -s)),
+    ("nx", |s, t| V3::new(-1.0, -t, s)),
+    ("py", |s, t| V3::new(s, 1.0, t)),
+    ("ny", |s, t| V3::new(s, -1.0, -t)),
+    ("pz", |s, t| V3::new(s, -t, 1.0)),
+    ("nz", |s, t| V3::new(-s, -t, -1.0)),
+];
+
+/// The width, height and pixels of a level of the mip chain of a map
+type Level = (usize, usize, Vec<Color>);
+
+/// The cumulative distributions over the rows of a map and the pixels within each row
+type Distributions = (Vec<f32>, Vec<f32>);
+
+/// An equirectangular environment map along with the distributions for sampling it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Environment {
+    pub path: String,
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+    /// Rotation of the map about the y axis
+    rotate: Angle,
+    /// Cumulative distribution over rows, then over the pixels in each row. Empty for maps that
+    /// aren't sampled or have no light to sample.
+    rows: Vec<f32>,
+    cols: Vec<f32>,
+}
+
+impl Environment {
+    /// Load the image at path, scaling its light by intensity. HDR and EXR images are taken to
+    /// be linear while any other format is gamma decoded. Maps larger than max_size in width or
+    /// height are halved until they fit.
+    pub fn load(
+        path: &str,
+        intensity: f32,
+        rotate: Angle,
+        sample: bool,
+        max_size: Option<usize>,
+    ) -> Result<Self, String> {
+        let cache = cache_path(path);
+        let cached = match read_cache(&cache, source_stamp(path)?, max_size) {
+            Ok(level) => Some(level),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                eprintln!("warning: ignoring environment cache {cache}: {e}");
+                None
+            }
+        };
+
+        let Some(((width, height, pixels), dists)) = cached else {
+            let (mut w, mut h, mut pixels) = decode(path)?;
+            while max_size.is_some_and(|m| w.max(h) > m.max(1)) {
+                (w, h, pixels) = half_size(w, h, &pixels);
+            }
+            let pixels = pixels.into_iter().map(|c| intensity * c).collect();
+
+            return Ok(Self::new(path, w, h, pixels, rotate, sample));
+        };
+
+        // the distributions don't change when every pixel is scaled by the same intensity
+        let (rows, cols) = dists
+            .filter(|_| sample && intensity > 0.0)
+            .unwrap_or_default();
+
+        Ok(Self {
+            path: path.to_string(),
+            width,
+            height,
+            pixels: pixels.into_iter().map(|c| intensity * c).collect(),
+            rotate,
+            rows,
+            cols,
+        })
+    }
+
+    pub fn new(
+        path: &str,
+        width: usize,
+        height: usize,
+        pixels: Vec<Color>,
+        rotate: Angle,
+        sample: bool,
+    ) -> Self {
+        let (rows, cols) = if sample {
+            distributions(width, height, &pixels).unwrap_or_default()
+        } else {
+            Default::default()
+        };
+
+        Self {
+            path: path.to_string(),
+            width,
+            height,
+            pixels,
+            rotate,
+            rows,
+            cols,
+        }
+    }
+
+    /// Whether the map can be importance sampled
+    pub fn can_sample(&self) -> bool {
+        !self.rows.is_empty()
+    }
+
+    /// The light arriving from direction dir
+    pub fn value(&self, dir: V3) -> Color {
+        let (u, v) = self.uv(dir);
+        let i = ((u * self.width as f32) as usize).min(self.width - 1);
+        let j = ((v * self.height as f32) as usize).min(self.height - 1);
+
+        self.pixels[j * self.width + i]
+    }
+
+    /// A direction chosen in proportion to the light arriving from it
+    pub fn sample(&self) -> Option<V3> {
+        if !self.can_sample() {
+            return None;
+        }
+        let (w, h) = (self.width, self.height);
+        // the last entry at or below x, skipping entries with no weight
+        let pick =
+            |cdf: &[f32], x: f32| cdf.partition_point(|&c| c <= x).clamp(1, cdf.len() - 1) - 1;
+
+        let j = pick(&self.rows, random_range(0.0..1.0));
+        let i = pick(
+            &self.cols[j * (w + 1)..(j + 1) * (w + 1)],
+            random_range(0.0..1.0),
+        );
+        let u = (i as f32 + random_range(0.0..1.0)) / w as f32;
+        let v = (j as f32 + random_range(0.0..1.0)) / h as f32;
+
+        Some(self.dir(u, v))
+    }
+
+    /// The density over solid angle of [Environment::sample] giving dir
+    pub fn pdf(&self, dir: V3) -> f32 {
+        if !self.can_sample() {
+            return 0.0;
+        }
+        let (w, h) = (self.width, self.height);
+        let (u, v) = self.uv(dir);
+        let i = ((u * w as f32) as usize).min(w - 1);
+        let j = ((v * h as f32) as usize).min(h - 1);
+        let sin = (PI * v).sin();
+        if sin <= 0.0 {
+            return 0.0;
+        }
+        let row = self.rows[j + 1] - self.rows[j];
+        let col = self.cols[j * (w + 1) + i + 1] - self.cols[j * (w + 1) + i];
+
+        // density over the unit square of the image, then over the sphere
+        row * col * (w * h) as f32 / (2.0 * PI * PI * sin)
+    }
+
+    /// Position in the image of the direction dir, in [0, 1] across and down
+    fn uv(&self, dir: V3) -> (f32, f32) {
+        let d = rotate_y(dir.unit_vector(), -self.rotate);
+        let u = 0.5 + d.x.atan2(-d.z) / (2.0 * PI);
+        let v = d.y.clamp(-1.0, 1.0).acos() / PI;
+
+        (u, v)
+    }
+
+    fn dir(&self, u: f32, v: f32) -> V3 {
+        let (sin_phi, cos_phi) = ((u - 0.5) * 2.0 * PI).sin_cos();
+        let (sin_theta, cos_theta) = (v * PI).sin_cos();
+        let d = V3::new(sin_theta * sin_phi, cos_theta, -sin_theta * cos_phi);
+
+        rotate_y(d, self.rotate)
+    }
+
+    /// The six faces of a cube map of size by size pixels showing the map, named by the axis
+    /// they face (px, nx, py, ny, pz and nz).
+    pub fn cube_faces(&self, size: u32) -> Vec<(&'static str, Rgb32FImage)> {
+        let texel = |i: u32| 2.0 * (i as f32 + 0.5) / size as f32 - 1.0;
+
+        CUBE_FACES
+            .iter()
+            .map(|(name, dir)| {
+                let face = Rgb32FImage::from_fn(size, size, |x, y| {
+                    let c = self.value(dir(texel(x), texel(y)));
+                    image::Rgb([c.r, c.g, c.b])
+                });
+                (*name, face)
+            })
+            .collect()
+    }
+}
+
+/// The path of the cache file for the map at path
+pub fn cache_path(path: &str) -> String {
+    format!("{path}.envcache")
+}
+
+/// Decode the map at path and write it to its cache file along with its distributions at every
+/// level of its mip chain, returning the number of levels written.
+pub fn precompute(path: &str) -> Result<usize, String> {
+    let stamp = source_stamp(path)?;
+    let mut levels = vec![decode(path)?];
+    while let Some((w, h, pixels)) = levels.last().filter(|(w, h, _)| w.max(h) > &1) {
+        levels.push(half_size(*w, *h, pixels));
+    }
+
+    let cache = cache_path(path);
+    write_cache(&cache, stamp, &levels).map_err(|e| format!("unable to write {cache}: {e}"))?;
+
+    Ok(levels.len())
+}
+
+/// The linear pixels of the map at path
+fn decode(path: &str) -> Result<Level, String> {
+    let img = image::open(path).map_err(|e| e.to_string())?;
+    let linear = matches!(
+        img,
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    );
+    let img = img.into_rgb32f();
+    let pixels = img
+        .pixels()
+        .map(|p| {
+            let c = Color::new(p[0], p[1], p[2]);
+            if linear {
+                c
+            } else {
+                c * c
+            }
+        })
+        .collect();
+
+    Ok((img.width() as usize, img.height() as usize, pixels))
+}
+
+/// The distributions for sampling a map in proportion to its light, or None if it has no light
+/// to sample.
+fn distributions(w: usize, h: usize, pixels: &[Color]) -> Option<Distributions> {
+    let mut rows = Vec::with_capacity(h + 1);
+    let mut cols = Vec::with_capacity(h * (w + 1));
+    rows.push(0.0);
+
+    for j in 0..h {
+        // rows near the poles cover less of the sphere
+        let sin = (PI * (j as f32 + 0.5) / h as f32).sin();
+        let start = cols.len();
+        cols.push(0.0);
+        for i in 0..w {
+            let weight = pixels[j * w + i].luminance().max(0.0) * sin;
+            cols.push(cols[cols.len() - 1] + weight);
+        }
+        let total = cols[start + w];
+        rows.push(rows[j] + total);
+        if total > 0.0 {
+            cols[start..].iter_mut().for_each(|c| *c /= total);
+        }
+    }
+
+    let total = rows[h];
+    if total > 0.0 && total.is_finite() {
+        rows.iter_mut().for_each(|r| *r /= total);
+        Some((rows, cols))
+    } else {
+        None
+    }
+}
+
+/// The next level of the mip chain, averaging each 2x2 block of pixels (folding the last row or
+/// column into the one before for odd sizes).
+fn half_size(w: usize, h: usize, pixels: &[Color]) -> Level {
+    let (hw, hh) = ((w / 2).max(1), (h / 2).max(1));
+    let span = |i: usize, n: usize, half: usize| {
+        let start = i * n / half;
+        start..((i + 1) * n / half).max(start + 1)
+    };
+    let mut half = Vec::with_capacity(hw * hh);
+    for y in 0..hh {
+        for x in 0..hw {
+            let (xs, ys) = (span(x, w, hw), span(y, h, hh));
+            let n = (xs.len() * ys.len()) as f32;
+            let sum = ys
+                .flat_map(|j| xs.clone().map(move |i| pixels[j * w + i]))
+                .fold(Color::BLACK, |a, b| a + b);
+            half.push(sum * (1.0 / n));
+        }
+    }
+
+    (hw, hh, half)
+}
+
+/// Cache files hold the stamp of the map they were written for followed by each level of the
+/// mip chain, finest first, as its size and pixels then its distributions (empty when there is
+/// no light to sample). Everything is stored little endian.
+fn write_cache(path: &str, stamp: [u64; 2], levels: &[Level]) -> io::Result<()> {
+    // written to a temporary file first so that an interrupted write never leaves a cache file
+    // that looks valid
+    let tmp = format!("{path}.tmp");
+    let mut f = BufWriter::new(File::create(&tmp)?);
+    f.write_all(MAGIC)?;
+    for n in [stamp[0], stamp[1], levels.len() as u64] {
+        f.write_all(&n.to_le_bytes())?;
+    }
+    for (w, h, pixels) in levels {
+        let (rows, cols) = distributions(*w, *h, pixels).unwrap_or_default();
+        for n in [*w, *h, rows.len(), cols.len()] {
+            f.write_all(&(n as u64).to_le_bytes())?;
+        }
+        let values = pixels.iter().flat_map(|c| [c.r, c.g, c.b]);
+        for x in values.chain(rows).chain(cols) {
+            f.write_all(&x.to_le_bytes())?;
+        }
+    }
+    f.into_inner()?.sync_all()?;
+
+    fs::rename(tmp, path)
+}
+
+/// The size, pixels and distributions of the finest level within max_size of the cache file at
+/// path, if it was written for the current version of the map.
+fn read_cache(
+    path: &str,
+    stamp: [u64; 2],
+    max_size: Option<usize>,
+) -> io::Result<(Level, Option<Distributions>)> {
+    let mut f = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    f.read_exact(&mut magic)?;
+    let [len, modified, n_levels] = [read_u64(&mut f)?, read_u64(&mut f)?, read_u64(&mut f)?];
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a cache file",
+        ));
+    }
+    if [len, modified] != stamp {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the map has changed, run raymart envmap to update it",
+        ));
+    }
+
+    for level in 0..n_levels {
+        let [w, h, n_rows, n_cols] = [(); 4].map(|_| read_u64(&mut f).map(|n| n as usize));
+        let (w, h, n_rows, n_cols) = (w?, h?, n_rows?, n_cols?);
+        let fits = max_size.is_none_or(|m| w.max(h) <= m.max(1));
+        if !fits && level + 1 < n_levels {
+            f.seek(SeekFrom::Current(4 * (3 * w * h + n_rows + n_cols) as i64))?;
+            continue;
+        }
+
+        let pixels = read_f32s(&mut f, 3 * w * h)?
+            .chunks_exact(3)
+            .map(|c| Color::new(c[0], c[1], c[2]))
+            .collect();
+        let rows = read_f32s(&mut f, n_rows)?;
+        let cols = read_f32s(&mut f, n_cols)?;
+        let dists = (!rows.is_empty()).then_some((rows, cols));
+
+        return Ok(((w, h, pixels), dists));
+    }
+
+    Err(io::Error::new(io::ErrorKind::InvalidData, "no levels"))
+}
+
+fn read_f32s(f: &mut impl Read, n: usize) -> io::Result<Vec<f32>> {
+    let mut buf = vec![0; 4 * n];
+    f.read_exact(&mut buf)?;
+
+    Ok(buf
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+fn rotate_y(v: V3, angle: Angle) -> V3 {
+    let (sin, cos) = angle.sin_cos();
+
+    V3::new(cos * v.x + sin * v.z, v.y, -sin * v.x + cos * v.z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v;
+    use simple_test_case::test_case;
+
+    /// A dim map with a bright pixel straight ahead
+    fn bright_spot() -> Environment {
+        let (w, h) = (8, 4);
+        let mut pixels = vec![Color::grey(0.1); w * h];
+        pixels[w + w / 2] = Color::grey(100.0);
+
+        Environment::new("spot", w, h, pixels, Angle::ZERO, true)
+    }
+
+    #[test_case(Angle::ZERO, v!(0.4, 0.3, -1); "ahead")]
+    #[test_case(Angle::deg(90.0), v!(-1, 0.3, -0.4); "rotated")]
+    #[test]
+    fn the_center_of_the_map_is_ahead_of_the_rotation(rotate: Angle, dir: V3) {
+        let env = Environment {
+            rotate,
+            ..bright_spot()
+        };
+
+        assert_eq!(env.value(dir), Color::grey(100.0));
+        assert_eq!(env.value(v!(0, 0.3, 1)), Color::grey(0.1));
+    }
+
+    #[test]
+    fn sampled_directions_match_the_pdf() {
+        // the mean of value / pdf over the sampled directions is the light arriving over the
+        // whole sphere
+        let env = bright_spot();
+        let n = 20_000;
+        let sampled = (0..n)
+            .filter_map(|_| env.sample())
+            .map(|dir| env.value(dir).g / env.pdf(dir))
+            .sum::<f32>()
+            / n as f32;
+
+        // each pixel covers the band of the sphere between its rows in height
+        let (w, h) = (env.width, env.height);
+        let expected: f32 = (0..h)
+            .map(|j| {
+                let band =
+                    (PI * j as f32 / h as f32).cos() - (PI * (j + 1) as f32 / h as f32).cos();
+                let area = 2.0 * PI * band / w as f32;
+                (0..w).map(|i| env.pixels[j * w + i].g * area).sum::<f32>()
+            })
+            .sum();
+
+        assert!(
+            (sampled - expected).abs() < 0.02 * expected,
+            "{sampled} != {expected}"
+        );
+    }
+
+    #[test]
+    fn bright_areas_are_sampled_more_often() {
+        let env = bright_spot();
+        let ahead = (0..1000)
+            .filter_map(|_| env.sample())
+            .filter(|&d| env.value(d) == Color::grey(100.0))
+            .count();
+
+        assert!(ahead > 900, "{ahead}");
+    }
+
+    #[test]
+    fn cube_faces_look_along_their_axis() {
+        let faces = bright_spot().cube_faces(10);
+        let face = |name: &str| &faces.iter().find(|(n, _)| *n == name).unwrap().1;
+
+        // the pixel through (0.3, 0.3, -1), up and to the right of the center of -z
+        assert_eq!(face("nz").get_pixel(3, 3).0, [100.0; 3]);
+        assert_eq!(face("pz").get_pixel(3, 3).0, [0.1; 3]);
+    }
+
+    /// Write a map that is brighter to the right with a bright spot to a fresh directory,
+    /// returning its path.
+    fn write_map(dir: &str, w: u32, h: u32) -> String {
+        let dir = std::env::temp_dir().join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("map.exr").to_string_lossy().into_owned();
+        let _ = fs::remove_file(cache_path(&path));
+        let img = Rgb32FImage::from_fn(w, h, |x, y| {
+            let v = if (x, y) == (w / 2, h / 4) {
+                100.0
+            } else {
+                0.1 + x as f32 / w as f32
+            };
+            image::Rgb([v; 3])
+        });
+        img.save(&path).unwrap();
+
+        path
+    }
+
+    #[test_case(None, "raymart_envcache_full", (16, 8); "full size")]
+    #[test_case(Some(5), "raymart_envcache_small", (4, 2); "smaller level")]
+    #[test]
+    fn cached_maps_match_decoded_maps(max_size: Option<usize>, dir: &str, size: (usize, usize)) {
+        let path = write_map(dir, 16, 8);
+        let decoded = Environment::load(&path, 2.0, Angle::ZERO, true, max_size).unwrap();
+        assert_eq!(precompute(&path), Ok(5));
+        let cached = Environment::load(&path, 2.0, Angle::ZERO, true, max_size).unwrap();
+
+        let close = |a: &[f32], b: &[f32]| {
+            a.len() == b.len() && a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-5)
+        };
+        assert_eq!((cached.width, cached.height), size);
+        assert_eq!((decoded.width, decoded.height), size);
+        assert_eq!(cached.pixels, decoded.pixels);
+        assert!(close(&cached.rows, &decoded.rows));
+        assert!(close(&cached.cols, &decoded.cols));
+    }
+
+    #[test]
+    fn maps_that_have_changed_since_they_were_cached_are_decoded() {
+        let path = write_map("raymart_envcache_stale", 16, 8);
+        precompute(&path).unwrap();
+        write_map("raymart_envcache_stale", 8, 4);
+
+        let env = Environment::load(&path, 1.0, Angle::ZERO, true, None).unwrap();
+
+        assert_eq!((env.width, env.height), (8, 4));
+    }
+
+    #[test]
+    fn black_maps_are_not_sampled() {
+        let env = Environment::new("black", 4, 2, vec![Color::BLACK; 8], Angle::ZERO, true);
+
+        assert!(!env.can_sample());
+        assert_eq!(env.sample(), None);
+    }
+}
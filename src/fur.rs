@@ -0,0 +1,181 @@
+//! Procedural fur grown from the faces of a mesh as strands of [Curve] segments.
+//!
+//! Each face grows a number of strands proportional to its area. Strands start out along the
+//! face normal, can curl around their growth direction in a helix and are pulled towards a guide
+//! strand grown from the center of their face to form clumps.
+use crate::{
+    hit::{Curve, Triangle},
+    material::Material,
+    v3::Onb,
+    P3, V3,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use std::f32::consts::TAU;
+
+/// Radius of the curl helix as a fraction of the strand length
+const CURL_RADIUS: f32 = 0.1;
+/// Radius of a strand at the tip as a fraction of its radius at the root
+const TIP_TAPER: f32 = 0.2;
+/// Maximum random deviation of strand roots from the face normal
+const JITTER: f32 = 0.3;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Fur {
+    pub material: String,
+    /// Number of strands per unit area of the mesh surface
+    pub density: f32,
+    /// Length of each strand
+    pub length: f32,
+    /// Radius of each strand at the root
+    #[serde(default = "default_radius")]
+    pub radius: f32,
+    /// Number of turns each strand makes around its growth direction
+    #[serde(default)]
+    pub curl: f32,
+    /// How strongly strand tips are pulled together into clumps in [0, 1]
+    #[serde(default)]
+    pub clump: f32,
+    /// Number of curve segments used for each strand
+    #[serde(default = "default_segments")]
+    pub segments: usize,
+    /// Seed for the random placement of strands so renders are repeatable
+    #[serde(default)]
+    pub seed: u64,
+}
+
+fn default_radius() -> f32 {
+    0.002
+}
+
+fn default_segments() -> usize {
+    4
+}
+
+impl Fur {
+    /// The points along a single strand starting at root.
+    fn strand(&self, root: P3, dir: V3, phase: f32) -> Vec<P3> {
+        let onb = Onb::new(dir);
+        let n = self.segments.max(1);
+
+        (0..=n)
+            .map(|k| {
+                let s = k as f32 / n as f32;
+                let theta = TAU * self.curl * s + phase;
+                let curl = CURL_RADIUS * self.length * s;
+                let offset = onb.to_world(V3::new(curl * theta.cos(), curl * theta.sin(), 0.0));
+
+                root + self.length * s * onb.w + offset
+            })
+            .collect()
+    }
+
+    /// The points along each strand grown from the given faces.
+    fn strands(&self, faces: &[Triangle]) -> Vec<Vec<P3>> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut strands = Vec::new();
+        let n = self.segments.max(1);
+
+        for face in faces {
+            let [a, b, c] = face.vertices();
+            let normal = face.unit_normal().as_v3();
+            let area = 0.5 * (b - a).cross(&(c - a)).length();
+
+            let expected = area * self.density;
+            let count =
+                expected as usize + (rng.random_range(0.0..1.0) < expected.fract()) as usize;
+            if count == 0 {
+                continue;
+            }
+
+            let centroid = a + ((b - a) + (c - a)) / 3.0;
+            let guide = self.strand(centroid, normal, 0.0);
+
+            for _ in 0..count {
+                // Uniformly sample a point on the face
+                let (mut u, mut v) = (rng.random_range(0.0..1.0), rng.random_range(0.0..1.0));
+                if u + v > 1.0 {
+                    (u, v) = (1.0 - u, 1.0 - v);
+                }
+                let root = a + u * (b - a) + v * (c - a);
+                let jitter = V3::new(
+                    rng.random_range(-JITTER..JITTER),
+                    rng.random_range(-JITTER..JITTER),
+                    rng.random_range(-JITTER..JITTER),
+                );
+                let dir = (normal + jitter).unit_vector();
+                let phase = rng.random_range(0.0..TAU);
+
+                let mut points = self.strand(root, dir, phase);
+                for (k, (p, g)) in points.iter_mut().zip(&guide).enumerate() {
+                    let pull = self.clump * k as f32 / n as f32;
+                    *p += pull * (*g - *p);
+                }
+                strands.push(points);
+            }
+        }
+
+        strands
+    }
+
+    /// Grow fur from the given faces of a mesh.
+    pub fn grow(&self, faces: &[Triangle], mat: &'static Material) -> Vec<Curve> {
+        let n = self.segments.max(1);
+        let mut curves = Vec::new();
+
+        for points in self.strands(faces) {
+            for (k, w) in points.windows(2).enumerate() {
+                let (s0, s1) = (k as f32 / n as f32, (k + 1) as f32 / n as f32);
+                let taper = 1.0 - (1.0 - TIP_TAPER) * 0.5 * (s0 + s1);
+                curves.push(Curve::new(w[0], w[1], self.radius * taper, (s0, s1), mat));
+            }
+        }
+
+        curves
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{material::CLAY, p};
+    use simple_test_case::test_case;
+
+    fn fur(density: f32, clump: f32) -> Fur {
+        Fur {
+            material: "fur".to_string(),
+            density,
+            length: 1.0,
+            radius: 0.01,
+            curl: 0.0,
+            clump,
+            segments: 4,
+            seed: 42,
+        }
+    }
+
+    fn face() -> Triangle {
+        Triangle::new(p!(0, 0, 0), p!(10, 0, 0), p!(0, 10, 0), &CLAY)
+    }
+
+    #[test]
+    fn strand_count_follows_density() {
+        let strands = fur(2.0, 0.0).strands(&[face()]);
+
+        // area is 50 so we expect 100 strands
+        assert_eq!(strands.len(), 100);
+        assert!(strands.iter().all(|s| s.len() == 5));
+    }
+
+    #[test_case(0.0, false; "no clumping")]
+    #[test_case(1.0, true; "full clumping")]
+    #[test]
+    fn clump_pulls_tips_together(clump: f32, joined: bool) {
+        let strands = fur(0.1, clump).strands(&[face()]);
+        let tip = strands[0][4];
+
+        let all_joined = strands.iter().all(|s| (s[4] - tip).length() < 1e-4);
+
+        assert_eq!(all_joined, joined);
+    }
+}
@@ -0,0 +1,227 @@
+//! White furnace tests for checking that materials conserve energy. Each material is put on a
+//! sphere lit only by a uniform white background and the light reaching the camera off the
+//! sphere is measured: a surface can never return more light than the background gives it, so
+//! anything above 1 is energy gained through a bug in the scattering of the material. White
+//! materials that don't absorb light (clear glass, white metal) should return close to 1 at
+//! every angle, with anything well below that being energy lost.
+//!
+//! ```sh
+//! $ raymart furnace             # built in materials with white albedo
+//! $ raymart furnace scene.toml  # the materials of a scene
+//! ```
+use crate::{
+    angle::Angle,
+    bvh::Bvh,
+    hit::{Hittable, Sphere},
+    material::Material,
+    p,
+    ray::{Background, Camera, RenderMode},
+    sampler::{self, NoisePattern, SamplerKind},
+    v, Color, P3, V3,
+};
+
+/// Bins of the angle between the camera ray and the surface normal that the light returned is
+/// averaged over, so that gains at grazing angles aren't hidden by the rest of the sphere.
+const BINS: usize = 4;
+/// Allowance for noise in the light returned before a material is reported as gaining energy,
+/// in standard errors of the mean of each bin so that it shrinks as more paths are traced
+const TOLERANCE: f32 = 3.0;
+
+/// The light returned by a material in a white furnace
+#[derive(Debug, Clone)]
+pub struct Furnace {
+    pub name: String,
+    /// Averaged over the whole sphere
+    pub mean: Color,
+    /// The largest channel of each bin of angles, from head on to grazing
+    pub by_angle: [f32; BINS],
+    /// The standard error of the largest channel of each bin
+    pub error: [f32; BINS],
+}
+
+impl Furnace {
+    /// Measure the light returned by mat tracing n paths through each pixel of a small image
+    /// of the sphere. The paths follow the locked noise pattern so that the same material always
+    /// measures the same.
+    pub fn measure(name: &str, mat: &'static Material, n: u16) -> Self {
+        let bvh = Bvh::new(vec![Hittable::from(Sphere::new(P3::ORIGIN, 1.0, mat))]);
+        let size = 32;
+        let camera = Camera::new(
+            1.0,
+            size,
+            1,
+            0,
+            64,
+            Background::Solid(Color::WHITE),
+            // the sphere just fills the image
+            Angle::rad(2.0 * 0.25f32.asin()),
+            p!(0, 0, 4),
+            P3::ORIGIN,
+            v!(0, 1, 0),
+            Angle::ZERO,
+            1.0,
+            RenderMode::Beauty,
+            None,
+            None,
+        );
+
+        let mut sum = Color::BLACK;
+        let mut count = 0;
+        let mut bins = [(Color::BLACK, 0.0, 0); BINS];
+        for j in 0..size {
+            for i in 0..size {
+                for s in 0..n {
+                    sampler::start_sample(
+                        NoisePattern::Locked,
+                        SamplerKind::Independent.sampler(),
+                        0,
+                        i,
+                        j,
+                        s as u64,
+                    );
+                    let (c, dist) = camera.sample_with_depth(i, j, &bvh);
+                    if !dist.is_finite() {
+                        continue;
+                    }
+                    // the distance to the unit sphere 4 units away gives the cosine to its normal
+                    let cos = ((15.0 - dist * dist) / (2.0 * dist)).clamp(0.0, 1.0);
+                    let bin = ((1.0 - cos) * BINS as f32).min(BINS as f32 - 1.0) as usize;
+                    let max = c.r.max(c.g).max(c.b);
+                    bins[bin].0 += c;
+                    bins[bin].1 += max * max;
+                    bins[bin].2 += 1;
+                    sum += c;
+                    count += 1;
+                }
+            }
+        }
+
+        sampler::end_samples();
+
+        let mean = |(c, n): (Color, u32)| c / n.max(1) as f32;
+        let by_angle = bins.map(|(c, _, n)| {
+            let c = mean((c, n));
+            c.r.max(c.g).max(c.b)
+        });
+        let error = std::array::from_fn(|i| {
+            let (_, sq, n) = bins[i];
+            let n = n.max(1) as f32;
+            let variance = (sq / n - by_angle[i] * by_angle[i]).max(0.0);
+
+            (variance / n).sqrt()
+        });
+
+        Self {
+            name: name.to_string(),
+            mean: mean((sum, count)),
+            by_angle,
+            error,
+        }
+    }
+
+    /// Whether more light was returned than the background gives at any angle
+    pub fn gains_energy(&self) -> bool {
+        // a little extra for rounding where every path returns the same light
+        self.by_angle
+            .iter()
+            .zip(self.error)
+            .any(|(&c, e)| c > 1.001 + TOLERANCE * e)
+    }
+}
+
+/// The built in materials with white albedo that should return all of the light they receive,
+/// or at least no more than that, at any roughness.
+pub fn builtin_materials() -> Vec<(&'static str, Material)> {
+    vec![
+        ("diffuse", Material::solid_color(Color::WHITE)),
+        ("metal", Material::metal(Color::WHITE, 0.0)),
+        ("rough metal", Material::metal(Color::WHITE, 0.5)),
+        ("glass", Material::dielectric(1.5, Color::WHITE)),
+        (
+            "frosted glass",
+            Material::rough_dielectric(1.5, Color::WHITE, 0.5),
+        ),
+        ("plastic", Material::plastic(Color::WHITE, 1.5, 0.0)),
+        ("rough plastic", Material::plastic(Color::WHITE, 1.5, 0.5)),
+        (
+            "specular",
+            Material::Specular {
+                albedo: Color::WHITE,
+                spec_albedo: Color::WHITE,
+                smoothness: 0.5,
+                prob: 0.5,
+            },
+        ),
+        ("hair", Material::hair(Color::WHITE, 0.3, 0.25)),
+    ]
+}
+
+/// Print the light returned by each material, flagging those that gain energy.
+pub fn report(results: &[Furnace]) {
+    let width = results
+        .iter()
+        .map(|r| r.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(8);
+    println!(
+        "{:<width$}  {:<23}  {:<31}",
+        "material", "mean", "max by angle (head on..grazing)"
+    );
+    for r in results {
+        let Color { r: red, g, b } = r.mean;
+        let by_angle: Vec<String> = r.by_angle.iter().map(|c| format!("{c:.3}")).collect();
+        println!(
+            "{:<width$}  {red:.3} {g:.3} {b:.3}      {}  {}",
+            r.name,
+            by_angle.join(" "),
+            if r.gains_energy() { "GAIN" } else { "ok" }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{Bsdf, ScatterRecord};
+    use crate::{HitRecord, Ray};
+
+    #[test]
+    fn builtin_materials_do_not_gain_energy() {
+        for (name, mat) in builtin_materials() {
+            let f = Furnace::measure(name, Box::leak(Box::new(mat)), 2);
+
+            assert!(!f.gains_energy(), "{f:?}");
+        }
+    }
+
+    #[test]
+    fn clear_glass_returns_all_of_the_light() {
+        let glass = Box::leak(Box::new(Material::dielectric(1.5, Color::WHITE)));
+
+        let f = Furnace::measure("glass", glass, 2);
+
+        assert!((f.mean.g - 1.0).abs() < 0.01, "{f:?}");
+    }
+
+    /// A diffuse surface that returns more light than it receives
+    #[derive(Debug)]
+    struct Gain;
+
+    impl Bsdf for Gain {
+        fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+            Some(ScatterRecord::diffuse(r_in, rec, Color::grey(1.2)))
+        }
+
+        fn albedo(&self, _: &Ray, _: &HitRecord) -> Color {
+            Color::grey(1.2)
+        }
+    }
+
+    #[test]
+    fn energy_gains_are_reported() {
+        let f = Furnace::measure("gain", Box::leak(Box::new(Material::custom(Gain))), 1);
+
+        assert!(f.gains_energy(), "{f:?}");
+    }
+}
@@ -0,0 +1,530 @@
+//! Path guiding: a spatial-directional tree (SD-tree) learning the distribution of light arriving
+//! at diffuse surfaces over the first passes of a render, which later bounces importance sample
+//! alongside the material so that paths head towards the bright parts of the scene.
+//!   Müller et al, "Practical Path Guiding for Efficient Light-Transport Simulation" (EGSR 2017)
+//!
+//! Space is split in half along alternating axes wherever many samples were recorded, and each
+//! region holds a quadtree over the sphere of directions (in cylindrical coordinates so that
+//! equal areas of the square are equal solid angles) that is refined where most light arrives.
+use crate::sampler::random_range;
+use crate::{bvh::AABBox, Color, P3, V3};
+use serde::Deserialize;
+use std::{
+    f32::consts::{FRAC_1_PI, PI, TAU},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Deepest level of the directional quadtrees
+const MAX_DIRECTIONAL_DEPTH: u8 = 20;
+/// Deepest level of the spatial tree
+const MAX_SPATIAL_DEPTH: u8 = 24;
+
+/// Settings for path guiding
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Guiding {
+    /// Number of passes recording the light arriving at surfaces before the distribution is
+    /// fixed and only sampled
+    #[serde(default = "default_training_passes")]
+    pub training_passes: u32,
+    /// Probability of sampling a direction from the learned distribution rather than the
+    /// material once trained
+    #[serde(default = "default_fraction")]
+    pub fraction: f32,
+    /// Number of samples recorded in a region of space above which it is split in two
+    #[serde(default = "default_spatial_threshold")]
+    pub spatial_threshold: u32,
+    /// Share of the light arriving in a region above which a cell of directions is subdivided
+    #[serde(default = "default_flux_threshold")]
+    pub flux_threshold: f32,
+}
+
+fn default_training_passes() -> u32 {
+    4
+}
+
+fn default_fraction() -> f32 {
+    0.5
+}
+
+fn default_spatial_threshold() -> u32 {
+    12000
+}
+
+fn default_flux_threshold() -> f32 {
+    0.01
+}
+
+impl Default for Guiding {
+    fn default() -> Self {
+        Self {
+            training_passes: default_training_passes(),
+            fraction: default_fraction(),
+            spatial_threshold: default_spatial_threshold(),
+            flux_threshold: default_flux_threshold(),
+        }
+    }
+}
+
+/// An f32 that can be added to from many threads at once.
+#[derive(Debug, Default)]
+struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, v: f32) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f32::from_bits(bits) + v).to_bits())
+            });
+    }
+}
+
+impl Clone for AtomicF32 {
+    fn clone(&self) -> Self {
+        Self(AtomicU32::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+/// A node of a directional quadtree: the light learned for each quadrant, used for sampling,
+/// and the light being recorded during the current pass. A child index of 0 marks a leaf.
+#[derive(Debug, Default, Clone)]
+struct QNode {
+    children: [u32; 4],
+    value: [f32; 4],
+    recorded: [AtomicF32; 4],
+}
+
+/// The quadrant of the unit square containing x, y and the position within it.
+fn quadrant(x: f32, y: f32) -> (usize, f32, f32) {
+    let (qx, qy) = (x >= 0.5, y >= 0.5);
+    let i = qx as usize + 2 * qy as usize;
+
+    (i, 2.0 * x - qx as u8 as f32, 2.0 * y - qy as u8 as f32)
+}
+
+/// A distribution over the sphere of directions.
+#[derive(Debug, Clone)]
+struct DTree {
+    nodes: Vec<QNode>,
+}
+
+impl Default for DTree {
+    fn default() -> Self {
+        Self {
+            nodes: vec![QNode::default()],
+        }
+    }
+}
+
+impl DTree {
+    fn record(&self, dir: V3, value: f32) {
+        let (mut x, mut y) = to_square(dir);
+        let mut node = &self.nodes[0];
+        loop {
+            let (i, cx, cy) = quadrant(x, y);
+            node.recorded[i].add(value);
+            if node.children[i] == 0 {
+                return;
+            }
+            (x, y) = (cx, cy);
+            node = &self.nodes[node.children[i] as usize];
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.nodes[0].value.iter().sum::<f32>() <= 0.0
+    }
+
+    /// A direction sampled in proportion to the learned light.
+    fn sample(&self) -> V3 {
+        let (mut ox, mut oy, mut size) = (0.0, 0.0, 1.0);
+        let mut node = &self.nodes[0];
+        loop {
+            let total: f32 = node.value.iter().sum();
+            if total <= 0.0 {
+                break;
+            }
+            let mut r = random_range(0.0..total);
+            let mut i = 0;
+            while i < 3 && (r >= node.value[i] || node.value[i] <= 0.0) {
+                r -= node.value[i];
+                i += 1;
+            }
+            size *= 0.5;
+            ox += (i % 2) as f32 * size;
+            oy += (i / 2) as f32 * size;
+            if node.children[i] == 0 {
+                break;
+            }
+            node = &self.nodes[node.children[i] as usize];
+        }
+
+        from_square(ox + random_range(0.0..size), oy + random_range(0.0..size))
+    }
+
+    /// Probability density of sampling dir per unit solid angle.
+    fn pdf(&self, dir: V3) -> f32 {
+        let (mut x, mut y) = to_square(dir);
+        let mut p = 1.0;
+        let mut node = &self.nodes[0];
+        loop {
+            let total: f32 = node.value.iter().sum();
+            if total <= 0.0 {
+                break;
+            }
+            let (i, cx, cy) = quadrant(x, y);
+            p *= 4.0 * node.value[i] / total;
+            if node.children[i] == 0 {
+                break;
+            }
+            (x, y) = (cx, cy);
+            node = &self.nodes[node.children[i] as usize];
+        }
+
+        p / (4.0 * PI)
+    }
+
+    /// Replace the learned light with that recorded over the last pass, subdividing cells that
+    /// received more than threshold of the total and merging the rest. Regions that recorded
+    /// nothing keep what they had learned.
+    fn refine(&mut self, threshold: f32) {
+        let total: f32 = self.nodes[0].recorded.iter().map(AtomicF32::load).sum();
+        if total <= 0.0 {
+            for node in self.nodes.iter_mut() {
+                node.recorded = Default::default();
+            }
+            return;
+        }
+
+        let mut nodes = vec![QNode::default()];
+        // new node, the matching old node if the old tree went that deep, the light given to the
+        // new node's square when it didn't, and the depth of the new node
+        let mut stack = vec![(0, Some(0), 0.0, 1)];
+        while let Some((n, old, inherited, depth)) = stack.pop() {
+            for i in 0..4 {
+                let (value, old_child) = match old {
+                    Some(o) => {
+                        let c = self.nodes[o].children[i] as usize;
+                        (self.nodes[o].recorded[i].load(), (c != 0).then_some(c))
+                    }
+                    None => (inherited / 4.0, None),
+                };
+                nodes[n].value[i] = value;
+                if value / total > threshold && depth < MAX_DIRECTIONAL_DEPTH {
+                    nodes.push(QNode::default());
+                    let child = nodes.len() - 1;
+                    nodes[n].children[i] = child as u32;
+                    stack.push((child, old_child, value, depth + 1));
+                }
+            }
+        }
+
+        self.nodes = nodes;
+    }
+}
+
+/// Position in the unit square of a direction in cylindrical coordinates.
+fn to_square(dir: V3) -> (f32, f32) {
+    let d = dir.unit_vector();
+    let x = ((d.z + 1.0) * 0.5).clamp(0.0, 1.0);
+    let y = (d.y.atan2(d.x) / TAU).rem_euclid(1.0);
+
+    (x, y.min(1.0 - f32::EPSILON))
+}
+
+/// The direction at a position in the unit square in cylindrical coordinates.
+fn from_square(x: f32, y: f32) -> V3 {
+    let z = 2.0 * x - 1.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let (sin, cos) = (TAU * y).sin_cos();
+
+    V3::new(r * cos, r * sin, z)
+}
+
+/// A region of space: a leaf holding a directional distribution or split in half along axis.
+#[derive(Debug)]
+struct SNode {
+    axis: u8,
+    children: Option<[usize; 2]>,
+    dtree: DTree,
+    samples: u32,
+    recorded: AtomicU32,
+}
+
+/// A surface interaction along a path whose bounce may be guided: where it happened, the
+/// direction of the bounce and its density, the throughput of the path after the bounce and the
+/// light gathered along the path before it.
+#[derive(Debug, Clone, Copy)]
+pub struct GuideVertex {
+    pub p: P3,
+    pub dir: V3,
+    pub pdf: f32,
+    pub throughput: Color,
+    pub light: Color,
+}
+
+/// The learned distribution of light for a scene.
+#[derive(Debug)]
+pub struct PathGuide {
+    settings: Guiding,
+    min: [f32; 3],
+    max: [f32; 3],
+    nodes: Vec<SNode>,
+    passes: u32,
+}
+
+impl PathGuide {
+    /// An untrained guide covering the given bounds.
+    pub fn new(settings: Guiding, bbox: &AABBox) -> Self {
+        // keep the tree finite for unbounded geometry
+        let bound = |v: f32| v.clamp(-1e6, 1e6);
+
+        Self {
+            settings,
+            min: [bbox.x.min, bbox.y.min, bbox.z.min].map(bound),
+            max: [bbox.x.max, bbox.y.max, bbox.z.max].map(bound),
+            nodes: vec![SNode {
+                axis: 0,
+                children: None,
+                dtree: DTree::default(),
+                samples: 0,
+                recorded: AtomicU32::new(0),
+            }],
+            passes: 0,
+        }
+    }
+
+    /// Whether light arriving at surfaces is still being recorded.
+    pub fn is_training(&self) -> bool {
+        self.passes < self.settings.training_passes
+    }
+
+    /// The index of the leaf region containing p.
+    fn leaf(&self, p: P3) -> usize {
+        let (mut min, mut max) = (self.min, self.max);
+        let mut n = 0;
+        while let Some([a, b]) = self.nodes[n].children {
+            let axis = self.nodes[n].axis as usize;
+            let mid = 0.5 * (min[axis] + max[axis]);
+            if p[axis] < mid {
+                max[axis] = mid;
+                n = a;
+            } else {
+                min[axis] = mid;
+                n = b;
+            }
+        }
+
+        n
+    }
+
+    /// Sample the direction of a diffuse bounce off a surface at p with the given normal, either
+    /// from the learned light or by keeping the cosine distributed direction chosen by the
+    /// material. Returns the direction, the factor to apply to the material attenuation and the
+    /// density of the direction, or None if the direction is below the surface.
+    pub fn scatter(&self, p: P3, normal: V3, material_dir: V3) -> Option<(V3, f32, f32)> {
+        let dtree = &self.nodes[self.leaf(p)].dtree;
+        let n = normal.unit_vector();
+        let material_pdf = |d: V3| n.dot(&d).max(0.0) * FRAC_1_PI;
+        if self.passes == 0 || dtree.is_empty() {
+            let d = material_dir.unit_vector();
+            return Some((d, 1.0, material_pdf(d)));
+        }
+
+        let fraction = self.settings.fraction.clamp(0.0, 1.0);
+        let d = if random_range(0.0..1.0) < fraction {
+            dtree.sample()
+        } else {
+            material_dir.unit_vector()
+        };
+        let cos = n.dot(&d);
+        if cos <= 0.0 {
+            return None;
+        }
+        let pdf = fraction * dtree.pdf(d) + (1.0 - fraction) * material_pdf(d);
+
+        Some((d, material_pdf(d) / pdf, pdf))
+    }
+
+    /// Record the light arriving at a vertex given the light gathered by the whole path.
+    pub fn record(&self, v: &GuideVertex, light: Color) {
+        let throughput = v.throughput.luminance();
+        let radiance = (light.luminance() - v.light.luminance()) / throughput;
+        if throughput <= 0.0 || v.pdf <= 0.0 || !radiance.is_finite() {
+            return;
+        }
+
+        let leaf = &self.nodes[self.leaf(v.p)];
+        leaf.recorded.fetch_add(1, Ordering::Relaxed);
+        leaf.dtree.record(v.dir, radiance.max(0.0) / v.pdf);
+    }
+
+    /// Learn from the light recorded over the pass that has just finished, splitting regions of
+    /// space that recorded many samples and refining their distributions of light.
+    pub fn end_pass(&mut self) {
+        if !self.is_training() {
+            return;
+        }
+        self.passes += 1;
+
+        let threshold = self.settings.flux_threshold;
+        for node in self.nodes.iter_mut() {
+            if node.children.is_none() {
+                node.dtree.refine(threshold);
+                node.samples = node.recorded.swap(0, Ordering::Relaxed);
+            }
+        }
+
+        // splits assume that samples are shared evenly between the two halves
+        let mut stack = vec![(0, 0)];
+        while let Some((n, depth)) = stack.pop() {
+            if let Some([a, b]) = self.nodes[n].children {
+                stack.extend([(a, depth + 1), (b, depth + 1)]);
+                continue;
+            }
+            if self.nodes[n].samples <= self.settings.spatial_threshold
+                || depth >= MAX_SPATIAL_DEPTH
+            {
+                continue;
+            }
+
+            let node = &self.nodes[n];
+            let child = || SNode {
+                axis: (node.axis + 1) % 3,
+                children: None,
+                dtree: node.dtree.clone(),
+                samples: node.samples / 2,
+                recorded: AtomicU32::new(0),
+            };
+            let children = [child(), child()];
+            let first = self.nodes.len();
+            self.nodes.extend(children);
+            self.nodes[n].children = Some([first, first + 1]);
+            stack.push((n, depth));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hit::Interval, p, v};
+    use simple_test_case::test_case;
+
+    fn guide() -> PathGuide {
+        let bbox = AABBox::new(
+            Interval::new(-1.0, 1.0),
+            Interval::new(-1.0, 1.0),
+            Interval::new(-1.0, 1.0),
+        );
+
+        PathGuide::new(Guiding::default(), &bbox)
+    }
+
+    fn vertex(p: P3, dir: V3) -> GuideVertex {
+        GuideVertex {
+            p,
+            dir,
+            pdf: FRAC_1_PI,
+            throughput: Color::WHITE,
+            light: Color::BLACK,
+        }
+    }
+
+    #[test_case(v!(0, 0, 1); "up")]
+    #[test_case(v!(0, 0, -1); "down")]
+    #[test_case(v!(0.3, -0.8, 0.1); "oblique")]
+    #[test]
+    fn square_mapping_round_trips(dir: V3) {
+        let (x, y) = to_square(dir);
+
+        assert!((from_square(x, y) - dir.unit_vector()).length() < 1e-4);
+    }
+
+    #[test]
+    fn learned_distribution_favours_bright_directions() {
+        let mut g = guide();
+        let bright = v!(1, 1, 0.2).unit_vector();
+        // each pass records into the cells refined after the one before
+        for _ in 0..3 {
+            for _ in 0..1000 {
+                g.record(&vertex(p!(0, 0, 0), bright), Color::WHITE);
+                g.record(
+                    &vertex(p!(0, 0, 0), V3::random_unit_vector()),
+                    Color::grey(0.01),
+                );
+            }
+            g.end_pass();
+        }
+
+        let dtree = &g.nodes[g.leaf(p!(0, 0, 0))].dtree;
+        let near = (0..1000)
+            .filter(|_| dtree.sample().dot(&bright) > 0.9)
+            .count();
+
+        assert!(dtree.pdf(bright) > 10.0 * dtree.pdf(-bright));
+        assert!(near > 800, "{near}");
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let mut g = guide();
+        for _ in 0..2000 {
+            let d = V3::random_unit_vector();
+            g.record(&vertex(p!(0, 0, 0), d), Color::grey(d.x.max(0.0)));
+        }
+        g.end_pass();
+        let dtree = &g.nodes[0].dtree;
+
+        let n = 200;
+        let integral: f32 = (0..n * n)
+            .map(|k| {
+                let (x, y) = ((k % n) as f32 + 0.5, (k / n) as f32 + 0.5);
+                dtree.pdf(from_square(x / n as f32, y / n as f32))
+            })
+            .sum::<f32>()
+            * 4.0
+            * PI
+            / (n * n) as f32;
+
+        assert!((integral - 1.0).abs() < 0.02, "{integral}");
+    }
+
+    #[test]
+    fn busy_regions_are_split() {
+        let mut g = PathGuide {
+            settings: Guiding {
+                spatial_threshold: 100,
+                ..Guiding::default()
+            },
+            ..guide()
+        };
+        for _ in 0..1000 {
+            g.record(&vertex(p!(0.5, 0.5, 0.5), v!(0, 0, 1)), Color::WHITE);
+        }
+        g.end_pass();
+
+        assert!(g.nodes.len() > 1);
+        assert_ne!(g.leaf(p!(0.5, 0.5, 0.5)), g.leaf(p!(-0.5, -0.5, -0.5)));
+    }
+
+    #[test]
+    fn guided_bounces_stay_above_the_surface() {
+        let mut g = guide();
+        for _ in 0..1000 {
+            g.record(&vertex(p!(0, 0, 0), v!(0, 0, 1)), Color::WHITE);
+        }
+        g.end_pass();
+
+        for _ in 0..1000 {
+            let material_dir = v!(0, 0, 1) + V3::random_unit_vector();
+            if let Some((d, weight, pdf)) = g.scatter(p!(0, 0, 0), v!(0, 0, 1), material_dir) {
+                assert!(d.z > 0.0 && weight.is_finite() && pdf > 0.0);
+            }
+        }
+    }
+}
@@ -1,14 +1,30 @@
+use crate::sampler::random_range;
 use crate::{
-    bvh::{AABBox, Bvh, MAX_BVH_DEPTH},
+    angle::Angle,
+    bvh::{sort_spatially, AABBox, Bvh, MAX_BVH_DEPTH},
+    counters::{self, Counter},
+    mat::M4,
     material::{Material, Texture},
+    simd::V3x4,
+    v3::{Onb, N3},
+    voxel::VoxelGrid,
     Color, Ray, P3, V3,
 };
-use rand::random_range;
-use std::{f32::consts::PI, ops::Add};
+use std::{
+    f32::consts::{PI, TAU},
+    fmt,
+    ops::Add,
+    sync::Arc,
+};
+use wide::{f32x4, CmpGe, CmpGt, CmpLe, CmpLt};
 
 const INV_PI: f32 = 1.0 / PI;
 const INV_2PI: f32 = 1.0 / (2.0 * PI);
 
+/// Minimum distance along a ray for a hit to count at a scene scale of 1, which stops scattered
+/// rays from hitting the surface that they are leaving.
+pub const RAY_EPSILON: f32 = 0.001;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Interval {
     pub min: f32,
@@ -87,24 +103,36 @@ impl Add<Interval> for f32 {
 pub struct HitRecord {
     pub t: f32,
     pub p: P3,
-    pub normal: V3,
+    pub normal: N3,
     pub front_face: bool,
     pub mat: &'static Material,
     pub u: f32,
     pub v: f32,
+    /// World space distance from the hit point to the nearest edge of the primitive
+    /// (infinite for primitives without edges)
+    pub edge_dist: f32,
+    /// Direction of the fibre at the hit point for curve primitives (zero otherwise)
+    pub tangent: V3,
+    /// ID of the scene object that was hit (0 if the object has not been assigned an ID)
+    pub obj_id: u32,
+    /// ID of the scene material that was hit (0 if the object has not been assigned an ID)
+    pub mat_id: u32,
+    /// World space displacement of the hit point over the shutter interval (zero for static
+    /// primitives)
+    pub motion: V3,
 }
 
 impl HitRecord {
     pub fn new(
         t: f32,
         p: P3,
-        outward_normal: V3,
+        outward_normal: N3,
         r: &Ray,
         mat: &'static Material,
         u: f32,
         v: f32,
     ) -> Self {
-        let front_face = r.dir.dot(&outward_normal) < 0.0;
+        let front_face = outward_normal.dot(&r.dir) < 0.0;
         let normal = if front_face {
             outward_normal
         } else {
@@ -119,14 +147,22 @@ impl HitRecord {
             mat,
             u,
             v,
+            edge_dist: f32::INFINITY,
+            tangent: V3::ZERO,
+            obj_id: 0,
+            mat_id: 0,
+            motion: V3::ZERO,
         }
     }
 
+    /// Replace the normal used for shading, keeping it on the side of the surface that was hit.
+    pub fn set_shading_normal(&mut self, n: N3) {
+        self.normal = if self.front_face { n } else { -n };
+    }
+
     /// Sets the [HitRecord] normal vector.
-    ///
-    /// `outward_normal` is assumed to be of unit length.
-    pub fn set_face_normal(&mut self, r: &Ray, outward_normal: V3) {
-        self.front_face = r.dir.dot(&outward_normal) < 0.0;
+    pub fn set_face_normal(&mut self, r: &Ray, outward_normal: N3) {
+        self.front_face = outward_normal.dot(&r.dir) < 0.0;
         self.normal = if self.front_face {
             outward_normal
         } else {
@@ -140,15 +176,43 @@ pub enum Hittable {
     // Primatives
     Empty,
     Sphere(Sphere),
+    Sphere4(Sphere4),
+    Disk(Disk),
     Quad(Quad),
     Triangle(Triangle),
+    Triangle4(Triangle4),
+    MovingTriangle(MovingTriangle),
+    Curve(Curve),
     ConstantMedium(ConstantMedium),
+    Voxels(VoxelGrid),
     // Compound
     List(HittableList),
     Bvh(Bvh),
     // Transforms
     Translate(Translate),
     Rotate(Rotate),
+    Transform(Transform),
+    // Clipping
+    Clip(Clip),
+    // Metadata
+    WithId(WithId),
+    // User defined
+    Custom(Arc<dyn Hit>),
+}
+
+/// A primitive defined outside of this crate, added to a scene with [Hittable::custom]. Hits are
+/// given in world space (see [HitRecord::new] for setting the normal) and need to lie inside of
+/// the bounding box for the BVH to find them.
+pub trait Hit: fmt::Debug + Send + Sync {
+    /// The closest hit along the ray within ray_t.
+    fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord>;
+
+    fn bounding_box(&self) -> AABBox;
+
+    /// Total power emitted by any emissive surfaces, as for [Hittable::emissive_power].
+    fn emissive_power(&self) -> Color {
+        Color::BLACK
+    }
 }
 
 impl Hittable {
@@ -156,21 +220,50 @@ impl Hittable {
         Self::Translate(Translate::new(self, offset))
     }
 
-    pub fn rotate(self, angle: f32) -> Hittable {
+    pub fn rotate(self, angle: Angle) -> Hittable {
         Self::Rotate(Rotate::new(self, angle))
     }
 
+    /// Apply a general affine transform. Panics if the matrix is not invertible.
+    pub fn transform(self, m: M4) -> Hittable {
+        Self::Transform(Transform::new(self, m))
+    }
+
+    /// Cut away all parts of this hittable outside of the region bounded by the given planes,
+    /// optionally closing off the cut surfaces with a cap material.
+    pub fn clip(self, planes: Vec<ClipPlane>, cap: Option<&'static Material>) -> Hittable {
+        Self::Clip(Clip::new(self, planes, cap))
+    }
+
+    pub fn with_id(self, id: u32, mat_id: u32) -> Hittable {
+        Self::WithId(WithId::new(self, id, mat_id))
+    }
+
+    pub fn custom(h: impl Hit + 'static) -> Hittable {
+        Self::Custom(Arc::new(h))
+    }
+
     pub fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
         match self {
             Self::Empty => None,
             Self::Sphere(s) => s.hits(r, ray_t),
+            Self::Sphere4(s) => s.hits(r, ray_t),
+            Self::Disk(d) => d.hits(r, ray_t),
             Self::Quad(q) => q.hits(r, ray_t),
             Self::Triangle(t) => t.hits(r, ray_t),
+            Self::Triangle4(t) => t.hits(r, ray_t),
+            Self::MovingTriangle(t) => t.hits(r, ray_t),
+            Self::Curve(c) => c.hits(r, ray_t),
             Self::ConstantMedium(c) => c.hits(r, ray_t),
+            Self::Voxels(v) => v.hits(r, ray_t),
             Self::List(l) => l.hits(r, ray_t),
             Self::Bvh(b) => b.hits(r, ray_t, &mut [0; MAX_BVH_DEPTH]),
             Self::Translate(t) => t.hits(r, ray_t),
             Self::Rotate(ro) => ro.hits(r, ray_t),
+            Self::Transform(t) => t.hits(r, ray_t),
+            Self::Clip(c) => c.hits(r, ray_t),
+            Self::WithId(w) => w.hits(r, ray_t),
+            Self::Custom(c) => c.hits(r, ray_t),
         }
     }
 
@@ -178,13 +271,202 @@ impl Hittable {
         match self {
             Self::Empty => AABBox::EMPTY,
             Self::Sphere(s) => s.bbox,
+            Self::Sphere4(s) => s.bbox,
+            Self::Disk(d) => d.bbox,
             Self::Quad(q) => q.bbox,
             Self::Triangle(t) => t.bbox,
+            Self::Triangle4(t) => t.bbox,
+            Self::MovingTriangle(t) => t.bbox,
+            Self::Curve(c) => c.bbox,
             Self::ConstantMedium(c) => c.bounding_box(),
+            Self::Voxels(v) => v.bbox,
             Self::List(l) => l.bbox,
             Self::Bvh(b) => b.bbox,
             Self::Translate(t) => t.bbox,
             Self::Rotate(r) => r.bbox,
+            Self::Transform(t) => t.bbox,
+            Self::Clip(c) => c.bbox,
+            Self::WithId(w) => w.bbox,
+            Self::Custom(c) => c.bounding_box(),
+        }
+    }
+}
+
+/// Counts of the primitives making up a [Hittable]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PrimitiveCounts {
+    pub spheres: usize,
+    pub disks: usize,
+    pub quads: usize,
+    pub triangles: usize,
+    pub curves: usize,
+    pub media: usize,
+    pub voxels: usize,
+    pub custom: usize,
+}
+
+impl PrimitiveCounts {
+    pub const fn total(&self) -> usize {
+        self.spheres
+            + self.disks
+            + self.quads
+            + self.triangles
+            + self.curves
+            + self.media
+            + self.voxels
+            + self.custom
+    }
+}
+
+impl Hittable {
+    pub fn count_primitives(&self, counts: &mut PrimitiveCounts) {
+        match self {
+            Self::Empty => (),
+            Self::Sphere(_) => counts.spheres += 1,
+            Self::Sphere4(s) => counts.spheres += s.n,
+            Self::Disk(_) => counts.disks += 1,
+            Self::Quad(_) => counts.quads += 1,
+            Self::Triangle(_) => counts.triangles += 1,
+            Self::Triangle4(t) => counts.triangles += t.n,
+            Self::MovingTriangle(_) => counts.triangles += 1,
+            Self::Curve(_) => counts.curves += 1,
+            Self::ConstantMedium(_) => counts.media += 1,
+            Self::Voxels(v) => counts.voxels += v.n_filled(),
+            Self::List(l) => l.objects.iter().for_each(|h| h.count_primitives(counts)),
+            Self::Bvh(b) => b
+                .hittables()
+                .iter()
+                .for_each(|h| h.count_primitives(counts)),
+            Self::Translate(t) => t.inner.count_primitives(counts),
+            Self::Rotate(r) => r.inner.count_primitives(counts),
+            Self::Transform(t) => t.inner.count_primitives(counts),
+            Self::Clip(c) => c.inner.count_primitives(counts),
+            Self::WithId(w) => w.inner.count_primitives(counts),
+            Self::Custom(_) => counts.custom += 1,
+        }
+    }
+
+    /// Total power emitted by all emissive surfaces, assuming that each emits uniformly over its
+    /// surface (textured emitters are sampled at a single point) as a Lambertian emitter with
+    /// power = π * radiance * area.
+    pub fn emissive_power(&self) -> Color {
+        let emitted = |mat: &Material, area: f32, p: P3| {
+            if mat.is_emissive() {
+                mat.color_emitted(0.5, 0.5, p) * PI * area
+            } else {
+                Color::BLACK
+            }
+        };
+        let sum = |hs: &[Hittable]| {
+            hs.iter()
+                .fold(Color::BLACK, |acc, h| acc + h.emissive_power())
+        };
+
+        match self {
+            Self::Empty | Self::ConstantMedium(_) => Color::BLACK,
+            Self::Sphere(s) => emitted(s.mat, 4.0 * PI * s.radius_sq, s.center),
+            Self::Sphere4(s) => s.spheres[..s.n].iter().fold(Color::BLACK, |acc, s| {
+                acc + emitted(s.mat, 4.0 * PI * s.radius_sq, s.center)
+            }),
+            Self::Disk(d) => emitted(d.mat, PI * d.radius_sq, d.center),
+            Self::Quad(q) => emitted(q.mat, q.u.cross(&q.v).length(), q.q),
+            Self::Triangle(t) => emitted(t.mat, 0.5 * t.normal.length(), t.a),
+            Self::Triangle4(t) => {
+                let areas = t.normal.length().to_array();
+                (0..t.n).fold(Color::BLACK, |acc, i| {
+                    acc + emitted(t.mats[i], 0.5 * areas[i], P3::ORIGIN + t.a.lane(i))
+                })
+            }
+            Self::MovingTriangle(t) => {
+                let [a, b, c] = t.keys[0];
+                emitted(t.mat, 0.5 * (b - a).cross(&(c - a)).length(), a)
+            }
+            Self::Curve(c) => emitted(c.mat, TAU * c.radius * c.len, c.a),
+            Self::Voxels(v) => v
+                .emissive_faces()
+                .into_iter()
+                .fold(Color::BLACK, |acc, (mat, area, p)| {
+                    acc + emitted(mat, area, p)
+                }),
+            Self::List(l) => sum(&l.objects),
+            Self::Bvh(b) => sum(b.hittables()),
+            Self::Translate(t) => t.inner.emissive_power(),
+            Self::Rotate(r) => r.inner.emissive_power(),
+            // approximate as the area scaling is not uniform in general
+            Self::Transform(t) => {
+                let (_, _, s) = t.m.decompose();
+                let area_scale = (s.x * s.y * s.z).abs().powf(2.0 / 3.0);
+                t.inner.emissive_power() * area_scale
+            }
+            // approximate as the clipped area is not known
+            Self::Clip(c) => c.inner.emissive_power(),
+            Self::WithId(w) => w.inner.emissive_power(),
+            Self::Custom(c) => c.emissive_power(),
+        }
+    }
+
+    /// The object ID given by [Hittable::with_id], if there is one
+    pub fn id(&self) -> Option<u32> {
+        match self {
+            Self::WithId(w) => Some(w.id),
+            _ => None,
+        }
+    }
+
+    /// Whether directions towards this hittable can be sampled with [Hittable::sample_toward],
+    /// so that it can be sampled as a light.
+    pub fn can_sample(&self) -> bool {
+        match self {
+            Self::Sphere(_) | Self::Quad(_) => true,
+            Self::List(l) => !l.objects.is_empty() && l.objects.iter().all(|h| h.can_sample()),
+            Self::Translate(t) => t.inner.can_sample(),
+            Self::Rotate(r) => r.inner.can_sample(),
+            Self::WithId(w) => w.inner.can_sample(),
+            _ => false,
+        }
+    }
+
+    /// A random direction from origin towards this hittable, with the density over solid angle
+    /// given by [Hittable::light_pdf]. None if no direction can be sampled from origin.
+    pub fn sample_toward(&self, origin: P3) -> Option<V3> {
+        match self {
+            Self::Sphere(s) => s.sample_toward(origin),
+            Self::Quad(q) => Some(q.sample_toward(origin)),
+            Self::List(l) if !l.objects.is_empty() => {
+                l.objects[random_range(0..l.objects.len())].sample_toward(origin)
+            }
+            Self::Translate(t) => t.inner.sample_toward(origin - t.offset),
+            Self::Rotate(r) => {
+                let o = P3::ORIGIN + r.rot_f(origin - P3::ORIGIN);
+                r.inner.sample_toward(o).map(|dir| r.rot_b(dir))
+            }
+            Self::WithId(w) => w.inner.sample_toward(origin),
+            _ => None,
+        }
+    }
+
+    /// The density over solid angle of [Hittable::sample_toward] giving dir from origin, ignoring
+    /// hits closer than epsilon.
+    pub fn light_pdf(&self, origin: P3, dir: V3, epsilon: f32) -> f32 {
+        match self {
+            Self::Sphere(s) => s.light_pdf(origin, dir),
+            Self::Quad(q) => q.light_pdf(origin, dir, epsilon),
+            Self::List(l) if !l.objects.is_empty() => {
+                let sum: f32 = l
+                    .objects
+                    .iter()
+                    .map(|h| h.light_pdf(origin, dir, epsilon))
+                    .sum();
+                sum / l.objects.len() as f32
+            }
+            Self::Translate(t) => t.inner.light_pdf(origin - t.offset, dir, epsilon),
+            Self::Rotate(r) => r.inner.light_pdf(
+                P3::ORIGIN + r.rot_f(origin - P3::ORIGIN),
+                r.rot_f(dir),
+                epsilon,
+            ),
+            Self::WithId(w) => w.inner.light_pdf(origin, dir, epsilon),
+            _ => 0.0,
         }
     }
 }
@@ -195,12 +477,48 @@ impl From<Sphere> for Hittable {
     }
 }
 
+impl From<Sphere4> for Hittable {
+    fn from(s: Sphere4) -> Self {
+        Self::Sphere4(s)
+    }
+}
+
+impl From<VoxelGrid> for Hittable {
+    fn from(v: VoxelGrid) -> Self {
+        Self::Voxels(v)
+    }
+}
+
+impl From<Disk> for Hittable {
+    fn from(d: Disk) -> Self {
+        Self::Disk(d)
+    }
+}
+
+impl From<Triangle4> for Hittable {
+    fn from(t: Triangle4) -> Self {
+        Self::Triangle4(t)
+    }
+}
+
 impl From<Quad> for Hittable {
     fn from(q: Quad) -> Self {
         Self::Quad(q)
     }
 }
 
+impl From<Curve> for Hittable {
+    fn from(c: Curve) -> Self {
+        Self::Curve(c)
+    }
+}
+
+impl From<MovingTriangle> for Hittable {
+    fn from(t: MovingTriangle) -> Self {
+        Self::MovingTriangle(t)
+    }
+}
+
 impl From<Triangle> for Hittable {
     fn from(t: Triangle) -> Self {
         Self::Triangle(t)
@@ -275,7 +593,7 @@ impl Sphere {
 
     /// The derivation of the calculation here is given in section 5 of Ray tracing in one weekend
     /// https://raytracing.github.io/books/RayTracingInOneWeekend.html
-    fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+    pub fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
         let oc = self.center - r.orig;
 
         let a = r.dir.square_length();
@@ -299,7 +617,51 @@ impl Sphere {
             }
         }
 
-        let p = r.at(root);
+        Some(self.record(r, root))
+    }
+
+    /// A direction from origin within the cone of directions that hit the sphere, or None if
+    /// origin is inside of it.
+    fn sample_toward(&self, origin: P3) -> Option<V3> {
+        let d = self.center - origin;
+        let one_minus_cos = self.cone(d)?;
+        let z = 1.0 - random_range(0.0..1.0) * one_minus_cos;
+        let phi = TAU * random_range(0.0..1.0);
+        let r = (1.0 - z * z).max(0.0).sqrt();
+
+        Some(Onb::new(d).to_world(V3::new(phi.cos() * r, phi.sin() * r, z)))
+    }
+
+    fn light_pdf(&self, origin: P3, dir: V3) -> f32 {
+        let d = self.center - origin;
+        match self.cone(d) {
+            Some(one_minus_cos) if d.dot(&dir) > 0.0 => {
+                let cos = d.dot(&dir) / (d.square_length() * dir.square_length()).sqrt();
+                if cos >= 1.0 - one_minus_cos {
+                    1.0 / (TAU * one_minus_cos)
+                } else {
+                    0.0
+                }
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// One minus the cosine of the half angle of the cone of directions that hit the sphere from
+    /// a point d away from its center, kept accurate for distant spheres.
+    fn cone(&self, d: V3) -> Option<f32> {
+        let dist_sq = d.square_length();
+        if dist_sq <= self.radius_sq {
+            return None;
+        }
+        let sin_sq = self.radius_sq / dist_sq;
+
+        Some(sin_sq / (1.0 + (1.0 - sin_sq).sqrt()))
+    }
+
+    /// The hit record for r hitting the sphere at t.
+    fn record(&self, r: &Ray, t: f32) -> HitRecord {
+        let p = r.at(t);
         let outward_normal = (p - self.center) * self.inv_radius;
 
         let theta = (-outward_normal.y).acos();
@@ -307,7 +669,58 @@ impl Sphere {
         let u = phi * INV_2PI;
         let v = theta * INV_PI;
 
-        Some(HitRecord::new(root, p, outward_normal, r, self.mat, u, v))
+        let outward_normal = N3::new_unchecked(outward_normal);
+
+        HitRecord::new(t, p, outward_normal, r, self.mat, u, v)
+    }
+}
+
+/// A flat circular disk, used for rendering particles as camera facing sprites.
+#[derive(Debug, Clone)]
+pub struct Disk {
+    center: P3,
+    normal: N3,
+    radius_sq: f32,
+    mat: &'static Material,
+    bbox: AABBox,
+}
+
+impl Disk {
+    pub fn new(center: P3, normal: V3, radius: f32, mat: &'static Material) -> Self {
+        let r = radius.max(0.0);
+        let rvec = V3::new(r, r, r);
+        let bbox = AABBox::new_from_points(center - rvec, center + rvec);
+
+        Self {
+            center,
+            normal: N3::new(normal),
+            radius_sq: r * r,
+            mat,
+            bbox,
+        }
+    }
+
+    fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let denom = self.normal.dot(&r.dir);
+        if denom.abs() < 1e-8 {
+            return None; // parallel to the disk
+        }
+
+        let t = self.normal.dot(&(self.center - r.orig)) / denom;
+        if !ray_t.contains(t) {
+            return None;
+        }
+
+        let p = r.at(t);
+        let d_sq = (p - self.center).square_length();
+        if d_sq > self.radius_sq {
+            return None;
+        }
+
+        let mut hr = HitRecord::new(t, p, self.normal, r, self.mat, 0.5, 0.5);
+        hr.edge_dist = self.radius_sq.sqrt() - d_sq.sqrt();
+
+        Some(hr)
     }
 }
 
@@ -317,7 +730,9 @@ pub struct Triangle {
     ab: V3,
     ac: V3,
     normal: V3,
-    unit_normal: V3,
+    unit_normal: N3,
+    edge_scale: V3, // barycentric coordinate -> distance to the opposite edge
+    normals: Option<[N3; 3]>, // per vertex normals for smooth shading
     mat: &'static Material,
     pub bbox: AABBox,
 }
@@ -329,7 +744,16 @@ impl Triangle {
         let ab = b - a;
         let ac = c - a;
         let normal = ab.cross(&ac);
-        let unit_normal = normal.unit_vector();
+        let unit_normal = N3::new(normal);
+
+        // The distance to an edge is the barycentric coordinate of the opposite vertex
+        // multiplied by the height of the triangle over that edge: 2 * area / edge length.
+        let area2 = normal.length();
+        let edge_scale = V3::new(
+            area2 / (c - b).length(),
+            area2 / ac.length(),
+            area2 / ab.length(),
+        );
 
         Self {
             a,
@@ -337,14 +761,32 @@ impl Triangle {
             ac,
             normal,
             unit_normal,
+            edge_scale,
+            normals: None,
             mat,
             bbox: AABBox::new_enclosing(bbox1, bbox2),
         }
     }
 
+    /// Shade with the normals at each vertex interpolated across the face in place of the flat
+    /// face normal. The face normal is still used for deciding which side of the triangle is hit.
+    pub fn with_normals(mut self, normals: [N3; 3]) -> Self {
+        self.normals = Some(normals);
+        self
+    }
+
+    pub fn vertices(&self) -> [P3; 3] {
+        [self.a, self.a + self.ab, self.a + self.ac]
+    }
+
+    pub fn unit_normal(&self) -> N3 {
+        self.unit_normal
+    }
+
     // Calculate the intersection of a ray with a triangle using the Möller–Trumbore algorithm
     //   https://en.wikipedia.org/wiki/M%C3%B6ller%E2%80%93Trumbore_intersection_algorithm
     pub fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        counters::add(Counter::TriangleTests, 1);
         // If r . normal is 0 then the ray is parallel to the triangle plane and no hit is possible
         let det = -(r.dir.dot(&self.normal));
         if det.abs() < 1e-8 {
@@ -370,8 +812,355 @@ impl Triangle {
         }
 
         let p = r.at(t);
+        let mut hr = HitRecord::new(t, p, self.unit_normal, r, self.mat, u, v);
+        hr.edge_dist = ((1.0 - u - v) * self.edge_scale.x)
+            .min(u * self.edge_scale.y)
+            .min(v * self.edge_scale.z);
+        if let Some(ns) = &self.normals {
+            hr.set_shading_normal(interpolate_normals(ns, u, v));
+        }
+
+        Some(hr)
+    }
+}
+
+/// The vertex normals ns blended at the barycentric coordinates (u, v) of a triangle.
+fn interpolate_normals(ns: &[N3; 3], u: f32, v: f32) -> N3 {
+    N3::new((1.0 - u - v) * ns[0] + u * ns[1] + v * ns[2])
+}
+
+/// A deforming triangle with vertex positions given at evenly spaced times over the shutter
+/// interval. Rays are intersected with the triangle linearly interpolated to the ray time.
+#[derive(Debug, Clone)]
+pub struct MovingTriangle {
+    keys: Vec<[P3; 3]>,
+    mat: &'static Material,
+    pub bbox: AABBox,
+}
+
+impl MovingTriangle {
+    /// Panics if fewer than two keys are given.
+    pub fn new(keys: Vec<[P3; 3]>, mat: &'static Material) -> MovingTriangle {
+        assert!(keys.len() >= 2, "MovingTriangle requires at least two keys");
+        let bbox = keys.iter().flatten().fold(AABBox::EMPTY, |bbox, &p| {
+            AABBox::new_enclosing(bbox, AABBox::new_from_points(p, p))
+        });
+
+        Self { keys, mat, bbox }
+    }
+
+    /// The triangle at time t in [0, 1)
+    pub fn at_time(&self, t: f32) -> Triangle {
+        let s = t.clamp(0.0, 1.0) * (self.keys.len() - 1) as f32;
+        let i = (s as usize).min(self.keys.len() - 2);
+        let f = s - i as f32;
+        let [a, b, c] = [0, 1, 2].map(|k| {
+            let (p0, p1) = (self.keys[i][k], self.keys[i + 1][k]);
+            p0 + (p1 - p0) * f
+        });
+
+        Triangle::new(a, b, c, self.mat)
+    }
+
+    pub fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let tri = self.at_time(r.time);
+        let mut hr = tri.hits(r, ray_t)?;
+
+        // the same barycentric point of the triangle at shutter open and close
+        let (ab, ac, ap) = (tri.ab, tri.ac, hr.p - tri.a);
+        let (d00, d01, d11) = (ab.dot(&ab), ab.dot(&ac), ac.dot(&ac));
+        let (d20, d21) = (ap.dot(&ab), ap.dot(&ac));
+        let denom = d00 * d11 - d01 * d01;
+        let wb = (d11 * d20 - d01 * d21) / denom;
+        let wc = (d00 * d21 - d01 * d20) / denom;
+        let at = |[a, b, c]: [P3; 3]| a + (b - a) * wb + (c - a) * wc;
+        hr.motion = at(self.keys[self.keys.len() - 1]) - at(self.keys[0]);
+
+        Some(hr)
+    }
+}
+
+/// Up to four spheres packed together so that they can be intersected in a single pass using
+/// SIMD operations.
+#[derive(Debug, Clone)]
+pub struct Sphere4 {
+    centers: V3x4,
+    radius_sq: f32x4,
+    spheres: [Sphere; 4],
+    n: usize,
+    bbox: AABBox,
+}
+
+impl Sphere4 {
+    /// Pack between 1 and 4 spheres. Unused lanes have a negative squared radius so that they
+    /// can never be hit.
+    pub fn new(spheres: &[Sphere]) -> Sphere4 {
+        assert!(
+            !spheres.is_empty() && spheres.len() <= 4,
+            "Sphere4 requires 1-4 spheres"
+        );
+
+        let packed: [Sphere; 4] =
+            std::array::from_fn(|i| spheres[i.min(spheres.len() - 1)].clone());
+        let mut radius_sq = [-1.0; 4];
+        for (r, s) in radius_sq.iter_mut().zip(spheres) {
+            *r = s.radius_sq;
+        }
+
+        Self {
+            centers: V3x4::from_v3s(packed.clone().map(|s| s.center - P3::ORIGIN)),
+            radius_sq: f32x4::new(radius_sq),
+            spheres: packed,
+            n: spheres.len(),
+            bbox: spheres
+                .iter()
+                .fold(AABBox::EMPTY, |b, s| AABBox::new_enclosing(b, s.bbox)),
+        }
+    }
+
+    /// The same intersection as [Sphere::hits] run across all four lanes at once, returning the
+    /// closest hit.
+    pub fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let dir = V3x4::splat(r.dir);
+        let oc = self.centers - V3x4::splat(r.orig - P3::ORIGIN);
+
+        let a = f32x4::splat(r.dir.square_length());
+        let h = dir.dot(&oc);
+        let c = oc.dot(&oc) - self.radius_sq;
+        let discriminant = h * h - a * c;
+        let hit = discriminant.cmp_ge(f32x4::ZERO);
+        if hit.none() {
+            return None;
+        }
+
+        let sqrt_disc = discriminant.max(f32x4::ZERO).sqrt();
+        let inv_a = f32x4::ONE / a;
+        let (min, max) = (f32x4::splat(ray_t.min), f32x4::splat(ray_t.max));
+        let surrounds = |t: f32x4| t.cmp_gt(min) & t.cmp_lt(max);
+        let near = (h - sqrt_disc) * inv_a;
+        let far = (h + sqrt_disc) * inv_a;
+        let t = surrounds(near).blend(near, far);
+
+        let mask = hit & surrounds(t);
+        if mask.none() {
+            return None;
+        }
+
+        let ts = mask.blend(t, f32x4::splat(f32::INFINITY)).to_array();
+        let mut i = 0;
+        for j in 1..self.n {
+            if ts[j] < ts[i] {
+                i = j;
+            }
+        }
+
+        Some(self.spheres[i].record(r, ts[i]))
+    }
+}
+
+/// Group primitives that have batched intersection routines into packs of up to four nearby
+/// primitives of the same type so that BVH leaves test several at once. Other hittables are
+/// returned as they are.
+pub fn batch_by_type(hittables: Vec<Hittable>) -> Vec<Hittable> {
+    let (mut tris, mut spheres, mut batched) = (Vec::new(), Vec::new(), Vec::new());
+    for h in hittables {
+        match h {
+            Hittable::Triangle(t) => tris.push(t),
+            Hittable::Sphere(s) => spheres.push(s),
+            h => batched.push(h),
+        }
+    }
+
+    sort_spatially(&mut tris, 4, |t| t.bbox);
+    batched.extend(tris.chunks(4).map(|c| match c {
+        [t] => Hittable::from(t.clone()),
+        ts => Triangle4::new(ts).into(),
+    }));
+    sort_spatially(&mut spheres, 4, |s| s.bbox);
+    batched.extend(spheres.chunks(4).map(|c| match c {
+        [s] => Hittable::from(s.clone()),
+        ss => Sphere4::new(ss).into(),
+    }));
+
+    batched
+}
+
+/// Up to four triangles packed together so that they can be intersected in a single pass
+/// using SIMD operations.
+#[derive(Debug, Clone)]
+pub struct Triangle4 {
+    a: V3x4,
+    ab: V3x4,
+    ac: V3x4,
+    normal: V3x4,
+    unit_normals: [N3; 4],
+    edge_scales: [V3; 4],
+    normals: [Option<[N3; 3]>; 4],
+    mats: [&'static Material; 4],
+    n: usize,
+    bbox: AABBox,
+}
+
+impl Triangle4 {
+    /// Pack between 1 and 4 triangles. Unused lanes are filled with degenerate triangles that can
+    /// never be hit.
+    pub fn new(tris: &[Triangle]) -> Triangle4 {
+        assert!(
+            !tris.is_empty() && tris.len() <= 4,
+            "Triangle4 requires 1-4 triangles"
+        );
+
+        let lane = |f: &dyn Fn(&Triangle) -> V3| {
+            let mut vs = [V3::default(); 4];
+            for (v, t) in vs.iter_mut().zip(tris) {
+                *v = f(t);
+            }
+            vs
+        };
+        let mut mats = [tris[0].mat; 4];
+        let mut normals = [None; 4];
+        for ((m, ns), t) in mats.iter_mut().zip(normals.iter_mut()).zip(tris) {
+            *m = t.mat;
+            *ns = t.normals;
+        }
+
+        Self {
+            a: V3x4::from_v3s(lane(&|t| t.a - P3::ORIGIN)),
+            ab: V3x4::from_v3s(lane(&|t| t.ab)),
+            ac: V3x4::from_v3s(lane(&|t| t.ac)),
+            normal: V3x4::from_v3s(lane(&|t| t.normal)),
+            unit_normals: lane(&|t| t.unit_normal.as_v3()).map(N3::new_unchecked),
+            edge_scales: lane(&|t| t.edge_scale),
+            normals,
+            mats,
+            n: tris.len(),
+            bbox: tris
+                .iter()
+                .fold(AABBox::EMPTY, |b, t| AABBox::new_enclosing(b, t.bbox)),
+        }
+    }
+
+    /// The same Möller–Trumbore intersection as [Triangle::hits] run across all four lanes at
+    /// once, returning the closest hit.
+    pub fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        counters::add(Counter::TriangleTests, self.n as u64);
+        let dir = V3x4::splat(r.dir);
+        let det = -dir.dot(&self.normal);
+        let inv_det = f32x4::ONE / det;
+        let ao = V3x4::splat(r.orig - P3::ORIGIN) - self.a;
+        let r_x_ao = ao.cross(&dir);
+
+        let t = ao.dot(&self.normal) * inv_det;
+        let u = self.ac.dot(&r_x_ao) * inv_det;
+        let v = -self.ab.dot(&r_x_ao) * inv_det;
+
+        let zero = f32x4::ZERO;
+        let mask = det.abs().cmp_ge(f32x4::splat(1e-8))
+            & t.cmp_gt(f32x4::splat(ray_t.min))
+            & t.cmp_lt(f32x4::splat(ray_t.max))
+            & u.cmp_ge(zero)
+            & v.cmp_ge(zero)
+            & (u + v).cmp_le(f32x4::ONE);
+        if mask.none() {
+            return None;
+        }
+
+        let ts = mask.blend(t, f32x4::splat(f32::INFINITY)).to_array();
+        let mut i = 0;
+        for j in 1..self.n {
+            if ts[j] < ts[i] {
+                i = j;
+            }
+        }
+
+        let (t, u, v) = (ts[i], u.to_array()[i], v.to_array()[i]);
+        let p = r.at(t);
+        let mut hr = HitRecord::new(t, p, self.unit_normals[i], r, self.mats[i], u, v);
+        let es = self.edge_scales[i];
+        hr.edge_dist = ((1.0 - u - v) * es.x).min(u * es.y).min(v * es.z);
+        if let Some(ns) = &self.normals[i] {
+            hr.set_shading_normal(interpolate_normals(ns, u, v));
+        }
 
-        Some(HitRecord::new(t, p, self.unit_normal, r, self.mat, u, v))
+        Some(hr)
+    }
+}
+
+/// A thin round curve segment (an uncapped cylinder) between two points, used to build up the
+/// strands of hair and fur. The u texture coordinate runs along the full strand from root (0) to
+/// tip (1).
+#[derive(Debug, Clone)]
+pub struct Curve {
+    a: P3,
+    axis: N3,
+    len: f32,
+    radius: f32,
+    u0: f32,
+    u1: f32,
+    mat: &'static Material,
+    pub bbox: AABBox,
+}
+
+impl Curve {
+    pub fn new(a: P3, b: P3, radius: f32, (u0, u1): (f32, f32), mat: &'static Material) -> Curve {
+        let ab = b - a;
+        let r = V3::new(radius, radius, radius);
+        let bbox = AABBox::new_enclosing(
+            AABBox::new_from_points(a - r, a + r),
+            AABBox::new_from_points(b - r, b + r),
+        );
+
+        Self {
+            a,
+            axis: N3::new(ab),
+            len: ab.length(),
+            radius,
+            u0,
+            u1,
+            mat,
+            bbox,
+        }
+    }
+
+    fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        // Solve for the intersection with the infinite cylinder using the components of the ray
+        // perpendicular to the axis and then check that the hit lies within the segment.
+        let w = self.axis.as_v3();
+        let ao = r.orig - self.a;
+        let d_perp = r.dir - r.dir.dot(&w) * w;
+        let o_perp = ao - ao.dot(&w) * w;
+
+        let a = d_perp.square_length();
+        if a < 1e-12 {
+            return None; // parallel to the axis
+        }
+        let h = d_perp.dot(&o_perp);
+        let c = o_perp.square_length() - self.radius * self.radius;
+        let disc = h * h - a * c;
+        if disc < 0.0 {
+            return None;
+        }
+
+        let sqrt_disc = disc.sqrt();
+        for t in [(-h - sqrt_disc) / a, (-h + sqrt_disc) / a] {
+            if !ray_t.surrounds(t) {
+                continue;
+            }
+            let s = (ao + t * r.dir).dot(&w);
+            if s < 0.0 || s > self.len {
+                continue;
+            }
+
+            let p = r.at(t);
+            let outward_normal = N3::new_unchecked((p - (self.a + s * w)) / self.radius);
+            let u = self.u0 + (self.u1 - self.u0) * s / self.len;
+            let mut hr = HitRecord::new(t, p, outward_normal, r, self.mat, u, 0.5);
+            hr.tangent = w;
+
+            return Some(hr);
+        }
+
+        None
     }
 }
 
@@ -383,8 +1172,9 @@ pub struct Quad {
     u: V3,
     v: V3,
     w: V3,
-    normal: V3,
+    normal: N3,
     d: f32,
+    edge_scale: (f32, f32), // (alpha, beta) -> distance to the corresponding edge
     mat: &'static Material,
     bbox: AABBox,
 }
@@ -396,9 +1186,11 @@ impl Quad {
         let bbox = AABBox::new_enclosing(diag1, diag2);
 
         let n = u.cross(&v);
-        let normal = n.unit_vector();
-        let d = normal.dot(&q);
+        let normal = N3::new(n);
+        let d = normal.dot(&(q - P3::ORIGIN));
         let w = n / n.dot(&n);
+        let area = n.length();
+        let edge_scale = (area / v.length(), area / u.length());
 
         Self {
             q,
@@ -407,6 +1199,7 @@ impl Quad {
             w,
             normal,
             d,
+            edge_scale,
             mat,
             bbox,
         }
@@ -418,7 +1211,7 @@ impl Quad {
             return None; // ray is parallel to our plane
         }
 
-        let t = (self.d - self.normal.dot(&r.orig)) / denom;
+        let t = (self.d - self.normal.dot(&(r.orig - P3::ORIGIN))) / denom;
         if !ray_t.contains(t) {
             return None; // hit point is outside of the ray interval
         }
@@ -432,18 +1225,39 @@ impl Quad {
             return None;
         }
 
-        Some(HitRecord::new(
-            t,
-            intersection,
-            self.normal,
-            r,
-            self.mat,
-            alpha,
-            beta,
-        ))
+        let mut hr = HitRecord::new(t, intersection, self.normal, r, self.mat, alpha, beta);
+        let (sa, sb) = self.edge_scale;
+        hr.edge_dist = (alpha.min(1.0 - alpha) * sa).min(beta.min(1.0 - beta) * sb);
+
+        Some(hr)
+    }
+
+    /// The direction from origin to a point chosen uniformly over the area of the quad
+    fn sample_toward(&self, origin: P3) -> V3 {
+        let p = self.q + random_range(0.0..1.0) * self.u + random_range(0.0..1.0) * self.v;
+
+        p - origin
+    }
+
+    fn light_pdf(&self, origin: P3, dir: V3, epsilon: f32) -> f32 {
+        let r = Ray::new(origin, dir);
+        let Some(hr) = self.hits(&r, Interval::new(epsilon, f32::INFINITY)) else {
+            return 0.0;
+        };
+        let dist_sq = hr.t * hr.t * dir.square_length();
+        let cos = self.normal.dot(&dir).abs() / dir.length();
+        let area = self.u.cross(&self.v).length();
+
+        dist_sq / (cos * area)
     }
 }
 
+/// Whether the parallelogram spanned by u and v has no area (an edge of zero length or parallel
+/// edges), leaving primitives built from it without a defined normal.
+pub fn is_degenerate(u: V3, v: V3) -> bool {
+    u.cross(&v).square_length() <= 1e-12 * u.square_length() * v.square_length()
+}
+
 /// Construct a closed cuboid containing the two provided opposite vertices: a, b.
 pub fn cuboid(a: P3, b: P3, mat: &'static Material) -> Hittable {
     let mut sides = HittableList::default();
@@ -464,23 +1278,116 @@ pub fn cuboid(a: P3, b: P3, mat: &'static Material) -> Hittable {
     sides.into()
 }
 
+/// The region of space filled by a [ConstantMedium]: either the inside of a closed, convex
+/// hittable or a boolean combination of other volumes.
+#[derive(Debug, Clone)]
+pub enum Volume {
+    Shape(Box<Hittable>),
+    Intersection(Box<Volume>, Box<Volume>),
+    Difference(Box<Volume>, Box<Volume>),
+}
+
+impl From<Hittable> for Volume {
+    fn from(h: Hittable) -> Self {
+        Self::Shape(Box::new(h))
+    }
+}
+
+impl Volume {
+    pub fn intersect(self, other: impl Into<Volume>) -> Volume {
+        Self::Intersection(Box::new(self), Box::new(other.into()))
+    }
+
+    pub fn subtract(self, other: impl Into<Volume>) -> Volume {
+        Self::Difference(Box::new(self), Box::new(other.into()))
+    }
+
+    pub fn bounding_box(&self) -> AABBox {
+        match self {
+            Self::Shape(h) => h.bounding_box(),
+            // conservative: the combined volume is always contained in the lhs
+            Self::Intersection(a, _) | Self::Difference(a, _) => a.bounding_box(),
+        }
+    }
+
+    /// The sorted, disjoint intervals of t for which the ray is inside of this volume.
+    pub fn spans(&self, r: &Ray) -> Vec<Interval> {
+        match self {
+            Self::Shape(h) => convex_span(h, r).into_iter().collect(),
+            Self::Intersection(a, b) => intersect_spans(&a.spans(r), &b.spans(r)),
+            Self::Difference(a, b) => subtract_spans(&a.spans(r), &b.spans(r)),
+        }
+    }
+}
+
+/// The span of t for which the ray is inside of a convex shape: between the first two hits.
+fn convex_span(h: &Hittable, r: &Ray) -> Option<Interval> {
+    let hr1 = h.hits(r, Interval::UNIVERSE)?;
+    let hr2 = h.hits(r, Interval::new(hr1.t + 0.1 * r.epsilon, f32::INFINITY))?;
+
+    Some(Interval::new(hr1.t, hr2.t))
+}
+
+fn intersect_spans(a: &[Interval], b: &[Interval]) -> Vec<Interval> {
+    let mut spans = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() && j < b.len() {
+        let min = a[i].min.max(b[j].min);
+        let max = a[i].max.min(b[j].max);
+        if min < max {
+            spans.push(Interval::new(min, max));
+        }
+        if a[i].max < b[j].max {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    spans
+}
+
+fn subtract_spans(a: &[Interval], b: &[Interval]) -> Vec<Interval> {
+    let mut spans = Vec::new();
+
+    for span in a {
+        let mut min = span.min;
+        for cut in b.iter().filter(|c| c.max > span.min && c.min < span.max) {
+            if cut.min > min {
+                spans.push(Interval::new(min, cut.min));
+            }
+            min = min.max(cut.max);
+        }
+        if min < span.max {
+            spans.push(Interval::new(min, span.max));
+        }
+    }
+
+    spans
+}
+
 #[derive(Debug, Clone)]
 pub struct ConstantMedium {
-    boundary: &'static Hittable,
+    boundary: &'static Volume,
     neg_inv_density: f32,
     phase_func: &'static Material,
 }
 
 impl ConstantMedium {
-    pub fn new(boundary: Hittable, density: f32, color: Color) -> ConstantMedium {
+    pub fn new(boundary: impl Into<Volume>, density: f32, color: Color) -> ConstantMedium {
         Self::new_with_texture(boundary, density, Texture::solid(color))
     }
 
-    pub fn new_with_texture(boundary: Hittable, density: f32, texture: Texture) -> ConstantMedium {
+    pub fn new_with_texture(
+        boundary: impl Into<Volume>,
+        density: f32,
+        texture: Texture,
+    ) -> ConstantMedium {
         let neg_inv_density = -1.0 / density;
 
         Self {
-            boundary: Box::leak(Box::new(boundary)),
+            boundary: Box::leak(Box::new(boundary.into())),
             neg_inv_density,
             phase_func: Box::leak(Box::new(Material::isotropic_texture(texture))),
         }
@@ -491,30 +1398,43 @@ impl ConstantMedium {
     }
 
     pub fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
-        let mut hr1 = self.boundary.hits(r, Interval::UNIVERSE)?;
-        let i2 = Interval::new(hr1.t + 0.0001, f32::INFINITY);
-        let mut hr2 = self.boundary.hits(r, i2)?;
-
-        hr1.t = hr1.t.max(ray_t.min);
-        hr2.t = hr2.t.min(ray_t.max);
-        if hr1.t > hr2.t {
-            return None;
+        match self.boundary {
+            Volume::Shape(h) => self.sample(r, ray_t, convex_span(h, r)),
+            v => self.sample(r, ray_t, v.spans(r)),
         }
+    }
 
-        hr1.t = hr1.t.max(0.0);
-
+    fn sample(
+        &self,
+        r: &Ray,
+        ray_t: Interval,
+        spans: impl IntoIterator<Item = Interval>,
+    ) -> Option<HitRecord> {
         let r_len = r.dir.length();
-        let dist_in_boundary = (hr2.t - hr1.t) * r_len;
-        let hit_dist = self.neg_inv_density * random_range(0.0..1.0f32).log2();
-        if hit_dist > dist_in_boundary {
-            return None;
-        }
+        let mut hit_dist = self.neg_inv_density * random_range(0.0..1.0f32).log2();
+
+        // The free path length is memoryless so it can be consumed across each span in turn
+        for span in spans {
+            let t0 = span.min.max(ray_t.min).max(0.0);
+            let t1 = span.max.min(ray_t.max);
+            if t0 > t1 {
+                continue;
+            }
+
+            let dist_in_boundary = (t1 - t0) * r_len;
+            if hit_dist > dist_in_boundary {
+                hit_dist -= dist_in_boundary;
+                continue;
+            }
+
+            let t = t0 + hit_dist / r_len;
+            let normal = N3::new_unchecked(V3::new(1.0, 0.0, 0.0)); // arbitrary
+            let (u, v) = (0.0, 0.0); // arbitrary
 
-        let t = hr1.t + hit_dist / r_len;
-        let normal = V3::new(1.0, 0.0, 0.0); // arbitrary
-        let (u, v) = (0.0, 0.0); // arbitrary
+            return Some(HitRecord::new(t, r.at(t), normal, r, self.phase_func, u, v));
+        }
 
-        Some(HitRecord::new(t, r.at(t), normal, r, self.phase_func, u, v))
+        None
     }
 }
 
@@ -538,7 +1458,9 @@ impl Translate {
 
     fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
         // Move the ray back by the offset
-        let offset_r = Ray::new(r.orig - self.offset, r.dir);
+        let offset_r = Ray::new(r.orig - self.offset, r.dir)
+            .with_time(r.time)
+            .with_epsilon(r.epsilon);
 
         // If the offset ray hits...
         let mut hr = self.inner.hits(&offset_r, ray_t)?;
@@ -559,10 +1481,8 @@ pub struct Rotate {
 }
 
 impl Rotate {
-    fn new(inner: Hittable, angle: f32) -> Rotate {
-        let rad = angle.to_radians();
-        let sin_theta = rad.sin();
-        let cos_theta = rad.cos();
+    fn new(inner: Hittable, angle: Angle) -> Rotate {
+        let (sin_theta, cos_theta) = angle.sin_cos();
         let bbox = inner.bounding_box();
 
         let mut min = P3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
@@ -577,12 +1497,9 @@ impl Rotate {
 
                     let new_x = cos_theta * x + sin_theta * z;
                     let new_z = -sin_theta * x + cos_theta * z;
-                    let v = V3::new(new_x, y, new_z);
-
-                    for c in 0..3 {
-                        min[c] = min[c].min(v[c]);
-                        max[c] = max[c].max(v[c]);
-                    }
+                    let p = P3::new(new_x, y, new_z);
+                    min = min.min(&p);
+                    max = max.max(&p);
                 }
             }
         }
@@ -617,24 +1534,220 @@ impl Rotate {
 
     fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
         // Transform the ray from world space to object space.
-        let rot_r = Ray::new(self.rot_f(r.orig), self.rot_f(r.dir));
+        let orig = P3::ORIGIN + self.rot_f(r.orig - P3::ORIGIN);
+        let rot_r = Ray::new(orig, self.rot_f(r.dir))
+            .with_time(r.time)
+            .with_epsilon(r.epsilon);
 
         // If the rotated ray hits...
         let mut hr = self.inner.hits(&rot_r, ray_t)?;
 
         // apply the rotation to the hit record and return
-        hr.p = self.rot_b(hr.p);
-        hr.normal = self.rot_b(hr.normal);
+        hr.p = P3::ORIGIN + self.rot_b(hr.p - P3::ORIGIN);
+        hr.normal = N3::new_unchecked(self.rot_b(hr.normal.as_v3()));
+        hr.motion = self.rot_b(hr.motion);
+
+        Some(hr)
+    }
+}
+
+/// A general affine transform of the inner hittable
+#[derive(Debug, Clone)]
+pub struct Transform {
+    inner: Box<Hittable>,
+    m: M4,   // object to world
+    inv: M4, // world to object
+    bbox: AABBox,
+}
+
+impl Transform {
+    fn new(inner: Hittable, m: M4) -> Transform {
+        let inv = m.inverse().expect("transform matrix to be invertible");
+        let b = inner.bounding_box();
+
+        let mut min = P3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+        let mut max = P3::new(-f32::INFINITY, -f32::INFINITY, -f32::INFINITY);
+        for x in [b.x.min, b.x.max] {
+            for y in [b.y.min, b.y.max] {
+                for z in [b.z.min, b.z.max] {
+                    let p = m.transform_point(P3::new(x, y, z));
+                    min = min.min(&p);
+                    max = max.max(&p);
+                }
+            }
+        }
+
+        Self {
+            inner: Box::new(inner),
+            m,
+            inv,
+            bbox: AABBox::new_from_points(min, max),
+        }
+    }
+
+    fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        // The direction is not normalised after transforming so t values are preserved
+        let obj_r = Ray::new(
+            self.inv.transform_point(r.orig),
+            self.inv.transform_vector(r.dir),
+        )
+        .with_time(r.time)
+        .with_epsilon(r.epsilon);
+        let mut hr = self.inner.hits(&obj_r, ray_t)?;
+
+        hr.p = self.m.transform_point(hr.p);
+        hr.normal = N3::new(self.inv.transform_normal(hr.normal.as_v3()));
+        hr.motion = self.m.transform_vector(hr.motion);
+
+        Some(hr)
+    }
+}
+
+/// A half-space bounded by a plane: the side that the normal points towards is clipped away.
+#[derive(Debug, Clone, Copy)]
+pub struct ClipPlane {
+    point: P3,
+    normal: N3,
+}
+
+impl ClipPlane {
+    pub fn new(point: P3, normal: V3) -> ClipPlane {
+        Self {
+            point,
+            normal: N3::new(normal),
+        }
+    }
+}
+
+/// The inner hittable clipped to the convex region bounded by a set of planes.
+///
+/// If a cap material is provided then the ray entering the clip region while inside of the
+/// inner hittable is treated as a hit on the cut surface. Whether or not the ray is inside is
+/// determined by the next hit being a back face, so capping requires closed geometry with
+/// outward facing normals.
+#[derive(Debug, Clone)]
+pub struct Clip {
+    inner: Box<Hittable>,
+    planes: Vec<ClipPlane>,
+    cap: Option<&'static Material>,
+    bbox: AABBox,
+}
+
+impl Clip {
+    fn new(inner: Hittable, planes: Vec<ClipPlane>, cap: Option<&'static Material>) -> Clip {
+        let bbox = inner.bounding_box();
+
+        Self {
+            inner: Box::new(inner),
+            planes,
+            cap,
+            bbox,
+        }
+    }
+
+    /// The interval of t values for which the ray is inside of the clip region along with the
+    /// plane that the ray enters through (if any).
+    fn clip_interval(&self, r: &Ray) -> Option<(Interval, Option<&ClipPlane>)> {
+        let (mut t0, mut t1) = (f32::NEG_INFINITY, f32::INFINITY);
+        let mut entry = None;
+
+        for plane in self.planes.iter() {
+            let denom = plane.normal.dot(&r.dir);
+            let dist = plane.normal.dot(&(r.orig - plane.point));
+
+            if denom.abs() < 1e-8 {
+                // parallel to the plane: either always inside or always outside
+                if dist > 0.0 {
+                    return None;
+                }
+                continue;
+            }
+
+            let t = -dist / denom;
+            if denom < 0.0 {
+                if t > t0 {
+                    t0 = t;
+                    entry = Some(plane);
+                }
+            } else {
+                t1 = t1.min(t);
+            }
+        }
+
+        (t0 < t1).then_some((Interval::new(t0, t1), entry))
+    }
+
+    fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let (clip_t, entry) = self.clip_interval(r)?;
+        let start = ray_t.min.max(clip_t.min);
+        let end = ray_t.max.min(clip_t.max);
+        if start >= end {
+            return None;
+        }
+
+        // Look past the end of the clip region so we can tell if we are inside at the entry point
+        let hr = self.inner.hits(r, Interval::new(start, ray_t.max));
+
+        if let (Some(cap), Some(plane)) = (self.cap, entry) {
+            let inside = hr.as_ref().is_some_and(|hr| !hr.front_face);
+            if clip_t.min > ray_t.min && inside {
+                let t = clip_t.min;
+                return Some(HitRecord::new(t, r.at(t), plane.normal, r, cap, 0.0, 0.0));
+            }
+        }
+
+        hr.filter(|hr| hr.t <= end)
+    }
+}
+
+/// Tags all hits against the inner hittable with scene object and material IDs
+#[derive(Debug, Clone)]
+pub struct WithId {
+    inner: Box<Hittable>,
+    id: u32,
+    mat_id: u32,
+    bbox: AABBox,
+}
+
+impl WithId {
+    fn new(inner: Hittable, id: u32, mat_id: u32) -> WithId {
+        let bbox = inner.bounding_box();
+
+        Self {
+            inner: Box::new(inner),
+            id,
+            mat_id,
+            bbox,
+        }
+    }
+
+    fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let mut hr = self.inner.hits(r, ray_t)?;
+        hr.obj_id = self.id;
+        hr.mat_id = self.mat_id;
 
         Some(hr)
     }
 }
 
+#[cfg(test)]
+mod conformance;
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{p, v};
     use simple_test_case::test_case;
 
+    #[test_case(v!(1, 0, 0), v!(0, 1, 0), false; "unit square")]
+    #[test_case(v!(1000, 0, 0), v!(0, 0.001, 0), false; "thin sliver")]
+    #[test_case(v!(0, 0, 0), v!(0, 1, 0), true; "zero length edge")]
+    #[test_case(v!(1, 2, 3), v!(-2, -4, -6), true; "parallel edges")]
+    #[test]
+    fn degenerate_edges_are_detected(u: V3, v: V3, expected: bool) {
+        assert_eq!(is_degenerate(u, v), expected);
+    }
+
     #[test_case(Interval::new(1.0, 2.0), Interval::new(1.0, 2.0), Interval::new(1.0, 2.0); "idempotent")]
     #[test_case(Interval::new(1.0, 3.0), Interval::new(2.0, 5.0), Interval::new(1.0, 5.0); "overlapping")]
     #[test_case(Interval::new(1.0, 2.0), Interval::new(3.0, 5.0), Interval::new(1.0, 5.0); "disjoint")]
@@ -646,4 +1759,152 @@ mod tests {
 
         assert_eq!(res, expected);
     }
+
+    #[test_case(0.1, 0.1, 0.1; "near a")]
+    #[test_case(0.5, 0.05, 0.05; "near ab")]
+    #[test_case(0.05, 0.5, 0.05; "near ac")]
+    #[test_case(0.45, 0.45, 0.1 / 2.0f32.sqrt(); "near bc")]
+    #[test]
+    fn triangle_edge_dist_is_correct(x: f32, y: f32, expected: f32) {
+        let mat = &crate::material::CLAY;
+        let t = Triangle::new(p!(0, 0, 0), p!(1, 0, 0), p!(0, 1, 0), mat);
+        let r = Ray::new(p!(x, y, 1), v!(0, 0, -1));
+
+        let hr = t.hits(&r, Interval::new(0.001, f32::INFINITY)).unwrap();
+
+        assert!((hr.edge_dist - expected).abs() < 1e-5, "{}", hr.edge_dist);
+    }
+
+    #[test]
+    fn triangle_vertex_normals_are_interpolated_across_the_face() {
+        let mat = &crate::material::CLAY;
+        let ns = [v!(0, 0, 1), v!(1, 0, 1), v!(0, 1, 1)].map(N3::new);
+        let t = Triangle::new(p!(0, 0, 0), p!(1, 0, 0), p!(0, 1, 0), mat).with_normals(ns);
+        let t4 = Triangle4::new(std::slice::from_ref(&t));
+        let r = Ray::new(p!(0.5, 0.5, -1), v!(0, 0, 1));
+
+        for hr in [
+            t.hits(&r, Interval::new(0.001, f32::INFINITY)),
+            t4.hits(&r, Interval::new(0.001, f32::INFINITY)),
+        ] {
+            let hr = hr.unwrap();
+            // hit from behind so the normal is flipped to face the ray
+            assert!(!hr.front_face);
+            let expected = -v!(0.5, 0.5, 1).unit_vector();
+            assert!(
+                (hr.normal.as_v3() - expected).length() < 1e-5,
+                "{:?}",
+                hr.normal
+            );
+        }
+    }
+
+    #[test_case(None, -1.0; "uncapped hits the far side")]
+    #[test_case(Some(&crate::material::CLAY), 0.5; "capped hits the cut")]
+    #[test]
+    fn clipped_sphere(cap: Option<&'static Material>, expected_y: f32) {
+        let mat = &crate::material::CLAY;
+        let s = Hittable::from(Sphere::new(p!(0, 0, 0), 1.0, mat));
+        let h = s.clip(vec![ClipPlane::new(p!(0, 0.5, 0), v!(0, 1, 0))], cap);
+        let r = Ray::new(p!(0, 5, 0), v!(0, -1, 0));
+
+        let hr = h.hits(&r, Interval::new(0.001, f32::INFINITY)).unwrap();
+
+        assert!((hr.p.y - expected_y).abs() < 1e-5, "{:?}", hr.p);
+    }
+
+    #[test_case(0.0, Some(1.0); "shutter open")]
+    #[test_case(0.25, Some(1.5); "interpolated")]
+    #[test_case(0.5, Some(2.0); "middle key")]
+    #[test_case(0.99, None; "moved out of the way")]
+    #[test]
+    fn moving_triangle_is_hit_at_ray_time(time: f32, expected_t: Option<f32>) {
+        let mat = &crate::material::CLAY;
+        let tri = |z: f32, dx: f32| [p!(dx, 0, z), p!(dx + 1.0, 0, z), p!(dx, 1, z)];
+        let t = MovingTriangle::new(vec![tri(0.0, 0.0), tri(-1.0, 0.0), tri(-1.0, 5.0)], mat);
+        let r = Ray::new(p!(0.1, 0.1, 1), v!(0, 0, -1)).with_time(time);
+
+        let hr = t.hits(&r, Interval::new(0.001, f32::INFINITY));
+
+        assert_eq!(hr.map(|hr| hr.t), expected_t);
+    }
+
+    #[test]
+    fn moving_triangle_hits_record_the_motion_over_the_shutter() {
+        let mat = &crate::material::CLAY;
+        let tri = |z: f32, dx: f32| [p!(dx, 0, z), p!(dx + 1.0, 0, z), p!(dx, 1, z)];
+        let t = MovingTriangle::new(vec![tri(0.0, 0.0), tri(-1.0, 0.0), tri(-1.0, 5.0)], mat);
+        let r = Ray::new(p!(0.1, 0.1, 1), v!(0, 0, -1)).with_time(0.25);
+
+        let hr = t.hits(&r, Interval::new(0.001, f32::INFINITY)).unwrap();
+
+        assert!(
+            (hr.motion - v!(5, 0, -1)).length() < 1e-5,
+            "{:?}",
+            hr.motion
+        );
+    }
+
+    /// A primitive defined outside of the crate: a sphere with its hits tagged in the v coordinate
+    #[derive(Debug)]
+    struct Marked(Sphere);
+
+    impl Hit for Marked {
+        fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+            let mut hr = self.0.hits(r, ray_t)?;
+            hr.v = -1.0;
+            Some(hr)
+        }
+
+        fn bounding_box(&self) -> AABBox {
+            self.0.bbox
+        }
+    }
+
+    #[test]
+    fn custom_primitives_work_with_bvhs_and_wrappers() {
+        let mat = &crate::material::CLAY;
+        let marked = |x: f32| Hittable::custom(Marked(Sphere::new(p!(x, 0, 0), 1.0, mat)));
+        let bvh = Bvh::new(vec![
+            Sphere::new(p!(-3, 0, 0), 1.0, mat).into(),
+            marked(0.0).with_id(2, 1),
+            marked(0.0).translate(v!(3, 0, 0)),
+        ]);
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let hit = |x: f32| {
+            let r = Ray::new(p!(x, 0, 5), v!(0, 0, -1));
+            bvh.hits(&r, ray_t, &mut [0; MAX_BVH_DEPTH])
+                .map(|hr| (hr.t, hr.v, hr.obj_id))
+        };
+
+        assert_eq!(hit(0.0), Some((4.0, -1.0, 2)));
+        assert_eq!(hit(3.0), Some((4.0, -1.0, 0)));
+        assert_eq!(hit(-3.0).map(|(_, v, _)| v), Some(0.5));
+        assert_eq!(hit(5.0), None);
+
+        let mut counts = PrimitiveCounts::default();
+        Hittable::Bvh(bvh).count_primitives(&mut counts);
+        assert_eq!((counts.spheres, counts.custom), (1, 2));
+    }
+
+    fn spans(ts: &[(f32, f32)]) -> Vec<Interval> {
+        ts.iter().map(|&(a, b)| Interval::new(a, b)).collect()
+    }
+
+    #[test_case(&[(0.0, 10.0)], &[(2.0, 4.0)], &[(2.0, 4.0)], &[(0.0, 2.0), (4.0, 10.0)]; "contained")]
+    #[test_case(&[(0.0, 5.0)], &[(3.0, 8.0)], &[(3.0, 5.0)], &[(0.0, 3.0)]; "overlapping")]
+    #[test_case(&[(0.0, 1.0)], &[(2.0, 3.0)], &[], &[(0.0, 1.0)]; "disjoint")]
+    #[test_case(&[(0.0, 4.0), (6.0, 10.0)], &[(3.0, 7.0)], &[(3.0, 4.0), (6.0, 7.0)], &[(0.0, 3.0), (7.0, 10.0)]; "straddling")]
+    #[test]
+    fn span_ops_work(
+        a: &[(f32, f32)],
+        b: &[(f32, f32)],
+        intersection: &[(f32, f32)],
+        difference: &[(f32, f32)],
+    ) {
+        let (a, b) = (spans(a), spans(b));
+
+        assert_eq!(intersect_spans(&a, &b), spans(intersection));
+        assert_eq!(subtract_spans(&a, &b), spans(difference));
+    }
 }
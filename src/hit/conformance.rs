@@ -0,0 +1,398 @@
+//! Known answer and property tests run against every primitive (and the wrappers and compounds
+//! built from them) so that new primitives are validated in the same way: add an example of it to
+//! [fixtures] and any cases specific to its shape below.
+use super::*;
+use crate::{material::CLAY, p, v, voxel::VoxelData};
+use proptest::prelude::*;
+use simple_test_case::test_case;
+use std::collections::HashMap;
+
+const T: Interval = Interval::new(0.001, f32::INFINITY);
+
+/// An example of a primitive filling the box from -1 to 1 along x and y
+struct Fixture {
+    name: &'static str,
+    hittable: Hittable,
+    /// Distance to the first hit along -z from (0, 0, 5)
+    t: f32,
+    /// Whether the primitive encloses a volume, in which case it is hit from behind by rays
+    /// leaving the origin
+    solid: bool,
+    /// Whether repeated queries give the same hit
+    deterministic: bool,
+}
+
+fn fixture(name: &'static str, hittable: impl Into<Hittable>, t: f32, solid: bool) -> Fixture {
+    Fixture {
+        name,
+        hittable: hittable.into(),
+        t,
+        solid,
+        deterministic: true,
+    }
+}
+
+fn sphere() -> Sphere {
+    Sphere::new(p!(0, 0, 0), 1.0, &CLAY)
+}
+
+fn triangle() -> Triangle {
+    Triangle::new(p!(-1, -1, 0), p!(1, -1, 0), p!(0, 1, 0), &CLAY)
+}
+
+fn voxels() -> VoxelGrid {
+    let data = VoxelData {
+        dims: [2, 2, 2],
+        cells: vec![1; 8],
+        palette: HashMap::new(),
+    };
+
+    VoxelGrid::new(data, p!(-1, -1, -1), 1.0, &HashMap::new())
+}
+
+/// A user defined primitive: the plane z = 0 within the unit square
+#[derive(Debug)]
+struct Tile;
+
+impl Hit for Tile {
+    fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let t = -r.orig.z / r.dir.z;
+        let p = r.at(t);
+        let side = Interval::new(-1.0, 1.0);
+        if !(ray_t.contains(t) && side.contains(p.x) && side.contains(p.y)) {
+            return None;
+        }
+
+        Some(HitRecord::new(
+            t,
+            p,
+            N3::new(v!(0, 0, 1)),
+            r,
+            &CLAY,
+            0.0,
+            0.0,
+        ))
+    }
+
+    fn bounding_box(&self) -> AABBox {
+        AABBox::new_from_points(p!(-1, -1, 0), p!(1, 1, 0))
+    }
+}
+
+fn fixtures() -> Vec<Fixture> {
+    let far = Sphere::new(p!(10, 10, 10), 1.0, &CLAY);
+    let away = Triangle::new(p!(10, 10, 10), p!(11, 10, 10), p!(10, 11, 10), &CLAY);
+    let t = triangle();
+
+    vec![
+        fixture("sphere", sphere(), 4.0, true),
+        fixture("sphere4", Sphere4::new(&[far, sphere()]), 4.0, true),
+        fixture(
+            "disk",
+            Disk::new(p!(0, 0, 0), v!(0, 0, 1), 1.0, &CLAY),
+            5.0,
+            false,
+        ),
+        fixture(
+            "quad",
+            Quad::new(p!(-1, -1, 0), v!(2, 0, 0), v!(0, 2, 0), &CLAY),
+            5.0,
+            false,
+        ),
+        fixture("triangle", triangle(), 5.0, false),
+        fixture("triangle4", Triangle4::new(&[away, triangle()]), 5.0, false),
+        fixture(
+            "moving triangle",
+            MovingTriangle::new(vec![[t.a, t.a + t.ab, t.a + t.ac]; 2], &CLAY),
+            5.0,
+            false,
+        ),
+        fixture(
+            "curve",
+            Curve::new(p!(-1, 0, 0), p!(1, 0, 0), 0.5, (0.0, 1.0), &CLAY),
+            4.5,
+            true,
+        ),
+        fixture("voxels", voxels(), 4.0, true),
+        fixture("custom", Hittable::custom(Tile), 5.0, false),
+        fixture(
+            "cuboid",
+            cuboid(p!(-1, -1, -1), p!(1, 1, 1), &CLAY),
+            4.0,
+            true,
+        ),
+        fixture(
+            "bvh",
+            Hittable::Bvh(Bvh::new(vec![sphere().into()])),
+            4.0,
+            true,
+        ),
+        fixture(
+            "translated",
+            Hittable::from(Sphere::new(p!(-1, 0, 0), 1.0, &CLAY)).translate(v!(1, 0, 0)),
+            4.0,
+            true,
+        ),
+        fixture(
+            "rotated",
+            Hittable::from(sphere()).rotate(Angle::deg(30.0)),
+            4.0,
+            true,
+        ),
+        fixture(
+            "transformed",
+            Hittable::from(Sphere::new(p!(0, 0, 0), 0.5, &CLAY)).transform(M4::scale(v!(2, 2, 2))),
+            4.0,
+            true,
+        ),
+        fixture(
+            "clipped",
+            Hittable::from(Sphere::new(p!(0, 0, -1), 2.0, &CLAY))
+                .clip(vec![ClipPlane::new(p!(0, 0, 0), v!(0, 0, 1))], Some(&CLAY)),
+            5.0,
+            true,
+        ),
+        Fixture {
+            // dense enough that rays scatter on entering
+            deterministic: false,
+            ..fixture(
+                "medium",
+                ConstantMedium::new(Hittable::from(sphere()), 1e6, Color::WHITE),
+                4.0,
+                true,
+            )
+        },
+    ]
+}
+
+fn get(name: &str) -> Hittable {
+    let f = fixtures().into_iter().find(|f| f.name == name);
+
+    f.unwrap_or_else(|| panic!("unknown fixture {name}"))
+        .hittable
+}
+
+fn valid(name: &str, r: &Ray, hr: &HitRecord) -> Result<(), String> {
+    let bbox = Hittable::bounding_box(&get(name)).expand(1e-3);
+    let checks = [
+        (hr.t.is_finite(), "t is not finite"),
+        (
+            (hr.p - r.at(hr.t)).length() < 1e-3 * hr.t.max(1.0),
+            "p is not on the ray",
+        ),
+        (
+            bbox.x.contains(hr.p.x) && bbox.y.contains(hr.p.y) && bbox.z.contains(hr.p.z),
+            "p is outside the bounding box",
+        ),
+        (
+            (hr.normal.as_v3().length() - 1.0).abs() < 1e-3,
+            "the normal is not unit length",
+        ),
+        (
+            hr.normal.dot(&r.dir) <= 1e-4,
+            "the normal faces along the ray",
+        ),
+        (hr.u.is_finite() && hr.v.is_finite(), "uv is not finite"),
+    ];
+
+    match checks.iter().find(|(ok, _)| !ok) {
+        Some((_, msg)) => Err(format!("{name}: {msg}: {r:?} {hr:?}")),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn rays_head_on_hit_the_front_face() {
+    for f in fixtures() {
+        let r = Ray::new(p!(0, 0, 5), v!(0, 0, -1));
+        let hr = f
+            .hittable
+            .hits(&r, T)
+            .unwrap_or_else(|| panic!("{} missed", f.name));
+
+        assert!((hr.t - f.t).abs() < 1e-3, "{}: t={}", f.name, hr.t);
+        if f.deterministic {
+            assert!(hr.front_face, "{}", f.name);
+            assert!(
+                (hr.normal.as_v3() - v!(0, 0, 1)).length() < 1e-5,
+                "{}",
+                f.name
+            );
+        }
+        valid(f.name, &r, &hr).unwrap();
+    }
+}
+
+#[test]
+fn hits_outside_the_ray_interval_are_ignored() {
+    for f in fixtures() {
+        let r = Ray::new(p!(0, 0.1, 5), v!(0, 0, -1));
+
+        assert!(
+            f.hittable
+                .hits(&r, Interval::new(0.001, f.t - 0.01))
+                .is_none(),
+            "{}",
+            f.name
+        );
+        // pointing away or starting beyond the primitive
+        let away = Ray::new(p!(0, 0.1, 5), v!(0, 0, 1));
+        assert!(f.hittable.hits(&away, T).is_none(), "{}", f.name);
+        let beyond = Ray::new(p!(0, 0.1, -5), v!(0, 0, -1));
+        assert!(f.hittable.hits(&beyond, T).is_none(), "{}", f.name);
+    }
+}
+
+#[test]
+fn solids_are_hit_from_behind_by_rays_starting_inside() {
+    for f in fixtures()
+        .into_iter()
+        .filter(|f| f.solid && f.deterministic)
+    {
+        let r = Ray::new(p!(0, 0.1, 0), v!(0, 0, -1));
+        let hr = f
+            .hittable
+            .hits(&r, T)
+            .unwrap_or_else(|| panic!("{} missed", f.name));
+
+        assert!(!hr.front_face, "{}", f.name);
+        valid(f.name, &r, &hr).unwrap();
+    }
+}
+
+#[test_case("sphere", p!(0, 0.999, 5), true; "sphere just inside the silhouette")]
+#[test_case("sphere", p!(0, 1.001, 5), false; "sphere just outside the silhouette")]
+#[test_case("disk", p!(0.7, 0.7, 5), true; "disk just inside the rim")]
+#[test_case("disk", p!(0.71, 0.71, 5), false; "disk just outside the rim")]
+#[test_case("quad", p!(0.999, 0.999, 5), true; "quad just inside a corner")]
+#[test_case("quad", p!(1.001, 0.0, 5), false; "quad just outside an edge")]
+#[test_case("triangle", p!(0, 0.999, 5), true; "triangle just inside the apex")]
+#[test_case("triangle", p!(0.51, 0.0, 5), false; "triangle just outside an edge")]
+#[test_case("curve", p!(0, 0.499, 5), true; "curve just inside the radius")]
+#[test_case("curve", p!(1.001, 0, 5), false; "curve just beyond the end")]
+#[test_case("voxels", p!(0.999, 0.999, 5), true; "voxels just inside a corner")]
+#[test_case("voxels", p!(1.001, 0.0, 5), false; "voxels just outside a face")]
+#[test]
+fn grazing_rays(name: &str, orig: P3, hit: bool) {
+    let r = Ray::new(orig, v!(0, 0, -1));
+
+    assert_eq!(get(name).hits(&r, T).is_some(), hit);
+}
+
+#[test_case("disk"; "disk")]
+#[test_case("quad"; "quad")]
+#[test_case("triangle"; "triangle")]
+#[test_case("triangle4"; "triangle4")]
+#[test]
+fn rays_in_the_plane_of_flat_primitives_miss(name: &str) {
+    let r = Ray::new(p!(-5, 0, 0), v!(1, 0, 0));
+
+    assert!(get(name).hits(&r, T).is_none());
+}
+
+#[test_case(Triangle::new(p!(0, 0, 0), p!(1, 1, 0), p!(2, 2, 0), &CLAY).into(); "collinear triangle")]
+#[test_case(Triangle::new(p!(0, 0, 0), p!(0, 0, 0), p!(0, 0, 0), &CLAY).into(); "point triangle")]
+#[test_case(Quad::new(p!(0, 0, 0), v!(1, 1, 0), v!(2, 2, 0), &CLAY).into(); "flat quad")]
+#[test_case(Curve::new(p!(0, 0, 0), p!(0, 0, 0), 0.5, (0.0, 1.0), &CLAY).into(); "zero length curve")]
+#[test]
+fn degenerate_primitives_are_never_hit(h: Hittable) {
+    for (orig, dir) in [
+        (p!(0, 0, 5), v!(0, 0, -1)),
+        (p!(1, 1, 5), v!(0, 0, -1)),
+        (p!(0.5, 0.5, 5), v!(0.1, 0.1, -1)),
+        (p!(-5, -5, 0), v!(1, 1, 0)),
+    ] {
+        assert!(
+            h.hits(&Ray::new(orig, dir), T).is_none(),
+            "{orig:?} {dir:?}"
+        );
+    }
+}
+
+// a zero discriminant is a tangent hit, so spheres without a radius are only hit by rays passing
+// exactly through their center
+#[test_case(0.0; "zero radius")]
+#[test_case(-1.0; "negative radius")]
+#[test]
+fn degenerate_spheres_are_only_hit_through_their_center(radius: f32) {
+    let h: Hittable = Sphere::new(p!(0, 0, 0), radius, &CLAY).into();
+
+    for (orig, dir, hit) in [
+        (p!(0, 0, 5), v!(0, 0, -1), true),
+        (p!(-5, -5, 0), v!(1, 1, 0), true),
+        (p!(1, 1, 5), v!(0, 0, -1), false),
+        (p!(0.5, 0.5, 5), v!(0.1, 0.1, -1), false),
+    ] {
+        assert_eq!(
+            h.hits(&Ray::new(orig, dir), T).is_some(),
+            hit,
+            "{orig:?} {dir:?}"
+        );
+    }
+}
+
+#[test]
+fn degenerate_rays_never_hit() {
+    for f in fixtures() {
+        for r in [
+            Ray::new(p!(0, 0, 5), v!(0, 0, 0)),
+            Ray::new(p!(f32::NAN, 0, 5), v!(0, 0, -1)),
+            Ray::new(p!(0, 0, 5), v!(0, f32::NAN, -1)),
+        ] {
+            assert!(f.hittable.hits(&r, T).is_none(), "{}: {r:?}", f.name);
+        }
+    }
+}
+
+fn ray() -> impl Strategy<Value = Ray> {
+    let c = || -3.0f32..3.0;
+    let d = || -1.0f32..1.0;
+
+    ((c(), c(), c()), (d(), d(), d()))
+        .prop_filter("zero length direction", |(_, (x, y, z))| {
+            x * x + y * y + z * z > 1e-4
+        })
+        .prop_map(|((x, y, z), (dx, dy, dz))| Ray::new(p!(x, y, z), v!(dx, dy, dz)))
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn hits_lie_on_the_ray_inside_the_bounding_box(r in ray()) {
+        for f in fixtures() {
+            if let Some(hr) = f.hittable.hits(&r, T) {
+                prop_assert!(T.contains(hr.t), "{}: t={}", f.name, hr.t);
+                if let Err(e) = valid(f.name, &r, &hr) {
+                    return Err(TestCaseError::fail(e));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_first_hit_along_the_ray_is_returned(r in ray()) {
+        for f in fixtures().into_iter().filter(|f| f.deterministic) {
+            if let Some(hr) = f.hittable.hits(&r, T) {
+                let closer = f.hittable.hits(&r, Interval::new(T.min, hr.t * 0.999 - 1e-4));
+                prop_assert!(closer.is_none(), "{}: {:?} before {:?}", f.name, closer, hr);
+            }
+        }
+    }
+
+    #[test]
+    fn hits_move_with_the_primitive(r in ray(), (x, y, z) in (-5.0f32..5.0, -5.0f32..5.0, -5.0f32..5.0)) {
+        let offset = v!(x, y, z);
+        let moved = Ray::new(r.orig + offset, r.dir);
+        for f in fixtures().into_iter().filter(|f| f.deterministic) {
+            let a = f.hittable.hits(&r, T).map(|hr| hr.t);
+            let b = f.hittable.translate(offset).hits(&moved, T).map(|hr| hr.t);
+            match (a, b) {
+                (Some(a), Some(b)) => prop_assert!((a - b).abs() < 1e-3 * a.max(1.0), "{}: {a} != {b}", f.name),
+                // rays that only just hit or miss may round either way
+                (Some(t), None) | (None, Some(t)) => prop_assert!(t < 0.01, "{}: {a:?} != {b:?}", f.name),
+                (None, None) => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! IES (IESNA LM-63) photometric profiles describing how the intensity of a real world light
+//! fixture varies with direction, used to shape the emission of lights.
+//!   https://docs.agi32.com/PhotometricToolbox/Content/Open_Tool/iesna_lm-63_format.htm
+//!
+//! Only type C photometry is supported: vertical angles are measured from the nadir (straight
+//! down from the fixture) and horizontal angles around it.
+use crate::V3;
+use std::{
+    fs,
+    io::{self, ErrorKind},
+};
+
+#[derive(Debug, Clone)]
+pub struct IesProfile {
+    /// Vertical angles in degrees, increasing
+    vertical: Vec<f32>,
+    /// Horizontal angles in degrees, increasing from 0
+    horizontal: Vec<f32>,
+    /// Intensity for each horizontal angle and then each vertical angle normalized so that the
+    /// peak intensity is 1
+    candela: Vec<Vec<f32>>,
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+impl IesProfile {
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(s: &str) -> io::Result<Self> {
+        // keyword lines are followed by the tilt line and then whitespace or comma separated data
+        let mut lines = s.lines();
+        let tilt = lines
+            .by_ref()
+            .map(str::trim)
+            .find(|l| l.starts_with("TILT="))
+            .ok_or_else(|| invalid("missing TILT line"))?;
+        let mut nums = lines
+            .flat_map(|l| l.split([' ', '\t', ',']))
+            .filter(|t| !t.is_empty())
+            .map(|t| {
+                t.parse::<f32>()
+                    .map_err(|_| invalid(format!("invalid number: {t}")))
+            });
+        let mut next = || {
+            nums.next()
+                .unwrap_or_else(|| Err(invalid("unexpected end of data")))
+        };
+
+        if tilt == "TILT=INCLUDE" {
+            // lamp to luminaire geometry then pairs of angles and multiplying factors
+            next()?;
+            let n = next()? as usize;
+            for _ in 0..2 * n {
+                next()?;
+            }
+        } else if tilt != "TILT=NONE" {
+            return Err(invalid("tilt data in a separate file is not supported"));
+        }
+
+        let _n_lamps = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let n_vertical = next()? as usize;
+        let n_horizontal = next()? as usize;
+        let photometric_type = next()?;
+        if photometric_type != 1.0 {
+            return Err(invalid("only type C photometry is supported"));
+        }
+        // units, width, length, height, ballast factor, future use and input watts
+        for _ in 0..7 {
+            next()?;
+        }
+
+        let vertical = (0..n_vertical)
+            .map(|_| next())
+            .collect::<io::Result<Vec<_>>>()?;
+        let horizontal = (0..n_horizontal)
+            .map(|_| next())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut candela = (0..n_horizontal)
+            .map(|_| {
+                (0..n_vertical)
+                    .map(|_| Ok(next()? * multiplier))
+                    .collect::<io::Result<Vec<_>>>()
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        if vertical.is_empty() || horizontal.is_empty() {
+            return Err(invalid("no angles given"));
+        }
+        let peak = candela.iter().flatten().fold(0.0f32, |a, &c| a.max(c));
+        if peak <= 0.0 {
+            return Err(invalid("no light is emitted"));
+        }
+        candela.iter_mut().flatten().for_each(|c| *c /= peak);
+
+        Ok(Self {
+            vertical,
+            horizontal,
+            candela,
+        })
+    }
+
+    /// Normalized intensity in the direction dir for a fixture pointing along nadir with its
+    /// zero horizontal angle towards reference (which should be perpendicular to nadir).
+    pub fn intensity(&self, dir: V3, nadir: V3, reference: V3) -> f32 {
+        let dir = dir.unit_vector();
+        let theta = dir.dot(&nadir).clamp(-1.0, 1.0).acos().to_degrees();
+        let side = nadir.cross(&reference);
+        let phi = dir.dot(&side).atan2(dir.dot(&reference)).to_degrees();
+
+        self.intensity_at(theta, phi.rem_euclid(360.0))
+    }
+
+    /// Normalized intensity at vertical angle theta and horizontal angle phi in degrees.
+    fn intensity_at(&self, theta: f32, phi: f32) -> f32 {
+        let (v0, v1) = (self.vertical[0], self.vertical[self.vertical.len() - 1]);
+        if theta < v0 || theta > v1 {
+            return 0.0;
+        }
+
+        // fold the horizontal angle using the symmetry implied by the last angle given
+        let phi = match self.horizontal[self.horizontal.len() - 1] {
+            h if h <= 0.0 => 0.0,
+            h if h <= 90.0 => {
+                let phi = if phi > 180.0 { 360.0 - phi } else { phi };
+                if phi > 90.0 {
+                    180.0 - phi
+                } else {
+                    phi
+                }
+            }
+            h if h <= 180.0 => {
+                if phi > 180.0 {
+                    360.0 - phi
+                } else {
+                    phi
+                }
+            }
+            _ => phi,
+        };
+
+        let (h, th) = bracket(&self.horizontal, phi);
+        let (v, tv) = bracket(&self.vertical, theta);
+        let at = |h: usize, v: usize| self.candela[h][v];
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let h1 = (h + 1).min(self.horizontal.len() - 1);
+        let v1 = (v + 1).min(self.vertical.len() - 1);
+
+        lerp(
+            lerp(at(h, v), at(h, v1), tv),
+            lerp(at(h1, v), at(h1, v1), tv),
+            th,
+        )
+    }
+}
+
+/// The index of the last angle in angles not greater than x and the fraction of the way towards
+/// the next angle.
+fn bracket(angles: &[f32], x: f32) -> (usize, f32) {
+    let i = angles.partition_point(|&a| a <= x).saturating_sub(1);
+    match angles.get(i + 1) {
+        Some(&next) if next > angles[i] => {
+            (i, ((x - angles[i]) / (next - angles[i])).clamp(0.0, 1.0))
+        }
+        _ => (i, 0.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{material::LightShape, v};
+    use simple_test_case::test_case;
+
+    // a rotationally symmetric downlight with a cosine falloff that is dark above the horizon
+    const DOWNLIGHT: &str = "IESNA:LM-63-2002
+[TEST] test
+[MANUFAC] raymart
+TILT=NONE
+1 1000 2.0 5 1 1 2 0.1 0.1 0.0
+1.0 1.0 20
+0 22.5 45 67.5 90
+0
+100 92.4 70.7 38.3 0
+";
+
+    #[test_case(v!(0, -1, 0), 1.0; "nadir")]
+    #[test_case(v!(1, -1, 0), 0.707; "45 degrees")]
+    #[test_case(v!(1, 0, 0), 0.0; "horizon")]
+    #[test_case(v!(0, 1, 0), 0.0; "above")]
+    #[test]
+    fn downlight_intensity(dir: V3, expected: f32) {
+        let profile = IesProfile::parse(DOWNLIGHT).unwrap();
+        let i = profile.intensity(dir, v!(0, -1, 0), v!(1, 0, 0));
+
+        assert!((i - expected).abs() < 1e-3, "{i} != {expected}");
+    }
+
+    #[test]
+    fn quadrant_symmetry_is_unfolded() {
+        let s = "TILT=NONE
+1 1000 1.0 2 2 1 2 0 0 0
+1 1 10
+0 90
+0 90
+1 1
+0.5 0.5
+";
+        let profile = IesProfile::parse(s).unwrap();
+
+        for (phi, expected) in [
+            (0.0, 1.0),
+            (90.0, 0.5),
+            (180.0, 1.0),
+            (270.0, 0.5),
+            (45.0, 0.75),
+        ] {
+            let i = profile.intensity_at(45.0, phi);
+            assert!((i - expected).abs() < 1e-4, "phi={phi}: {i}");
+        }
+    }
+
+    #[test]
+    fn mean_intensity_of_a_hemisphere() {
+        let profile = Box::leak(Box::new(IesProfile::parse(DOWNLIGHT).unwrap()));
+        let shape = LightShape::new(Some(profile), None, v!(0, -1, 0), v!(0, 0, -1));
+        let mean = shape.mean().luminance();
+
+        // the cosine lobe over the lower hemisphere has a mean of 1/4 over the sphere
+        assert!((mean - 0.25).abs() < 0.01, "{mean}");
+    }
+}
@@ -0,0 +1,49 @@
+//! Summary statistics about a scene: giving feedback on what has been loaded without needing to
+//! render it.
+use crate::{bvh::Bvh, hit::PrimitiveCounts, ray::Camera, scene::Scene, Color};
+
+pub fn print_scene_info(scene: &Scene, bvh: &Bvh, camera: &Camera) {
+    let b = bvh.bbox;
+    println!("Bounding box:");
+    println!("  x = [{}, {}]", b.x.min, b.x.max);
+    println!("  y = [{}, {}]", b.y.min, b.y.max);
+    println!("  z = [{}, {}]", b.z.min, b.z.max);
+
+    let mut counts = PrimitiveCounts::default();
+    for h in bvh.hittables() {
+        h.count_primitives(&mut counts);
+    }
+    println!("\nScene contents:");
+    println!("  meshes     = {}", scene.meshes.len());
+    println!("  objects    = {}", scene.objects.len());
+    println!("  particles  = {}", scene.particles.len());
+    println!("  voxels     = {}", scene.voxels.len());
+    println!("  materials  = {}", scene.materials.len());
+    println!("  primitives = {}", counts.total());
+    println!("    spheres   = {}", counts.spheres);
+    println!("    disks     = {}", counts.disks);
+    println!("    quads     = {}", counts.quads);
+    println!("    triangles = {}", counts.triangles);
+    println!("    curves    = {}", counts.curves);
+    println!("    media     = {}", counts.media);
+    println!("    voxels    = {}", counts.voxels);
+    println!("    custom    = {}", counts.custom);
+
+    let power = bvh
+        .hittables()
+        .iter()
+        .fold(Color::BLACK, |acc, h| acc + h.emissive_power());
+    println!("\nTotal emissive power:");
+    println!("  rgb = [{:.2}, {:.2}, {:.2}]", power.r, power.g, power.b);
+    println!("  luminance = {:.2}", power.luminance());
+
+    let (w, h) = camera.dimensions();
+    let hits = camera.map_pixels(|i, j| camera.primary_hit(i, j, bvh).1.is_some());
+    let coverage = hits.iter().filter(|&&hit| hit).count() as f32 / hits.len().max(1) as f32;
+    let c = camera.center();
+    println!("\nCamera:");
+    println!("  position = [{}, {}, {}]", c.x, c.y, c.z);
+    println!("  image    = {w}x{h}");
+    println!("  fov      = {}", scene.fov);
+    println!("  geometry coverage = {:.2}% of pixels", 100.0 * coverage);
+}
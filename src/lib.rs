@@ -0,0 +1,76 @@
+pub mod analysis;
+pub mod angle;
+pub mod aov;
+pub mod batch;
+pub mod burnin;
+pub mod bvh;
+pub mod cli;
+pub mod color;
+pub mod counters;
+pub mod dataset;
+pub mod deep;
+pub mod diff;
+pub mod environment;
+pub mod fur;
+pub mod furnace;
+pub mod guide;
+pub mod hit;
+pub mod ies;
+pub mod info;
+pub mod lights;
+pub mod lut;
+pub mod mat;
+pub mod material;
+pub mod noise;
+pub mod occlusion;
+pub mod output;
+pub mod particles;
+pub mod pathviz;
+pub mod post;
+pub mod ray;
+pub mod sampler;
+pub mod scene;
+pub mod simd;
+pub mod sky;
+pub mod stars;
+pub mod stats;
+pub mod sun;
+pub mod temporal;
+pub mod texture_cache;
+pub mod toon;
+pub mod v3;
+pub mod voxel;
+
+pub use color::Color;
+pub use hit::HitRecord;
+pub use ray::{Camera, Ray};
+pub use v3::{P3, V3};
+
+pub const BG_COLOR: Color = Color::new(0.7, 0.8, 1.0); // default scene background color
+pub const ASPECT_RATIO: f32 = 16.0 / 10.0; // image aspect ratio
+pub const IMAGE_WIDTH: u16 = 1000; // image width in pixels
+pub const SAMPLES_PER_PIXEL: u16 = 4500; // number of random samples per pixel
+pub const STEP_SIZE: u16 = 100; // number of samples per render step
+pub const DEBUG_SAMPLES_PER_PIXEL: u16 = 10; // number of random samples per pixel
+pub const MAX_BOUNCES: u8 = 50; // maximum number of ray bounces allowed
+pub const SCENE_PATH: &str = "scene.toml";
+
+#[macro_export]
+macro_rules! p {
+    ($x:expr, $y:expr, $z:expr) => {
+        P3::new($x as f32, $y as f32, $z as f32)
+    };
+}
+
+#[macro_export]
+macro_rules! v {
+    ($x:expr, $y:expr, $z:expr) => {
+        V3::new($x as f32, $y as f32, $z as f32)
+    };
+}
+
+/// Print the error and exit with a non-zero status.
+pub fn exit_with(e: impl std::fmt::Display) -> ! {
+    eprintln!("{e}");
+    std::process::exit(1);
+}
@@ -0,0 +1,140 @@
+//! Direct sampling of the lights of a scene. Diffuse bounces send an extra ray towards a
+//! randomly chosen light as well as continuing the path in a direction chosen by the material,
+//! and the light found by each is weighted by the power heuristic of multiple importance
+//! sampling: small or distant lights are found by sampling them directly, while large lights
+//! close to the surface are found just as well by the bounce and aren't double counted.
+//!
+//! Only spheres and quads (along with boxes and other lists of them) that are scene objects in
+//! their own right can be sampled. Any other emissive surface is still found by bounces alone.
+use crate::{hit::Hittable, sampler::random_range, P3, V3};
+
+/// The emissive scene objects that can be sampled directly, chosen between uniformly.
+#[derive(Debug, Default, Clone)]
+pub struct Lights {
+    lights: Vec<(u32, Hittable)>,
+}
+
+impl Lights {
+    /// The emissive hittables that can be sampled, keyed by their object ID.
+    pub fn new(hittables: &[Hittable]) -> Self {
+        let lights = hittables
+            .iter()
+            .filter(|h| h.can_sample() && h.emissive_power().luminance() > 0.0)
+            .filter_map(|h| Some((h.id()?, h.clone())))
+            .collect();
+
+        Self { lights }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    /// Whether the object with this ID is one of the lights
+    pub fn contains(&self, obj_id: u32) -> bool {
+        self.lights.iter().any(|(id, _)| *id == obj_id)
+    }
+
+    /// A direction from origin towards a randomly chosen light
+    pub fn sample(&self, origin: P3) -> Option<V3> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let (_, h) = &self.lights[random_range(0..self.lights.len())];
+
+        h.sample_toward(origin)
+    }
+
+    /// The density over solid angle of [Lights::sample] giving dir from origin, through any of
+    /// the lights further away than epsilon.
+    pub fn pdf(&self, origin: P3, dir: V3, epsilon: f32) -> f32 {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        let sum: f32 = self
+            .lights
+            .iter()
+            .map(|(_, h)| h.light_pdf(origin, dir, epsilon))
+            .sum();
+
+        sum / self.lights.len() as f32
+    }
+}
+
+/// The weight given to a sample from the strategy with density pdf when the same direction could
+/// have been sampled by another strategy with density other.
+pub fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other * other);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hit::{Quad, Sphere, RAY_EPSILON},
+        material::Material,
+        p,
+        sampler::{self, NoisePattern, SamplerKind},
+        v, Color,
+    };
+    use simple_test_case::test_case;
+
+    fn light() -> &'static Material {
+        Box::leak(Box::new(Material::diffuse_light(Color::WHITE)))
+    }
+
+    // solid angles seen from the origin: 2π(1 - cos) for the sphere and the sum of the corner
+    // terms asin(ab / sqrt((a² + h²)(b² + h²))) for quads 1 above the origin
+    #[test_case(Hittable::from(Sphere::new(p!(0, 0, -3), 1.0, light())), 0.359_368; "sphere")]
+    #[test_case(Hittable::from(Quad::new(p!(-1, 1, -1), v!(2, 0, 0), v!(0, 0, 2), light())), 2.094_395; "quad")]
+    #[test_case(Hittable::from(Quad::new(p!(-1, 1, -1), v!(2, 0, 0), v!(0, 0, 2), light())).translate(v!(1, 0, 0)), 1.369_438; "translated quad")]
+    #[test]
+    fn sampled_directions_match_the_pdf(h: Hittable, solid_angle: f32) {
+        // the mean of 1 / pdf over the sampled directions is the solid angle they cover
+        let n = 20_000;
+        let sampled = (0..n)
+            .filter_map(|i| {
+                // a stratified sequence keeps the estimate from wandering between runs
+                let sobol = SamplerKind::Sobol.sampler();
+                sampler::start_sample(NoisePattern::Locked, sobol, 0, 0, 0, i as u64);
+                h.sample_toward(P3::ORIGIN)
+            })
+            .map(|dir| 1.0 / h.light_pdf(P3::ORIGIN, dir, RAY_EPSILON))
+            .sum::<f32>()
+            / n as f32;
+        sampler::end_samples();
+
+        assert!(
+            (sampled - solid_angle).abs() < 0.01 * solid_angle,
+            "{sampled} != {solid_angle}"
+        );
+    }
+
+    #[test]
+    fn only_emissive_objects_that_can_be_sampled_are_lights() {
+        let grey = Box::leak(Box::new(Material::solid_color(Color::grey(0.5))));
+        let hittables = vec![
+            Hittable::from(Sphere::new(p!(0, 0, 0), 1.0, light())).with_id(1, 1),
+            Hittable::from(Sphere::new(p!(3, 0, 0), 1.0, grey)).with_id(2, 2),
+            Hittable::from(Sphere::new(p!(6, 0, 0), 1.0, light())),
+        ];
+
+        let lights = Lights::new(&hittables);
+
+        assert!(lights.contains(1));
+        assert!(!lights.contains(2));
+        assert_eq!(lights.lights.len(), 1);
+    }
+
+    #[test]
+    fn power_heuristic_weights_sum_to_one() {
+        for (a, b) in [(1.0, 1.0), (0.5, 3.0), (10.0, 0.0)] {
+            assert!((power_heuristic(a, b) + power_heuristic(b, a) - 1.0).abs() < 1e-6);
+        }
+    }
+}
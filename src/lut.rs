@@ -0,0 +1,257 @@
+//! Look up tables in the .cube format written by Resolve and most other grading tools, applied
+//! to the encoded pixels after tonemapping so that renders can match a film stock or footage
+//! that was graded elsewhere.
+//!
+//! A 1D table is a curve for each channel and a 3D table is a lattice of output colors over the
+//! input cube, with the red index changing fastest. Values between entries are interpolated
+//! linearly (trilinearly for 3D tables) and inputs outside the domain of the table are clamped
+//! to it.
+//!
+//! ```text
+//! TITLE "film"
+//! LUT_3D_SIZE 33
+//! DOMAIN_MIN 0.0 0.0 0.0
+//! DOMAIN_MAX 1.0 1.0 1.0
+//! 0.0 0.0 0.0
+//! ...
+//! ```
+use crate::Color;
+use serde::Deserialize;
+use std::fs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dims {
+    One,
+    Three,
+}
+
+/// A 1D or 3D look up table, deserialized from the path of a .cube file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Lut {
+    pub path: String,
+    dims: Dims,
+    size: usize,
+    min: Color,
+    max: Color,
+    table: Vec<Color>,
+}
+
+impl TryFrom<String> for Lut {
+    type Error = String;
+
+    fn try_from(path: String) -> Result<Self, Self::Error> {
+        Self::load(&path)
+    }
+}
+
+impl Lut {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let s = fs::read_to_string(path).map_err(|e| format!("unable to read {path}: {e}"))?;
+        let mut lut = Self::parse(&s).map_err(|e| format!("{path}: {e}"))?;
+        lut.path = path.to_string();
+
+        Ok(lut)
+    }
+
+    /// Parse the contents of a .cube file, ignoring any keywords other than the size and domain.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut dims = None;
+        let mut min = Color::BLACK;
+        let mut max = Color::WHITE;
+        let mut table = Vec::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let keyword = match tokens.first() {
+                Some(t) if !t.starts_with('#') => *t,
+                _ => continue,
+            };
+            let parse =
+                |ts: &[&str]| -> Result<Vec<f32>, _> { ts.iter().map(|t| t.parse()).collect() };
+            let values = parse(&tokens[1..]);
+            let invalid = || format!("line {}: invalid {keyword:?} line", i + 1);
+
+            // table entries are the only lines starting with a number
+            if keyword.parse::<f32>().is_ok() {
+                match parse(&tokens).as_deref() {
+                    Ok(&[r, g, b]) => table.push(Color::new(r, g, b)),
+                    _ => return Err(format!("line {}: expected 3 values", i + 1)),
+                }
+                continue;
+            }
+
+            match keyword {
+                "LUT_1D_SIZE" | "LUT_3D_SIZE" => {
+                    let size = match values.as_deref() {
+                        Ok(&[n]) if n >= 2.0 && n.fract() == 0.0 => n as usize,
+                        _ => return Err(invalid()),
+                    };
+                    let d = if keyword == "LUT_1D_SIZE" {
+                        Dims::One
+                    } else {
+                        Dims::Three
+                    };
+                    dims = Some((d, size));
+                }
+                "DOMAIN_MIN" | "DOMAIN_MAX" => {
+                    let c = match values.as_deref() {
+                        Ok(&[r, g, b]) => Color::new(r, g, b),
+                        _ => return Err(invalid()),
+                    };
+                    if keyword == "DOMAIN_MIN" {
+                        min = c;
+                    } else {
+                        max = c;
+                    }
+                }
+                // Resolve's older form of the domain, the same for every channel
+                "LUT_1D_INPUT_RANGE" | "LUT_3D_INPUT_RANGE" => match values.as_deref() {
+                    Ok(&[lo, hi]) => (min, max) = (Color::grey(lo), Color::grey(hi)),
+                    _ => return Err(invalid()),
+                },
+                _ => (),
+            }
+        }
+
+        let (dims, size) = dims.ok_or("missing LUT_1D_SIZE or LUT_3D_SIZE")?;
+        let expected = match dims {
+            Dims::One => size,
+            Dims::Three => size * size * size,
+        };
+        if table.len() != expected {
+            return Err(format!(
+                "expected {expected} table entries, found {}",
+                table.len()
+            ));
+        }
+        if min.r >= max.r || min.g >= max.g || min.b >= max.b {
+            return Err("DOMAIN_MIN must be below DOMAIN_MAX".to_string());
+        }
+
+        Ok(Self {
+            path: String::new(),
+            dims,
+            size,
+            min,
+            max,
+            table,
+        })
+    }
+
+    /// The color c is mapped to by the table.
+    pub fn apply(&self, c: Color) -> Color {
+        // the index of the entry below each channel and the fraction of the way to the next
+        let n = self.size - 1;
+        let index = |x: f32, min: f32, max: f32| {
+            let t = ((x - min) / (max - min)).clamp(0.0, 1.0) * n as f32;
+            let i = (t as usize).min(n - 1);
+            (i, t - i as f32)
+        };
+        let (r, g, b) = (
+            index(c.r, self.min.r, self.max.r),
+            index(c.g, self.min.g, self.max.g),
+            index(c.b, self.min.b, self.max.b),
+        );
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+        match self.dims {
+            Dims::One => {
+                let t = &self.table;
+                Color::new(
+                    lerp(t[r.0].r, t[r.0 + 1].r, r.1),
+                    lerp(t[g.0].g, t[g.0 + 1].g, g.1),
+                    lerp(t[b.0].b, t[b.0 + 1].b, b.1),
+                )
+            }
+            Dims::Three => {
+                let at =
+                    |i: usize, j: usize, k: usize| self.table[i + self.size * (j + self.size * k)];
+                let mix = |a: Color, b: Color, t: f32| a * (1.0 - t) + b * t;
+                let plane = |k: usize| {
+                    mix(
+                        mix(at(r.0, g.0, k), at(r.0 + 1, g.0, k), r.1),
+                        mix(at(r.0, g.0 + 1, k), at(r.0 + 1, g.0 + 1, k), r.1),
+                        g.1,
+                    )
+                };
+
+                mix(plane(b.0), plane(b.0 + 1), b.1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    fn assert_close(a: Color, b: Color) {
+        let d = (a.r - b.r)
+            .abs()
+            .max((a.g - b.g).abs())
+            .max((a.b - b.b).abs());
+        assert!(d < 1e-5, "{a:?} != {b:?}");
+    }
+
+    const IDENTITY_3D: &str = "
+# the smallest 3D table, mapping every color to itself
+TITLE \"identity\"
+LUT_3D_SIZE 2
+0 0 0
+1 0 0
+0 1 0
+1 1 0
+0 0 1
+1 0 1
+0 1 1
+1 1 1
+";
+
+    const INVERT_1D: &str = "
+LUT_1D_SIZE 3
+DOMAIN_MIN 0 0 0
+DOMAIN_MAX 2 2 2
+1.0 1.0 1.0
+0.5 0.5 0.5
+0.0 0.0 0.0
+";
+
+    #[test_case(IDENTITY_3D, Color::new(0.2, 0.5, 0.9), Color::new(0.2, 0.5, 0.9); "3d identity")]
+    #[test_case(IDENTITY_3D, Color::new(-1.0, 0.5, 3.0), Color::new(0.0, 0.5, 1.0); "3d clamped")]
+    #[test_case(INVERT_1D, Color::new(0.0, 0.5, 2.0), Color::new(1.0, 0.75, 0.0); "1d over domain")]
+    #[test]
+    fn colors_are_interpolated_between_entries(cube: &str, c: Color, expected: Color) {
+        let lut = Lut::parse(cube).unwrap();
+
+        assert_close(lut.apply(c), expected);
+    }
+
+    #[test]
+    fn red_changes_fastest_in_3d_tables() {
+        // swap red and blue by listing the identity with the channels reversed
+        let swapped: String = IDENTITY_3D
+            .lines()
+            .map(|l| match l.split_whitespace().collect::<Vec<_>>()[..] {
+                [r, g, b] if r.parse::<f32>().is_ok() => format!("{b} {g} {r}\n"),
+                _ => format!("{l}\n"),
+            })
+            .collect();
+        let lut = Lut::parse(&swapped).unwrap();
+
+        assert_close(
+            lut.apply(Color::new(1.0, 0.0, 0.25)),
+            Color::new(0.25, 0.0, 1.0),
+        );
+    }
+
+    #[test_case("0 0 0\n1 1 1\n", "missing LUT_1D_SIZE or LUT_3D_SIZE"; "no size")]
+    #[test_case("LUT_3D_SIZE 2\n0 0 0\n1 1 1\n", "expected 8 table entries, found 2"; "too few entries")]
+    #[test_case("LUT_1D_SIZE 2\n0 0\n1 1 1\n", "line 2: expected 3 values"; "short line")]
+    #[test_case("LUT_1D_SIZE two\n", "line 1: invalid \"LUT_1D_SIZE\" line"; "invalid size")]
+    #[test]
+    fn malformed_tables_are_errors(cube: &str, expected: &str) {
+        assert_eq!(Lut::parse(cube).unwrap_err(), expected);
+    }
+}
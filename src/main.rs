@@ -1,60 +1,383 @@
-pub mod bvh;
-pub mod color;
-pub mod hit;
-pub mod material;
-pub mod noise;
-pub mod ray;
-pub mod scene;
-pub mod v3;
+use raymart::{
+    angle, aov, batch,
+    bvh::Bvh,
+    cli::{Args, USAGE},
+    counters, dataset, deep, diff, environment, exit_with, furnace, info, material, pathviz,
+    scene::Scene,
+    stats::RenderStats,
+    temporal::History,
+    Camera, P3, V3,
+};
+use std::{collections::HashMap, env, path::Path, time::Instant};
 
-use std::env;
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(|s| s.as_str()) {
+        Some("batch") => run_batch(&args[1..]),
+        Some("dataset") => run_dataset(&args[1..]),
+        Some("diff") => run_diff(&args[1..]),
+        Some("envmap") => run_envmap(&args[1..]),
+        Some("paths") => run_paths(&args[1..]),
+        Some("info") => run_info(args.get(1).cloned()),
+        Some("render") => render(&args[1..]),
+        Some("validate") => run_validate(&args[1..]),
+        Some("bench") => run_bench(&args[1..]),
+        Some("furnace") => run_furnace(args.get(1)),
+        Some("-h" | "--help" | "help") => println!("{USAGE}"),
+        _ => render(&args),
+    }
+}
 
-use bvh::Bvh;
-use color::Color;
-use hit::HitRecord;
-use ray::Ray;
-use scene::Scene;
-use v3::{P3, V3};
+/// The scene named in args with any settings given on the command line applied, exiting with
+/// the usage if the arguments are invalid.
+fn load_args(args: &[String]) -> (String, Scene) {
+    let args = Args::parse(args).unwrap_or_else(|e| {
+        eprintln!("{e}\n\n{USAGE}");
+        std::process::exit(1);
+    });
 
-pub const BG_COLOR: Color = Color::new(0.7, 0.8, 1.0); // default scene background color
-pub const ASPECT_RATIO: f32 = 16.0 / 10.0; // image aspect ratio
-pub const IMAGE_WIDTH: u16 = 1000; // image width in pixels
-pub const SAMPLES_PER_PIXEL: u16 = 4500; // number of random samples per pixel
-pub const STEP_SIZE: u16 = 100; // number of samples per render step
-pub const DEBUG_SAMPLES_PER_PIXEL: u16 = 10; // number of random samples per pixel
-pub const MAX_BOUNCES: u8 = 50; // maximum number of ray bounces allowed
-pub const SCENE_PATH: &str = "scene.toml";
+    args.load().unwrap_or_else(|e| exit_with(e))
+}
 
-#[macro_export]
-macro_rules! p {
-    ($x:expr, $y:expr, $z:expr) => {
-        P3::new($x as f32, $y as f32, $z as f32)
+fn run_batch(args: &[String]) {
+    let Some(path) = args.first() else {
+        eprintln!("usage: raymart batch <manifest>");
+        std::process::exit(1);
     };
+    let manifest = batch::Manifest::load(path).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+
+    let results = manifest.run(render_frames);
+    eprintln!();
+    batch::print_summary(&results);
 }
 
-#[macro_export]
-macro_rules! v {
-    ($x:expr, $y:expr, $z:expr) => {
-        V3::new($x as f32, $y as f32, $z as f32)
+fn run_dataset(args: &[String]) {
+    let Some(path) = args.first() else {
+        eprintln!("usage: raymart dataset <dataset>");
+        std::process::exit(1);
     };
+    let results = dataset::Dataset::load(path)
+        .and_then(|d| d.run(render_frames))
+        .unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(1);
+        });
+    eprintln!();
+    batch::print_summary(&results);
 }
 
-fn main() {
-    let path = env::args().nth(1).unwrap_or_else(|| SCENE_PATH.to_string());
+fn run_diff(args: &[String]) {
+    let (a, b) = match args {
+        [a, b, ..] => (a, b),
+        _ => {
+            eprintln!("usage: raymart diff <image_a> <image_b> [out.png]");
+            std::process::exit(1);
+        }
+    };
+    let out = args.get(2).map(|s| s.as_str()).unwrap_or("diff.png");
+
+    let stats = diff::diff_images(a, b, out).unwrap_or_else(|e| exit_with(e));
+    println!("rmse         = {:.6}", stats.rmse);
+    println!("psnr         = {:.2}dB", stats.psnr);
+    println!("mean delta e = {:.4}", stats.mean_delta_e);
+    println!("max delta e  = {:.4}", stats.max_delta_e);
+    println!("mean flip    = {:.4}", stats.mean_flip);
+    eprintln!("difference heatmap written to {out}");
+}
+
+/// Precompute the cache file of an environment map, optionally writing it out as the six faces
+/// of a cube map next to it.
+fn run_envmap(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("usage: raymart envmap <map> [cubemap_size]");
+        std::process::exit(1);
+    };
+    let (path, size) = match args {
+        [path] => (path, None),
+        [path, size] => match size.parse::<u32>() {
+            Ok(size) if size > 0 => (path, Some(size)),
+            _ => usage(),
+        },
+        _ => usage(),
+    };
+
+    let levels = environment::precompute(path).unwrap_or_else(|e| exit_with(e));
+    let cache = environment::cache_path(path);
+    eprintln!("{levels} levels and their sampling tables written to {cache}");
+
+    if let Some(size) = size {
+        let env = environment::Environment::load(path, 1.0, angle::Angle::ZERO, false, None)
+            .unwrap_or_else(|e| exit_with(e));
+        let stem = Path::new(path)
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        for (face, img) in env.cube_faces(size) {
+            let out = Path::new(path).with_file_name(format!("{stem}_{face}.exr"));
+            img.save(&out).unwrap_or_else(|e| exit_with(e));
+            eprintln!("cube map face written to {}", out.display());
+        }
+    }
+}
+
+fn run_paths(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("usage: raymart paths <scene> <x> <y> [n_paths] [out.obj|out.ply]");
+        std::process::exit(1);
+    };
+    let (path, x, y) = match args {
+        [path, x, y, ..] => match (x.parse(), y.parse()) {
+            (Ok(x), Ok(y)) => (path, x, y),
+            _ => usage(),
+        },
+        _ => usage(),
+    };
+    let n: usize = args.get(3).and_then(|n| n.parse().ok()).unwrap_or(16);
+    let out = args.get(4).map(|s| s.as_str()).unwrap_or("paths.obj");
+
+    let s = Scene::try_from_file(path).unwrap_or_else(|e| exit_with(e));
+    let (hittables, camera) = s.load_scene().unwrap_or_else(|e| exit_with(e));
+    let bvh_tree = s.build_bvh(hittables);
+
+    // escaping rays are drawn out to twice the size of the scene
+    let b = bvh_tree.bbox;
+    let escape_len = 2.0 * V3::new(b.x.size(), b.y.size(), b.z.size()).length();
+    let paths: Vec<Vec<P3>> = (0..n)
+        .map(|_| camera.trace_path(x, y, &bvh_tree, escape_len))
+        .collect();
+
+    pathviz::write_paths(out, &paths).unwrap_or_else(|e| exit_with(e));
+    eprintln!("{n} paths from pixel ({x}, {y}) written to {out}");
+}
+
+fn run_info(path: Option<String>) {
+    let args = Args {
+        scene: path,
+        ..Default::default()
+    };
+    let (path, s) = args.load().unwrap_or_else(|e| exit_with(e));
+    let (hittables, camera) = s.load_scene().unwrap_or_else(|e| exit_with(e));
+    let bvh_tree = s.build_bvh(hittables);
+
+    println!("scene = {path}\n");
+    info::print_scene_info(&s, &bvh_tree, &camera);
+}
+
+fn render(args: &[String]) {
+    let (path, scene) = load_args(args);
+    eprintln!("scene = {path}");
+
+    render_frames(&scene, &path);
+
+    eprintln!("\nDone");
+}
+
+/// Check that the scene parses and that everything it refers to exists without rendering it.
+fn run_validate(args: &[String]) {
+    let (path, scene) = load_args(args);
+    // everything the scene refers to by name is checked when loading the arguments
+    match scene.meshes.iter().find(|m| !Path::new(&m.path).is_file()) {
+        None => println!(
+            "{path} is valid: {} materials, {} meshes, {} objects",
+            scene.materials.len(),
+            scene.meshes.len(),
+            scene.objects.len()
+        ),
+        Some(m) => {
+            eprintln!("{path}: mesh not found: {}", m.path);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run white furnace tests of the built in materials or of those of the scene at path, exiting
+/// with an error if any of them gain energy.
+fn run_furnace(path: Option<&String>) {
+    let materials: Vec<(String, &'static material::Material)> = match path {
+        Some(path) => {
+            let args = Args {
+                scene: Some(path.clone()),
+                ..Default::default()
+            };
+            let (_, s) = args.load().unwrap_or_else(|e| exit_with(e));
+            let materials = s.build_materials().unwrap_or_else(|e| exit_with(e));
+            let mut materials: Vec<_> = materials.into_iter().collect();
+            materials.sort_by(|a, b| a.0.cmp(&b.0));
+            // lights add energy by design
+            materials.retain(|(_, m)| !m.is_emissive());
+            materials
+        }
+        None => furnace::builtin_materials()
+            .into_iter()
+            .map(|(name, m)| (name.to_string(), &*Box::leak(Box::new(m))))
+            .collect(),
+    };
+
+    let results: Vec<furnace::Furnace> = materials
+        .iter()
+        .map(|(name, m)| furnace::Furnace::measure(name, m, 16))
+        .collect();
+    furnace::report(&results);
+
+    if results.iter().any(|r| r.gains_energy()) {
+        std::process::exit(1);
+    }
+}
+
+/// Time loading, building the tree for and rendering a single frame of the scene. The image is
+/// written to the temp directory rather than over the output of the scene.
+fn run_bench(args: &[String]) {
+    let (path, mut s) = load_args(args);
+    let ext = s.output.format.extension();
+    let out = env::temp_dir().join(format!("raymart-bench.{ext}"));
+    s.output.path = Some(out.to_string_lossy().into_owned());
+    s.output.preview = false;
     eprintln!("scene = {path}");
 
-    let s = Scene::try_from_file(&path).unwrap_or_default();
-    let (hittables, camera) = s.load_scene();
+    let start = Instant::now();
+    let (hittables, camera) = s.load_scene().unwrap_or_else(|e| exit_with(e));
+    let loaded = start.elapsed();
+    let bvh_tree = s.build_bvh(hittables);
+    let built = start.elapsed() - loaded;
+    counters::reset();
+    camera.render(&bvh_tree, &s.output, None, None);
+    let rendered = start.elapsed() - loaded - built;
+    counters::report();
 
-    eprintln!("Computing bvh tree...");
-    let bvh_tree = Bvh::new(hittables);
+    let (w, h) = camera.dimensions();
+    let samples = w as f64 * h as f64 * camera.samples_per_pixel() as f64;
+    println!("\nload      = {:.3}s", loaded.as_secs_f64());
+    println!("bvh       = {:.3}s", built.as_secs_f64());
+    println!("render    = {:.3}s", rendered.as_secs_f64());
+    println!(
+        "samples/s = {:.3}M",
+        samples / rendered.as_secs_f64() / 1_000_000.0
+    );
+}
+
+/// What is kept from the previous animation frame of a look for rendering the next one.
+struct PrevFrame {
+    /// Refit rather than rebuilt where possible
+    bvh: Bvh,
+    /// For the camera motion in motion vectors
+    camera: Camera,
+    /// Blended into the next frame when temporal reuse is enabled
+    history: Option<History>,
+}
+
+/// Render each animation frame and selected look of the scene loaded from path.
+fn render_frames(scene: &Scene, path: &str) {
+    let mut prev_frames: HashMap<Option<String>, PrevFrame> = HashMap::new();
+    for (frame, scene) in scene.animation_frames() {
+        if let Some(frame) = frame {
+            eprintln!("\nframe = {frame}");
+        }
+        let looks = scene.selected_looks().unwrap_or_else(|e| exit_with(e));
+        for (look, mut s) in looks {
+            let prev = prev_frames.remove(&look);
+            s.output.metadata = s.metadata(path);
+            if let Some(frame) = frame {
+                s.output
+                    .metadata
+                    .push(("Frame".to_string(), frame.to_string()));
+            }
+            if let Some(look) = &look {
+                eprintln!("\nlook = {look}");
+                s.output.metadata.push(("Look".to_string(), look.clone()));
+            }
+            prev_frames.insert(look, render_scene(&s, prev));
+        }
+    }
+}
+
+/// Render the scene, refitting the tree from the previous animation frame if it has the same
+/// number of hittables, and return what is needed from this frame to render the next.
+fn render_scene(s: &Scene, prev: Option<PrevFrame>) -> PrevFrame {
+    let (hittables, camera) = s.load_scene().unwrap_or_else(|e| exit_with(e));
+    let (prev_bvh, prev_camera, history) = match prev {
+        Some(p) => (Some(p.bvh), Some(p.camera), p.history),
+        None => (None, None, None),
+    };
+
+    let bvh_tree = match prev_bvh {
+        Some(mut bvh) if bvh.hittables().len() == hittables.len() => {
+            eprintln!("Refitting bvh tree...");
+            bvh.update(hittables);
+            bvh
+        }
+        _ => {
+            eprintln!("Computing bvh tree...");
+            s.build_bvh(hittables)
+        }
+    };
     eprintln!(
         "BVH bounding box:\n  x={:?}\n  y={:?}\n  z={:?}",
         bvh_tree.bbox.x, bvh_tree.bbox.y, bvh_tree.bbox.z,
     );
 
     eprintln!("Rendering...");
-    camera.render_ppm(bvh_tree);
+    let (object_names, material_names) = (s.object_names(), s.material_names());
+    let stats = s
+        .output
+        .stats
+        .then(|| RenderStats::new(object_names.len(), material_names.len()));
+    counters::reset();
+    let history = camera.render(&bvh_tree, &s.output, stats.as_ref(), history.as_ref());
+    counters::report();
 
-    eprintln!("\nDone");
+    if let Some(stats) = stats {
+        stats.report(&object_names, &material_names);
+    }
+
+    if s.output.id_mattes {
+        eprintln!("\nWriting ID mattes...");
+        aov::write_id_mattes(
+            &camera,
+            &bvh_tree,
+            &s.output,
+            &object_names,
+            &material_names,
+        )
+        .unwrap_or_else(|e| exit_with(e));
+    }
+
+    if s.output.deep {
+        eprintln!("\nWriting deep samples...");
+        deep::write_deep(&camera, &bvh_tree, &s.output).unwrap_or_else(|e| exit_with(e));
+    }
+
+    if s.output.depth_map {
+        eprintln!("\nWriting depth map...");
+        aov::write_depth_map(&camera, &bvh_tree, &s.output).unwrap_or_else(|e| exit_with(e));
+    }
+
+    if s.output.segmentation {
+        eprintln!("\nWriting segmentation...");
+        let (object_tags, material_tags) = (s.object_tags(), s.material_tags());
+        aov::write_segmentation(&camera, &bvh_tree, &s.output, &object_tags, &material_tags)
+            .unwrap_or_else(|e| exit_with(e));
+    }
+
+    if s.output.position || s.output.object_position {
+        eprintln!("\nWriting positions...");
+        aov::write_positions(&camera, &bvh_tree, &s.output, &s.object_spaces())
+            .unwrap_or_else(|e| exit_with(e));
+    }
+
+    if s.output.motion_vectors {
+        eprintln!("\nWriting motion vectors...");
+        let (w, h) = camera.dimensions();
+        let motion = aov::motion_vectors(&camera, prev_camera.as_ref(), &bvh_tree);
+        aov::write_motion_vectors(&s.output, w, h, &motion).unwrap_or_else(|e| exit_with(e));
+    }
+
+    PrevFrame {
+        bvh: bvh_tree,
+        camera,
+        history,
+    }
 }
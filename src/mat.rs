@@ -0,0 +1,348 @@
+//! A 4x4 matrix for general affine transforms of points, vectors and normals
+//!   https://pbr-book.org/4ed/Geometry_and_Transformations/Transformations
+use crate::{
+    angle::Angle,
+    v,
+    v3::{Quat, P3, V3},
+};
+use std::ops::Mul;
+
+/// A row-major 4x4 matrix operating on column vectors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct M4 {
+    pub m: [[f32; 4]; 4],
+}
+
+impl Default for M4 {
+    fn default() -> Self {
+        M4::IDENTITY
+    }
+}
+
+impl M4 {
+    pub const IDENTITY: M4 = M4::new([
+        [1.0, 0.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0, 0.0],
+        [0.0, 0.0, 0.0, 1.0],
+    ]);
+
+    pub const fn new(m: [[f32; 4]; 4]) -> M4 {
+        Self { m }
+    }
+
+    pub const fn translate(t: V3) -> M4 {
+        M4::new([
+            [1.0, 0.0, 0.0, t.x],
+            [0.0, 1.0, 0.0, t.y],
+            [0.0, 0.0, 1.0, t.z],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub const fn scale(s: V3) -> M4 {
+        M4::new([
+            [s.x, 0.0, 0.0, 0.0],
+            [0.0, s.y, 0.0, 0.0],
+            [0.0, 0.0, s.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    /// A rotation of angle around axis.
+    pub fn rotate(axis: V3, angle: Angle) -> M4 {
+        M4::from_quat(Quat::from_axis_angle(axis, angle.radians()))
+    }
+
+    /// The rotation matrix corresponding to a unit quaternion.
+    pub fn from_quat(q: Quat) -> M4 {
+        let Quat { w, x, y, z } = q;
+
+        M4::new([
+            [
+                1.0 - 2.0 * (y * y + z * z),
+                2.0 * (x * y - w * z),
+                2.0 * (x * z + w * y),
+                0.0,
+            ],
+            [
+                2.0 * (x * y + w * z),
+                1.0 - 2.0 * (x * x + z * z),
+                2.0 * (y * z - w * x),
+                0.0,
+            ],
+            [
+                2.0 * (x * z - w * y),
+                2.0 * (y * z + w * x),
+                1.0 - 2.0 * (x * x + y * y),
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    /// The unit quaternion corresponding to the rotation in the upper 3x3 of this matrix, which
+    /// is assumed to be a pure rotation.
+    ///   https://www.euclideanspace.com/maths/geometry/rotations/conversions/matrixToQuaternion/
+    pub fn to_quat(&self) -> Quat {
+        let m = &self.m;
+        let trace = m[0][0] + m[1][1] + m[2][2];
+
+        let q = if trace > 0.0 {
+            let s = 0.5 / (trace + 1.0).sqrt();
+            Quat::new(
+                0.25 / s,
+                (m[2][1] - m[1][2]) * s,
+                (m[0][2] - m[2][0]) * s,
+                (m[1][0] - m[0][1]) * s,
+            )
+        } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = 2.0 * (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt();
+            Quat::new(
+                (m[2][1] - m[1][2]) / s,
+                0.25 * s,
+                (m[0][1] + m[1][0]) / s,
+                (m[0][2] + m[2][0]) / s,
+            )
+        } else if m[1][1] > m[2][2] {
+            let s = 2.0 * (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt();
+            Quat::new(
+                (m[0][2] - m[2][0]) / s,
+                (m[0][1] + m[1][0]) / s,
+                0.25 * s,
+                (m[1][2] + m[2][1]) / s,
+            )
+        } else {
+            let s = 2.0 * (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt();
+            Quat::new(
+                (m[1][0] - m[0][1]) / s,
+                (m[0][2] + m[2][0]) / s,
+                (m[1][2] + m[2][1]) / s,
+                0.25 * s,
+            )
+        };
+
+        q.normalize()
+    }
+
+    pub fn transpose(&self) -> M4 {
+        let mut t = [[0.0; 4]; 4];
+        for (i, row) in t.iter_mut().enumerate() {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x = self.m[j][i];
+            }
+        }
+
+        M4::new(t)
+    }
+
+    /// The inverse of this matrix (if it is invertible) computed via cofactor expansion.
+    pub fn inverse(&self) -> Option<M4> {
+        let m = &self.m;
+        let s0 = m[0][0] * m[1][1] - m[1][0] * m[0][1];
+        let s1 = m[0][0] * m[1][2] - m[1][0] * m[0][2];
+        let s2 = m[0][0] * m[1][3] - m[1][0] * m[0][3];
+        let s3 = m[0][1] * m[1][2] - m[1][1] * m[0][2];
+        let s4 = m[0][1] * m[1][3] - m[1][1] * m[0][3];
+        let s5 = m[0][2] * m[1][3] - m[1][2] * m[0][3];
+
+        let c5 = m[2][2] * m[3][3] - m[3][2] * m[2][3];
+        let c4 = m[2][1] * m[3][3] - m[3][1] * m[2][3];
+        let c3 = m[2][1] * m[3][2] - m[3][1] * m[2][2];
+        let c2 = m[2][0] * m[3][3] - m[3][0] * m[2][3];
+        let c1 = m[2][0] * m[3][2] - m[3][0] * m[2][2];
+        let c0 = m[2][0] * m[3][1] - m[3][0] * m[2][1];
+
+        let det = s0 * c5 - s1 * c4 + s2 * c3 + s3 * c2 - s4 * c1 + s5 * c0;
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        Some(M4::new([
+            [
+                (m[1][1] * c5 - m[1][2] * c4 + m[1][3] * c3) * inv_det,
+                (-m[0][1] * c5 + m[0][2] * c4 - m[0][3] * c3) * inv_det,
+                (m[3][1] * s5 - m[3][2] * s4 + m[3][3] * s3) * inv_det,
+                (-m[2][1] * s5 + m[2][2] * s4 - m[2][3] * s3) * inv_det,
+            ],
+            [
+                (-m[1][0] * c5 + m[1][2] * c2 - m[1][3] * c1) * inv_det,
+                (m[0][0] * c5 - m[0][2] * c2 + m[0][3] * c1) * inv_det,
+                (-m[3][0] * s5 + m[3][2] * s2 - m[3][3] * s1) * inv_det,
+                (m[2][0] * s5 - m[2][2] * s2 + m[2][3] * s1) * inv_det,
+            ],
+            [
+                (m[1][0] * c4 - m[1][1] * c2 + m[1][3] * c0) * inv_det,
+                (-m[0][0] * c4 + m[0][1] * c2 - m[0][3] * c0) * inv_det,
+                (m[3][0] * s4 - m[3][1] * s2 + m[3][3] * s0) * inv_det,
+                (-m[2][0] * s4 + m[2][1] * s2 - m[2][3] * s0) * inv_det,
+            ],
+            [
+                (-m[1][0] * c3 + m[1][1] * c1 - m[1][2] * c0) * inv_det,
+                (m[0][0] * c3 - m[0][1] * c1 + m[0][2] * c0) * inv_det,
+                (-m[3][0] * s3 + m[3][1] * s1 - m[3][2] * s0) * inv_det,
+                (m[2][0] * s3 - m[2][1] * s1 + m[2][2] * s0) * inv_det,
+            ],
+        ]))
+    }
+
+    /// Transform a point (w = 1), applying the perspective divide if required.
+    pub fn transform_point(&self, p: P3) -> P3 {
+        let m = &self.m;
+        let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+        let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+        let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+
+        if w == 1.0 {
+            P3::new(x, y, z)
+        } else {
+            P3::new(x / w, y / w, z / w)
+        }
+    }
+
+    /// Transform a direction vector (w = 0), ignoring any translation.
+    pub fn transform_vector(&self, v: V3) -> V3 {
+        let m = &self.m;
+
+        V3::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+
+    /// Transform a surface normal. Normals need to be transformed by the inverse transpose of
+    /// the matrix used for points so self here must be the *inverse* of that matrix. The result
+    /// is not normalised.
+    pub fn transform_normal(&self, n: V3) -> V3 {
+        let m = &self.m;
+
+        V3::new(
+            m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        )
+    }
+
+    /// Decompose an affine transform without shear into (translation, rotation, scale) such that
+    /// M = T * R * S.
+    pub fn decompose(&self) -> (V3, Quat, V3) {
+        let m = &self.m;
+        let translation = v!(m[0][3], m[1][3], m[2][3]);
+
+        let col = |j: usize| v!(m[0][j], m[1][j], m[2][j]);
+        let mut scale = v!(col(0).length(), col(1).length(), col(2).length());
+
+        // a negative determinant means that there is a reflection which we fold into x
+        let det = col(0).dot(&col(1).cross(&col(2)));
+        if det < 0.0 {
+            scale.x = -scale.x;
+        }
+
+        let mut r = M4::IDENTITY;
+        #[allow(clippy::needless_range_loop)]
+        for i in 0..3 {
+            for j in 0..3 {
+                r.m[i][j] = m[i][j] / scale[j];
+            }
+        }
+
+        (translation, r.to_quat(), scale)
+    }
+}
+
+impl Mul<M4> for M4 {
+    type Output = M4;
+
+    fn mul(self, rhs: M4) -> Self::Output {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x = (0..4).map(|k| self.m[i][k] * rhs.m[k][j]).sum();
+            }
+        }
+
+        M4::new(m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{p, v};
+    use simple_test_case::test_case;
+
+    fn assert_close(a: V3, b: V3) {
+        assert!((a - b).length() < 1e-4, "{a:?} != {b:?}");
+    }
+
+    fn assert_m4_close(a: M4, b: M4) {
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((a.m[i][j] - b.m[i][j]).abs() < 1e-4, "{a:?} != {b:?}");
+            }
+        }
+    }
+
+    fn trs() -> M4 {
+        M4::translate(v!(1, 2, 3))
+            * M4::rotate(v!(1, 1, 0), Angle::rad(0.8))
+            * M4::scale(v!(2, 0.5, 3))
+    }
+
+    #[test_case(M4::IDENTITY; "identity")]
+    #[test_case(M4::translate(v!(1, -2, 3)); "translate")]
+    #[test_case(M4::scale(v!(2, 3, 4)); "scale")]
+    #[test_case(M4::rotate(v!(0, 1, 0), Angle::rad(0.3)); "rotate")]
+    #[test_case(trs(); "composite")]
+    #[test]
+    fn inverse_works(m: M4) {
+        let inv = m.inverse().unwrap();
+
+        assert_m4_close(m * inv, M4::IDENTITY);
+        assert_m4_close(inv * m, M4::IDENTITY);
+    }
+
+    #[test]
+    fn singular_matrix_has_no_inverse() {
+        assert_eq!(M4::scale(v!(1, 0, 1)).inverse(), None);
+    }
+
+    #[test]
+    fn transform_point_and_vector() {
+        let m = M4::translate(v!(1, 2, 3)) * M4::rotate(v!(0, 1, 0), Angle::deg(90.0));
+
+        assert_close(m.transform_point(p!(1, 0, 0)) - P3::ORIGIN, v!(1, 2, 2));
+        assert_close(m.transform_vector(v!(1, 0, 0)), v!(0, 0, -1));
+    }
+
+    #[test]
+    fn transformed_normals_stay_perpendicular() {
+        let m = trs();
+        let inv = m.inverse().unwrap();
+        let (t, n) = (v!(1, -1, 0), v!(1, 1, 0)); // tangent and normal of a plane
+
+        let t2 = m.transform_vector(t);
+        let n2 = inv.transform_normal(n);
+
+        assert!(t2.dot(&n2).abs() < 1e-4);
+    }
+
+    #[test]
+    fn decompose_round_trips() {
+        let (t, r, s) = trs().decompose();
+        let m = M4::translate(t) * M4::from_quat(r) * M4::scale(s);
+
+        assert_close(t, v!(1, 2, 3));
+        assert_close(s, v!(2, 0.5, 3));
+        assert_m4_close(m, trs());
+    }
+
+    #[test]
+    fn transpose_is_involution() {
+        assert_m4_close(trs().transpose().transpose(), trs());
+        assert_eq!(trs().transpose().m[0][3], trs().m[3][0]);
+    }
+}
@@ -1,6 +1,88 @@
-use crate::{hit::Interval, noise::Perlin, Color, HitRecord, Ray, P3, V3};
-use image::{open, RgbImage};
-use rand::random_range;
+use crate::sampler::random_range;
+use crate::{
+    angle::Angle,
+    hit::Interval,
+    ies::IesProfile,
+    noise::Perlin,
+    occlusion::OcclusionGrid,
+    texture_cache::{self, TiledImage},
+    v3::{Onb, N3},
+    Color, HitRecord, Ray, P3, V3,
+};
+use std::{
+    f32::consts::{FRAC_1_PI, PI, TAU},
+    fmt,
+    sync::OnceLock,
+};
+use wide::f32x4;
+
+/// The kind of interaction that produced a scattered ray. Volume scattering counts as diffuse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bounce {
+    Diffuse,
+    Glossy,
+    Transmission,
+}
+
+/// A scattered ray sampled by a material. The attenuation is the reflectance of the surface
+/// weighted by the cosine term and divided by the pdf, so that paths only need to multiply it
+/// through.
+#[derive(Debug, Clone, Copy)]
+pub struct ScatterRecord {
+    pub ray: Ray,
+    pub attenuation: Color,
+    /// Density over solid angle of the direction of the ray within the lobe that was sampled,
+    /// or zero for specular bounces
+    pub pdf: f32,
+    /// Whether the direction was chosen by a perfect or fuzzy reflection or refraction whose
+    /// density isn't known, so that it can't be weighted against other ways of sampling it
+    pub specular: bool,
+    pub bounce: Bounce,
+}
+
+impl ScatterRecord {
+    /// A cosine weighted diffuse bounce of r_in about the normal at rec
+    pub fn diffuse(r_in: &Ray, rec: &HitRecord, attenuation: Color) -> Self {
+        let local = V3::random_cosine_direction();
+        let dir = Onb::new(rec.normal.as_v3()).to_world(local);
+
+        Self {
+            ray: r_in.spawn(rec.p, dir, rec.normal),
+            attenuation,
+            pdf: local.z * FRAC_1_PI,
+            specular: false,
+            bounce: Bounce::Diffuse,
+        }
+    }
+
+    /// A bounce in a uniformly random direction over the whole sphere, e.g. inside a volume
+    pub fn uniform(r_in: &Ray, rec: &HitRecord, attenuation: Color) -> Self {
+        Self {
+            ray: r_in.spawn(rec.p, V3::random_unit_vector(), rec.normal),
+            attenuation,
+            pdf: 1.0 / (4.0 * PI),
+            specular: false,
+            bounce: Bounce::Diffuse,
+        }
+    }
+
+    /// A bounce along dir without a known density
+    pub fn specular(
+        r_in: &Ray,
+        rec: &HitRecord,
+        dir: V3,
+        attenuation: Color,
+        bounce: Bounce,
+    ) -> Self {
+        Self {
+            ray: r_in.spawn(rec.p, dir, rec.normal),
+            attenuation,
+            pdf: 0.0,
+            specular: true,
+            bounce,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum Texture {
@@ -13,12 +95,26 @@ pub enum Texture {
         even: &'static Texture,
     },
     Image {
-        raw: &'static RgbImage,
+        raw: &'static TiledImage,
     },
     Noise {
         noise: &'static Perlin<256>,
         scale: f32,
     },
+    /// Values computed by user code, see [Texture::callback].
+    Callback {
+        f: &'static TextureFn,
+    },
+}
+
+/// A function from the (u, v) texture coordinates and world space position of a hit to the
+/// color of the texture there.
+pub struct TextureFn(Box<dyn Fn(f32, f32, P3) -> Color + Send + Sync>);
+
+impl fmt::Debug for TextureFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TextureFn(..)")
+    }
 }
 
 impl Texture {
@@ -34,10 +130,10 @@ impl Texture {
         }
     }
 
-    pub fn image(path: &str) -> Texture {
-        let raw = Box::leak(Box::new(open(path).unwrap().into_rgb8()));
-
-        Self::Image { raw }
+    pub fn image(path: &str) -> Result<Texture, String> {
+        Ok(Self::Image {
+            raw: texture_cache::load(path)?,
+        })
     }
 
     pub fn noise(scale: f32) -> Texture {
@@ -47,6 +143,16 @@ impl Texture {
         }
     }
 
+    /// A texture evaluated by calling f for every lookup, letting applications drive texture
+    /// values from their own data. The texture is shared by every render thread so anything
+    /// that changes over time (e.g. the frame of a simulation) needs to be read through
+    /// something like an atomic or a lock that the application updates between renders.
+    pub fn callback(f: impl Fn(f32, f32, P3) -> Color + Send + Sync + 'static) -> Texture {
+        Self::Callback {
+            f: Box::leak(Box::new(TextureFn(Box::new(f)))),
+        }
+    }
+
     pub fn value(&self, u: f32, v: f32, p: P3) -> Color {
         match self {
             Self::SolidColor { albedo } => *albedo,
@@ -57,6 +163,7 @@ impl Texture {
             } => checker_value(u, v, p, *inv_scale, odd, even),
             Self::Image { raw } => image_value(u, v, p, raw),
             Self::Noise { noise, scale } => noise_value(p, noise, *scale),
+            Self::Callback { f } => (f.0)(u, v, p),
         }
     }
 }
@@ -73,7 +180,7 @@ fn checker_value(u: f32, v: f32, p: P3, inv_scale: f32, odd: &Texture, even: &Te
     }
 }
 
-fn image_value(mut u: f32, mut v: f32, _p: P3, raw: &RgbImage) -> Color {
+fn image_value(mut u: f32, mut v: f32, _p: P3, raw: &TiledImage) -> Color {
     // Clamp input texture coordinates to [0,1] x [1,0]
     u = Interval::UNIT.clamp(u);
     v = 1.0 - Interval::UNIT.clamp(v); // Flip V to image coordinates
@@ -84,9 +191,9 @@ fn image_value(mut u: f32, mut v: f32, _p: P3, raw: &RgbImage) -> Color {
     let scale = 1.0 / 255.0;
 
     Color::new(
-        scale * px.0[0] as f32,
-        scale * px.0[1] as f32,
-        scale * px.0[2] as f32,
+        scale * px[0] as f32,
+        scale * px[1] as f32,
+        scale * px[2] as f32,
     )
 }
 
@@ -94,6 +201,222 @@ fn noise_value(p: P3, noise: &Perlin<256>, scale: f32) -> Color {
     Color::new(0.5, 0.5, 0.5) * (1.0 + (scale * p.z + 10.0 * noise.turb(p, 7)).sin())
 }
 
+/// Source of the mix factor in [0, 1] for blended materials: a texture or a shading input that
+/// depends on the geometry around the hit.
+#[derive(Debug, Clone, Copy)]
+pub enum Mask {
+    Texture(Texture),
+    /// Facing ratio between the surface and the viewer raised to a power: 1 where the surface
+    /// faces the viewer falling to 0 at grazing angles.
+    Facing {
+        power: f32,
+    },
+    /// World space height ramping from 0 at min to 1 at max
+    Height {
+        min: f32,
+        max: f32,
+    },
+    /// Ambient occlusion from geometry within distance, which is 0 until the grid is baked
+    Occlusion {
+        distance: f32,
+        grid: &'static OnceLock<OcclusionGrid>,
+    },
+}
+
+impl Mask {
+    pub fn occlusion(distance: f32) -> Mask {
+        Self::Occlusion {
+            distance,
+            grid: Box::leak(Box::new(OnceLock::new())),
+        }
+    }
+
+    /// The mix factor at a hit viewed along r_in.
+    pub fn value(&self, r_in: &Ray, rec: &HitRecord) -> f32 {
+        match self {
+            Self::Facing { power } => rec.normal.dot(&r_in.dir.unit_vector()).abs().powf(*power),
+            Self::Occlusion { grid, .. } => grid
+                .get()
+                .map_or(0.0, |g| g.value(rec.p, rec.normal.as_v3())),
+            _ => self.value_at(rec.u, rec.v, rec.p),
+        }
+    }
+
+    /// The mix factor at a point without a viewer or surface normal, where surfaces are taken to
+    /// face the viewer and be unoccluded.
+    pub fn value_at(&self, u: f32, v: f32, p: P3) -> f32 {
+        match self {
+            Self::Texture(t) => t.value(u, v, p).luminance().clamp(0.0, 1.0),
+            Self::Facing { .. } => 1.0,
+            Self::Height { min, max } => ((p.y - min) / (max - min)).clamp(0.0, 1.0),
+            Self::Occlusion { .. } => 0.0,
+        }
+    }
+}
+
+/// How far the reflections of a metal are blurred, in [0, 1]
+#[derive(Debug, Clone, Copy)]
+pub enum Fuzz {
+    Value(f32),
+    /// Separate fuzz for the red, green and blue channels, giving blurred reflections colored
+    /// fringes. Each bounce follows one randomly chosen channel.
+    Channels([f32; 3]),
+    /// Fuzz varying over the surface, such as a hammered or patinated texture
+    Mask(Mask),
+}
+
+impl From<f32> for Fuzz {
+    fn from(fuzz: f32) -> Self {
+        Self::Value(fuzz)
+    }
+}
+
+impl Fuzz {
+    /// The fuzz at a hit viewed along r_in, averaged over the channels when they differ.
+    pub fn value(&self, r_in: &Ray, rec: &HitRecord) -> f32 {
+        let fuzz = match self {
+            Self::Value(f) => *f,
+            Self::Channels([r, g, b]) => (r + g + b) / 3.0,
+            Self::Mask(m) => m.value(r_in, rec),
+        };
+
+        fuzz.clamp(0.0, 1.0)
+    }
+}
+
+/// Directional shaping of the emission of a light pointing along a direction: an IES
+/// photometric profile and/or an image projected through a square frustum like a gobo in front
+/// of a spot light.
+#[derive(Debug, Clone, Copy)]
+pub struct LightShape {
+    profile: Option<&'static IesProfile>,
+    /// Projected image and the tangent of half the projection angle
+    gobo: Option<(Texture, f32)>,
+    /// w along the light direction, v towards the top of the gobo image and u to its right
+    /// (the zero horizontal angle of the profile)
+    frame: Onb,
+    /// Mean of the shaping factor over all directions
+    mean: Color,
+}
+
+impl LightShape {
+    /// Shape a light pointing along direction with the top of any gobo image facing up. The
+    /// gobo is projected over a square of the given full angle.
+    pub fn new(
+        profile: Option<&'static IesProfile>,
+        gobo: Option<(Texture, Angle)>,
+        direction: V3,
+        up: V3,
+    ) -> Self {
+        let w = direction.unit_vector();
+        let right = w.cross(&up);
+        let frame = if right.near_zero() {
+            Onb::new(w)
+        } else {
+            let u = right.unit_vector();
+            Onb {
+                u,
+                v: u.cross(&w),
+                w,
+            }
+        };
+        let gobo = gobo.map(|(texture, angle)| (texture, (angle / 2.0).tan()));
+        let mut shape = Self {
+            profile,
+            gobo,
+            frame,
+            mean: Color::WHITE,
+        };
+
+        // average over a Fibonacci lattice of directions covering the sphere
+        const N: usize = 4096;
+        let golden = PI * (3.0 - 5f32.sqrt());
+        let sum = (0..N).fold(Color::BLACK, |acc, i| {
+            let y = 1.0 - 2.0 * (i as f32 + 0.5) / N as f32;
+            let r = (1.0 - y * y).sqrt();
+            let phi = golden * i as f32;
+            acc + shape.factor(V3::new(r * phi.cos(), y, r * phi.sin()))
+        });
+        shape.mean = sum / N as f32;
+
+        shape
+    }
+
+    /// Mean of the shaping factor over all directions.
+    pub fn mean(&self) -> Color {
+        self.mean
+    }
+
+    /// Scale applied to the emission in the direction dir away from the light.
+    pub fn factor(&self, dir: V3) -> Color {
+        let f = &self.frame;
+        let mut c = Color::WHITE;
+        if let Some(profile) = self.profile {
+            c *= profile.intensity(dir, f.w, f.u);
+        }
+        if let Some((texture, tan_half)) = &self.gobo {
+            let forward = dir.dot(&f.w);
+            if forward <= 0.0 {
+                return Color::BLACK;
+            }
+            let x = dir.dot(&f.u) / (forward * tan_half);
+            let y = dir.dot(&f.v) / (forward * tan_half);
+            if x.abs() > 1.0 || y.abs() > 1.0 {
+                return Color::BLACK;
+            }
+            c *= texture.value(0.5 + 0.5 * x, 0.5 + 0.5 * y, P3::ORIGIN);
+        }
+
+        c
+    }
+}
+
+/// The complex index of refraction (eta + ik) of a metal for the red, green and blue channels,
+/// from measured data at 650nm, 550nm and 450nm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Conductor {
+    pub eta: Color,
+    pub k: Color,
+}
+
+impl Conductor {
+    pub const GOLD: Conductor = Conductor {
+        eta: Color::new(0.143, 0.374, 1.442),
+        k: Color::new(3.983, 2.385, 1.603),
+    };
+    pub const COPPER: Conductor = Conductor {
+        eta: Color::new(0.200, 0.924, 1.102),
+        k: Color::new(3.912, 2.452, 2.142),
+    };
+    pub const ALUMINIUM: Conductor = Conductor {
+        eta: Color::new(1.657, 0.880, 0.521),
+        k: Color::new(9.224, 6.270, 4.837),
+    };
+
+    /// The Fresnel reflectance of unpolarized light arriving at cos_theta to the normal. The
+    /// three channels are evaluated together in the lanes of a SIMD register (with blue repeated
+    /// in the spare lane) as this is computed for every bounce off a conductor.
+    pub fn reflectance(&self, cos_theta: f32) -> Color {
+        let lanes = |c: Color| f32x4::new([c.r, c.g, c.b, c.b]);
+        let (eta, k) = (lanes(self.eta), lanes(self.k));
+        let cos2 = f32x4::splat(cos_theta.clamp(0.0, 1.0).powi(2));
+        let sin2 = 1.0 - cos2;
+
+        let t0 = eta * eta - k * k - sin2;
+        let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2b2 + t0)).max(f32x4::ZERO).sqrt();
+        let t1 = a2b2 + cos2;
+        let t2 = 2.0 * cos2.sqrt() * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        let [r, g, b, _] = (0.5 * (rs + rp)).to_array();
+
+        Color::new(r, g, b)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Material {
     Lambertian {
@@ -105,22 +428,134 @@ pub enum Material {
         smoothness: f32,
         prob: f32,
     },
+    /// Reflections tinted by albedo and, for measured metals, by the Fresnel reflectance of the
+    /// conductor
     Metal {
         albedo: Color,
-        fuzz: f32,
+        fuzz: Fuzz,
+        conductor: Option<Conductor>,
     },
+    /// A diffuse base under a clear coat that reflects more towards grazing angles
+    Plastic {
+        albedo: Color,
+        ref_index: f32,
+        roughness: f32,
+    },
+    /// Glass and other clear materials, frosted by the GGX roughness of their microfacets
     Dielectric {
         ref_index: f32,
         albedo: Color,
+        roughness: f32,
     },
     DiffuseLight {
         texture: Texture,
     },
+    /// Light whose radiance seen from each direction is the texture scaled by its shape
+    ShapedLight {
+        texture: Texture,
+        shape: LightShape,
+    },
     Isotropic {
         texture: Texture,
     },
+    Hair {
+        albedo: Color,
+        roughness: f32,
+        spec_prob: f32,
+    },
+    /// Layered planet surface: a day albedo with specular oceans where the mask is bright and
+    /// emissive night lights on the side facing away from the sun.
+    Planet {
+        day: Texture,
+        night: Option<Texture>,
+        night_strength: f32,
+        ocean: Option<Texture>,
+        spec_prob: f32,
+        sun: V3,
+    },
+    /// Partially transparent cloud layer: the brightness of the texture gives the probability of
+    /// a ray scattering off the clouds rather than passing through them.
+    Clouds {
+        texture: Texture,
+    },
+    /// Stylized reflections for non-photorealistic renders: the first tints.len() glossy bounces
+    /// along a path reflect with the tint for that bounce while later ones are diffuse, giving
+    /// flat toon-like reflections.
+    Stylized {
+        albedo: Color,
+        roughness: f32,
+        tints: &'static [Color],
+        /// Probability of a reflection, or its value facing the surface if fresnel is set
+        reflectance: f32,
+        fresnel: bool,
+    },
+    /// Mix of two materials where the brightness of the mask gives the probability of a hit
+    /// using b rather than a, e.g. patches of rust over clean metal.
+    Blend {
+        a: &'static Material,
+        b: &'static Material,
+        mask: Mask,
+    },
+    /// Different materials on either side of a surface, e.g. the printed and plain sides of a
+    /// sheet of paper. The front is the side that the outward normal of the surface points to.
+    TwoSided {
+        front: &'static Material,
+        back: &'static Material,
+    },
+    /// Material implemented outside of the renderer, see [Bsdf].
+    Custom(&'static dyn Bsdf),
 }
 
+/// The scattering and emission of a user defined material. Only [Bsdf::scatter] and
+/// [Bsdf::albedo] are required: the defaults describe a surface that emits no light.
+///
+/// Materials are shared between every hit on every thread so implementations are immutable,
+/// drawing any randomness they need from [crate::sampler::random_range].
+pub trait Bsdf: fmt::Debug + Send + Sync {
+    /// Sample a scattered ray, or None if the ray is absorbed.
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord>;
+
+    /// The base color of the surface at a hit, see [Material::albedo].
+    fn albedo(&self, r_in: &Ray, rec: &HitRecord) -> Color;
+
+    /// Roughness in [0, 1] of the glossy and transmission lobes at rec.
+    fn roughness(&self, _r_in: &Ray, _rec: &HitRecord) -> f32 {
+        0.0
+    }
+
+    /// See [Material::cosine_diffuse].
+    fn cosine_diffuse(&self) -> bool {
+        false
+    }
+
+    /// Whether the material emits light, making objects using it light sources.
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    /// Light emitted at a point averaged over all directions, see [Material::color_emitted].
+    fn color_emitted(&self, _u: f32, _v: f32, _p: P3) -> Color {
+        Color::BLACK
+    }
+
+    /// Light emitted at a hit, see [Material::emitted].
+    fn emitted(&self, _r_in: &Ray, rec: &HitRecord) -> Color {
+        self.color_emitted(rec.u, rec.v, rec.p)
+    }
+}
+
+/// Smoothness of the specular reflection from planet oceans
+const OCEAN_SMOOTHNESS: f32 = 0.95;
+/// Sharpness of the fade in of night lights across the terminator
+const TERMINATOR_SHARPNESS: f32 = 5.0;
+
+/// Neutral grey diffuse material used in place of all non-emissive materials for clay renders.
+pub static CLAY: Material = Material::Lambertian {
+    texture: Texture::SolidColor {
+        albedo: Color::grey(0.7),
+    },
+};
+
 impl Material {
     pub fn solid_color(albedo: Color) -> Material {
         Self::Lambertian {
@@ -134,10 +569,10 @@ impl Material {
         }
     }
 
-    pub fn image(path: &str) -> Material {
-        Self::Lambertian {
-            texture: Texture::image(path),
-        }
+    pub fn image(path: &str) -> Result<Material, String> {
+        Ok(Self::Lambertian {
+            texture: Texture::image(path)?,
+        })
     }
 
     pub fn noise(scale: f32) -> Material {
@@ -146,14 +581,41 @@ impl Material {
         }
     }
 
-    pub fn metal(albedo: Color, fuzz: f32) -> Material {
-        let fuzz = if fuzz < 1.0 { fuzz } else { 1.0 };
+    pub fn metal(albedo: Color, fuzz: impl Into<Fuzz>) -> Material {
+        Self::Metal {
+            albedo,
+            fuzz: fuzz.into(),
+            conductor: None,
+        }
+    }
 
-        Self::Metal { albedo, fuzz }
+    /// A measured metal, with reflections tinted by tint on top of the color of the conductor
+    pub fn conductor(conductor: Conductor, tint: Color, fuzz: impl Into<Fuzz>) -> Material {
+        Self::Metal {
+            albedo: tint,
+            fuzz: fuzz.into(),
+            conductor: Some(conductor),
+        }
     }
 
     pub fn dielectric(ref_index: f32, albedo: Color) -> Material {
-        Self::Dielectric { ref_index, albedo }
+        Self::rough_dielectric(ref_index, albedo, 0.0)
+    }
+
+    pub fn plastic(albedo: Color, ref_index: f32, roughness: f32) -> Material {
+        Self::Plastic {
+            albedo,
+            ref_index,
+            roughness: roughness.clamp(0.0, 1.0),
+        }
+    }
+
+    pub fn rough_dielectric(ref_index: f32, albedo: Color, roughness: f32) -> Material {
+        Self::Dielectric {
+            ref_index,
+            albedo,
+            roughness: roughness.clamp(0.0, 1.0),
+        }
     }
 
     pub fn diffuse_light(albedo: Color) -> Material {
@@ -166,6 +628,13 @@ impl Material {
         Self::DiffuseLight { texture }
     }
 
+    pub fn shaped_light(albedo: Color, shape: LightShape) -> Material {
+        Self::ShapedLight {
+            texture: Texture::solid(albedo),
+            shape,
+        }
+    }
+
     pub fn isotropic(albedo: Color) -> Material {
         Self::Isotropic {
             texture: Texture::solid(albedo),
@@ -176,49 +645,310 @@ impl Material {
         Self::Isotropic { texture }
     }
 
-    pub fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<(Ray, Color)> {
+    pub fn hair(albedo: Color, roughness: f32, spec_prob: f32) -> Material {
+        Self::Hair {
+            albedo,
+            roughness,
+            spec_prob,
+        }
+    }
+
+    pub fn stylized(
+        albedo: Color,
+        roughness: f32,
+        tints: Vec<Color>,
+        reflectance: f32,
+        fresnel: bool,
+    ) -> Material {
+        Self::Stylized {
+            albedo,
+            roughness: roughness.clamp(0.0, 1.0),
+            tints: Box::leak(tints.into_boxed_slice()),
+            reflectance: reflectance.clamp(0.0, 1.0),
+            fresnel,
+        }
+    }
+
+    pub fn planet(
+        day: Texture,
+        night: Option<(Texture, f32)>,
+        ocean: Option<(Texture, f32)>,
+        sun: V3,
+    ) -> Material {
+        let (night, night_strength) = night.unzip();
+        let (ocean, spec_prob) = ocean.unzip();
+
+        Self::Planet {
+            day,
+            night,
+            night_strength: night_strength.unwrap_or(0.0),
+            ocean,
+            spec_prob: spec_prob.unwrap_or(0.0),
+            sun: sun.unit_vector(),
+        }
+    }
+
+    pub fn clouds(texture: Texture) -> Material {
+        Self::Clouds { texture }
+    }
+
+    pub fn blend(a: &'static Material, b: &'static Material, mask: Mask) -> Material {
+        Self::Blend { a, b, mask }
+    }
+
+    pub fn two_sided(front: &'static Material, back: &'static Material) -> Material {
+        Self::TwoSided { front, back }
+    }
+
+    /// Use a user defined [Bsdf] as a material. Like the materials of a scene it lives for the
+    /// rest of the program.
+    pub fn custom(bsdf: impl Bsdf + 'static) -> Material {
+        Self::Custom(Box::leak(Box::new(bsdf)))
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        match self {
+            Self::DiffuseLight { .. } | Self::ShapedLight { .. } => true,
+            Self::Blend { a, b, .. } => a.is_emissive() || b.is_emissive(),
+            Self::TwoSided { front, back } => front.is_emissive() || back.is_emissive(),
+            Self::Custom(bsdf) => bsdf.is_emissive(),
+            _ => false,
+        }
+    }
+
+    /// Roughness in [0, 1] of the glossy and transmission lobes of this material at rec, or zero
+    /// for materials without one. Blends are weighted by their mask.
+    pub fn roughness(&self, r_in: &Ray, rec: &HitRecord) -> f32 {
         match self {
-            Self::Lambertian { texture } => lambertian_scatter(texture, rec),
+            Self::Specular { smoothness, .. } => 1.0 - smoothness,
+            Self::Metal { fuzz, .. } => fuzz.value(r_in, rec),
+            Self::Hair { roughness, .. }
+            | Self::Stylized { roughness, .. }
+            | Self::Dielectric { roughness, .. }
+            | Self::Plastic { roughness, .. } => *roughness,
+            Self::Planet { .. } => 1.0 - OCEAN_SMOOTHNESS,
+            Self::Blend { a, b, mask } => {
+                let m = mask.value(r_in, rec);
+                a.roughness(r_in, rec) * (1.0 - m) + b.roughness(r_in, rec) * m
+            }
+            Self::TwoSided { front, back } => facing(front, back, rec).roughness(r_in, rec),
+            Self::Custom(bsdf) => bsdf.roughness(r_in, rec),
+            Self::Lambertian { .. }
+            | Self::DiffuseLight { .. }
+            | Self::ShapedLight { .. }
+            | Self::Isotropic { .. }
+            | Self::Clouds { .. } => 0.0,
+        }
+    }
+
+    /// Whether diffuse bounces off this material are cosine distributed about the normal with an
+    /// attenuation of the surface albedo, so that they can be sampled in other directions (e.g.
+    /// by path guiding) and reweighted.
+    pub fn cosine_diffuse(&self) -> bool {
+        match self {
+            Self::Lambertian { .. }
+            | Self::Specular { .. }
+            | Self::Plastic { .. }
+            | Self::Planet { .. }
+            | Self::Clouds { .. }
+            | Self::Stylized { .. } => true,
+            Self::Blend { a, b, .. } => a.cosine_diffuse() && b.cosine_diffuse(),
+            Self::TwoSided { front, back } => front.cosine_diffuse() && back.cosine_diffuse(),
+            Self::Custom(bsdf) => bsdf.cosine_diffuse(),
+            // volumes and fibres scatter diffusely over the whole sphere
+            Self::Isotropic { .. } | Self::Hair { .. } => false,
+            Self::Metal { .. }
+            | Self::Dielectric { .. }
+            | Self::DiffuseLight { .. }
+            | Self::ShapedLight { .. } => false,
+        }
+    }
+
+    /// Sample a scattered ray and its attenuation along with the kind of bounce that produced it,
+    /// or None if the ray is absorbed.
+    pub fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        match self {
+            Self::Lambertian { texture } => lambertian_scatter(texture, r_in, rec),
             Self::Specular {
                 albedo,
                 spec_albedo,
                 smoothness,
                 prob,
             } => specular_scatter(albedo, spec_albedo, *smoothness, *prob, r_in, rec),
-            Self::Metal { albedo, fuzz } => metal_scatter(albedo, *fuzz, r_in, rec),
-            Self::Dielectric { ref_index, albedo } => {
-                dielectric_scatter(*ref_index, albedo, r_in, rec)
+            Self::Metal {
+                albedo,
+                fuzz,
+                conductor,
+            } => metal_scatter(albedo, fuzz, conductor.as_ref(), r_in, rec),
+            Self::Plastic {
+                albedo,
+                ref_index,
+                roughness,
+            } => plastic_scatter(albedo, *ref_index, *roughness, r_in, rec),
+            Self::Dielectric {
+                ref_index,
+                albedo,
+                roughness,
+            } if *roughness > 0.0 => {
+                rough_dielectric_scatter(*ref_index, albedo, *roughness, r_in, rec)
+            }
+            Self::Dielectric {
+                ref_index, albedo, ..
+            } => dielectric_scatter(*ref_index, albedo, r_in, rec),
+            Self::Isotropic { texture } => isotropic_scatter(texture, r_in, rec),
+            Self::Hair {
+                albedo,
+                roughness,
+                spec_prob,
+            } => hair_scatter(albedo, *roughness, *spec_prob, r_in, rec),
+            Self::Planet {
+                day,
+                ocean,
+                spec_prob,
+                ..
+            } => planet_scatter(day, ocean.as_ref(), *spec_prob, r_in, rec),
+            Self::Clouds { texture } => clouds_scatter(texture, r_in, rec),
+            Self::Stylized {
+                albedo,
+                roughness,
+                tints,
+                reflectance,
+                fresnel,
+            } => stylized_scatter(albedo, *roughness, tints, *reflectance, *fresnel, r_in, rec),
+            Self::Blend { a, b, mask } => {
+                if random_range(0.0..1.0) < mask.value(r_in, rec) {
+                    b.scatter(r_in, rec)
+                } else {
+                    a.scatter(r_in, rec)
+                }
+            }
+            Self::TwoSided { front, back } => facing(front, back, rec).scatter(r_in, rec),
+            Self::Custom(bsdf) => bsdf.scatter(r_in, rec),
+            Self::DiffuseLight { .. } | Self::ShapedLight { .. } => None,
+        }
+    }
+
+    /// The base color of the surface at a hit, ignoring how it scatters light, for shading that
+    /// doesn't trace the light transport (e.g. toon shading).
+    pub fn albedo(&self, r_in: &Ray, rec: &HitRecord) -> Color {
+        let (u, v, p) = (rec.u, rec.v, rec.p);
+        match self {
+            Self::Lambertian { texture }
+            | Self::Isotropic { texture }
+            | Self::Clouds { texture }
+            | Self::DiffuseLight { texture }
+            | Self::ShapedLight { texture, .. }
+            | Self::Planet { day: texture, .. } => texture.value(u, v, p),
+            Self::Metal {
+                albedo,
+                conductor: Some(c),
+                ..
+            } => *albedo * c.reflectance(1.0),
+            Self::Specular { albedo, .. }
+            | Self::Metal { albedo, .. }
+            | Self::Plastic { albedo, .. }
+            | Self::Dielectric { albedo, .. }
+            | Self::Hair { albedo, .. }
+            | Self::Stylized { albedo, .. } => *albedo,
+            Self::Blend { a, b, mask } => {
+                let t = mask.value(r_in, rec);
+                a.albedo(r_in, rec) * (1.0 - t) + b.albedo(r_in, rec) * t
             }
-            Self::Isotropic { texture } => isotropic_scatter(texture, rec),
-            Self::DiffuseLight { .. } => None,
+            Self::TwoSided { front, back } => facing(front, back, rec).albedo(r_in, rec),
+            Self::Custom(bsdf) => bsdf.albedo(r_in, rec),
         }
     }
 
     pub fn color_emitted(&self, u: f32, v: f32, p: P3) -> Color {
         match self {
             Self::DiffuseLight { texture } => texture.value(u, v, p),
+            // averaged over all directions
+            Self::ShapedLight { texture, shape } => texture.value(u, v, p) * shape.mean(),
+            Self::Blend { a, b, mask } => {
+                let t = mask.value_at(u, v, p);
+                a.color_emitted(u, v, p) * (1.0 - t) + b.color_emitted(u, v, p) * t
+            }
+            // without a hit there is no side to pick, see Material::emitted
+            Self::TwoSided { front, .. } => front.color_emitted(u, v, p),
+            Self::Custom(bsdf) => bsdf.color_emitted(u, v, p),
             _ => Color::BLACK,
         }
     }
+
+    /// Light emitted at the given hit which, unlike [Material::color_emitted], may depend on
+    /// the orientation of the surface and the direction it is viewed from.
+    pub fn emitted(&self, r_in: &Ray, rec: &HitRecord) -> Color {
+        match self {
+            Self::Planet {
+                night: Some(night),
+                night_strength,
+                sun,
+                ..
+            } => {
+                let darkness = (-rec.normal.dot(sun) * TERMINATOR_SHARPNESS).clamp(0.0, 1.0);
+                night.value(rec.u, rec.v, rec.p) * (night_strength * darkness)
+            }
+            Self::ShapedLight { texture, shape } => {
+                texture.value(rec.u, rec.v, rec.p) * shape.factor(-r_in.dir)
+            }
+            Self::Blend { a, b, mask } => {
+                let t = mask.value(r_in, rec);
+                a.emitted(r_in, rec) * (1.0 - t) + b.emitted(r_in, rec) * t
+            }
+            Self::TwoSided { front, back } => facing(front, back, rec).emitted(r_in, rec),
+            Self::Custom(bsdf) => bsdf.emitted(r_in, rec),
+            _ => self.color_emitted(rec.u, rec.v, rec.p),
+        }
+    }
 }
 
-fn lambertian_scatter(texture: &Texture, rec: &HitRecord) -> Option<(Ray, Color)> {
-    let mut scatter_direction = rec.normal + V3::random_unit_vector();
-    if scatter_direction.near_zero() {
-        scatter_direction = rec.normal;
+/// The side of a two sided material seen at rec.
+fn facing(front: &'static Material, back: &'static Material, rec: &HitRecord) -> &'static Material {
+    if rec.front_face {
+        front
+    } else {
+        back
     }
-    let scattered = Ray::new(rec.p, scatter_direction);
+}
+
+fn lambertian_scatter(texture: &Texture, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
     let attenuation = texture.value(rec.u, rec.v, rec.p);
 
-    Some((scattered, attenuation))
+    Some(ScatterRecord::diffuse(r_in, rec, attenuation))
 }
 
-fn metal_scatter(albedo: &Color, fuzz: f32, r_in: &Ray, rec: &HitRecord) -> Option<(Ray, Color)> {
+fn metal_scatter(
+    albedo: &Color,
+    fuzz: &Fuzz,
+    conductor: Option<&Conductor>,
+    r_in: &Ray,
+    rec: &HitRecord,
+) -> Option<ScatterRecord> {
+    // following a single channel carries all of its light, so it is weighted by the number of
+    // channels that could have been chosen
+    let (fuzz, channel) = match fuzz {
+        Fuzz::Channels(fs) => {
+            let i = random_range(0..3);
+            let mut channel = [0.0; 3];
+            channel[i] = 3.0;
+            (fs[i].clamp(0.0, 1.0), Color::from(channel))
+        }
+        fuzz => (fuzz.value(r_in, rec), Color::WHITE),
+    };
     let reflected = r_in.dir.reflect(rec.normal).unit_vector() + (fuzz * V3::random_unit_vector());
-    let scattered = Ray::new(rec.p, reflected);
+    let attenuation = match conductor {
+        Some(c) => *albedo * c.reflectance(-rec.normal.dot(&r_in.dir.unit_vector())),
+        None => *albedo,
+    } * channel;
 
-    if scattered.dir.dot(&rec.normal) > 0.0 {
-        Some((scattered, *albedo))
+    if rec.normal.dot(&reflected) > 0.0 {
+        Some(ScatterRecord::specular(
+            r_in,
+            rec,
+            reflected,
+            attenuation,
+            Bounce::Glossy,
+        ))
     } else {
         None
     }
@@ -231,20 +961,61 @@ fn specular_scatter(
     prob: f32,
     r_in: &Ray,
     rec: &HitRecord,
-) -> Option<(Ray, Color)> {
-    let diffuse_dir = rec.normal + V3::random_unit_vector();
-    let is_specular = prob > random_range(0.0..1.0);
-    let (dir, color) = if is_specular {
-        let specular_dir = r_in.dir.reflect(rec.normal);
-        (
-            diffuse_dir * (1.0 - smoothness) + specular_dir * smoothness,
-            *spec_albedo,
-        )
-    } else {
-        (diffuse_dir, *albedo)
+) -> Option<ScatterRecord> {
+    let diffuse = ScatterRecord::diffuse(r_in, rec, *albedo);
+    if prob <= random_range(0.0..1.0) {
+        return Some(diffuse);
+    }
+
+    let specular_dir = r_in.dir.reflect(rec.normal);
+    let dir = diffuse.ray.dir * (1.0 - smoothness) + specular_dir * smoothness;
+
+    Some(ScatterRecord::specular(
+        r_in,
+        rec,
+        dir,
+        *spec_albedo,
+        Bounce::Glossy,
+    ))
+}
+
+fn stylized_scatter(
+    albedo: &Color,
+    roughness: f32,
+    tints: &[Color],
+    reflectance: f32,
+    fresnel: bool,
+    r_in: &Ray,
+    rec: &HitRecord,
+) -> Option<ScatterRecord> {
+    let unit_dir = r_in.dir.unit_vector();
+    let prob = match tints.get(r_in.glossy_bounces as usize) {
+        None => 0.0,
+        Some(_) if fresnel => {
+            let cos_theta = (-rec.normal.dot(&unit_dir)).clamp(0.0, 1.0);
+            reflectance + (1.0 - reflectance) * (1.0 - cos_theta).powi(5)
+        }
+        Some(_) => reflectance,
     };
+    if prob <= random_range(0.0..1.0) {
+        return Some(ScatterRecord::diffuse(r_in, rec, *albedo));
+    }
 
-    Some((Ray::new(rec.p, dir), color))
+    // rough reflections that would pass below the surface are mirrored back above it rather
+    // than being absorbed so that reflections stay flat and bright
+    let mut dir = unit_dir.reflect(rec.normal) + roughness * V3::random_unit_vector();
+    if rec.normal.dot(&dir) <= 0.0 {
+        dir = dir.reflect(rec.normal);
+    }
+    let tint = tints[r_in.glossy_bounces as usize];
+
+    Some(ScatterRecord::specular(
+        r_in,
+        rec,
+        dir,
+        tint,
+        Bounce::Glossy,
+    ))
 }
 
 fn dielectric_scatter(
@@ -252,7 +1023,7 @@ fn dielectric_scatter(
     albedo: &Color,
     r_in: &Ray,
     rec: &HitRecord,
-) -> Option<(Ray, Color)> {
+) -> Option<ScatterRecord> {
     let ri = if rec.front_face {
         1.0 / ref_index
     } else {
@@ -260,17 +1031,141 @@ fn dielectric_scatter(
     };
     let unit_dir = r_in.dir.unit_vector();
 
-    let cos_theta = (-unit_dir.dot(&rec.normal)).min(1.0);
+    let cos_theta = (-rec.normal.dot(&unit_dir)).min(1.0);
     let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
     let cannot_refract = ri * sin_theta > 1.0;
 
-    let direction = if cannot_refract || reflectance(cos_theta, ri) > random_range(0.0..1.0) {
-        unit_dir.reflect(rec.normal)
+    let (direction, bounce) =
+        if cannot_refract || reflectance(cos_theta, ri) > random_range(0.0..1.0) {
+            (unit_dir.reflect(rec.normal), Bounce::Glossy)
+        } else {
+            (unit_dir.refract(rec.normal, ri), Bounce::Transmission)
+        };
+
+    Some(ScatterRecord::specular(
+        r_in, rec, direction, *albedo, bounce,
+    ))
+}
+
+/// Rough glass: a microfacet normal is drawn from the GGX distribution (in proportion to its
+/// area projected onto the surface) and the ray reflected off or refracted through it as for
+/// smooth glass, weighted by the masking and shadowing of the microfacets. Directions that leave
+/// from the wrong side of the surface are absorbed.
+///   https://www.graphics.cornell.edu/~bjw/microfacetbsdf.pdf
+fn rough_dielectric_scatter(
+    ref_index: f32,
+    albedo: &Color,
+    roughness: f32,
+    r_in: &Ray,
+    rec: &HitRecord,
+) -> Option<ScatterRecord> {
+    let ri = if rec.front_face {
+        1.0 / ref_index
     } else {
-        unit_dir.refract(rec.normal, ri)
+        ref_index
     };
+    let unit_dir = r_in.dir.unit_vector();
+    let alpha_sq = roughness.powi(4);
+    let (m, cos_m) = sample_ggx(rec.normal, alpha_sq);
 
-    Some((Ray::new(rec.p, direction), *albedo))
+    let cos_theta = -m.dot(&unit_dir);
+    if cos_theta <= 0.0 {
+        return None;
+    }
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    let cannot_refract = ri * sin_theta > 1.0;
+
+    let (direction, bounce) =
+        if cannot_refract || reflectance(cos_theta, ri) > random_range(0.0..1.0) {
+            (unit_dir.reflect(m), Bounce::Glossy)
+        } else {
+            (unit_dir.refract(m, ri), Bounce::Transmission)
+        };
+    let cos_in = -rec.normal.dot(&unit_dir);
+    let cos_out = rec.normal.dot(&direction);
+    if cos_in <= 0.0 || (bounce == Bounce::Glossy) != (cos_out > 0.0) {
+        return None;
+    }
+
+    let weight =
+        cos_theta * ggx_g1(cos_in, alpha_sq) * ggx_g1(cos_out, alpha_sq) / (cos_in * cos_m);
+
+    Some(ScatterRecord::specular(
+        r_in,
+        rec,
+        direction,
+        *albedo * weight,
+        bounce,
+    ))
+}
+
+/// A microfacet normal about n drawn from the GGX distribution with the given alpha squared in
+/// proportion to its area projected onto the surface, along with its cosine to n.
+fn sample_ggx(n: N3, alpha_sq: f32) -> (N3, f32) {
+    let (r1, r2): (f32, f32) = (random_range(0.0..1.0), random_range(0.0..1.0));
+    let cos_m = (1.0 / (1.0 + alpha_sq * r1 / (1.0 - r1))).sqrt();
+    let sin_m = (1.0 - cos_m * cos_m).max(0.0).sqrt();
+    let local = V3::new(sin_m * (TAU * r2).cos(), sin_m * (TAU * r2).sin(), cos_m);
+
+    (
+        N3::new_unchecked(Onb::new(n.as_v3()).to_world(local)),
+        cos_m,
+    )
+}
+
+/// Smith masking for GGX in a direction with the given cosine to the surface normal
+fn ggx_g1(cos: f32, alpha_sq: f32) -> f32 {
+    let cos_sq = (cos * cos).min(1.0);
+
+    2.0 / (1.0 + (1.0 + alpha_sq * (1.0 - cos_sq) / cos_sq).sqrt())
+}
+
+/// A clear coat over a diffuse base: light is reflected off the coat with the Fresnel reflectance
+/// of a dielectric of the given index and the rest is scattered by the base, so that the surface
+/// grows more reflective towards grazing angles without reflecting more light than it receives.
+/// Rough coats reflect off GGX microfacets.
+fn plastic_scatter(
+    albedo: &Color,
+    ref_index: f32,
+    roughness: f32,
+    r_in: &Ray,
+    rec: &HitRecord,
+) -> Option<ScatterRecord> {
+    let unit_dir = r_in.dir.unit_vector();
+    let cos_in = (-rec.normal.dot(&unit_dir)).clamp(0.0, 1.0);
+    if reflectance(cos_in, 1.0 / ref_index) <= random_range(0.0..1.0) {
+        return Some(ScatterRecord::diffuse(r_in, rec, *albedo));
+    }
+
+    if roughness == 0.0 {
+        let dir = unit_dir.reflect(rec.normal);
+        return Some(ScatterRecord::specular(
+            r_in,
+            rec,
+            dir,
+            Color::WHITE,
+            Bounce::Glossy,
+        ));
+    }
+
+    let alpha_sq = roughness.powi(4);
+    let (m, cos_m) = sample_ggx(rec.normal, alpha_sq);
+    let cos_theta = -m.dot(&unit_dir);
+    let dir = unit_dir.reflect(m);
+    let cos_out = rec.normal.dot(&dir);
+    if cos_theta <= 0.0 || cos_in <= 0.0 || cos_out <= 0.0 {
+        return None;
+    }
+    let weight =
+        cos_theta * ggx_g1(cos_in, alpha_sq) * ggx_g1(cos_out, alpha_sq) / (cos_in * cos_m);
+
+    Some(ScatterRecord::specular(
+        r_in,
+        rec,
+        dir,
+        Color::WHITE * weight,
+        Bounce::Glossy,
+    ))
 }
 
 /// Use Schlick's approximation for reflectance.
@@ -281,9 +1176,481 @@ fn reflectance(cosine: f32, ref_index: f32) -> f32 {
     r0_sq + (1.0 - r0_sq) * (1.0 - cosine).powi(5)
 }
 
-fn isotropic_scatter(texture: &Texture, rec: &HitRecord) -> Option<(Ray, Color)> {
-    let scattered = Ray::new(rec.p, V3::random_unit_vector());
+fn planet_scatter(
+    day: &Texture,
+    ocean: Option<&Texture>,
+    spec_prob: f32,
+    r_in: &Ray,
+    rec: &HitRecord,
+) -> Option<ScatterRecord> {
+    let albedo = day.value(rec.u, rec.v, rec.p);
+    let mask = match ocean {
+        Some(t) => t.value(rec.u, rec.v, rec.p).luminance().clamp(0.0, 1.0),
+        None => 0.0,
+    };
+
+    specular_scatter(
+        &albedo,
+        &Color::WHITE,
+        OCEAN_SMOOTHNESS,
+        spec_prob * mask,
+        r_in,
+        rec,
+    )
+}
+
+fn clouds_scatter(texture: &Texture, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+    let c = texture.value(rec.u, rec.v, rec.p);
+    let coverage = c.luminance().clamp(0.0, 1.0);
+
+    if coverage > random_range(0.0..1.0) {
+        // normalise so that coverage only controls opacity and not brightness
+        lambertian_scatter(&Texture::solid(c / coverage), r_in, rec)
+    } else {
+        Some(ScatterRecord::specular(
+            r_in,
+            rec,
+            r_in.dir,
+            Color::WHITE,
+            Bounce::Transmission,
+        ))
+    }
+}
+
+fn isotropic_scatter(texture: &Texture, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
     let attenuation = texture.value(rec.u, rec.v, rec.p);
 
-    Some((scattered, attenuation))
+    Some(ScatterRecord::uniform(r_in, rec, attenuation))
+}
+
+/// A simplified Kay-Kajiya style fibre model. Specular reflections leave on the cone around the
+/// fibre tangent that makes the same angle with it as the incoming ray and are not tinted by the
+/// pigment. All other light is treated as entering the fibre and scattering in all directions.
+///   Kajiya & Kay, "Rendering fur with three dimensional textures" (SIGGRAPH 1989)
+fn hair_scatter(
+    albedo: &Color,
+    roughness: f32,
+    spec_prob: f32,
+    r_in: &Ray,
+    rec: &HitRecord,
+) -> Option<ScatterRecord> {
+    if rec.tangent.near_zero() {
+        return lambertian_scatter(&Texture::solid(*albedo), r_in, rec);
+    }
+
+    if spec_prob > random_range(0.0..1.0) {
+        let d = r_in.dir.unit_vector();
+        let cos_t = d.dot(&rec.tangent);
+        let sin_t = (1.0 - cos_t * cos_t).max(0.0).sqrt();
+        let phi = random_range(0.0..TAU);
+        let cone =
+            Onb::new(rec.tangent).to_world(V3::new(sin_t * phi.cos(), sin_t * phi.sin(), cos_t));
+        let dir = cone + roughness * V3::random_unit_vector();
+
+        Some(ScatterRecord::specular(
+            r_in,
+            rec,
+            dir,
+            Color::WHITE,
+            Bounce::Glossy,
+        ))
+    } else {
+        Some(ScatterRecord::uniform(r_in, rec, *albedo))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{p, v, v3::N3};
+    use simple_test_case::test_case;
+
+    #[test_case(v!(0.2, -1, 0), 1.0; "right half of the image")]
+    #[test_case(v!(-0.2, -1, 0), 0.0; "left half of the image")]
+    #[test_case(v!(1, -1, 0), 0.0; "outside of the projection")]
+    #[test_case(v!(0.2, 1, 0), 0.0; "behind the light")]
+    #[test]
+    fn gobo_is_projected_along_the_light_direction(dir: V3, expected: f32) {
+        let raw = image::RgbImage::from_fn(2, 1, |x, _| image::Rgb([255 * x as u8; 3]));
+        let gobo = Texture::Image {
+            raw: Box::leak(Box::new(TiledImage::new(&raw, None))),
+        };
+        let shape = LightShape::new(
+            None,
+            Some((gobo, Angle::deg(60.0))),
+            v!(0, -1, 0),
+            v!(0, 0, -1),
+        );
+
+        assert_eq!(shape.factor(dir), Color::grey(expected));
+    }
+
+    #[test_case(0, Some(Color::new(1.0, 0.5, 0.5)); "first reflection")]
+    #[test_case(1, Some(Color::new(0.5, 0.5, 1.0)); "second reflection")]
+    #[test_case(2, None; "out of reflections")]
+    #[test]
+    fn stylized_reflections_are_tinted_per_bounce(glossy_bounces: u8, tint: Option<Color>) {
+        let albedo = Color::grey(0.2);
+        let mat: &'static Material = Box::leak(Box::new(Material::stylized(
+            albedo,
+            0.0,
+            vec![Color::new(1.0, 0.5, 0.5), Color::new(0.5, 0.5, 1.0)],
+            1.0,
+            false,
+        )));
+        let mut r = Ray::new(p!(-1, 1, 0), v!(1, -1, 0));
+        r.glossy_bounces = glossy_bounces;
+        let rec = HitRecord::new(1.0, p!(0, 0, 0), N3::new(v!(0, 1, 0)), &r, mat, 0.5, 0.5);
+
+        let ScatterRecord {
+            ray: scattered,
+            attenuation,
+            bounce,
+            ..
+        } = mat.scatter(&r, &rec).unwrap();
+        match tint {
+            Some(tint) => {
+                assert_eq!((attenuation, bounce), (tint, Bounce::Glossy));
+                assert!((scattered.dir - v!(1, 1, 0).unit_vector()).length() < 1e-5);
+            }
+            None => assert_eq!((attenuation, bounce), (albedo, Bounce::Diffuse)),
+        }
+    }
+
+    #[test_case(v!(0, -1, 0), Color::new(1.0, 0.0, 0.0); "from the front")]
+    #[test_case(v!(0, 1, 0), Color::new(0.0, 0.0, 1.0); "from the back")]
+    #[test]
+    fn two_sided_materials_shade_each_side_differently(dir: V3, expected: Color) {
+        let front: &'static Material =
+            Box::leak(Box::new(Material::solid_color(Color::new(1.0, 0.0, 0.0))));
+        let back: &'static Material =
+            Box::leak(Box::new(Material::diffuse_light(Color::new(0.0, 0.0, 1.0))));
+        let mat = Material::two_sided(front, back);
+        let r = Ray::new(P3::ORIGIN - dir, dir);
+        let rec = HitRecord::new(1.0, P3::ORIGIN, N3::new(v!(0, 1, 0)), &r, &CLAY, 0.5, 0.5);
+
+        assert_eq!(mat.albedo(&r, &rec), expected);
+        assert_eq!(mat.scatter(&r, &rec).is_some(), rec.front_face);
+        assert_eq!(mat.emitted(&r, &rec) == Color::BLACK, rec.front_face);
+        assert!(mat.is_emissive());
+    }
+
+    #[test]
+    fn stylized_fresnel_reflects_more_at_grazing_angles() {
+        let mat = Material::stylized(Color::grey(0.5), 0.3, vec![Color::WHITE], 0.1, true);
+        let n = N3::new(v!(0, 1, 0));
+        let reflected = |dir: V3| {
+            let r = Ray::new(P3::ORIGIN - dir, dir);
+            let rec = HitRecord::new(1.0, P3::ORIGIN, n, &r, &CLAY, 0.5, 0.5);
+            (0..2000)
+                .filter(|_| matches!(mat.scatter(&r, &rec), Some(s) if s.bounce == Bounce::Glossy && s.ray.dir.y > 0.0))
+                .count()
+        };
+
+        assert!(reflected(v!(1, -0.05, 0)) > 3 * reflected(v!(0, -1, 0)));
+    }
+
+    #[test]
+    fn gold_reflects_yellow_head_on_and_white_at_grazing_angles() {
+        let head_on = Conductor::GOLD.reflectance(1.0);
+        let grazing = Conductor::GOLD.reflectance(0.01);
+
+        assert!(
+            head_on.r > head_on.g && head_on.g > head_on.b,
+            "{head_on:?}"
+        );
+        assert!((head_on.r - 0.97).abs() < 0.01, "{head_on:?}");
+        assert!(grazing.b > 0.9, "{grazing:?}");
+    }
+
+    #[test_case(Conductor::GOLD; "gold")]
+    #[test_case(Conductor::COPPER; "copper")]
+    #[test_case(Conductor::ALUMINIUM; "aluminium")]
+    #[test]
+    fn conductors_never_reflect_more_than_they_receive(c: Conductor) {
+        for i in 0..=100 {
+            let r = c.reflectance(i as f32 / 100.0);
+            assert!(
+                [r.r, r.g, r.b].iter().all(|&v| (0.0..=1.0).contains(&v)),
+                "{r:?}"
+            );
+        }
+    }
+
+    #[test_case(v!(1, 0, 0), 0.0; "day side")]
+    #[test_case(v!(0, 1, 0), 0.0; "terminator")]
+    #[test_case(v!(-1, 0, 0), 2.0; "night side")]
+    #[test]
+    fn planet_night_lights_face_away_from_the_sun(normal: V3, expected: f32) {
+        let mat: &'static Material = Box::leak(Box::new(Material::planet(
+            Texture::solid(Color::grey(0.5)),
+            Some((Texture::solid(Color::WHITE), 2.0)),
+            None,
+            v!(1, 0, 0),
+        )));
+        let r = Ray::new(P3::ORIGIN + normal * 2.0, -normal);
+        let rec = HitRecord::new(1.0, p!(0, 0, 0), N3::new(normal), &r, mat, 0.5, 0.5);
+
+        assert_eq!(mat.emitted(&r, &rec), Color::grey(expected));
+    }
+
+    #[test_case(Material::solid_color(Color::WHITE), Bounce::Diffuse; "diffuse")]
+    #[test_case(Material::metal(Color::WHITE, 0.0), Bounce::Glossy; "metal")]
+    #[test_case(Material::dielectric(1.5, Color::WHITE), Bounce::Glossy; "total internal reflection")]
+    #[test]
+    fn scattering_reports_the_bounce_kind(mat: Material, expected: Bounce) {
+        let mat: &'static Material = Box::leak(Box::new(mat));
+        // grazing ray leaving the surface, which is inside of the glass
+        let r = Ray::new(p!(-1, -0.1, 0), v!(1, 0.1, 0));
+        let rec = HitRecord::new(1.0, p!(0, 0, 0), N3::new(v!(0, 1, 0)), &r, mat, 0.5, 0.5);
+
+        let bounce = mat.scatter(&r, &rec).unwrap().bounce;
+
+        assert_eq!(bounce, expected);
+    }
+
+    #[test_case(0.0; "sharp coat")]
+    #[test_case(0.4; "rough coat")]
+    #[test]
+    fn plastic_reflects_more_at_grazing_angles_without_gaining_energy(roughness: f32) {
+        let mat: &'static Material = Box::leak(Box::new(Material::plastic(
+            Color::grey(0.5),
+            1.5,
+            roughness,
+        )));
+        let n = N3::new(v!(0, 1, 0));
+        // the fraction of bounces off the coat and the mean light returned for a white coat
+        let scatter = |dir: V3| {
+            let r = Ray::new(P3::ORIGIN - dir, dir);
+            let rec = HitRecord::new(1.0, P3::ORIGIN, n, &r, mat, 0.5, 0.5);
+            let (mut glossy, mut energy) = (0, 0.0);
+            for _ in 0..4000 {
+                if let Some(srec) = mat.scatter(&r, &rec) {
+                    glossy += (srec.bounce == Bounce::Glossy) as usize;
+                    energy += srec.attenuation.g / 4000.0;
+                }
+            }
+            (glossy as f32 / 4000.0, energy)
+        };
+
+        let (head_on, head_on_energy) = scatter(v!(0, -1, 0));
+        let (grazing, grazing_energy) = scatter(v!(1, -0.05, 0));
+
+        assert!((head_on - 0.04).abs() < 0.02, "{head_on}");
+        // some rough reflections at grazing angles fall below the surface and are lost
+        assert!(grazing > 0.4, "{grazing}");
+        for energy in [head_on_energy, grazing_energy] {
+            assert!(energy < 1.05, "{energy}");
+        }
+        assert!(mat.cosine_diffuse());
+    }
+
+    #[test]
+    fn frosted_glass_spreads_transmission_around_the_refracted_direction() {
+        let mat: &'static Material =
+            Box::leak(Box::new(Material::rough_dielectric(1.5, Color::WHITE, 0.5)));
+        let r = Ray::new(p!(0, 1, 0), v!(0, -1, 0));
+        let rec = HitRecord::new(1.0, p!(0, 0, 0), N3::new(v!(0, 1, 0)), &r, mat, 0.5, 0.5);
+
+        let (mut spread, mut mean_x, mut n) = (0.0, 0.0, 0);
+        for _ in 0..2000 {
+            let Some(srec) = mat.scatter(&r, &rec) else {
+                continue;
+            };
+            let dir = srec.ray.dir.unit_vector();
+            match srec.bounce {
+                Bounce::Transmission => {
+                    assert!(dir.y < 0.0, "{dir:?}");
+                    spread += dir.x.abs();
+                    mean_x += dir.x;
+                    n += 1;
+                }
+                _ => assert!(dir.y > 0.0, "{dir:?}"),
+            }
+            assert!(srec.attenuation.is_finite());
+        }
+
+        assert!(n > 1000, "{n}");
+        assert!(spread / n as f32 > 0.05, "{}", spread / n as f32);
+        assert!((mean_x / n as f32).abs() < 0.05, "{}", mean_x / n as f32);
+        assert!(mat.roughness(&r, &rec) == 0.5);
+    }
+
+    #[test]
+    fn metal_fuzz_per_channel_follows_one_channel_per_bounce() {
+        let mat: &'static Material = Box::leak(Box::new(Material::metal(
+            Color::WHITE,
+            Fuzz::Channels([0.0, 0.3, 0.6]),
+        )));
+        let r = Ray::new(p!(0, 1, 0), v!(0.3, -1, 0));
+        let rec = HitRecord::new(1.0, p!(0, 0, 0), N3::new(v!(0, 1, 0)), &r, mat, 0.5, 0.5);
+
+        let (mut sum, n) = (Color::BLACK, 20_000);
+        for _ in 0..n {
+            let Some(srec) = mat.scatter(&r, &rec) else {
+                continue;
+            };
+            let Color { r: red, g, b } = srec.attenuation;
+            assert_eq!(
+                [red, g, b].iter().filter(|&&c| c > 0.0).count(),
+                1,
+                "{srec:?}"
+            );
+            if red > 0.0 {
+                // the red channel isn't blurred at all
+                assert!((srec.ray.dir.unit_vector() - v!(0.3, 1, 0).unit_vector()).length() < 1e-4);
+            }
+            sum += srec.attenuation;
+        }
+
+        // each channel keeps all of its light on average near head on, where little of even the
+        // roughest lobe is scattered into the surface
+        let mean = sum / n as f32;
+        for c in [mean.r, mean.g, mean.b] {
+            assert!((c - 1.0).abs() < 0.1, "{mean:?}");
+        }
+        assert!((mat.roughness(&r, &rec) - 0.3).abs() < 1e-6);
+    }
+
+    #[test_case(Material::solid_color(Color::WHITE), Some(FRAC_1_PI); "diffuse")]
+    #[test_case(Material::isotropic(Color::WHITE), Some(0.25 * FRAC_1_PI); "volume")]
+    #[test_case(Material::metal(Color::WHITE, 0.5), None; "metal")]
+    #[test_case(Material::dielectric(1.5, Color::WHITE), None; "glass")]
+    #[test]
+    fn scattering_reports_the_density_of_the_direction(mat: Material, cos_pdf: Option<f32>) {
+        let mat: &'static Material = Box::leak(Box::new(mat));
+        let r = Ray::new(p!(0, 1, 0), v!(0.3, -1, 0));
+        let rec = HitRecord::new(1.0, p!(0, 0, 0), N3::new(v!(0, 1, 0)), &r, mat, 0.5, 0.5);
+
+        let mut mean_cos = 0.0;
+        for _ in 0..1000 {
+            let srec = mat.scatter(&r, &rec).unwrap();
+            let dir = srec.ray.dir.unit_vector();
+            match cos_pdf {
+                // cosine weighted lobes have a density of cos(theta) / pi
+                Some(k) if mat.cosine_diffuse() => {
+                    assert!(dir.y >= 0.0);
+                    assert!((srec.pdf - k * dir.y).abs() < 1e-4, "{srec:?}");
+                    mean_cos += dir.y / 1000.0;
+                }
+                Some(k) => assert!((srec.pdf - k).abs() < 1e-6, "{srec:?}"),
+                None => assert!(srec.specular && srec.pdf == 0.0, "{srec:?}"),
+            }
+            assert_eq!(srec.specular, cos_pdf.is_none());
+        }
+
+        // the mean of cos(theta) over a cosine distribution is 2/3
+        if mat.cosine_diffuse() {
+            assert!((mean_cos - 2.0 / 3.0).abs() < 0.05, "{mean_cos}");
+        }
+    }
+
+    #[test_case(Mask::Facing { power: 1.0 }, v!(0, -1, 0), 1.0; "facing head on")]
+    #[test_case(Mask::Facing { power: 2.0 }, v!(1, -1, 0), 0.5; "facing at 45 degrees")]
+    #[test_case(Mask::Height { min: -1.0, max: 3.0 }, v!(0, -1, 0), 0.5; "height")]
+    #[test_case(Mask::occlusion(1.0), v!(0, -1, 0), 0.0; "unbaked occlusion")]
+    #[test]
+    fn shading_masks_use_the_hit(mask: Mask, dir: V3, expected: f32) {
+        let mat: &'static Material = Box::leak(Box::new(Material::solid_color(Color::WHITE)));
+        let r = Ray::new(p!(0, 1, 0) - dir, dir);
+        let rec = HitRecord::new(1.0, p!(0, 1, 0), N3::new(v!(0, 1, 0)), &r, mat, 0.5, 0.5);
+
+        assert!((mask.value(&r, &rec) - expected).abs() < 1e-5);
+    }
+
+    #[test_case(0.0, Bounce::Diffuse; "clean")]
+    #[test_case(1.0, Bounce::Glossy; "fully masked")]
+    #[test]
+    fn blends_scatter_from_the_masked_material(mask: f32, expected: Bounce) {
+        let a: &'static Material = Box::leak(Box::new(Material::solid_color(Color::WHITE)));
+        let b: &'static Material = Box::leak(Box::new(Material::metal(Color::WHITE, 0.0)));
+        let mat: &'static Material = Box::leak(Box::new(Material::blend(
+            a,
+            b,
+            Mask::Texture(Texture::solid(Color::grey(mask))),
+        )));
+        let r = Ray::new(p!(0, 1, 0), v!(0, -1, 0));
+        let rec = HitRecord::new(1.0, p!(0, 0, 0), N3::new(v!(0, 1, 0)), &r, mat, 0.5, 0.5);
+
+        for _ in 0..10 {
+            assert_eq!(mat.scatter(&r, &rec).unwrap().bounce, expected);
+        }
+    }
+
+    #[test]
+    fn callback_textures_are_evaluated_from_user_data() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        static FRAME: AtomicU32 = AtomicU32::new(0);
+        let tex = Texture::callback(|u, _, p| {
+            let frame = FRAME.load(Ordering::Relaxed) as f32;
+            Color::new(u, p.x, frame)
+        });
+        let checker = Texture::checker(1.0, tex, Texture::solid(Color::BLACK));
+
+        assert_eq!(
+            tex.value(0.25, 0.0, p!(2, 0, 0)),
+            Color::new(0.25, 2.0, 0.0)
+        );
+        FRAME.store(3, Ordering::Relaxed);
+        assert_eq!(
+            tex.value(0.25, 0.0, p!(2, 0, 0)),
+            Color::new(0.25, 2.0, 3.0)
+        );
+        assert_eq!(
+            checker.value(0.5, 0.0, p!(1.5, 0, 0)),
+            Color::new(0.5, 1.5, 3.0)
+        );
+    }
+
+    /// A perfect mirror glowing with a fixed color
+    #[derive(Debug)]
+    struct GlowingMirror(Color);
+
+    impl Bsdf for GlowingMirror {
+        fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+            let dir = r_in.dir.reflect(rec.normal);
+            Some(ScatterRecord::specular(
+                r_in,
+                rec,
+                dir,
+                Color::WHITE,
+                Bounce::Glossy,
+            ))
+        }
+
+        fn albedo(&self, _r_in: &Ray, _rec: &HitRecord) -> Color {
+            Color::WHITE
+        }
+
+        fn is_emissive(&self) -> bool {
+            true
+        }
+
+        fn color_emitted(&self, _u: f32, _v: f32, _p: P3) -> Color {
+            self.0
+        }
+    }
+
+    #[test]
+    fn custom_materials_scatter_and_emit() {
+        let mat: &'static Material =
+            Box::leak(Box::new(Material::custom(GlowingMirror(Color::grey(2.0)))));
+        let r = Ray::new(p!(-1, 1, 0), v!(1, -1, 0));
+        let rec = HitRecord::new(1.0, p!(0, 0, 0), N3::new(v!(0, 1, 0)), &r, mat, 0.5, 0.5);
+
+        let srec = mat.scatter(&r, &rec).unwrap();
+        assert_eq!(srec.ray.dir, v!(1, 1, 0));
+        assert_eq!(srec.attenuation, Color::WHITE);
+        assert_eq!(srec.bounce, Bounce::Glossy);
+        assert!(mat.is_emissive());
+        assert_eq!(mat.emitted(&r, &rec), Color::grey(2.0));
+        assert_eq!(mat.roughness(&r, &rec), 0.0);
+        assert!(!mat.cosine_diffuse());
+
+        // custom materials can be blended like any other
+        let dark: &'static Material = Box::leak(Box::new(Material::solid_color(Color::BLACK)));
+        let blend = Material::blend(dark, mat, Mask::Texture(Texture::solid(Color::grey(0.25))));
+        assert_eq!(blend.emitted(&r, &rec), Color::grey(0.5));
+    }
 }
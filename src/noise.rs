@@ -1,7 +1,7 @@
 use crate::{P3, V3};
-use rand::random_range;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Perlin<const N: usize = 256> {
     rand_vec: [V3; N],
     perm_x: [usize; N],
@@ -17,13 +17,23 @@ impl Default for Perlin {
 
 impl<const N: usize> Perlin<N> {
     pub fn new() -> Self {
+        Self::from_rng(&mut rand::rng())
+    }
+
+    /// Noise that is reproducible for a given seed.
+    pub fn seeded(seed: u64) -> Self {
+        Self::from_rng(&mut StdRng::seed_from_u64(seed))
+    }
+
+    fn from_rng(rng: &mut impl Rng) -> Self {
         let mut rand_vec = [V3::default(); N];
         let mut perm_x = [0; N];
         let mut perm_y = [0; N];
         let mut perm_z = [0; N];
 
         for i in 0..N {
-            rand_vec[i] = V3::random(-1.0, 1.0).unit_vector();
+            let mut r = || rng.random_range(-1.0..1.0);
+            rand_vec[i] = V3::new(r(), r(), r()).unit_vector();
             for s in [&mut perm_x, &mut perm_y, &mut perm_z] {
                 s[i] = i;
             }
@@ -31,7 +41,7 @@ impl<const N: usize> Perlin<N> {
 
         for s in [&mut perm_x, &mut perm_y, &mut perm_z] {
             for i in (N - 1)..0 {
-                let target = random_range(0..i);
+                let target = rng.random_range(0..i);
                 s.swap(i, target);
             }
         }
@@ -98,7 +108,7 @@ impl<const N: usize> Perlin<N> {
         for _ in 0..depth {
             acc += weight * self.noise(temp_p);
             weight *= 0.5;
-            temp_p *= 2.0;
+            temp_p = temp_p.scale(2.0);
         }
 
         acc.abs()
@@ -0,0 +1,173 @@
+//! Cheap ambient occlusion baked onto a regular grid over the scene for use as a shading input,
+//! e.g. to blend in grime where geometry is crowded together. Each grid point stores the
+//! occlusion of the hemispheres around the six axis directions so that lookups can be weighted
+//! by the surface normal.
+use crate::{
+    bvh::{Bvh, MAX_BVH_DEPTH},
+    counters::{self, Counter},
+    hit::Interval,
+    Ray, P3, V3,
+};
+use rayon::prelude::*;
+
+/// Maximum number of grid points along each axis
+const MAX_RES: usize = 64;
+/// Rays cast from each grid point
+const N_RAYS: usize = 64;
+
+#[derive(Debug)]
+pub struct OcclusionGrid {
+    origin: P3,
+    cell: f32,
+    dims: [usize; 3],
+    /// Occlusion of the +x, -x, +y, -y, +z, -z hemispheres at each grid point
+    values: Vec<[f32; 6]>,
+}
+
+impl OcclusionGrid {
+    /// Bake the occlusion from geometry within distance over the bounding box of the scene.
+    pub fn bake(bvh: &Bvh, distance: f32) -> Self {
+        let b = bvh.bbox;
+        let size = [b.x.size(), b.y.size(), b.z.size()];
+        let longest = size.iter().fold(0.0f32, |a, &s| a.max(s));
+        let cell = (distance / 2.0).max(longest / (MAX_RES - 2) as f32);
+        // offset by half a cell so that grid points do not lie on the walls of the scene
+        let origin = P3::new(b.x.min, b.y.min, b.z.min) - V3::new(1.0, 1.0, 1.0) * (cell / 2.0);
+        let dims = size.map(|s| (s / cell).ceil() as usize + 2);
+
+        let values = (0..dims[0] * dims[1] * dims[2])
+            .into_par_iter()
+            .map(|i| {
+                let (x, y, z) = (
+                    i % dims[0],
+                    (i / dims[0]) % dims[1],
+                    i / (dims[0] * dims[1]),
+                );
+                let p = origin + V3::new(x as f32, y as f32, z as f32) * cell;
+                hemisphere_occlusion(bvh, p, distance)
+            })
+            .collect();
+
+        Self {
+            origin,
+            cell,
+            dims,
+            values,
+        }
+    }
+
+    /// Occlusion in [0, 1] of the hemisphere around the normal of a surface at p.
+    pub fn value(&self, p: P3, normal: V3) -> f32 {
+        // step off of the surface so that the grid points used are all in front of it
+        let q = p + normal * self.cell - self.origin;
+        let mut corners = [[0.0; 6]; 8];
+        let mut frac = [0.0; 3];
+        let mut ix = [0; 3];
+        for axis in 0..3 {
+            let max = self.dims[axis] - 1;
+            let g = (q[axis] / self.cell).clamp(0.0, max as f32);
+            ix[axis] = (g.floor() as usize).min(max.saturating_sub(1));
+            frac[axis] = g - ix[axis] as f32;
+        }
+
+        for (n, c) in corners.iter_mut().enumerate() {
+            let (dx, dy, dz) = (n & 1, (n >> 1) & 1, (n >> 2) & 1);
+            let x = (ix[0] + dx).min(self.dims[0] - 1);
+            let y = (ix[1] + dy).min(self.dims[1] - 1);
+            let z = (ix[2] + dz).min(self.dims[2] - 1);
+            *c = self.values[x + self.dims[0] * (y + self.dims[1] * z)];
+        }
+
+        // weight each hemisphere by how closely the normal is aligned with its axis
+        let w = [0, 1, 2].map(|axis| {
+            let side = if normal[axis] >= 0.0 { 0 } else { 1 };
+            (2 * axis + side, normal[axis] * normal[axis])
+        });
+
+        corners
+            .iter()
+            .enumerate()
+            .map(|(n, c)| {
+                let weight: f32 = (0..3)
+                    .map(|axis| {
+                        if (n >> axis) & 1 == 1 {
+                            frac[axis]
+                        } else {
+                            1.0 - frac[axis]
+                        }
+                    })
+                    .product();
+                let occ: f32 = w.iter().map(|&(h, wh)| c[h] * wh).sum();
+
+                weight * occ
+            })
+            .sum::<f32>()
+            .clamp(0.0, 1.0)
+    }
+}
+
+/// Cosine weighted fraction of rays from p that hit something within distance for each of the
+/// axis aligned hemispheres.
+fn hemisphere_occlusion(bvh: &Bvh, p: P3, distance: f32) -> [f32; 6] {
+    let mut stack = [0; MAX_BVH_DEPTH];
+    let mut hit = [0.0; 6];
+    let mut total = [0.0; 6];
+    let epsilon = bvh.ray_epsilon();
+
+    for _ in 0..N_RAYS {
+        let dir = V3::random_unit_vector();
+        let r = Ray::new(p, dir).with_epsilon(epsilon);
+        counters::add(Counter::ShadowRays, 1);
+        let occluded = bvh
+            .hits(&r, Interval::new(r.epsilon, distance), &mut stack)
+            .is_some();
+
+        for axis in 0..3 {
+            let h = if dir[axis] >= 0.0 {
+                2 * axis
+            } else {
+                2 * axis + 1
+            };
+            let cos = dir[axis].abs();
+            total[h] += cos;
+            if occluded {
+                hit[h] += cos;
+            }
+        }
+    }
+
+    [0, 1, 2, 3, 4, 5].map(|h| {
+        if total[h] > 0.0 {
+            hit[h] / total[h]
+        } else {
+            0.0
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hit::{Hittable, Quad},
+        material::Material,
+        p, v, Color,
+    };
+
+    #[test]
+    fn corners_are_more_occluded_than_open_floor() {
+        let mat: &'static Material = Box::leak(Box::new(Material::solid_color(Color::WHITE)));
+        let quad = |q, u, v| Hittable::from(Quad::new(q, u, v, mat));
+        let bvh = Bvh::new(vec![
+            quad(p!(0, 0, 0), v!(10, 0, 0), v!(0, 0, 10)),
+            quad(p!(0, 0, 0), v!(0, 10, 0), v!(0, 0, 10)),
+        ]);
+        let grid = OcclusionGrid::bake(&bvh, 2.0);
+
+        let open = grid.value(p!(8, 0, 5), v!(0, 1, 0));
+        let corner = grid.value(p!(0.2, 0, 5), v!(0, 1, 0));
+
+        assert!(open < 0.1, "{open}");
+        assert!(corner > open + 0.2, "{corner} {open}");
+    }
+}
@@ -0,0 +1,928 @@
+//! Writing rendered pixel buffers out to disk in the supported image formats
+use crate::{
+    analysis::MID_GREY,
+    aov::Passes,
+    burnin::burn_in,
+    color::ColorSpace,
+    deep::attribute,
+    lut::Lut,
+    post::{chromatic_aberration, vignette, Bloom, Flare, Grain, Guides},
+    Color,
+};
+use image::{imageops::FilterType, DynamicImage, ImageBuffer, ImageFormat, ImageResult, Rgb, Rgba};
+use png::{chunk::ChunkType, BitDepth};
+use serde::Deserialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Plain text 8-bit PPM
+    #[default]
+    Ppm,
+    /// 8-bit PNG
+    Png,
+    /// 16-bit PNG
+    Png16,
+    /// 16-bit TIFF
+    Tiff,
+    /// 32-bit float OpenEXR of the linear HDR values, left for tonemapping in compositing
+    Exr,
+}
+
+impl OutputFormat {
+    pub const fn extension(&self) -> &'static str {
+        match self {
+            Self::Ppm => "ppm",
+            Self::Png | Self::Png16 => "png",
+            Self::Tiff => "tiff",
+            Self::Exr => "exr",
+        }
+    }
+
+    /// The format written to files with the given extension, 8-bit for PNGs.
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_ascii_lowercase().as_str() {
+            "ppm" => Some(Self::Ppm),
+            "png" => Some(Self::Png),
+            "tif" | "tiff" => Some(Self::Tiff),
+            "exr" => Some(Self::Exr),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Output {
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// Write a luminance histogram and false-color image alongside the render
+    #[serde(default)]
+    pub analysis: bool,
+    /// Write a flat shaded single sample preview before starting the full render
+    #[serde(default = "default_preview")]
+    pub preview: bool,
+    /// Report per object and material hit counts at the end of the render
+    #[serde(default)]
+    pub stats: bool,
+    /// Write cryptomatte style object and material ID mattes alongside the render
+    #[serde(default)]
+    pub id_mattes: bool,
+    /// Write a deep EXR of the render binned by depth for deep compositing
+    #[serde(default)]
+    pub deep: bool,
+    /// Write an EXR of per pixel motion since the previous frame alongside the render
+    #[serde(default)]
+    pub motion_vectors: bool,
+    /// Write the light in the render split into diffuse, specular and emission EXRs
+    #[serde(default)]
+    pub passes: Option<Passes>,
+    /// Write an EXR of the depth seen through each pixel with the camera parameters as JSON
+    #[serde(default)]
+    pub depth_map: bool,
+    /// Write an image of flat colors for each tag of the objects and materials seen through each
+    /// pixel with a JSON legend
+    #[serde(default)]
+    pub segmentation: bool,
+    /// Write an EXR of the scene space position of the surface seen through each pixel
+    #[serde(default)]
+    pub position: bool,
+    /// Write an EXR of the position of the surface seen through each pixel in the space of the
+    /// object it belongs to
+    #[serde(default)]
+    pub object_position: bool,
+    /// Write a half resolution EXR of the luminance and its variance after each pass showing
+    /// where the render is still noisy
+    #[serde(default)]
+    pub variance: bool,
+    /// Number of primary ray samples per pixel used for AOVs
+    #[serde(default = "default_aov_samples")]
+    pub aov_samples: u16,
+    /// Stops of exposure applied to the render before post-processing, the same for every frame
+    /// of an animation
+    #[serde(default)]
+    pub exposure: f32,
+    /// Pick the exposure from the first pass of the render so that the median luminance is
+    /// mid-grey, with exposure then applied on top as compensation. This is chosen afresh for
+    /// each frame so animations should use a fixed exposure once a good one has been found.
+    #[serde(default)]
+    pub auto_exposure: bool,
+    /// Bloom applied to bright areas of the image before tonemapping
+    #[serde(default)]
+    pub bloom: Option<Bloom>,
+    /// Lens flare applied to bright areas of the image before tonemapping
+    #[serde(default)]
+    pub flare: Option<Flare>,
+    /// Darkening of the image corners after tonemapping in [0, 1]
+    #[serde(default)]
+    pub vignette: f32,
+    /// Look up table in the .cube format applied to the image after tonemapping, before the
+    /// stylistic effects
+    #[serde(default)]
+    pub lut: Option<Lut>,
+    /// Offset between the red and blue channels in the image corners in pixels
+    #[serde(default)]
+    pub chromatic_aberration: f32,
+    /// Film grain added after tonemapping
+    #[serde(default)]
+    pub grain: Option<Grain>,
+    /// Color space that scene colors are given in and rendering is performed in
+    #[serde(default)]
+    pub working_space: ColorSpace,
+    /// Color space to convert to and encode the output image in. When not set, the working
+    /// space values are written with a simple gamma 2 encoding and no color space tag.
+    #[serde(default)]
+    pub color_space: Option<ColorSpace>,
+    /// Extra pixels rendered beyond each edge of the frame for stabilization in post
+    #[serde(default)]
+    pub overscan: u16,
+    /// Framing guides drawn over a proxy of the image written alongside the render
+    #[serde(default)]
+    pub guides: Option<Guides>,
+    /// Draw the render metadata over the bottom left corner of the image
+    #[serde(default)]
+    pub burn_in: bool,
+    /// Image shown behind the scene in place of the background, which still lights the scene
+    #[serde(default)]
+    pub backplate: Option<String>,
+    /// Render metadata embedded in the image files that are written
+    #[serde(skip)]
+    pub metadata: Vec<(String, String)>,
+}
+
+fn default_preview() -> bool {
+    true
+}
+
+fn default_aov_samples() -> u16 {
+    16
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self {
+            path: None,
+            format: OutputFormat::default(),
+            analysis: false,
+            preview: default_preview(),
+            stats: false,
+            id_mattes: false,
+            deep: false,
+            motion_vectors: false,
+            passes: None,
+            depth_map: false,
+            segmentation: false,
+            position: false,
+            object_position: false,
+            variance: false,
+            aov_samples: default_aov_samples(),
+            exposure: 0.0,
+            auto_exposure: false,
+            bloom: None,
+            flare: None,
+            vignette: 0.0,
+            lut: None,
+            chromatic_aberration: 0.0,
+            grain: None,
+            working_space: ColorSpace::default(),
+            color_space: None,
+            overscan: 0,
+            guides: None,
+            burn_in: false,
+            backplate: None,
+            metadata: Vec::new(),
+        }
+    }
+}
+
+impl Output {
+    /// The path to write to: defaults to "test.$ext" for the selected format.
+    pub fn path(&self) -> String {
+        match &self.path {
+            Some(path) => path.clone(),
+            None => format!("test.{}", self.format.extension()),
+        }
+    }
+
+    /// The path for an auxiliary output written alongside the main image, e.g. for a path of
+    /// "test.ppm" the histogram is written to "test.histogram.csv".
+    pub fn aux_path(&self, name: &str, ext: &str) -> String {
+        let path = self.path();
+
+        Path::new(&path)
+            .with_extension(format!("{name}.{ext}"))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Load the backplate image (if there is one) resized to the given dimensions, converting
+    /// the gamma encoded values to linear colors.
+    pub fn load_backplate(&self, width: u16, height: u16) -> ImageResult<Option<Vec<Color>>> {
+        let path = match &self.backplate {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let img = image::open(path)?
+            .resize_exact(width as u32, height as u32, FilterType::Triangle)
+            .into_rgb8();
+        let pixels = img
+            .pixels()
+            .map(|p| {
+                let c = Color::new(p[0] as f32, p[1] as f32, p[2] as f32) / 255.0;
+                c * c
+            })
+            .collect();
+
+        Ok(Some(pixels))
+    }
+
+    /// This output with the exposure for the linear HDR pixels added when auto exposure is
+    /// enabled.
+    pub fn auto_exposed(&self, pixels: &[Color]) -> Output {
+        let mut output = self.clone();
+        if self.auto_exposure {
+            let stops = auto_exposure(pixels);
+            eprintln!("\nAuto exposure: {stops:+.2} stops");
+            output.exposure += stops;
+        }
+
+        output
+    }
+
+    /// Apply any configured post-processing effects to the linear HDR pixels.
+    pub fn post_process(&self, width: u16, height: u16, pixels: &[Color]) -> Vec<Color> {
+        let (w, h) = (width as usize, height as usize);
+        let gain = self.exposure.exp2();
+        let mut processed: Vec<Color> = pixels.iter().map(|&c| c * gain).collect();
+        if let Some(bloom) = &self.bloom {
+            bloom.apply(&mut processed, w, h);
+        }
+        if let Some(flare) = &self.flare {
+            flare.apply(&mut processed, w, h);
+        }
+
+        processed
+    }
+
+    /// Tonemap the linear HDR pixels to gamma encoded display values and apply any configured
+    /// stylistic effects.
+    pub fn tonemap(&self, width: u16, height: u16, pixels: &[Color]) -> Vec<Color> {
+        let (w, h) = (width as usize, height as usize);
+        let mut encoded: Vec<Color> = match self.color_space {
+            Some(cs) => pixels
+                .iter()
+                .map(|&c| {
+                    let c = self.working_space.convert(c, cs);
+                    Color::new(cs.encode(c.r), cs.encode(c.g), cs.encode(c.b))
+                })
+                .collect(),
+            None => pixels.iter().map(Color::gamma_encoded).collect(),
+        };
+        if let Some(lut) = &self.lut {
+            encoded.iter_mut().for_each(|c| *c = lut.apply(*c));
+        }
+        if self.chromatic_aberration > 0.0 {
+            chromatic_aberration(&mut encoded, w, h, self.chromatic_aberration);
+        }
+        if self.vignette > 0.0 {
+            vignette(&mut encoded, w, h, self.vignette);
+        }
+        if let Some(grain) = &self.grain {
+            grain.apply(&mut encoded);
+        }
+
+        encoded
+    }
+
+    /// Write the linear HDR pixels to the output path. If alpha is given then pixels are taken
+    /// to be premultiplied by it and formats that support transparency are written with an alpha
+    /// channel. EXRs are written without tonemapping, stylistic effects or burn in.
+    pub fn write(
+        &self,
+        width: u16,
+        height: u16,
+        pixels: &[Color],
+        alpha: Option<&[f32]>,
+    ) -> ImageResult<()> {
+        let path = self.path();
+        let (w, h) = (width as u32, height as u32);
+        let linear = pixels;
+        let straight: Vec<Color>;
+        let pixels = match alpha {
+            Some(alpha) => {
+                straight = pixels
+                    .iter()
+                    .zip(alpha)
+                    .map(|(&c, &a)| if a > 0.0 { c / a } else { c })
+                    .collect();
+                &straight
+            }
+            None => pixels,
+        };
+        let mut pixels = self.tonemap(width, height, pixels);
+        if self.burn_in {
+            let lines: Vec<String> = self
+                .metadata
+                .iter()
+                .map(|(k, v)| format!("{k}: {v}"))
+                .collect();
+            burn_in(&mut pixels, w as usize, h as usize, &lines);
+        }
+
+        if let Some(guides) = &self.guides {
+            let mut proxy = pixels.clone();
+            guides.draw(&mut proxy, w as usize, h as usize, self.overscan as usize);
+            let path = self.aux_path("guides", "png");
+            self.save_png(&path, w, h, BitDepth::Eight, false, |j, row| {
+                encode_row(&proxy, None, w as usize, j, BitDepth::Eight, row)
+            })?;
+        }
+
+        match self.format {
+            OutputFormat::Ppm => self.save_ppm(&path, w, h, &pixels)?,
+
+            OutputFormat::Png | OutputFormat::Png16 => {
+                let depth = if self.format == OutputFormat::Png {
+                    BitDepth::Eight
+                } else {
+                    BitDepth::Sixteen
+                };
+                self.save_png(&path, w, h, depth, alpha.is_some(), |j, row| {
+                    encode_row(&pixels, alpha, w as usize, j, depth, row)
+                })?;
+            }
+
+            OutputFormat::Tiff => {
+                image16(w, h, &pixels, alpha).save_with_format(path, ImageFormat::Tiff)?;
+            }
+
+            OutputFormat::Exr => self.save_exr(&path, w, h, linear, alpha)?,
+        }
+
+        Ok(())
+    }
+
+    /// Write the linear pixels as 32-bit floats, converted to the primaries of the output color
+    /// space if one was given. Pixels stay premultiplied by alpha as compositors expect of EXRs.
+    /// As with PNGs, the render metadata and the chromaticities of the color space are written
+    /// as header attributes.
+    fn save_exr(
+        &self,
+        path: &str,
+        w: u32,
+        h: u32,
+        pixels: &[Color],
+        alpha: Option<&[f32]>,
+    ) -> io::Result<()> {
+        let pixels: Vec<Color> = match self.color_space {
+            Some(cs) => (pixels.iter())
+                .map(|&c| self.working_space.convert(c, cs))
+                .collect(),
+            None => pixels.to_vec(),
+        };
+        let exr = flat_exr(
+            w as usize,
+            h as usize,
+            &pixels,
+            alpha,
+            self.color_space,
+            &self.metadata,
+        );
+
+        std::fs::write(path, exr)
+    }
+
+    /// Stream a plain text PPM to disk a row at a time with the render metadata as comments.
+    /// Samples are padded to a fixed width so that each row sits at a known offset: when the
+    /// previous pass of a progressive render is still on disk with the same header, only the
+    /// rows whose encoded values have changed are rewritten in place.
+    fn save_ppm(&self, path: &str, w: u32, h: u32, pixels: &[Color]) -> io::Result<()> {
+        let mut header = String::from("P3\n");
+        for (k, v) in self.metadata.iter() {
+            header.push_str(&format!("# {k}: {v}\n"));
+        }
+        header.push_str(&format!("{w} {h}\n255\n"));
+
+        let row_len = w as usize * 12; // "rrr ggg bbb\n"
+        let encode_row = |j: usize| -> Vec<u8> {
+            let mut row = Vec::with_capacity(row_len);
+            for c in &pixels[j * w as usize..(j + 1) * w as usize] {
+                let [r, g, b] = c.encoded_to_rgb8();
+                row.extend(format!("{r:>3} {g:>3} {b:>3}\n").as_bytes());
+            }
+            row
+        };
+
+        let len = (header.len() + row_len * h as usize) as u64;
+        if let Ok(mut f) = OpenOptions::new().read(true).write(true).open(path) {
+            let mut existing = vec![0; header.len()];
+            if f.metadata()?.len() == len
+                && f.read_exact(&mut existing).is_ok()
+                && existing == header.as_bytes()
+            {
+                let rows = (0..h as usize).map(encode_row);
+                rewrite_changed_rows(&mut f, header.len(), rows)?;
+                return Ok(());
+            }
+        }
+
+        let mut f = BufWriter::new(File::create(path)?);
+        f.write_all(header.as_bytes())?;
+        for j in 0..h as usize {
+            f.write_all(&encode_row(j))?;
+        }
+
+        f.flush()
+    }
+
+    /// Stream a PNG to disk a row at a time, tagging it with the output color space if one was
+    /// given and embedding the render metadata as text chunks. encode_row is called with each
+    /// row index and an empty buffer to fill with that row's samples.
+    fn save_png(
+        &self,
+        path: &str,
+        w: u32,
+        h: u32,
+        depth: BitDepth,
+        has_alpha: bool,
+        mut encode_row: impl FnMut(usize, &mut Vec<u8>),
+    ) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), w, h);
+        encoder.set_color(if has_alpha {
+            png::ColorType::Rgba
+        } else {
+            png::ColorType::Rgb
+        });
+        encoder.set_depth(depth);
+
+        // ancillary chunks must come before the image data
+        let mut writer = encoder.write_header()?;
+        for (k, v) in self.metadata.iter() {
+            let text = [k.as_bytes(), &[0], v.as_bytes()].concat();
+            writer.write_chunk(ChunkType(*b"tEXt"), &text)?;
+        }
+        if let Some(cs) = self.color_space {
+            for (ty, data) in png_color_chunks(cs) {
+                writer.write_chunk(ChunkType(ty), &data)?;
+            }
+        }
+
+        let mut stream = writer.stream_writer()?;
+        let mut row = Vec::new();
+        for j in 0..h as usize {
+            row.clear();
+            encode_row(j, &mut row);
+            stream.write_all(&row)?;
+        }
+        stream.finish()?;
+
+        Ok(())
+    }
+}
+
+/// Append the encoded samples of row j of the pixels, with an alpha channel if alpha is given,
+/// to buf as big endian 8 or 16-bit values.
+fn encode_row(
+    pixels: &[Color],
+    alpha: Option<&[f32]>,
+    w: usize,
+    j: usize,
+    depth: BitDepth,
+    buf: &mut Vec<u8>,
+) {
+    let row = j * w..(j + 1) * w;
+    for (n, c) in pixels[row.clone()].iter().enumerate() {
+        let a = alpha.map(|alpha| alpha[row.start + n].clamp(0.0, 1.0));
+        if depth == BitDepth::Sixteen {
+            c.encoded_to_rgb16()
+                .into_iter()
+                .chain(a.map(|a| (a * 65535.0).round() as u16))
+                .for_each(|v| buf.extend(v.to_be_bytes()));
+        } else {
+            buf.extend(c.encoded_to_rgb8());
+            buf.extend(a.map(|a| (a * 255.0).round() as u8));
+        }
+    }
+}
+
+/// A 16-bit image of the encoded pixels with an alpha channel if alpha is given.
+fn image16(w: u32, h: u32, pixels: &[Color], alpha: Option<&[f32]>) -> DynamicImage {
+    match alpha {
+        Some(alpha) => {
+            let raw = pixels
+                .iter()
+                .zip(alpha)
+                .flat_map(|(c, a)| {
+                    let [r, g, b] = c.encoded_to_rgb16();
+                    [r, g, b, (a.clamp(0.0, 1.0) * 65535.0).round() as u16]
+                })
+                .collect();
+            ImageBuffer::<Rgba<u16>, Vec<u16>>::from_raw(w, h, raw)
+                .unwrap()
+                .into()
+        }
+        None => {
+            let raw = pixels.iter().flat_map(|c| c.encoded_to_rgb16()).collect();
+            ImageBuffer::<Rgb<u16>, Vec<u16>>::from_raw(w, h, raw)
+                .unwrap()
+                .into()
+        }
+    }
+}
+
+/// PNG chunks (type and data) describing the given color space: chromaticities (cHRM) and gamma
+/// (gAMA) for all readers, the sRGB chunk where applicable and coding-independent code points
+/// (cICP) for spaces that have them.
+///   https://www.w3.org/TR/png-3/#11addnlcolinfo
+fn png_color_chunks(cs: ColorSpace) -> Vec<([u8; 4], Vec<u8>)> {
+    let chrm: Vec<u8> = cs
+        .chromaticities()
+        .iter()
+        .flatten()
+        .flat_map(|v| ((v * 100_000.0).round() as u32).to_be_bytes())
+        .collect();
+    let mut chunks = vec![(*b"cHRM", chrm)];
+
+    match cs {
+        ColorSpace::Srgb => {
+            chunks.push((*b"sRGB", vec![0])); // perceptual rendering intent
+            chunks.push((*b"gAMA", 45_455u32.to_be_bytes().to_vec()));
+            chunks.push((*b"cICP", vec![1, 13, 0, 1]));
+        }
+        ColorSpace::Rec709 => chunks.push((*b"cICP", vec![1, 1, 0, 1])),
+        ColorSpace::AcesCg => chunks.push((*b"gAMA", 100_000u32.to_be_bytes().to_vec())),
+    }
+
+    chunks
+}
+
+/// Overwrite the equal length rows of a file starting at offset with any of the given rows that
+/// differ from what is already there, returning the number of rows that were rewritten.
+fn rewrite_changed_rows<F: Read + Write + Seek>(
+    f: &mut F,
+    offset: usize,
+    rows: impl Iterator<Item = Vec<u8>>,
+) -> io::Result<usize> {
+    let mut pos = offset as u64;
+    let mut old = Vec::new();
+    let mut rewritten = 0;
+    for row in rows {
+        old.resize(row.len(), 0);
+        f.seek(SeekFrom::Start(pos))?;
+        f.read_exact(&mut old)?;
+        if row != old {
+            f.seek(SeekFrom::Start(pos))?;
+            f.write_all(&row)?;
+            rewritten += 1;
+        }
+        pos += row.len() as u64;
+    }
+
+    Ok(rewritten)
+}
+
+/// Encode an uncompressed single part scanline EXR with (A,) B, G and R float channels and one
+/// scanline per chunk. Metadata is written as string header attributes along with the
+/// chromaticities of the color space if one was given.
+///   https://openexr.com/en/latest/OpenEXRFileLayout.html
+fn flat_exr(
+    width: usize,
+    height: usize,
+    pixels: &[Color],
+    alpha: Option<&[f32]>,
+    color_space: Option<ColorSpace>,
+    metadata: &[(String, String)],
+) -> Vec<u8> {
+    const FLOAT: i32 = 2;
+    let channels: &[&str] = if alpha.is_some() {
+        &["A", "B", "G", "R"] // must be sorted
+    } else {
+        &["B", "G", "R"]
+    };
+
+    let mut buf = Vec::new();
+    buf.extend(20000630i32.to_le_bytes()); // magic number
+    buf.extend(2i32.to_le_bytes()); // version 2 with no flags set
+
+    let mut chlist = Vec::new();
+    for name in channels {
+        chlist.extend(name.as_bytes());
+        chlist.push(0);
+        chlist.extend(FLOAT.to_le_bytes());
+        chlist.extend([0, 0, 0, 0]); // pLinear and reserved
+        chlist.extend(1i32.to_le_bytes()); // x sampling
+        chlist.extend(1i32.to_le_bytes()); // y sampling
+    }
+    chlist.push(0);
+
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+
+    attribute(&mut buf, "channels", "chlist", &chlist);
+    if let Some(cs) = color_space {
+        // EXR orders these red, green, blue then white
+        let [white, r, g, b] = cs.chromaticities();
+        let chromaticities: Vec<u8> = [r, g, b, white]
+            .iter()
+            .flatten()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        attribute(
+            &mut buf,
+            "chromaticities",
+            "chromaticities",
+            &chromaticities,
+        );
+    }
+    attribute(&mut buf, "compression", "compression", &[0]);
+    attribute(&mut buf, "dataWindow", "box2i", &window);
+    attribute(&mut buf, "displayWindow", "box2i", &window);
+    attribute(&mut buf, "lineOrder", "lineOrder", &[0]);
+    attribute(&mut buf, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute(&mut buf, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut buf,
+        "screenWindowWidth",
+        "float",
+        &1.0f32.to_le_bytes(),
+    );
+    for (k, v) in metadata {
+        attribute(&mut buf, k, "string", v.as_bytes());
+    }
+    buf.push(0); // end of header
+
+    let chunks: Vec<Vec<u8>> = pixels
+        .chunks(width)
+        .enumerate()
+        .map(|(y, row)| {
+            let channel = |f: &dyn Fn(usize, &Color) -> f32| -> Vec<u8> {
+                row.iter()
+                    .enumerate()
+                    .flat_map(|(x, c)| f(x, c).to_le_bytes())
+                    .collect()
+            };
+            let mut data = Vec::new();
+            if let Some(alpha) = alpha {
+                data.extend(channel(&|x, _| alpha[y * width + x]));
+            }
+            data.extend(channel(&|_, c| c.b));
+            data.extend(channel(&|_, c| c.g));
+            data.extend(channel(&|_, c| c.r));
+
+            let mut chunk = (y as i32).to_le_bytes().to_vec();
+            chunk.extend((data.len() as i32).to_le_bytes());
+            chunk.extend(data);
+
+            chunk
+        })
+        .collect();
+
+    // offset table of the absolute file position of each chunk
+    let mut pos = (buf.len() + 8 * chunks.len()) as u64;
+    for c in chunks.iter() {
+        buf.extend(pos.to_le_bytes());
+        pos += c.len() as u64;
+    }
+    for c in chunks {
+        buf.extend(c);
+    }
+
+    buf
+}
+
+/// Stops of exposure placing the median luminance of the pixels at mid-grey. Pixels without any
+/// light (such as a black background) are ignored so that they don't drag the median down.
+pub fn auto_exposure(pixels: &[Color]) -> f32 {
+    let mut lum: Vec<f32> = pixels
+        .iter()
+        .map(Color::luminance)
+        .filter(|l| *l > 0.0 && l.is_finite())
+        .collect();
+    if lum.is_empty() {
+        return 0.0;
+    }
+    let mid = lum.len() / 2;
+    let (_, median, _) = lum.select_nth_unstable_by(mid, f32::total_cmp);
+
+    (MID_GREY / *median).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+    use std::fs;
+
+    #[test]
+    fn exposure_is_applied_in_stops() {
+        let output = Output {
+            exposure: -1.0,
+            ..Default::default()
+        };
+
+        let processed = output.post_process(1, 1, &[Color::grey(3.0)]);
+
+        assert_eq!(processed, vec![Color::grey(1.5)]);
+    }
+
+    #[test_case(&[0.36, 0.36, 0.36], -1.0; "bright")]
+    #[test_case(&[0.0, 0.0, 0.01, 0.045, 100.0], 2.0; "black pixels ignored")]
+    #[test_case(&[0.0, 0.0], 0.0; "all black")]
+    #[test]
+    fn auto_exposure_puts_the_median_at_mid_grey(greys: &[f32], stops: f32) {
+        let pixels: Vec<Color> = greys.iter().map(|&g| Color::grey(g)).collect();
+
+        assert!((auto_exposure(&pixels) - stops).abs() < 1e-5);
+    }
+
+    #[test]
+    fn tagged_png_with_metadata_can_be_decoded() {
+        let output = Output {
+            color_space: Some(ColorSpace::Srgb),
+            metadata: vec![("Scene".to_string(), "scene.toml".to_string())],
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join("raymart_tagged.png");
+        let img: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_pixel(4, 4, Rgb([10, 20, 30]));
+        output
+            .save_png(
+                &path.to_string_lossy(),
+                4,
+                4,
+                BitDepth::Eight,
+                false,
+                |_, row| row.extend([10, 20, 30].repeat(4)),
+            )
+            .unwrap();
+
+        let decoded = image::open(&path).unwrap().into_rgb8();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(decoded, img);
+        assert!(bytes.windows(4).any(|w| w == b"sRGB"));
+        assert!(bytes.windows(20).any(|w| w == b"tEXtScene\0scene.toml"));
+    }
+
+    #[test]
+    fn transparent_pixels_are_written_with_straight_alpha() {
+        let path = std::env::temp_dir().join("raymart_alpha.png");
+        let output = Output {
+            path: Some(path.to_string_lossy().into_owned()),
+            format: OutputFormat::Png,
+            ..Default::default()
+        };
+        // a fully covered pixel, a half covered one and a miss
+        let pixels = [Color::grey(0.25), Color::grey(0.125), Color::BLACK];
+        output.write(3, 1, &pixels, Some(&[1.0, 0.5, 0.0])).unwrap();
+
+        let decoded = image::open(&path).unwrap().into_rgba8();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(decoded.get_pixel(0, 0).0, [128, 128, 128, 255]);
+        assert_eq!(decoded.get_pixel(1, 0).0, [128, 128, 128, 128]);
+        assert_eq!(decoded.get_pixel(2, 0).0[3], 0);
+    }
+
+    #[test]
+    fn sixteen_bit_png_rows_are_big_endian() {
+        let path = std::env::temp_dir().join("raymart_png16.png");
+        let output = Output {
+            path: Some(path.to_string_lossy().into_owned()),
+            format: OutputFormat::Png16,
+            ..Default::default()
+        };
+        let pixels = [Color::WHITE, Color::BLACK, Color::BLACK, Color::WHITE];
+        output.write(2, 2, &pixels, None).unwrap();
+
+        let decoded = image::open(&path).unwrap().into_rgb16();
+        fs::remove_file(&path).unwrap();
+
+        // white is very slightly compressed by tone mapping: byte swapped it would be < 0xff00
+        assert!(decoded.get_pixel(0, 0).0.iter().all(|&v| v > 0xff00));
+        assert_eq!(decoded.get_pixel(1, 0).0, [0; 3]);
+        assert_eq!(decoded.get_pixel(0, 1).0, [0; 3]);
+    }
+
+    #[test]
+    fn exr_keeps_values_over_one_and_premultiplied_alpha() {
+        let path = std::env::temp_dir().join("raymart_hdr.exr");
+        let output = Output {
+            path: Some(path.to_string_lossy().into_owned()),
+            format: OutputFormat::Exr,
+            ..Default::default()
+        };
+        let pixels = [Color::new(8.0, 0.5, 0.0), Color::grey(0.125)];
+        output.write(2, 1, &pixels, Some(&[1.0, 0.5])).unwrap();
+
+        let decoded = image::open(&path).unwrap().into_rgba32f();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(decoded.get_pixel(0, 0).0, [8.0, 0.5, 0.0, 1.0]);
+        assert_eq!(decoded.get_pixel(1, 0).0, [0.125, 0.125, 0.125, 0.5]);
+    }
+
+    #[test]
+    fn exr_headers_carry_metadata_and_chromaticities() {
+        let path = std::env::temp_dir().join("raymart_header.exr");
+        let output = Output {
+            path: Some(path.to_string_lossy().into_owned()),
+            format: OutputFormat::Exr,
+            color_space: Some(ColorSpace::AcesCg),
+            metadata: vec![("samples".to_string(), "64".to_string())],
+            ..Default::default()
+        };
+        output.write(1, 1, &[Color::grey(0.5)], None).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        let decoded = image::open(&path).unwrap().into_rgb32f();
+        fs::remove_file(&path).unwrap();
+
+        let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"samples\0string\0\x02\0\0\x0064"));
+        let red: Vec<u8> = [0.713f32, 0.293]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        assert!(contains(
+            &[b"chromaticities\0chromaticities\0\x20\0\0\0", &red[..]].concat()
+        ));
+        assert!((decoded.get_pixel(0, 0).0[0] - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn ppm_is_written_with_metadata_comments() {
+        let path = std::env::temp_dir().join("raymart_stream.ppm");
+        let output = Output {
+            path: Some(path.to_string_lossy().into_owned()),
+            metadata: vec![("Scene".to_string(), "scene.toml".to_string())],
+            ..Default::default()
+        };
+        output
+            .write(2, 1, &[Color::WHITE, Color::BLACK], None)
+            .unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            text,
+            "P3\n# Scene: scene.toml\n2 1\n255\n255 255 255\n  0   0   0\n"
+        );
+    }
+
+    #[test]
+    fn progressive_ppm_passes_only_rewrite_changed_rows() {
+        let path = std::env::temp_dir().join("raymart_progressive.ppm");
+        let output = Output {
+            path: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let first = [Color::WHITE, Color::BLACK, Color::grey(0.2), Color::BLACK];
+        let second = [Color::WHITE, Color::BLACK, Color::grey(0.5), Color::WHITE];
+
+        output.write(2, 2, &first, None).unwrap();
+        let mut f = io::Cursor::new(fs::read(&path).unwrap());
+        output.write(2, 2, &second, None).unwrap();
+        let progressive = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        output.write(2, 2, &second, None).unwrap();
+        let full = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let header = b"P3\n2 2\n255\n".len();
+        let rows = full[header..].chunks(24).map(|r| r.to_vec());
+        let rewritten = rewrite_changed_rows(&mut f, header, rows).unwrap();
+
+        assert_eq!(progressive, full);
+        assert_eq!(rewritten, 1);
+        assert_eq!(f.into_inner(), full);
+    }
+
+    #[test]
+    fn backplate_is_resized_and_linearized() {
+        let path = std::env::temp_dir().join("raymart_backplate.png");
+        let img: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_pixel(8, 4, Rgb([255, 51, 0]));
+        img.save(&path).unwrap();
+        let output = Output {
+            backplate: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+
+        let plate = output.load_backplate(4, 2).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(plate.len(), 8);
+        assert!(plate
+            .iter()
+            .all(|c| (c.r - 1.0).abs() < 1e-6 && (c.g - 0.04).abs() < 1e-6));
+    }
+}
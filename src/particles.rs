@@ -0,0 +1,375 @@
+//! Import of particle data (simulation output, point clouds, scientific data) from CSV or PLY
+//! files, rendered as either spheres or camera facing disks.
+//!
+//! CSV files have one particle per line with columns determined by their count:
+//!   x,y,z | x,y,z,radius | x,y,z,r,g,b | x,y,z,radius,r,g,b
+//! Lines starting with '#' or that can not be parsed as numbers (e.g. headers) are skipped.
+//!
+//! PLY files may be ASCII or binary little endian and are read from the vertex element using the
+//! x, y, z properties along with the optional radius (or scale) and red, green, blue properties.
+//! Integer colors are assumed to be in the range [0, 255].
+//!   https://en.wikipedia.org/wiki/PLY_(file_format)
+use crate::{
+    hit::{Disk, Hittable, Sphere},
+    material::Material,
+    scene::Tag,
+    Color, P3,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParticleShape {
+    #[default]
+    Sphere,
+    Disk,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Particles {
+    pub path: String,
+    /// Material used for particles without a color of their own
+    pub material: String,
+    /// Radius used for particles without a radius of their own
+    #[serde(default = "default_radius")]
+    pub radius: f32,
+    #[serde(default)]
+    pub shape: ParticleShape,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Inclusive range of animation frames in which the particles are visible
+    #[serde(default)]
+    pub visible_frames: Option<[u32; 2]>,
+    /// Label for segmentation, in place of the tag of the material
+    #[serde(default)]
+    pub tag: Option<Tag>,
+}
+
+fn default_radius() -> f32 {
+    0.01
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Particle {
+    pub p: P3,
+    pub radius: Option<f32>,
+    pub color: Option<Color>,
+}
+
+impl Particles {
+    /// Load the particles and convert them to hittables. Disks are oriented to face the camera
+    /// position.
+    pub fn as_hittables(&self, mat: &'static Material, camera: P3) -> io::Result<Vec<Hittable>> {
+        let particles = load_particles(&self.path)?;
+        let mut colored: HashMap<[u8; 3], &'static Material> = HashMap::new();
+
+        let hittables = particles
+            .into_iter()
+            .map(|pt| {
+                let radius = pt.radius.unwrap_or(self.radius);
+                // share materials between particles with the same (8-bit) color
+                let mat = match pt.color {
+                    Some(c) => *colored
+                        .entry(c.to_rgb8())
+                        .or_insert_with(|| Box::leak(Box::new(Material::solid_color(c)))),
+                    None => mat,
+                };
+
+                match self.shape {
+                    ParticleShape::Sphere => Sphere::new(pt.p, radius, mat).into(),
+                    ParticleShape::Disk => Disk::new(pt.p, camera - pt.p, radius, mat).into(),
+                }
+            })
+            .collect();
+
+        Ok(hittables)
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+/// Load particles from path as PLY if it has a ".ply" extension and CSV otherwise.
+pub fn load_particles(path: &str) -> io::Result<Vec<Particle>> {
+    let bytes = fs::read(path)?;
+    let is_ply = Path::new(path).extension().is_some_and(|ext| ext == "ply");
+
+    if is_ply {
+        parse_ply(&bytes)
+    } else {
+        Ok(parse_csv(&String::from_utf8_lossy(&bytes)))
+    }
+}
+
+pub fn parse_csv(s: &str) -> Vec<Particle> {
+    let mut particles = Vec::new();
+
+    for line in s.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Ok(vals) = line
+            .split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+        else {
+            continue; // header or otherwise malformed
+        };
+
+        let (radius, color) = match vals[..] {
+            [_, _, _] => (None, None),
+            [_, _, _, r] => (Some(r), None),
+            [_, _, _, r, g, b] => (None, Some(Color::new(r, g, b))),
+            [_, _, _, rad, r, g, b] => (Some(rad), Some(Color::new(r, g, b))),
+            _ => continue,
+        };
+
+        particles.push(Particle {
+            p: P3::new(vals[0], vals[1], vals[2]),
+            radius,
+            color,
+        });
+    }
+
+    particles
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyType {
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => return None,
+        })
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+
+    fn read_le(&self, b: &[u8]) -> f32 {
+        match self {
+            Self::I8 => b[0] as i8 as f32,
+            Self::U8 => b[0] as f32,
+            Self::I16 => i16::from_le_bytes([b[0], b[1]]) as f32,
+            Self::U16 => u16::from_le_bytes([b[0], b[1]]) as f32,
+            Self::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32,
+            Self::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32,
+            Self::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            Self::F64 => f64::from_le_bytes(b[..8].try_into().unwrap()) as f32,
+        }
+    }
+
+    fn is_float(&self) -> bool {
+        matches!(self, Self::F32 | Self::F64)
+    }
+}
+
+pub fn parse_ply(bytes: &[u8]) -> io::Result<Vec<Particle>> {
+    const END: &[u8] = b"end_header\n";
+    let header_len = bytes
+        .windows(END.len())
+        .position(|w| w == END)
+        .ok_or_else(|| invalid("missing end_header"))?
+        + END.len();
+    let header = String::from_utf8_lossy(&bytes[..header_len]);
+    let body = &bytes[header_len..];
+
+    let mut format = None;
+    let mut n_vertices = 0;
+    let mut props: Vec<(String, PlyType)> = Vec::new();
+    let mut in_vertex = false;
+
+    for line in header.lines() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts[..] {
+            ["format", "ascii", _] => format = Some(PlyFormat::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(PlyFormat::BinaryLittleEndian),
+            ["format", f, _] => return Err(invalid(format!("unsupported PLY format: {f}"))),
+            ["element", "vertex", n] => {
+                in_vertex = true;
+                n_vertices = n.parse().map_err(|_| invalid("invalid vertex count"))?;
+            }
+            // we only read the vertex data so it needs to come first
+            ["element", ..] if n_vertices == 0 => {
+                return Err(invalid("vertex must be the first PLY element"))
+            }
+            ["element", ..] => in_vertex = false,
+            ["property", "list", ..] if in_vertex => {
+                return Err(invalid("list properties on vertices are not supported"))
+            }
+            ["property", ty, name] if in_vertex => {
+                let ty = PlyType::parse(ty).ok_or_else(|| invalid(format!("unknown type {ty}")))?;
+                props.push((name.to_string(), ty));
+            }
+            _ => (),
+        }
+    }
+
+    let format = format.ok_or_else(|| invalid("missing PLY format"))?;
+    let index = |name: &str| props.iter().position(|(n, _)| n == name);
+    let (ix, iy, iz) = match (index("x"), index("y"), index("z")) {
+        (Some(x), Some(y), Some(z)) => (x, y, z),
+        _ => return Err(invalid("PLY vertices must have x, y and z properties")),
+    };
+    let irad = index("radius").or_else(|| index("scale"));
+    let icol = match (index("red"), index("green"), index("blue")) {
+        (Some(r), Some(g), Some(b)) => Some((r, g, b)),
+        _ => None,
+    };
+    let col_scale = match icol {
+        Some((r, _, _)) if !props[r].1.is_float() => 1.0 / 255.0,
+        _ => 1.0,
+    };
+
+    let rows: Vec<Vec<f32>> = match format {
+        PlyFormat::Ascii => {
+            let rows: Vec<Vec<f32>> = String::from_utf8_lossy(body)
+                .lines()
+                .take(n_vertices)
+                .map(|line| {
+                    line.split_whitespace()
+                        .map(|v| v.parse::<f32>().map_err(|_| invalid("invalid PLY value")))
+                        .collect()
+                })
+                .collect::<io::Result<_>>()?;
+            if rows.len() < n_vertices {
+                return Err(invalid("truncated PLY vertex data"));
+            }
+            rows
+        }
+
+        PlyFormat::BinaryLittleEndian => {
+            let stride: usize = props.iter().map(|(_, ty)| ty.size()).sum();
+            if body.len() < stride * n_vertices {
+                return Err(invalid("truncated PLY vertex data"));
+            }
+            body.chunks_exact(stride)
+                .take(n_vertices)
+                .map(|row| {
+                    let mut offset = 0;
+                    props
+                        .iter()
+                        .map(|(_, ty)| {
+                            let v = ty.read_le(&row[offset..]);
+                            offset += ty.size();
+                            v
+                        })
+                        .collect()
+                })
+                .collect()
+        }
+    };
+
+    rows.into_iter()
+        .map(|row| {
+            if row.len() < props.len() {
+                return Err(invalid("PLY vertex has too few values"));
+            }
+            Ok(Particle {
+                p: P3::new(row[ix], row[iy], row[iz]),
+                radius: irad.map(|i| row[i]),
+                color: icol.map(|(r, g, b)| Color::new(row[r], row[g], row[b]) * col_scale),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case("1,2,3", None, None; "position only")]
+    #[test_case("1,2,3,0.5", Some(0.5), None; "with radius")]
+    #[test_case("1,2,3,0.1,0.2,0.3", None, Some(Color::new(0.1, 0.2, 0.3)); "with color")]
+    #[test_case("1, 2, 3, 0.5, 0.1, 0.2, 0.3", Some(0.5), Some(Color::new(0.1, 0.2, 0.3)); "with both")]
+    #[test]
+    fn parse_csv_works(line: &str, radius: Option<f32>, color: Option<Color>) {
+        let s = format!("# comment\nx,y,z\n{line}\n");
+        let particles = parse_csv(&s);
+
+        let expected = Particle {
+            p: P3::new(1.0, 2.0, 3.0),
+            radius,
+            color,
+        };
+        assert_eq!(particles, vec![expected]);
+    }
+
+    #[test]
+    fn parse_ascii_ply_works() {
+        let s = "ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\nproperty float y\n\
+                 property float z\nproperty uchar red\nproperty uchar green\nproperty uchar blue\n\
+                 end_header\n1 2 3 255 0 0\n4 5 6 0 255 0\n";
+        let particles = parse_ply(s.as_bytes()).unwrap();
+
+        assert_eq!(particles.len(), 2);
+        assert_eq!(particles[1].p, P3::new(4.0, 5.0, 6.0));
+        assert_eq!(particles[0].color, Some(Color::new(1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn ascii_ply_with_missing_vertices_is_an_error() {
+        let s = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+                 property float z\nend_header\n1 2 3\n4 5 6\n";
+        let err = parse_ply(s.as_bytes()).unwrap_err();
+
+        assert_eq!(err.to_string(), "truncated PLY vertex data");
+    }
+
+    #[test]
+    fn parse_binary_ply_works() {
+        let mut bytes = b"ply\nformat binary_little_endian 1.0\nelement vertex 1\n\
+                          property float x\nproperty float y\nproperty float z\n\
+                          property float radius\nend_header\n"
+            .to_vec();
+        for v in [1.0f32, 2.0, 3.0, 0.25] {
+            bytes.extend(v.to_le_bytes());
+        }
+        let particles = parse_ply(&bytes).unwrap();
+
+        let expected = Particle {
+            p: P3::new(1.0, 2.0, 3.0),
+            radius: Some(0.25),
+            color: None,
+        };
+        assert_eq!(particles, vec![expected]);
+    }
+}
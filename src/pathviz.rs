@@ -0,0 +1,90 @@
+//! Export of traced light paths as polylines so that light transport issues (rays escaping
+//! through cracks, total internal reflection loops etc) can be inspected in external tools
+//! such as Blender.
+//!   https://en.wikipedia.org/wiki/Wavefront_.obj_file
+//!   https://en.wikipedia.org/wiki/PLY_(file_format)
+use crate::P3;
+use std::{fmt::Write, fs, io, path::Path};
+
+/// Render paths as an OBJ file with one line element per path.
+pub fn to_obj(paths: &[Vec<P3>]) -> String {
+    let mut s = String::from("# raymart debug paths\n");
+    let mut offset = 1; // OBJ indices are 1-based
+
+    for p in paths.iter() {
+        for v in p.iter() {
+            writeln!(s, "v {} {} {}", v.x, v.y, v.z).unwrap();
+        }
+        let ixs: Vec<String> = (offset..offset + p.len()).map(|i| i.to_string()).collect();
+        writeln!(s, "l {}", ixs.join(" ")).unwrap();
+        offset += p.len();
+    }
+
+    s
+}
+
+/// Render paths as an ASCII PLY file with one edge per path segment.
+pub fn to_ply(paths: &[Vec<P3>]) -> String {
+    let n_vertices: usize = paths.iter().map(|p| p.len()).sum();
+    let n_edges: usize = paths.iter().map(|p| p.len().saturating_sub(1)).sum();
+
+    let mut s = String::new();
+    s.push_str("ply\nformat ascii 1.0\ncomment raymart debug paths\n");
+    writeln!(s, "element vertex {n_vertices}").unwrap();
+    s.push_str("property float x\nproperty float y\nproperty float z\n");
+    writeln!(s, "element edge {n_edges}").unwrap();
+    s.push_str("property int vertex1\nproperty int vertex2\nend_header\n");
+
+    for v in paths.iter().flatten() {
+        writeln!(s, "{} {} {}", v.x, v.y, v.z).unwrap();
+    }
+
+    let mut offset = 0;
+    for p in paths.iter() {
+        for i in 1..p.len() {
+            writeln!(s, "{} {}", offset + i - 1, offset + i).unwrap();
+        }
+        offset += p.len();
+    }
+
+    s
+}
+
+/// Write the given paths to path as PLY if it has a ".ply" extension and OBJ otherwise.
+pub fn write_paths(path: &str, paths: &[Vec<P3>]) -> io::Result<()> {
+    let is_ply = Path::new(path).extension().is_some_and(|ext| ext == "ply");
+    let s = if is_ply { to_ply(paths) } else { to_obj(paths) };
+
+    fs::write(path, s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p;
+
+    fn paths() -> Vec<Vec<P3>> {
+        vec![
+            vec![p!(0, 0, 0), p!(1, 0, 0), p!(1, 1, 0)],
+            vec![p!(0, 0, 0), p!(0, 0, 1)],
+        ]
+    }
+
+    #[test]
+    fn obj_line_indices_are_offset_per_path() {
+        let s = to_obj(&paths());
+        let lines: Vec<&str> = s.lines().filter(|l| l.starts_with("l ")).collect();
+
+        assert_eq!(lines, vec!["l 1 2 3", "l 4 5"]);
+    }
+
+    #[test]
+    fn ply_edges_join_consecutive_vertices() {
+        let s = to_ply(&paths());
+        let body: Vec<&str> = s.split("end_header\n").nth(1).unwrap().lines().collect();
+
+        assert!(s.contains("element vertex 5\n"));
+        assert!(s.contains("element edge 3\n"));
+        assert_eq!(&body[5..], &["0 1", "1 2", "3 4"]);
+    }
+}
@@ -0,0 +1,485 @@
+//! Post-processing effects applied to rendered images.
+//!
+//! Bloom and lens flare are applied to the linear HDR pixel buffer before it is tonemapped for
+//! output and work from a bright pass of the image: the light above a threshold. Bloom blurs
+//! the bright pass with a separable Gaussian and adds it back to the image. Lens flare adds
+//! ghosts (copies of the bright pass reflected through the image center) and an optional
+//! horizontal anamorphic streak.
+//!   https://john-chapman.github.io/2017/11/05/pseudo-lens-flare.html
+//!
+//! Vignetting, chromatic aberration and film grain are stylistic effects applied to the gamma
+//! encoded pixels after tonemapping. Framing guides are drawn over a copy of the final image.
+use crate::Color;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Bloom {
+    /// Luminance above which pixels contribute to the bloom
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+    /// Standard deviation of the blur in pixels
+    #[serde(default = "default_bloom_radius")]
+    pub radius: f32,
+    /// Scale factor for the blurred light added back to the image
+    #[serde(default = "default_bloom_intensity")]
+    pub intensity: f32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Flare {
+    /// Luminance above which pixels contribute to the flare
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+    /// Number of ghosts reflected through the image center
+    #[serde(default = "default_ghosts")]
+    pub ghosts: usize,
+    /// How much smaller each successive ghost is than the last
+    #[serde(default = "default_dispersal")]
+    pub dispersal: f32,
+    /// Length of the horizontal streak as a fraction of the image width (0 to disable)
+    #[serde(default)]
+    pub streak: f32,
+    /// Scale factor for the flare added to the image
+    #[serde(default = "default_flare_intensity")]
+    pub intensity: f32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Grain {
+    /// Maximum change in the encoded pixel value in [0, 1]
+    pub amount: f32,
+    /// Seed for the grain pattern so that it is stable between progressive writes
+    #[serde(default)]
+    pub seed: u64,
+}
+
+/// Framing guides drawn over a proxy of the image. Safe areas follow SMPTE ST 2046-1.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Guides {
+    /// Outline the central 90% of the frame where titles should be placed
+    #[serde(default = "default_true")]
+    pub title_safe: bool,
+    /// Outline the central 93% of the frame where important action should be kept
+    #[serde(default = "default_true")]
+    pub action_safe: bool,
+    /// Rule of thirds grid
+    #[serde(default = "default_true")]
+    pub thirds: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_threshold() -> f32 {
+    1.0
+}
+
+fn default_bloom_radius() -> f32 {
+    8.0
+}
+
+fn default_bloom_intensity() -> f32 {
+    0.2
+}
+
+fn default_ghosts() -> usize {
+    4
+}
+
+fn default_dispersal() -> f32 {
+    0.35
+}
+
+fn default_flare_intensity() -> f32 {
+    0.05
+}
+
+/// The light above threshold luminance, preserving the hue of each pixel.
+fn bright_pass(pixels: &[Color], threshold: f32) -> Vec<Color> {
+    pixels
+        .par_iter()
+        .map(|&c| {
+            let l = c.luminance();
+            if l > threshold {
+                c * ((l - threshold) / l)
+            } else {
+                Color::BLACK
+            }
+        })
+        .collect()
+}
+
+/// Normalized 1D Gaussian kernel covering 3 standard deviations either side of the center.
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let r = (3.0 * sigma).ceil().max(1.0) as isize;
+    let weights: Vec<f32> = (-r..=r)
+        .map(|x| (-(x * x) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let total: f32 = weights.iter().sum();
+
+    weights.into_iter().map(|w| w / total).collect()
+}
+
+/// Convolve each row of the image with the given kernel, clamping at the image edges.
+fn convolve_rows(pixels: &[Color], width: usize, kernel: &[f32]) -> Vec<Color> {
+    let r = (kernel.len() / 2) as isize;
+
+    pixels
+        .par_chunks(width)
+        .flat_map_iter(|row| {
+            (0..width as isize).map(move |i| {
+                kernel
+                    .iter()
+                    .enumerate()
+                    .fold(Color::BLACK, |acc, (k, &w)| {
+                        let x = (i + k as isize - r).clamp(0, width as isize - 1);
+                        acc + row[x as usize] * w
+                    })
+            })
+        })
+        .collect()
+}
+
+fn transpose(pixels: &[Color], width: usize, height: usize) -> Vec<Color> {
+    (0..width * height)
+        .map(|n| pixels[(n % height) * width + n / height])
+        .collect()
+}
+
+/// Separable Gaussian blur.
+fn blur(pixels: &[Color], width: usize, height: usize, sigma: f32) -> Vec<Color> {
+    let kernel = gaussian_kernel(sigma);
+    let rows = convolve_rows(pixels, width, &kernel);
+    let cols = convolve_rows(&transpose(&rows, width, height), height, &kernel);
+
+    transpose(&cols, height, width)
+}
+
+impl Bloom {
+    /// Blooms without a radius have nothing to spread the light over so are skipped.
+    pub fn apply(&self, pixels: &mut [Color], width: usize, height: usize) {
+        if self.radius <= 0.0 || self.radius.is_nan() {
+            return;
+        }
+        let bright = bright_pass(pixels, self.threshold);
+        let bloom = blur(&bright, width, height, self.radius);
+
+        for (p, b) in pixels.iter_mut().zip(bloom) {
+            *p += b * self.intensity;
+        }
+    }
+}
+
+impl Flare {
+    /// Ghosts are copies of the bright pass reflected through the image center, each shrunk
+    /// further towards the center than the last and fading out towards the edges of the image.
+    /// Ghosts are never magnified as that turns single bright pixels into visible blocks.
+    fn ghosts(&self, bright: &[Color], width: usize, height: usize) -> Vec<Color> {
+        let (w, h) = (width as f32, height as f32);
+
+        (0..width * height)
+            .into_par_iter()
+            .map(|n| {
+                let (u, v) = ((n % width) as f32 / w - 0.5, (n / width) as f32 / h - 0.5);
+
+                (0..self.ghosts).fold(Color::BLACK, |acc, i| {
+                    let scale = -(1.0 + self.dispersal * i as f32);
+                    let (su, sv) = (0.5 + u * scale, 0.5 + v * scale);
+                    if !(0.0..1.0).contains(&su) || !(0.0..1.0).contains(&sv) {
+                        return acc;
+                    }
+                    let d = ((su - 0.5).powi(2) + (sv - 0.5).powi(2)).sqrt() / 0.5_f32.sqrt();
+                    let weight = (1.0 - d).max(0.0).powi(4);
+                    let (x, y) = ((su * w) as usize, (sv * h) as usize);
+
+                    acc + bright[y * width + x] * weight
+                })
+            })
+            .collect()
+    }
+
+    /// A long horizontal streak with an exponential falloff.
+    fn streak(&self, bright: &[Color], width: usize) -> Vec<Color> {
+        let len = (self.streak * width as f32).max(1.0);
+        let r = len.ceil() as isize;
+        let weights: Vec<f32> = (-r..=r)
+            .map(|x| (-(x.abs() as f32) * 4.0 / len).exp())
+            .collect();
+        let total: f32 = weights.iter().sum();
+        let kernel: Vec<f32> = weights.into_iter().map(|w| w / total).collect();
+
+        convolve_rows(bright, width, &kernel)
+    }
+
+    pub fn apply(&self, pixels: &mut [Color], width: usize, height: usize) {
+        let bright = bright_pass(pixels, self.threshold);
+        let mut flare = self.ghosts(&bright, width, height);
+        if self.streak > 0.0 {
+            for (f, s) in flare.iter_mut().zip(self.streak(&bright, width)) {
+                *f += s;
+            }
+        }
+        // soften the ghosts so they read as out of focus reflections
+        let flare = blur(&flare, width, height, 2.0);
+
+        for (p, f) in pixels.iter_mut().zip(flare) {
+            *p += f * self.intensity;
+        }
+    }
+}
+
+/// Darken the image towards the corners, with the corners scaled by 1 - strength.
+pub fn vignette(pixels: &mut [Color], width: usize, height: usize, strength: f32) {
+    let (cx, cy) = (0.5 * width as f32, 0.5 * height as f32);
+    let inv_r_sq = 1.0 / (cx * cx + cy * cy);
+
+    for (n, p) in pixels.iter_mut().enumerate() {
+        let (dx, dy) = ((n % width) as f32 + 0.5 - cx, (n / width) as f32 + 0.5 - cy);
+        let r_sq = (dx * dx + dy * dy) * inv_r_sq;
+        *p *= (1.0 - strength * r_sq * r_sq).max(0.0);
+    }
+}
+
+/// Bilinearly sample a single channel of the image, clamping at the edges.
+fn sample_channel(
+    pixels: &[Color],
+    width: usize,
+    height: usize,
+    x: f32,
+    y: f32,
+    channel: impl Fn(&Color) -> f32,
+) -> f32 {
+    let x = x.clamp(0.0, (width - 1) as f32);
+    let y = y.clamp(0.0, (height - 1) as f32);
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x.fract(), y.fract());
+    let c = |x: usize, y: usize| channel(&pixels[y * width + x]);
+
+    let top = c(x0, y0) * (1.0 - fx) + c(x1, y0) * fx;
+    let bottom = c(x0, y1) * (1.0 - fx) + c(x1, y1) * fx;
+
+    top * (1.0 - fy) + bottom * fy
+}
+
+/// Lateral chromatic aberration: the red channel is scaled out from the image center and the
+/// blue channel scaled in so that they are offset by amount pixels in the corners.
+pub fn chromatic_aberration(pixels: &mut [Color], width: usize, height: usize, amount: f32) {
+    let (cx, cy) = (0.5 * (width - 1) as f32, 0.5 * (height - 1) as f32);
+    let k = amount / (cx * cx + cy * cy).sqrt().max(1.0);
+    let src = pixels.to_vec();
+
+    pixels.par_iter_mut().enumerate().for_each(|(n, p)| {
+        let (dx, dy) = ((n % width) as f32 - cx, (n / width) as f32 - cy);
+        // sampling closer to the center spreads the channel outwards
+        let (sr, sb) = (1.0 - k, 1.0 + k);
+        p.r = sample_channel(&src, width, height, cx + dx * sr, cy + dy * sr, |c| c.r);
+        p.b = sample_channel(&src, width, height, cx + dx * sb, cy + dy * sb, |c| c.b);
+    });
+}
+
+impl Guides {
+    const TITLE_SAFE: f32 = 0.9;
+    const ACTION_SAFE: f32 = 0.93;
+
+    /// Draw the guides over the frame within an image with the given overscan border, outlining
+    /// the frame itself if there is a border.
+    pub fn draw(&self, pixels: &mut [Color], width: usize, height: usize, overscan: usize) {
+        let (fw, fh) = (width - 2 * overscan, height - 2 * overscan);
+        let mut rect = |scale: f32, color: Color| {
+            let inset = |n: usize| (0.5 * (1.0 - scale) * n as f32).round() as usize;
+            let (dx, dy) = (inset(fw), inset(fh));
+            let (l, t) = (overscan + dx, overscan + dy);
+            let (r, b) = (overscan + fw - 1 - dx, overscan + fh - 1 - dy);
+            hline(pixels, width, t, l..=r, color);
+            hline(pixels, width, b, l..=r, color);
+            vline(pixels, width, l, t..=b, color);
+            vline(pixels, width, r, t..=b, color);
+        };
+
+        if overscan > 0 {
+            rect(1.0, Color::new(1.0, 0.0, 0.0));
+        }
+        if self.action_safe {
+            rect(Self::ACTION_SAFE, Color::new(1.0, 1.0, 0.0));
+        }
+        if self.title_safe {
+            rect(Self::TITLE_SAFE, Color::WHITE);
+        }
+        if self.thirds {
+            let grey = Color::grey(0.6);
+            let (r, b) = (overscan + fw - 1, overscan + fh - 1);
+            for i in 1..3 {
+                let x = overscan + i * fw / 3;
+                let y = overscan + i * fh / 3;
+                vline(pixels, width, x, overscan..=b, grey);
+                hline(pixels, width, y, overscan..=r, grey);
+            }
+        }
+    }
+}
+
+fn hline(
+    pixels: &mut [Color],
+    width: usize,
+    y: usize,
+    xs: std::ops::RangeInclusive<usize>,
+    c: Color,
+) {
+    for x in xs {
+        pixels[y * width + x] = c;
+    }
+}
+
+fn vline(
+    pixels: &mut [Color],
+    width: usize,
+    x: usize,
+    ys: std::ops::RangeInclusive<usize>,
+    c: Color,
+) {
+    for y in ys {
+        pixels[y * width + x] = c;
+    }
+}
+
+impl Grain {
+    /// Add monochrome noise, strongest in the midtones so that blacks and highlights stay clean.
+    pub fn apply(&self, pixels: &mut [Color]) {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+
+        for p in pixels.iter_mut() {
+            // sum of uniforms for an approximately Gaussian distribution in [-1, 1]
+            let noise = (0..3).map(|_| rng.random_range(-1.0..1.0)).sum::<f32>() / 3.0;
+            let l = p.luminance().clamp(0.0, 1.0);
+            let d = self.amount * noise * 4.0 * l * (1.0 - l);
+            *p += Color::grey(d);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    fn point_image(w: usize, h: usize, x: usize, y: usize, c: f32) -> Vec<Color> {
+        let mut pixels = vec![Color::BLACK; w * h];
+        pixels[y * w + x] = Color::grey(c);
+
+        pixels
+    }
+
+    #[test]
+    fn blur_preserves_energy() {
+        let pixels = point_image(32, 24, 16, 12, 10.0);
+        let blurred = blur(&pixels, 32, 24, 2.0);
+        let total: f32 = blurred.iter().map(|c| c.r).sum();
+
+        assert!((total - 10.0).abs() < 1e-3, "{total}");
+        assert!(blurred[12 * 32 + 17].r > 0.0);
+    }
+
+    #[test]
+    fn bloom_ignores_pixels_below_threshold() {
+        let mut pixels = vec![Color::grey(0.5); 16 * 16];
+        let bloom = Bloom {
+            threshold: 1.0,
+            radius: 2.0,
+            intensity: 1.0,
+        };
+        bloom.apply(&mut pixels, 16, 16);
+
+        assert!(pixels.iter().all(|&c| c == Color::grey(0.5)));
+    }
+
+    #[test_case(0.0; "zero")]
+    #[test_case(-1.0; "negative")]
+    #[test_case(f32::NAN; "nan")]
+    #[test]
+    fn bloom_without_a_radius_is_skipped(radius: f32) {
+        let mut pixels = point_image(16, 16, 8, 8, 10.0);
+        let bloom = Bloom {
+            threshold: 1.0,
+            radius,
+            intensity: 1.0,
+        };
+        bloom.apply(&mut pixels, 16, 16);
+
+        assert_eq!(pixels, point_image(16, 16, 8, 8, 10.0));
+    }
+
+    #[test]
+    fn vignette_darkens_corners_only() {
+        let mut pixels = vec![Color::WHITE; 9 * 9];
+        vignette(&mut pixels, 9, 9, 0.5);
+
+        assert_eq!(pixels[4 * 9 + 4], Color::WHITE);
+        // pixel centers are just inside the corners so are scaled by slightly more than 0.5
+        assert!(pixels[0].r > 0.5 && pixels[0].r < 0.75, "{:?}", pixels[0]);
+    }
+
+    #[test]
+    fn chromatic_aberration_separates_red_and_blue() {
+        let (w, h) = (32, 32);
+        let mut pixels = point_image(w, h, 28, 16, 1.0);
+        chromatic_aberration(&mut pixels, w, h, 4.0);
+
+        // red is pushed out from the center and blue pulled in
+        let max_at = |channel: fn(&Color) -> f32| {
+            (0..w).max_by(|&a, &b| {
+                channel(&pixels[16 * w + a]).total_cmp(&channel(&pixels[16 * w + b]))
+            })
+        };
+        assert!(max_at(|c| c.r) > Some(28));
+        assert!(max_at(|c| c.b) < Some(28));
+        assert_eq!(max_at(|c| c.g), Some(28));
+    }
+
+    #[test]
+    fn flare_ghosts_appear_opposite_bright_pixels() {
+        let (w, h) = (64, 64);
+        let bright = point_image(w, h, 8, 16, 10.0);
+        let flare = Flare {
+            threshold: 1.0,
+            ghosts: 1,
+            dispersal: 0.0,
+            streak: 0.0,
+            intensity: 1.0,
+        };
+        let ghosts = flare.ghosts(&bright, w, h);
+
+        // reflected through the center: (8, 16) -> (56, 48)
+        assert!(ghosts[48 * w + 56].r > 0.0);
+        assert_eq!(ghosts[16 * w + 8], Color::BLACK);
+    }
+
+    #[test]
+    fn guides_are_drawn_within_the_frame() {
+        let (w, h, overscan) = (40, 30, 5);
+        let mut pixels = vec![Color::BLACK; w * h];
+        let guides = Guides {
+            title_safe: true,
+            action_safe: false,
+            thirds: true,
+        };
+        guides.draw(&mut pixels, w, h, overscan);
+        let at = |x: usize, y: usize| pixels[y * w + x];
+
+        // frame outline and nothing outside of it
+        assert_eq!(at(5, 5), Color::new(1.0, 0.0, 0.0));
+        assert_eq!(at(34, 24), Color::new(1.0, 0.0, 0.0));
+        assert!((0..w).all(|x| at(x, 4) == Color::BLACK));
+        // title safe is inset by 5% of the 30x20 frame
+        assert_eq!(at(7, 10), Color::WHITE);
+        assert_eq!(at(32, 10), Color::WHITE);
+        assert_eq!(at(20, 6), Color::WHITE);
+        assert_eq!(at(20, 23), Color::WHITE);
+        // thirds
+        assert_eq!(at(15, 15), Color::grey(0.6));
+        assert_eq!(at(25, 15), Color::grey(0.6));
+    }
+}
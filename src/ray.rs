@@ -1,28 +1,443 @@
 use crate::{
-    bvh::{Bvh, MAX_BVH_DEPTH},
-    hit::Interval,
-    v3::{P3, V3},
+    analysis::write_analysis,
+    angle::Angle,
+    aov::{write_passes, write_variance, Passes},
+    bvh::{Bvh, Frustum, MAX_BVH_DEPTH},
+    color::WhiteBalance,
+    environment::Environment,
+    exit_with,
+    guide::{Guiding, PathGuide},
+    hit::{HitRecord, Interval, RAY_EPSILON},
+    material::{Bounce, Material},
+    noise::Perlin,
+    output::Output,
+    sampler::{self, random_range, NoisePattern, PathRng, Sampler, SamplerKind},
+    sky::Sky,
+    stars::Starfield,
+    stats::RenderStats,
+    temporal::{History, Temporal},
+    toon::{GSample, Outline, Toon},
+    v3::{Onb, N3, P3, V3},
     Color,
 };
-use rand::random_range;
+use rand::Rng;
 use rayon::prelude::*;
-use std::{cmp::max, fs, time::Instant};
+use serde::Deserialize;
+use std::{
+    cmp::max,
+    f32::consts::{PI, TAU},
+    sync::Arc,
+    time::Instant,
+};
 
-#[derive(Debug, Clone, Copy)]
+/// Edge width (in pixels) used for wireframe renders when no explicit width is given.
+pub const DEFAULT_WIRE_WIDTH: f32 = 1.0;
+const WIRE_COLOR: Color = Color::BLACK;
+
+mod integrator;
+
+pub use integrator::{Integrator, ObjectColors, PathTracer, ToonShading, Tracer, Wireframe};
+
+/// Procedural handheld camera motion for animations. The camera position and the point it looks
+/// at are each offset by smooth noise sampled along the frame number.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct CameraShake {
+    /// Approximate maximum offset in scene units
+    pub amplitude: f32,
+    /// Noise cycles per frame
+    #[serde(default = "default_shake_frequency")]
+    pub frequency: f32,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+fn default_shake_frequency() -> f32 {
+    0.1
+}
+
+impl CameraShake {
+    /// Offsets for look_from and look_at in the given frame.
+    pub fn offsets(&self, frame: u32) -> (V3, V3) {
+        let noise: Perlin = Perlin::seeded(self.seed);
+        let t = frame as f32 * self.frequency;
+        // sample separate tracks of the noise for each component, avoiding the lattice where
+        // the noise is always zero
+        let track = |i: usize| {
+            let [x, y, z] = [0, 1, 2]
+                .map(|c| noise.noise(P3::new(t, 3.5 * c as f32 + 0.5, 7.5 * i as f32 + 0.5)));
+            V3::new(x, y, z) * self.amplitude
+        };
+
+        (track(0), track(1))
+    }
+}
+
+/// Light arriving from directions that escape the scene
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Background {
+    Solid(Color),
+    /// Blend from the bottom color to the top color with the height of the ray direction
+    Gradient {
+        top: Color,
+        bottom: Color,
+    },
+    /// A sky blending from the horizon color up to the zenith color over a ground color. Higher
+    /// sharpness gives a narrower band of the horizon color.
+    Horizon {
+        zenith: Color,
+        horizon: Color,
+        ground: Color,
+        sharpness: f32,
+    },
+    /// A sun disk of uniform radiance in front of a sky
+    Sun {
+        sky: &'static Background,
+        dir: V3,
+        radiance: Color,
+        /// Cosine of the angular radius of the disk
+        cos_radius: f32,
+    },
+    /// An equirectangular image of the light arriving from every direction
+    Environment(&'static Environment),
+    /// A physically based daylight sky
+    Sky(Sky),
+    /// Procedural stars for space scenes
+    Stars(&'static Starfield),
+}
+
+impl Background {
+    pub fn value(&self, dir: V3) -> Color {
+        let lerp = |a: Color, b: Color, t: f32| a * (1.0 - t) + b * t;
+        let y = dir.unit_vector().y;
+
+        match *self {
+            Self::Solid(c) => c,
+            Self::Gradient { top, bottom } => lerp(bottom, top, 0.5 * (y + 1.0)),
+            Self::Horizon {
+                zenith,
+                horizon,
+                ground,
+                sharpness,
+            } => {
+                let (c, t) = if y >= 0.0 { (zenith, y) } else { (ground, -y) };
+                lerp(horizon, c, 1.0 - (1.0 - t).powf(sharpness))
+            }
+            Self::Sun {
+                sky,
+                dir: sun,
+                radiance,
+                cos_radius,
+            } => {
+                if dir.unit_vector().dot(&sun) >= cos_radius {
+                    sky.value(dir) + radiance
+                } else {
+                    sky.value(dir)
+                }
+            }
+            Self::Environment(env) => env.value(dir),
+            Self::Sky(sky) => sky.value(dir),
+            Self::Stars(stars) => stars.value(dir),
+        }
+    }
+
+    /// Whether the light of the background can be sampled directly, which is the case for
+    /// environment maps and for sun disks (in place of the sky behind them).
+    pub fn can_sample(&self) -> bool {
+        match self {
+            Self::Environment(env) => env.can_sample(),
+            Self::Sun { radiance, .. } => radiance.luminance() > 0.0,
+            _ => false,
+        }
+    }
+
+    /// A direction chosen in proportion to the light arriving from it, or uniformly over the
+    /// disk of a sun.
+    pub fn sample(&self) -> Option<V3> {
+        match *self {
+            Self::Environment(env) => env.sample(),
+            Self::Sun {
+                dir, cos_radius, ..
+            } => {
+                let z = 1.0 - random_range(0.0..1.0) * (1.0 - cos_radius);
+                let phi = TAU * random_range(0.0..1.0);
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                Some(Onb::new(dir).to_world(V3::new(phi.cos() * r, phi.sin() * r, z)))
+            }
+            _ => None,
+        }
+    }
+
+    /// The density over solid angle of [Background::sample] giving dir
+    pub fn pdf(&self, dir: V3) -> f32 {
+        match *self {
+            Self::Environment(env) => env.pdf(dir),
+            Self::Sun {
+                dir: sun,
+                cos_radius,
+                ..
+            } if dir.unit_vector().dot(&sun) >= cos_radius => 1.0 / (TAU * (1.0 - cos_radius)),
+            _ => 0.0,
+        }
+    }
+}
+
+/// A short range occlusion term applied to the lighting of the surfaces seen by the camera. Diffuse
+/// bounces that hit other geometry within radius have their contribution scaled down by strength,
+/// firming up contact shadows that otherwise take many samples to resolve. This darkens the
+/// image compared to the unbiased render so is best suited to previews.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ContactShadows {
+    /// Distance within which hits count as occluders, in scene units
+    pub radius: f32,
+    #[serde(default = "default_contact_strength")]
+    pub strength: f32,
+}
+
+fn default_contact_strength() -> f32 {
+    1.0
+}
+
+/// Maximum numbers of bounces of each kind along a path, within the overall max_bounces
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub struct BounceLimits {
+    #[serde(default)]
+    pub diffuse: Option<u8>,
+    /// Reflections from metals, glossy coatings, glass and hair
+    #[serde(default)]
+    pub glossy: Option<u8>,
+    /// Refraction through glass and light passing through clouds
+    #[serde(default)]
+    pub transmission: Option<u8>,
+}
+
+/// How camera rays are mapped to image pixels
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "kind")]
+pub enum Projection {
+    /// A pinhole (or thin lens) camera with the given field of view
+    #[default]
+    Perspective,
+    /// A full 360x180 degree panorama centered on the view direction. The image height is half
+    /// of its width.
+    Equirectangular,
+    /// Omni-directional stereo: equirectangular panoramas for the left (top) and right (bottom)
+    /// eyes stacked in a square image. Each column is rendered from eye positions offset
+    /// perpendicular to its viewing direction by half of the interpupillary distance.
+    ///   https://developers.google.com/vr/jump/rendering-ods-content.pdf
+    Ods {
+        /// Interpupillary distance in scene units
+        #[serde(default = "default_ipd")]
+        ipd: f32,
+    },
+}
+
+fn default_ipd() -> f32 {
+    0.064
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderMode {
+    /// Full path tracing using the scene materials
+    #[default]
+    Beauty,
+    /// Path tracing with all non-emissive materials replaced by a neutral grey
+    Clay,
+    /// Primitive edges only, drawn over flat white surfaces
+    Wireframe,
+    /// A stable random color per scene object with simple facing ratio shading
+    Objects,
+    /// Flat shading in bands from a single light (see [Toon])
+    Toon,
+}
+
+/// The summed radiance of the samples taken for a pixel in a render pass, along with how many of
+/// them hit the scene and how many were kept after dropping those with non-finite radiance.
+#[derive(Debug, Default, Clone, Copy)]
+struct PixelSum {
+    color: Color,
+    hits: f32,
+    samples: u32,
+    /// Sum of the squared luminance of the samples for estimating variance
+    lum_sq: f32,
+    lobes: LobeLight,
+}
+
+/// The light along a camera path that scattered from a diffuse or specular lobe at the first hit,
+/// for writing passes. Light emitted at or seen directly through the first hit is in neither.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LobeLight {
+    pub diffuse: Color,
+    pub specular: Color,
+}
+
+impl LobeLight {
+    fn add(&mut self, specular: bool, c: Color) {
+        if specular {
+            self.specular += c;
+        } else {
+            self.diffuse += c;
+        }
+    }
+}
+
+/// Per thread state reused across the pixels of a render pass so that the hot loop does not
+/// allocate.
+struct Scratch {
+    stack: [usize; MAX_BVH_DEPTH],
+    rng: PathRng,
+}
+
+impl Default for Scratch {
+    fn default() -> Self {
+        Self {
+            stack: [0; MAX_BVH_DEPTH],
+            rng: PathRng,
+        }
+    }
+}
+
+/// Per pixel sums of radiance, coverage, samples and squared luminance over all render passes.
+/// Sums are kept in f64 so that precision is not lost at very high sample counts and are only
+/// divided through when resolving.
+#[derive(Debug, Default)]
+struct Accumulator {
+    sums: Vec<[f64; 6]>,
+    /// Sums of the diffuse and specular light when writing passes
+    lobes: Option<Vec<[f64; 6]>>,
+}
+
+impl Accumulator {
+    fn add(&mut self, pass: &[PixelSum]) {
+        if self.sums.is_empty() {
+            self.sums = vec![[0.0; 6]; pass.len()];
+        }
+        self.sums.par_iter_mut().zip(pass).for_each(|(s, p)| {
+            s[0] += p.color.r as f64;
+            s[1] += p.color.g as f64;
+            s[2] += p.color.b as f64;
+            s[3] += p.hits as f64;
+            s[4] += p.samples as f64;
+            s[5] += p.lum_sq as f64;
+        });
+
+        if let Some(lobes) = self.lobes.as_mut() {
+            if lobes.is_empty() {
+                *lobes = vec![[0.0; 6]; pass.len()];
+            }
+            lobes.par_iter_mut().zip(pass).for_each(|(s, p)| {
+                let LobeLight { diffuse, specular } = p.lobes;
+                for (s, v) in s.iter_mut().zip([
+                    diffuse.r, diffuse.g, diffuse.b, specular.r, specular.g, specular.b,
+                ]) {
+                    *s += v as f64;
+                }
+            });
+        }
+    }
+
+    /// The mean diffuse and specular light of each pixel with the given gains applied, when
+    /// writing passes.
+    fn resolve_lobes(&self, gains: Color) -> Option<(Vec<Color>, Vec<Color>)> {
+        let lobes = self.lobes.as_ref()?;
+
+        Some(
+            (lobes.par_iter().zip(&self.sums))
+                .map(|(l, s)| {
+                    let scale = 1.0 / s[4].max(1.0);
+                    let c = |k: usize| {
+                        let c = Color::new(l[k] as f32, l[k + 1] as f32, l[k + 2] as f32);
+                        c * gains * scale as f32
+                    };
+                    (c(0), c(3))
+                })
+                .unzip(),
+        )
+    }
+
+    /// The mean luminance and the variance of that mean (how noisy the current estimate is)
+    /// over each 2x2 block of pixels, along with the dimensions of the half resolution buffer.
+    fn variance(&self, width: usize) -> (usize, usize, Vec<[f32; 2]>) {
+        let height = self.sums.len() / width;
+        let (w, h) = (width.div_ceil(2), height.div_ceil(2));
+        let pixel = |s: &[f64; 6]| {
+            let n = s[4].max(1.0);
+            let mean = Color::new(s[0] as f32, s[1] as f32, s[2] as f32).luminance() as f64 / n;
+            (mean, (s[5] / n - mean * mean).max(0.0) / n)
+        };
+
+        let buf = (0..w * h)
+            .into_par_iter()
+            .map(|i| {
+                let (x, y) = (2 * (i % w), 2 * (i / w));
+                let (mut lum, mut var, mut n) = (0.0, 0.0, 0.0);
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    if x + dx < width && y + dy < height {
+                        let (l, v) = pixel(&self.sums[x + dx + (y + dy) * width]);
+                        (lum, var, n) = (lum + l, var + v, n + 1.0);
+                    }
+                }
+                [(lum / n) as f32, (var / n) as f32]
+            })
+            .collect();
+
+        (w, h, buf)
+    }
+
+    /// The mean radiance of each pixel with the given gains applied and its coverage.
+    fn resolve(&self, gains: Color) -> (Vec<Color>, Vec<f32>) {
+        self.sums
+            .par_iter()
+            .map(|s| {
+                let scale = 1.0 / s[4].max(1.0);
+                let c = Color::new(
+                    (s[0] * scale) as f32,
+                    (s[1] * scale) as f32,
+                    (s[2] * scale) as f32,
+                );
+                (c * gains, (s[3] * scale) as f32)
+            })
+            .unzip()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Camera {
-    image_width: u16,   // rendered image width (pixels)
-    image_height: u16,  // rendered image height (pixels)
-    samples_pp: u16,    // number of random samples per pixel
-    iterations: u16,    // number of iterations with the given step size
-    max_bounces: u8,    // maximum number of ray bounces allowed
-    bg: Color,          // scene background color
-    center: P3,         // camera center
-    pixel_origin: P3,   // location of pixel 0,0
-    pixel_delta_u: V3,  // offset to pixel to the right
-    pixel_delta_v: V3,  // offset to pixel below
-    defocus_angle: f32, // angle of the defocus disk
-    defocus_disk_u: V3, // defocus disk horizontal radius
-    defocus_disk_v: V3, // defocus disk vertical radius
+    image_width: u16,                        // rendered image width (pixels)
+    image_height: u16,                       // rendered image height (pixels)
+    samples_pp: u16,                         // number of random samples per pixel
+    iterations: u16,                         // number of iterations with the given step size
+    max_bounces: u8,                         // maximum number of ray bounces allowed
+    bg: Background,                          // scene background
+    center: P3,                              // camera center
+    pixel_origin: P3,                        // location of pixel 0,0
+    pixel_delta_u: V3,                       // offset to pixel to the right
+    pixel_delta_v: V3,                       // offset to pixel below
+    defocus_angle: Angle,                    // angle of the defocus disk
+    defocus_disk_u: V3,                      // defocus disk horizontal radius
+    defocus_disk_v: V3,                      // defocus disk vertical radius
+    integrator: &'static dyn Integrator,     // how the light along camera rays is computed
+    wire_width: f32,      // angular width of overlaid primitive edges (0 to disable)
+    white_balance: Color, // per channel gains applied to the rendered image
+    projection: Projection, // mapping from pixels to camera rays
+    u: V3,                // camera right unit vector
+    v: V3,                // camera up unit vector
+    w: V3,                // unit vector opposite the view direction
+    bounce_limits: [u8; 3], // maximum bounces of each kind, indexed by Bounce
+    bg_visible: bool,     // whether primary rays that miss the scene see the background
+    backplate: Option<Color>, // seen by primary rays that miss when the background is hidden
+    backplate_image: Option<Arc<[Color]>>, // composited behind the transparent render
+    contact_shadows: Option<ContactShadows>, // near field occlusion of camera visible surfaces
+    toon: Toon,           // shading used in the toon render mode
+    outline: Option<Outline>, // lines drawn over edges in the image
+    samples_mult: &'static [u8], // scattered rays traced from hits, by material ID
+    guiding: Option<Guiding>, // learning where light comes from to guide diffuse bounces
+    noise: NoisePattern,  // how random numbers are chosen for each pixel
+    sampler: &'static dyn Sampler, // the sequence random numbers are read from
+    frame: u32,           // animation frame being rendered
+    temporal: Option<Temporal>, // blending with the previous animation frame
+    passes: Option<Passes>, // splitting light into diffuse and specular passes
+    ray_epsilon: f32,     // minimum distance along camera rays for a hit to count
 }
 
 impl Camera {
@@ -33,13 +448,16 @@ impl Camera {
         samples_pp: u16,
         step_size: u16,
         max_bounces: u8,
-        bg: Color,
-        vfov: f32,
+        bg: Background,
+        vfov: Angle,
         look_from: P3,
         look_at: P3,
         v_up: V3,
-        defocus_angle: f32,
+        defocus_angle: Angle,
         focus_dist: f32,
+        mode: RenderMode,
+        wire_width: Option<f32>,
+        white_balance: Option<WhiteBalance>,
     ) -> Self {
         let image_height = max(1, (image_width as f32 / aspect_ratio) as u16);
         let center = look_from;
@@ -51,8 +469,7 @@ impl Camera {
         };
 
         // viewport dimensions
-        let theta = vfov.to_radians();
-        let h = (theta / 2.0).tan();
+        let h = (vfov / 2.0).tan();
         let viewport_height = 2.0 * h * focus_dist;
         let viewport_width = viewport_height * (image_width as f32 / image_height as f32);
 
@@ -71,10 +488,20 @@ impl Camera {
         let pixel_origin = viewport_upper_left + 0.5 * (pixel_delta_u + pixel_delta_v);
 
         // Calculate the camera defocus disk basis vectors.
-        let defocus_radius = focus_dist * (defocus_angle / 2.0).to_radians().tan();
+        let defocus_radius = focus_dist * (defocus_angle / 2.0).tan();
         let defocus_disk_u = u * defocus_radius;
         let defocus_disk_v = v * defocus_radius;
 
+        // Edge widths are given in pixels so convert to an angle using the pixel size on the
+        // viewport. The distance to the nearest edge is then compared against this angle
+        // multiplied by the distance to the hit point.
+        let wire_px = match (mode, wire_width) {
+            (_, Some(w)) => w,
+            (RenderMode::Wireframe, None) => DEFAULT_WIRE_WIDTH,
+            _ => 0.0,
+        };
+        let wire_width = wire_px * pixel_delta_u.length() / focus_dist;
+
         Self {
             image_width,
             image_height,
@@ -89,16 +516,300 @@ impl Camera {
             defocus_angle,
             defocus_disk_u,
             defocus_disk_v,
+            integrator: mode.integrator(),
+            wire_width,
+            white_balance: white_balance.map_or(Color::WHITE, |wb| wb.gains()),
+            projection: Projection::Perspective,
+            u,
+            v,
+            w,
+            bounce_limits: [max_bounces; 3],
+            bg_visible: true,
+            backplate: None,
+            backplate_image: None,
+            contact_shadows: None,
+            toon: Toon::default(),
+            outline: None,
+            samples_mult: &[],
+            guiding: None,
+            noise: NoisePattern::Random,
+            sampler: SamplerKind::Independent.sampler(),
+            frame: 0,
+            temporal: None,
+            passes: None,
+            ray_epsilon: RAY_EPSILON,
+        }
+    }
+
+    /// Scale the minimum hit distance of camera rays, and of the rays scattered along their
+    /// paths, with the size of the scene.
+    pub fn with_scene_scale(mut self, scale: f32) -> Self {
+        self.ray_epsilon = RAY_EPSILON * scale;
+
+        self
+    }
+
+    /// Render using the given integrator in place of the one for the render mode.
+    pub fn with_integrator(mut self, integrator: &'static dyn Integrator) -> Self {
+        self.integrator = integrator;
+        self
+    }
+
+    /// Track the light scattered from diffuse and specular lobes at the first hit of each path
+    /// for writing passes.
+    pub fn with_passes(mut self, passes: Option<Passes>) -> Self {
+        self.passes = passes;
+
+        self
+    }
+
+    /// Blend each animation frame with the previous frame reprojected to the current view.
+    pub fn with_temporal(mut self, temporal: Option<Temporal>) -> Self {
+        self.temporal = temporal;
+
+        self
+    }
+
+    /// Choose the random numbers used by each pixel following the given pattern for the given
+    /// animation frame.
+    pub fn with_noise(mut self, noise: NoisePattern, frame: u32) -> Self {
+        self.noise = noise;
+        self.frame = frame;
+
+        self
+    }
+
+    /// Read the random numbers of each sample from the given sampler.
+    pub fn with_sampler(mut self, sampler: &'static dyn Sampler) -> Self {
+        self.sampler = sampler;
+
+        self
+    }
+
+    /// Learn where light arrives from over the first passes of the render and use it to guide
+    /// diffuse bounces.
+    pub fn with_guiding(mut self, guiding: Option<Guiding>) -> Self {
+        self.guiding = guiding;
+
+        self
+    }
+
+    /// Split paths at their first hit on a material into the given number of scattered rays,
+    /// indexed by material ID.
+    pub fn with_samples_mult(mut self, samples_mult: Vec<u8>) -> Self {
+        self.samples_mult = Box::leak(samples_mult.into_boxed_slice());
+
+        self
+    }
+
+    /// Shade surfaces with the given settings when rendering in the toon mode.
+    pub fn with_toon(mut self, toon: Toon) -> Self {
+        self.toon = toon;
+
+        self
+    }
+
+    /// Draw outlines over the rendered image.
+    pub fn with_outline(mut self, outline: Option<Outline>) -> Self {
+        self.outline = outline;
+
+        self
+    }
+
+    /// Darken camera visible surfaces by their occlusion from nearby geometry.
+    pub fn with_contact_shadows(mut self, contact_shadows: Option<ContactShadows>) -> Self {
+        self.contact_shadows = contact_shadows;
+
+        self
+    }
+
+    /// Hide the background from the camera while still using it to light the scene. Primary
+    /// rays that miss the scene see the backplate color or are transparent if there is none.
+    pub fn with_hidden_bg(mut self, backplate: Option<Color>) -> Self {
+        self.bg_visible = false;
+        self.backplate = backplate;
+
+        self
+    }
+
+    /// Hide the background from the camera and composite the given image, with a color for
+    /// each pixel of the camera, behind the transparent render.
+    pub fn with_backplate_image(mut self, pixels: Vec<Color>) -> Self {
+        self.bg_visible = false;
+        self.backplate = None;
+        self.backplate_image = Some(pixels.into());
+
+        self
+    }
+
+    /// Whether the rendered image has an alpha channel.
+    pub const fn is_transparent(&self) -> bool {
+        !self.bg_visible && self.backplate.is_none()
+    }
+
+    /// What primary rays that miss the scene see.
+    pub fn camera_bg(&self, r: &Ray) -> Color {
+        if self.bg_visible {
+            self.bg.value(r.dir)
+        } else {
+            self.backplate.unwrap_or(Color::BLACK)
+        }
+    }
+
+    /// Limit the number of bounces of each kind along a path, with unset limits falling back
+    /// to the overall maximum number of bounces.
+    pub fn with_bounce_limits(mut self, limits: Option<BounceLimits>) -> Self {
+        if let Some(l) = limits {
+            for (bounce, limit) in [
+                (Bounce::Diffuse, l.diffuse),
+                (Bounce::Glossy, l.glossy),
+                (Bounce::Transmission, l.transmission),
+            ] {
+                self.bounce_limits[bounce as usize] = limit.unwrap_or(self.max_bounces);
+            }
+        }
+
+        self
+    }
+
+    /// Use the given projection, setting the image height to fit for panoramas.
+    pub fn with_projection(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        match projection {
+            Projection::Perspective => (),
+            Projection::Equirectangular => self.image_height = max(1, self.image_width / 2),
+            Projection::Ods { .. } => self.image_height = self.image_width,
+        }
+
+        self
+    }
+
+    /// Extend the image by the given number of pixels beyond each edge of the frame while
+    /// keeping the framing of the original image area. Panoramas have no edges to extend so are
+    /// left unchanged.
+    pub fn with_overscan(mut self, px: u16) -> Self {
+        if self.projection != Projection::Perspective {
+            return self;
+        }
+
+        self.image_width += 2 * px;
+        self.image_height += 2 * px;
+        self.pixel_origin -= px as f32 * (self.pixel_delta_u + self.pixel_delta_v);
+
+        self
+    }
+
+    pub const fn dimensions(&self) -> (u16, u16) {
+        (self.image_width, self.image_height)
+    }
+
+    /// Samples taken for each pixel over every iteration of a render
+    pub const fn samples_per_pixel(&self) -> u32 {
+        self.iterations as u32 * self.samples_pp as u32
+    }
+
+    pub const fn center(&self) -> P3 {
+        self.center
+    }
+
+    /// The right, up and backward (opposite the view direction) unit vectors of the camera.
+    pub fn basis(&self) -> [V3; 3] {
+        // u and v are only unit length when v_up is perpendicular to the view direction
+        [self.u.unit_vector(), self.v.unit_vector(), self.w]
+    }
+
+    /// The region containing every camera ray, when there is one smaller than all directions.
+    /// The viewport is grown by the size of the defocus disk as rays are sent from points
+    /// across the disk to points on the viewport.
+    pub fn frustum(&self) -> Option<Frustum> {
+        if self.projection != Projection::Perspective {
+            return None;
         }
+
+        let (w, h) = (self.image_width as f32, self.image_height as f32);
+        let (du, dv) = (self.pixel_delta_u, self.pixel_delta_v);
+        let top_left = self.pixel_origin - 0.5 * (du + dv);
+        let (r_u, r_v) = (self.defocus_disk_u, self.defocus_disk_v);
+        let corners = [
+            top_left - r_u + r_v,
+            top_left + w * du + r_u + r_v,
+            top_left + w * du + h * dv + r_u - r_v,
+            top_left + h * dv - r_u - r_v,
+        ];
+
+        Some(Frustum::new(self.center, corners))
     }
 
-    pub fn render_ppm(&self, bvh: Bvh) {
+    /// Render the image, returning the history to blend with the next animation frame when
+    /// temporal reuse is enabled.
+    pub fn render(
+        &self,
+        bvh: &Bvh,
+        output: &Output,
+        stats: Option<&RenderStats>,
+        history: Option<&History>,
+    ) -> Option<History> {
         let start = Instant::now();
+        let mut acc = Accumulator {
+            lobes: self.passes.map(|_| Vec::new()),
+            ..Default::default()
+        };
+        let mut pass = Vec::new();
         let mut pixels = Vec::new();
+        // parts of the scene outside of the view are skipped when tracing camera rays
+        let visible = self.frustum().map(|f| bvh.visible_nodes(&f));
+        let (w, h) = (self.image_width as usize, self.image_height as usize);
+        let surfaces =
+            (self.outline.is_some() || self.temporal.is_some()).then(|| self.surfaces(bvh));
+        let outline =
+            (self.outline.as_ref().zip(surfaces.as_deref())).map(|(o, s)| (o, o.mask(w, h, s)));
+        let reprojected = match (self.temporal, surfaces.as_deref(), history) {
+            (Some(t), Some(s), Some(history)) => Some(t.reproject(self, s, history)),
+            _ => None,
+        };
+        let mut frames = Vec::new();
+        let mut guide = self.guiding.map(|g| PathGuide::new(g, &bvh.bbox));
+        // the exposure is chosen from the first pass when it is automatic
+        let mut exposed: Option<Output> = None;
+
+        if output.preview {
+            output
+                .write(
+                    self.image_width,
+                    self.image_height,
+                    &self.render_preview(bvh),
+                    None,
+                )
+                .unwrap();
+            let preview_time = Instant::now().duration_since(start);
+            eprintln!("Preview written in {}ms", preview_time.as_millis());
+        }
 
         for i in 1..=self.iterations {
-            let scale = 1.0 / (i * self.samples_pp) as f32;
-            let new_pixels = self.render_pass(&bvh);
+            let g = guide.as_ref();
+            self.render_pass(i - 1, bvh, visible.as_deref(), g, stats, &mut pass);
+            if let Some(g) = guide.as_mut() {
+                g.end_pass();
+            }
+            let dropped: u32 = pass
+                .iter()
+                .map(|p| self.samples_pp as u32 - p.samples)
+                .sum();
+            if dropped > 0 {
+                eprintln!("\nDropped {dropped} samples with non-finite radiance");
+            }
+            acc.add(&pass);
+            if output.variance {
+                let (w, h, variance) = acc.variance(self.image_width as usize);
+                write_variance(output, w as u32, h as u32, &variance)
+                    .unwrap_or_else(|e| exit_with(e));
+            }
+            if let Some((diffuse, specular)) = acc.resolve_lobes(self.white_balance) {
+                let (resolved, _) = acc.resolve(self.white_balance);
+                let (w, h) = (self.image_width, self.image_height);
+                write_passes(output, w, h, &resolved, &diffuse, &specular)
+                    .unwrap_or_else(|e| exit_with(e));
+            }
 
             let render_time = Instant::now().duration_since(start);
             eprintln!(
@@ -107,65 +818,254 @@ impl Camera {
                 render_time.as_secs()
             );
 
-            let scaled = new_pixels.into_par_iter().map(|p| p * scale).collect();
-            if pixels.is_empty() {
-                pixels = scaled;
-            } else {
-                let k = (i - 1) as f32 / i as f32;
-                pixels = pixels
-                    .into_iter()
-                    .zip(scaled.into_iter())
-                    .map(|(prev, p)| prev * k + p)
-                    .collect()
+            let (resolved, mut alpha) = acc.resolve(self.white_balance);
+            pixels = resolved;
+            if let Some(t) = self.temporal {
+                (pixels, frames) = t.blend(w, &pixels, reprojected.as_deref());
+            }
+            if let Some((o, mask)) = &outline {
+                o.composite(&mut pixels, &mut alpha, mask);
             }
 
-            let s: String = pixels.iter().map(|c| c.ppm_string()).collect();
-            fs::write(
-                "test.ppm",
-                format!("P3\n{} {}\n255\n{s}", self.image_width, self.image_height),
-            )
-            .unwrap();
+            let output = exposed.get_or_insert_with(|| output.auto_exposed(&pixels));
+            let processed = match self.backplate_image.as_deref() {
+                Some(plate) => {
+                    let composited: Vec<Color> = (pixels.iter().zip(&alpha).zip(plate))
+                        .map(|((&c, &a), &p)| c + p * (1.0 - a))
+                        .collect();
+                    output.post_process(self.image_width, self.image_height, &composited)
+                }
+                None => output.post_process(self.image_width, self.image_height, &pixels),
+            };
+            let transparent = self.is_transparent() && self.backplate_image.is_none();
+            let alpha = transparent.then_some(alpha.as_slice());
+            output
+                .write(self.image_width, self.image_height, &processed, alpha)
+                .unwrap();
         }
 
         let render_time = Instant::now().duration_since(start);
         eprintln!("\nRender time: {}s", render_time.as_secs());
+
+        if output.analysis {
+            write_analysis(output, self.image_width, self.image_height, &pixels)
+                .unwrap_or_else(|e| exit_with(e));
+        }
+
+        self.temporal
+            .and(surfaces)
+            .map(|s| History::new(self.clone(), s, pixels, frames))
+    }
+
+    /// The summed radiance of each pixel, written into pass which is reused between passes.
+    /// Samples with non-finite radiance are dropped rather than being allowed to poison the image.
+    fn render_pass(
+        &self,
+        pass_index: u16,
+        bvh: &Bvh,
+        visible: Option<&[bool]>,
+        guide: Option<&PathGuide>,
+        stats: Option<&RenderStats>,
+        pass: &mut Vec<PixelSum>,
+    ) {
+        let width = self.image_width as usize;
+        pass.clear();
+        pass.resize(width * self.image_height as usize, PixelSum::default());
+
+        pass.par_chunks_mut(width).enumerate().for_each_init(
+            Scratch::default,
+            |scratch, (j, row)| {
+                for (i, sum) in row.iter_mut().enumerate() {
+                    for s in 0..self.samples_pp {
+                        let index = pass_index as u64 * self.samples_pp as u64 + s as u64;
+                        sampler::start_sample(
+                            self.noise,
+                            self.sampler,
+                            self.frame,
+                            i as u16,
+                            j as u16,
+                            index,
+                        );
+                        let r = self.get_ray(i as f32, j as f32, &mut scratch.rng);
+                        let (c, dist, lobes) =
+                            self.ray_color(r, bvh, visible, guide, stats, &mut scratch.stack);
+                        if !c.is_finite() {
+                            if cfg!(debug_assertions) {
+                                eprintln!("\nnon-finite radiance {c:?} at pixel ({i}, {j})");
+                            }
+                            continue;
+                        }
+
+                        sum.color += c;
+                        sum.hits += if dist.is_finite() { 1.0 } else { 0.0 };
+                        sum.samples += 1;
+                        sum.lum_sq += c.luminance() * c.luminance();
+                        sum.lobes.diffuse += lobes.diffuse;
+                        sum.lobes.specular += lobes.specular;
+                    }
+                }
+                sampler::end_samples();
+                eprint!(".");
+            },
+        );
     }
 
-    fn render_pass(&self, bvh: &Bvh) -> Vec<Color> {
+    /// A single primary ray per pixel with flat shading from a headlight at the camera: gives a
+    /// near instant view of camera framing and geometry placement.
+    fn render_preview(&self, bvh: &Bvh) -> Vec<Color> {
+        self.map_pixels(|i, j| match self.primary_hit(i, j, bvh) {
+            (_, Some(hr)) if hr.mat.is_emissive() => Color::WHITE,
+            (r, Some(hr)) => Color::grey(0.1 + 0.7 * hr.normal.dot(&r.dir.unit_vector()).abs()),
+            (r, None) => self.camera_bg(&r),
+        })
+    }
+
+    /// Evaluate f for each pixel (in row-major order) in parallel.
+    pub fn map_pixels<T, F>(&self, f: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(u16, u16) -> T + Sync + Send,
+    {
         (0..self.image_height)
             .into_par_iter()
-            .flat_map(move |j| {
-                let res = (0..self.image_width).into_par_iter().map(move |i| {
-                    let (fi, fj) = (i as f32, j as f32);
-                    (0..self.samples_pp)
-                        .into_par_iter()
-                        .map(|_| self.ray_color(self.get_ray(fi, fj), bvh))
-                        .reduce(Color::default, |mut a, b| {
-                            a += b;
-                            a
-                        })
-                });
-                eprint!(".");
-                res
+            .flat_map(|j| {
+                let f = &f;
+                (0..self.image_width).into_par_iter().map(move |i| f(i, j))
             })
             .collect()
     }
 
+    /// Trace a single randomly sampled primary ray for pixel i, j and return the closest hit.
+    pub fn primary_hit(&self, i: u16, j: u16, bvh: &Bvh) -> (Ray, Option<HitRecord>) {
+        let r = self.get_ray(i as f32, j as f32, &mut rand::rng());
+        let hr = bvh.hits(
+            &r,
+            Interval::new(r.epsilon, f32::INFINITY),
+            &mut [0; MAX_BVH_DEPTH],
+        );
+
+        (r, hr)
+    }
+
+    /// Trace a single path through pixel i, j returning the vertices visited: the ray origin,
+    /// each surface interaction and, if the path escapes the scene, a final point escape_len along
+    /// the escaping ray.
+    pub fn trace_path(&self, i: u16, j: u16, bvh: &Bvh, escape_len: f32) -> Vec<P3> {
+        let mut r = self.get_ray(i as f32, j as f32, &mut rand::rng());
+        let mut stack = [0; MAX_BVH_DEPTH];
+        let mut vertices = vec![r.orig];
+
+        for _ in 0..self.max_bounces {
+            let hr = match bvh.hits(&r, Interval::new(r.epsilon, f32::INFINITY), &mut stack) {
+                Some(hr) => hr,
+                None => {
+                    vertices.push(r.orig + r.dir.unit_vector() * escape_len);
+                    break;
+                }
+            };
+            vertices.push(hr.p);
+
+            match hr.mat.scatter(&r, &hr) {
+                Some(srec) => r = srec.ray,
+                None => break,
+            }
+        }
+
+        vertices
+    }
+
     /// Construct a camera ray originating from the defocus disk and directed at a randomly
     /// sampled point around the pixel location i, j.
-    fn get_ray(&self, i: f32, j: f32) -> Ray {
+    fn get_ray(&self, i: f32, j: f32, rng: &mut impl Rng) -> Ray {
         // Vector to a random point in the [-.5,-.5]-[+.5,+.5] unit square
-        let offset = V3::new(random_range(-0.5..0.5), random_range(-0.5..0.5), 0.0);
-        let sample = self.pixel_origin
-            + ((i + offset.x) * self.pixel_delta_u)
-            + ((j + offset.y) * self.pixel_delta_v);
-        let ray_origin = if self.defocus_angle <= 0.0 {
+        let offset = V3::new(
+            rng.random_range(-0.5..0.5),
+            rng.random_range(-0.5..0.5),
+            0.0,
+        );
+        let time = rng.random_range(0.0..1.0);
+
+        self.ray_through(i + offset.x, j + offset.y).with_time(time)
+    }
+
+    /// The camera ray through the point x, y of the image, where pixel centers lie at whole
+    /// numbers.
+    pub fn ray_through(&self, x: f32, y: f32) -> Ray {
+        match self.projection {
+            Projection::Perspective => (),
+            Projection::Equirectangular => {
+                return self.panorama_ray(x + 0.5, y + 0.5, self.image_height as f32, 0.0)
+            }
+            Projection::Ods { ipd } => {
+                let eye_height = 0.5 * self.image_height as f32;
+                let (x, y) = (x + 0.5, y + 0.5);
+                let (y, eye) = if y < eye_height {
+                    (y, -0.5 * ipd)
+                } else {
+                    (y - eye_height, 0.5 * ipd)
+                };
+                return self.panorama_ray(x, y, eye_height, eye);
+            }
+        }
+
+        let sample = self.pixel_origin + (x * self.pixel_delta_u) + (y * self.pixel_delta_v);
+        let ray_origin = if self.defocus_angle <= Angle::ZERO {
             self.center
         } else {
             self.defocus_disk_sample()
         };
 
-        Ray::new(self.center, sample - ray_origin)
+        Ray::new(self.center, sample - ray_origin).with_epsilon(self.ray_epsilon)
+    }
+
+    /// The point of the image that p is seen at, where pixel centers lie at whole numbers, or
+    /// None if p is behind the camera. Only perspective projections are supported.
+    pub fn project(&self, p: P3) -> Option<(f32, f32)> {
+        if self.projection != Projection::Perspective {
+            return None;
+        }
+
+        let d = p - self.center;
+        let depth = -d.dot(&self.w);
+        if depth <= 0.0 {
+            return None;
+        }
+
+        // scale onto the viewport then measure from the center of pixel 0,0
+        let to_origin = self.pixel_origin - self.center;
+        let q = d * (-to_origin.dot(&self.w) / depth) - to_origin;
+
+        Some((
+            q.dot(&self.pixel_delta_u) / self.pixel_delta_u.square_length(),
+            q.dot(&self.pixel_delta_v) / self.pixel_delta_v.square_length(),
+        ))
+    }
+
+    /// The surface seen through the center of each pixel, used to find edges for outlines and
+    /// to reproject the previous frame.
+    fn surfaces(&self, bvh: &Bvh) -> Vec<GSample> {
+        self.map_pixels(|i, j| {
+            let r = self.ray_through(i as f32, j as f32);
+            bvh.hits(
+                &r,
+                Interval::new(r.epsilon, f32::INFINITY),
+                &mut [0; MAX_BVH_DEPTH],
+            )
+            .map(|hr| (hr.t * r.dir.length(), hr.p, hr.normal.as_v3(), hr.obj_id))
+        })
+    }
+
+    /// A ray for the point x, y of an equirectangular panorama of the given height with its
+    /// origin offset to the right of the view direction by eye_offset.
+    fn panorama_ray(&self, x: f32, y: f32, height: f32, eye_offset: f32) -> Ray {
+        let theta = (x / self.image_width as f32 - 0.5) * TAU; // longitude
+        let phi = (0.5 - y / height) * PI; // latitude
+        let (right, forward) = (self.u, -self.w);
+
+        let dir = phi.cos() * (theta.sin() * right + theta.cos() * forward) + phi.sin() * self.v;
+        let orig = self.center + eye_offset * (theta.cos() * right - theta.sin() * forward);
+
+        Ray::new(orig, dir).with_epsilon(self.ray_epsilon)
     }
 
     // Returns a random point in the camera defocus disk.
@@ -175,34 +1075,75 @@ impl Camera {
         self.center + (p.x * self.defocus_disk_u) + (p.y * self.defocus_disk_v)
     }
 
-    fn ray_color(&self, mut r: Ray, bvh: &Bvh) -> Color {
-        let mut incoming_light = Color::BLACK;
-        let mut rcolor = Color::WHITE;
-        let mut stack = [0; MAX_BVH_DEPTH];
+    /// Trace a single randomly sampled path through pixel i, j returning the radiance along with
+    /// the distance to the first hit (infinite if the path escapes immediately).
+    pub fn sample_with_depth(&self, i: u16, j: u16, bvh: &Bvh) -> (Color, f32) {
+        let r = self.get_ray(i as f32, j as f32, &mut PathRng);
+        let (c, dist, _) = self.ray_color(r, bvh, None, None, None, &mut [0; MAX_BVH_DEPTH]);
 
-        for _ in 0..self.max_bounces {
-            let hr = match bvh.hits(&r, Interval::new(0.001, f32::INFINITY), &mut stack) {
-                Some(hr) => hr,
-                None => return rcolor * self.bg,
-            };
+        (c, dist)
+    }
 
-            let emitted_light = hr.mat.color_emitted(hr.u, hr.v, hr.p);
-            incoming_light += emitted_light * rcolor;
+    /// The radiance along r from the integrator of the camera and the distance to its first
+    /// hit. Nodes of the tree that are not visible are skipped for the camera ray but not for
+    /// later bounces.
+    fn ray_color(
+        &self,
+        r: Ray,
+        bvh: &Bvh,
+        visible: Option<&[bool]>,
+        guide: Option<&PathGuide>,
+        stats: Option<&RenderStats>,
+        stack: &mut [usize; MAX_BVH_DEPTH],
+    ) -> (Color, f32, LobeLight) {
+        if let Some(s) = stats {
+            s.record_path();
+        }
+        let mut t = Tracer {
+            bvh,
+            visible,
+            guide,
+            stats,
+            stack,
+        };
 
-            match hr.mat.scatter(&r, &hr) {
-                Some((scattered, attenuation)) => {
-                    rcolor *= attenuation;
-                    r = scattered;
-                }
-                None => break,
-            };
+        self.integrator.radiance(self, r, &mut t)
+    }
 
-            if (rcolor.x + rcolor.y + rcolor.z) < 0.0001 {
-                break; // early exit if we can't contribute more light from here
-            }
+    /// Whether a hit at dist from the camera lies on one of the overlaid primitive edges.
+    pub fn on_wire(&self, hr: &HitRecord, dist: f32) -> bool {
+        hr.edge_dist < self.wire_width * dist
+    }
+
+    /// Whether light scattered from the first hit goes in the specular pass rather than the
+    /// diffuse pass, which also takes glossy bounces that are too rough to be seen as reflections.
+    fn is_specular(&self, bounce: Bounce, mat: &Material, r: &Ray, hr: &HitRecord) -> bool {
+        match self.passes {
+            Some(p) if bounce != Bounce::Diffuse => mat.roughness(r, hr) <= p.specular_roughness,
+            _ => false,
         }
+    }
 
-        incoming_light
+    /// The scattered ray continuing a path, or None if the bounce takes the path over the limit
+    /// for its kind of bounce.
+    fn next_ray(&self, scattered: Ray, bounce: Bounce, bounces: &mut [u8; 3]) -> Option<Ray> {
+        bounces[bounce as usize] += 1;
+        if bounces[bounce as usize] > self.bounce_limits[bounce as usize] {
+            return None;
+        }
+        let mut next = scattered;
+        next.glossy_bounces = bounces[Bounce::Glossy as usize];
+
+        Some(next)
+    }
+
+    /// Contact shadows are checked for the hit following a diffuse bounce off the first hit.
+    fn contact_after(&self, depth: u8, bounce: Bounce) -> Option<ContactShadows> {
+        if depth == 0 && bounce == Bounce::Diffuse {
+            self.contact_shadows
+        } else {
+            None
+        }
     }
 }
 
@@ -212,6 +1153,12 @@ pub struct Ray {
     pub dir: V3,
     pub inv_dir: wide::f32x4,
     pub ro: wide::f32x4,
+    /// Time within the shutter interval of the frame in [0, 1)
+    pub time: f32,
+    /// Number of glossy bounces along the path before this ray
+    pub glossy_bounces: u8,
+    /// Minimum distance along the ray for a hit to count
+    pub epsilon: f32,
 }
 
 impl Ray {
@@ -224,10 +1171,523 @@ impl Ray {
             dir,
             inv_dir,
             ro,
+            time: 0.0,
+            glossy_bounces: 0,
+            epsilon: RAY_EPSILON,
         }
     }
 
+    /// A ray continuing the path of this one from a surface hit at origin with the given normal,
+    /// carrying over its time, bounce counts and epsilon. The origin is pushed off the surface on the
+    /// side that dir leaves from so that rounding errors can't make the new ray hit the surface
+    /// it starts on.
+    pub fn spawn(&self, origin: P3, dir: V3, normal: N3) -> Ray {
+        let offset = normal.as_v3() * self.epsilon;
+        let orig = if normal.dot(&dir) >= 0.0 {
+            origin + offset
+        } else {
+            origin - offset
+        };
+        let mut r = Ray::new(orig, dir)
+            .with_time(self.time)
+            .with_epsilon(self.epsilon);
+        r.glossy_bounces = self.glossy_bounces;
+
+        r
+    }
+
+    /// This ray at the given time within the shutter interval.
+    pub const fn with_time(mut self, time: f32) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// This ray ignoring hits closer than epsilon.
+    pub const fn with_epsilon(mut self, epsilon: f32) -> Self {
+        self.epsilon = epsilon;
+        self
+    }
+
     pub fn at(&self, t: f32) -> P3 {
         self.orig + t * self.dir
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hit::{Hittable, Quad, Sphere},
+        material::{Material, CLAY},
+        p, v,
+    };
+    use simple_test_case::test_case;
+
+    #[test_case(v!(1, 1, 0), 1.0; "reflected")]
+    #[test_case(v!(1, -1, 0), -1.0; "transmitted")]
+    #[test]
+    fn spawned_rays_leave_from_the_side_of_their_direction(dir: V3, side: f32) {
+        let quad = Hittable::from(Quad::new(p!(-1, 0, -1), v!(2, 0, 0), v!(0, 0, 2), &CLAY));
+        let mut r = Ray::new(p!(-1, 1, 0), v!(1, -1, 0))
+            .with_time(0.5)
+            .with_epsilon(0.01);
+        r.glossy_bounces = 2;
+        let ray_t = Interval::new(0.0, f32::INFINITY);
+        let hr = quad.hits(&r, ray_t).unwrap();
+
+        let next = r.spawn(hr.p, dir, hr.normal);
+        assert_eq!(next.orig.y.signum(), side);
+        assert_eq!(
+            (next.time, next.glossy_bounces, next.epsilon),
+            (0.5, 2, 0.01)
+        );
+        // leaving the surface even with no minimum distance along the ray
+        assert!(quad.hits(&next, ray_t).is_none());
+    }
+
+    fn camera(projection: Projection) -> Camera {
+        Camera::new(
+            1.0,
+            200,
+            1,
+            0,
+            1,
+            Background::Solid(Color::BLACK),
+            Angle::deg(90.0),
+            p!(0, 0, 0),
+            p!(0, 0, -1),
+            v!(0, 1, 0),
+            Angle::ZERO,
+            1.0,
+            RenderMode::Beauty,
+            None,
+            None,
+        )
+        .with_projection(projection)
+    }
+
+    #[test_case(100.0, 50.0, v!(0, 0, -1); "center is forward")]
+    #[test_case(150.0, 50.0, v!(1, 0, 0); "right")]
+    #[test_case(0.0, 50.0, v!(0, 0, 1); "left edge is behind")]
+    #[test_case(100.0, 0.0, v!(0, 1, 0); "top is up")]
+    #[test]
+    fn equirectangular_directions_are_correct(x: f32, y: f32, expected: V3) {
+        let c = camera(Projection::Equirectangular);
+        let r = c.panorama_ray(x, y, 100.0, 0.0);
+
+        assert_eq!(c.dimensions(), (200, 100));
+        assert!((r.dir - expected).length() < 1e-5, "{:?}", r.dir);
+    }
+
+    #[test]
+    fn ods_eyes_are_offset_perpendicular_to_the_view() {
+        let c = camera(Projection::Ods { ipd: 0.1 });
+        let (left, right) = (
+            c.get_ray(100.0, 20.0, &mut rand::rng()),
+            c.get_ray(100.0, 120.0, &mut rand::rng()),
+        );
+
+        assert_eq!(c.dimensions(), (200, 200));
+        assert!((left.orig.x + 0.05).abs() < 1e-2, "{:?}", left.orig);
+        assert!((right.orig.x - 0.05).abs() < 1e-2, "{:?}", right.orig);
+        assert!(left.orig.z.abs() < 1e-2 && right.orig.z.abs() < 1e-2);
+    }
+
+    #[test]
+    fn frustum_culling_only_skips_geometry_out_of_view() {
+        let c = camera(Projection::Perspective).with_overscan(10);
+        let hittables: Vec<Hittable> = (0..400)
+            .map(|i| {
+                let (x, z) = ((i % 20) as f32 - 9.5, (i / 20) as f32 - 9.5);
+                Sphere::new(p!(3.0 * x, 0, 3.0 * z), 1.0, &CLAY).into()
+            })
+            .collect();
+        let bvh = Bvh::new(hittables);
+        let visible = bvh.visible_nodes(&c.frustum().unwrap());
+        let ray_t = Interval::new(RAY_EPSILON, f32::INFINITY);
+        let mut rng = rand::rng();
+
+        assert!(visible[0]);
+        assert!(visible.iter().filter(|&&v| !v).count() > visible.len() / 3);
+        for _ in 0..2000 {
+            let (i, j) = (rng.random_range(0.0..220.0), rng.random_range(0.0..220.0));
+            let r = c.get_ray(i, j, &mut rng);
+            let t = |visible| {
+                bvh.hits_visible(&r, ray_t, &mut [0; MAX_BVH_DEPTH], visible)
+                    .map(|hr| hr.t)
+            };
+            assert_eq!(t(Some(&visible)), t(None), "pixel ({i}, {j})");
+        }
+    }
+
+    #[test]
+    fn contact_shadows_darken_surfaces_next_to_occluders() {
+        // a floor meeting a wall at x = 0 under a white sky, viewed from above
+        let mat: &'static Material = Box::leak(Box::new(Material::solid_color(Color::grey(0.5))));
+        let bvh = Bvh::new(vec![
+            Quad::new(p!(-1, 0, -5), v!(10, 0, 0), v!(0, 0, 10), mat).into(),
+            Quad::new(p!(0, 0, -5), v!(0, 5, 0), v!(0, 0, 10), mat).into(),
+        ]);
+        let camera = |cs| {
+            Camera::new(
+                1.0,
+                200,
+                1,
+                0,
+                4,
+                Background::Solid(Color::WHITE),
+                Angle::deg(90.0),
+                p!(2, 4, 0),
+                p!(2, 0, 0),
+                v!(0, 0, -1),
+                Angle::ZERO,
+                1.0,
+                RenderMode::Beauty,
+                None,
+                None,
+            )
+            .with_contact_shadows(cs)
+        };
+        let mean = |c: &Camera, i: u16| {
+            let n = 4000;
+            (0..n)
+                .map(|_| c.sample_with_depth(i, 100, &bvh).0.luminance())
+                .sum::<f32>()
+                / n as f32
+        };
+
+        let (plain, contact) = (
+            camera(None),
+            camera(Some(ContactShadows {
+                radius: 1.0,
+                strength: 1.0,
+            })),
+        );
+        // pixels over the floor 0.1 and 4 units away from the wall
+        let (near, far) = (52, 150);
+
+        assert!(mean(&contact, near) < 0.85 * mean(&plain, near));
+        assert!((mean(&contact, far) - mean(&plain, far)).abs() < 0.05 * mean(&plain, far));
+    }
+
+    #[test_case(Material::metal(Color::WHITE, 0.1), true; "smooth metal")]
+    #[test_case(Material::metal(Color::WHITE, 0.9), false; "rough metal")]
+    #[test_case(Material::solid_color(Color::grey(0.5)), false; "diffuse")]
+    #[test]
+    fn light_is_split_by_the_lobe_scattered_from_at_the_first_hit(mat: Material, specular: bool) {
+        let mat: &'static Material = Box::leak(Box::new(mat));
+        let bvh = Bvh::new(vec![Quad::new(
+            p!(-5, 0, -5),
+            v!(10, 0, 0),
+            v!(0, 0, 10),
+            mat,
+        )
+        .into()]);
+        let camera = Camera::new(
+            1.0,
+            200,
+            1,
+            0,
+            4,
+            Background::Solid(Color::WHITE),
+            Angle::deg(90.0),
+            p!(0, 1, 0),
+            p!(0, 0, 0),
+            v!(0, 0, -1),
+            Angle::ZERO,
+            1.0,
+            RenderMode::Beauty,
+            None,
+            None,
+        )
+        .with_passes(Some(Passes::default()));
+        let r = Ray::new(p!(0, 1, 0), v!(0.1, -1, 0.1));
+
+        let (c, _, lobes) = camera.ray_color(r, &bvh, None, None, None, &mut [0; MAX_BVH_DEPTH]);
+        let (expected, other) = if specular {
+            (lobes.specular, lobes.diffuse)
+        } else {
+            (lobes.diffuse, lobes.specular)
+        };
+
+        assert!(c.luminance() > 0.0);
+        assert_eq!((expected, other), (c, Color::BLACK));
+    }
+
+    /// Shades the first hit by its normal as a debug view
+    #[derive(Debug)]
+    struct Normals;
+
+    impl Integrator for Normals {
+        fn radiance(&self, camera: &Camera, r: Ray, t: &mut Tracer<'_>) -> (Color, f32, LobeLight) {
+            match t.camera_hit(&r) {
+                Some((hr, dist)) => {
+                    let n = hr.normal.as_v3();
+                    (Color::new(n.x, n.y, n.z), dist, LobeLight::default())
+                }
+                None => (camera.camera_bg(&r), f32::INFINITY, LobeLight::default()),
+            }
+        }
+    }
+
+    #[test]
+    fn cameras_render_with_the_integrator_they_are_given() {
+        let bvh = Bvh::new(vec![Sphere::new(p!(0, 0, -2), 1.0, &CLAY).into()]);
+        let c = camera(Projection::Perspective).with_integrator(&Normals);
+
+        let (color, dist) = c.sample_with_depth(100, 100, &bvh);
+        assert!((color.b - 1.0).abs() < 0.01, "{color:?}");
+        assert!((dist - 1.0).abs() < 0.01, "{dist}");
+        assert_eq!(
+            c.sample_with_depth(0, 0, &bvh),
+            (Color::BLACK, f32::INFINITY)
+        );
+    }
+
+    #[test]
+    fn samples_mult_reduces_noise_without_changing_the_mean() {
+        // the same floor and wall as above with the floor given material ID 1
+        let mat: &'static Material = Box::leak(Box::new(Material::solid_color(Color::grey(0.5))));
+        let bvh = Bvh::new(vec![
+            Hittable::from(Quad::new(p!(-1, 0, -5), v!(10, 0, 0), v!(0, 0, 10), mat)).with_id(1, 1),
+            Quad::new(p!(0, 0, -5), v!(0, 5, 0), v!(0, 0, 10), mat).into(),
+        ]);
+        let camera = |mult| {
+            Camera::new(
+                1.0,
+                200,
+                1,
+                0,
+                4,
+                Background::Solid(Color::WHITE),
+                Angle::deg(90.0),
+                p!(2, 4, 0),
+                p!(2, 0, 0),
+                v!(0, 0, -1),
+                Angle::ZERO,
+                1.0,
+                RenderMode::Beauty,
+                None,
+                None,
+            )
+            .with_samples_mult(vec![1, mult])
+        };
+        let mean_and_var = |c: &Camera| {
+            let n = 4000;
+            let ls: Vec<f32> = (0..n)
+                .map(|_| c.sample_with_depth(60, 100, &bvh).0.luminance())
+                .collect();
+            let mean = ls.iter().sum::<f32>() / n as f32;
+            let var = ls.iter().map(|l| (l - mean).powi(2)).sum::<f32>() / n as f32;
+
+            (mean, var)
+        };
+
+        let (mean, var) = mean_and_var(&camera(1));
+        let (split_mean, split_var) = mean_and_var(&camera(8));
+
+        assert!(
+            (split_mean - mean).abs() < 0.03 * mean,
+            "{split_mean} != {mean}"
+        );
+        assert!(split_var < 0.5 * var, "{split_var} >= {var}");
+    }
+
+    #[test]
+    fn sampling_lights_reduces_noise_without_changing_the_mean() {
+        let grey: &'static Material = Box::leak(Box::new(Material::solid_color(Color::grey(0.5))));
+        let lamp: &'static Material =
+            Box::leak(Box::new(Material::diffuse_light(Color::grey(50.0))));
+        let floor = Hittable::from(Quad::new(p!(-5, 0, -5), v!(10, 0, 0), v!(0, 0, 10), grey));
+        let sphere = Hittable::from(Sphere::new(p!(0, 2, 0), 0.2, lamp));
+        let camera = Camera::new(
+            1.0,
+            200,
+            1,
+            0,
+            4,
+            Background::Solid(Color::BLACK),
+            Angle::deg(90.0),
+            p!(1, 1, 0),
+            P3::ORIGIN,
+            v!(0, 1, 0),
+            Angle::ZERO,
+            1.0,
+            RenderMode::Beauty,
+            None,
+            None,
+        );
+        // only objects with an ID can be sampled as lights
+        let mean_and_var = |light: Hittable| {
+            let bvh = Bvh::new(vec![floor.clone().with_id(1, 1), light]);
+            let n = 4000;
+            let ls: Vec<f32> = (0..n)
+                .map(|_| {
+                    let r = Ray::new(p!(1, 1, 0), v!(-1, -1, 0));
+                    let (c, _, _) =
+                        camera.ray_color(r, &bvh, None, None, None, &mut [0; MAX_BVH_DEPTH]);
+                    c.luminance()
+                })
+                .collect();
+            let mean = ls.iter().sum::<f32>() / n as f32;
+            let var = ls.iter().map(|l| (l - mean).powi(2)).sum::<f32>() / n as f32;
+
+            (mean, var)
+        };
+
+        let (mean, var) = mean_and_var(sphere.clone().with_id(2, 2));
+        let (_, unsampled_var) = mean_and_var(sphere);
+
+        // radiance off the floor below a small sphere: albedo * radiance * (r / h)^2
+        let expected = 0.5 * 50.0 * (0.2f32 / 2.0).powi(2);
+        assert!(
+            (mean - expected).abs() < 0.03 * expected,
+            "{mean} != {expected}"
+        );
+        assert!(var < 0.1 * unsampled_var, "{var} >= {unsampled_var}");
+    }
+
+    #[test]
+    fn sampling_the_sun_lights_surfaces_without_noise() {
+        let grey: &'static Material = Box::leak(Box::new(Material::solid_color(Color::grey(0.5))));
+        let floor = Hittable::from(Quad::new(p!(-5, 0, -5), v!(10, 0, 0), v!(0, 0, 10), grey));
+        let size = Angle::deg(0.53);
+        let irradiance = 2.0;
+        let bg = crate::sun::disk(
+            Background::Solid(Color::BLACK),
+            v!(0, 1, 0),
+            crate::sun::radiance(90.0, irradiance, size),
+            size,
+        );
+        let camera = Camera::new(
+            1.0,
+            200,
+            1,
+            0,
+            4,
+            bg,
+            Angle::deg(90.0),
+            p!(1, 1, 0),
+            P3::ORIGIN,
+            v!(0, 1, 0),
+            Angle::ZERO,
+            1.0,
+            RenderMode::Beauty,
+            None,
+            None,
+        );
+        let bvh = Bvh::new(vec![floor.with_id(1, 1)]);
+        let n = 1000;
+        let ls: Vec<f32> = (0..n)
+            .map(|_| {
+                let r = Ray::new(p!(1, 1, 0), v!(-1, -1, 0));
+                let (c, _, _) =
+                    camera.ray_color(r, &bvh, None, None, None, &mut [0; MAX_BVH_DEPTH]);
+                c.luminance()
+            })
+            .collect();
+        let mean = ls.iter().sum::<f32>() / n as f32;
+        let var = ls.iter().map(|l| (l - mean).powi(2)).sum::<f32>() / n as f32;
+
+        // a lambertian floor lit from overhead: albedo * irradiance / pi
+        let expected = 0.5 * irradiance * std::f32::consts::FRAC_1_PI;
+        assert!(
+            (mean - expected).abs() < 0.03 * expected,
+            "{mean} != {expected}"
+        );
+        assert!(var < 0.01 * expected * expected, "{var}");
+    }
+
+    #[test_case(v!(0, 1, 0), Color::WHITE; "zenith")]
+    #[test_case(v!(1, 0, 0), Color::grey(0.5); "horizon")]
+    #[test_case(v!(0, -1, 0), Color::BLACK; "ground")]
+    #[test]
+    fn horizon_background_blends_from_the_horizon(dir: V3, expected: Color) {
+        let bg = Background::Horizon {
+            zenith: Color::WHITE,
+            horizon: Color::grey(0.5),
+            ground: Color::BLACK,
+            sharpness: 3.0,
+        };
+
+        assert_eq!(bg.value(dir * 2.0), expected);
+    }
+
+    #[test]
+    fn accumulating_many_passes_keeps_precision() {
+        let mut acc = Accumulator::default();
+        let sum = |c: f32, hits: f32, samples: u32| PixelSum {
+            color: Color::grey(c),
+            hits,
+            samples,
+            ..Default::default()
+        };
+        // a typical pass sum next to a much dimmer one in a second pixel
+        for _ in 0..20_000 {
+            acc.add(&[sum(12.3, 100.0, 100), sum(0.01, 0.0, 100)]);
+        }
+
+        let (pixels, alpha) = acc.resolve(Color::new(1.0, 0.5, 1.0));
+
+        assert!((pixels[0].r - 0.123).abs() < 1e-6, "{:?}", pixels[0]);
+        assert!((pixels[0].g - 0.0615).abs() < 1e-6, "{:?}", pixels[0]);
+        assert!((pixels[1].r - 0.0001).abs() < 1e-9, "{:?}", pixels[1]);
+        assert_eq!(alpha, [1.0, 0.0]);
+    }
+
+    #[test]
+    fn dropped_samples_do_not_darken_pixels() {
+        let mut acc = Accumulator::default();
+        let sum = |c: f32, samples: u32| PixelSum {
+            color: Color::grey(c),
+            hits: samples as f32,
+            samples,
+            ..Default::default()
+        };
+        acc.add(&[sum(5.0, 10)]);
+        acc.add(&[sum(3.0, 6)]); // 4 samples in this pass were non-finite
+
+        let (pixels, alpha) = acc.resolve(Color::WHITE);
+
+        assert_eq!(pixels, [Color::grey(0.5)]);
+        assert_eq!(alpha, [1.0]);
+    }
+
+    #[test]
+    fn variance_is_resolved_at_half_resolution() {
+        let mut acc = Accumulator::default();
+        // samples of 0 and 2 in the first pixel, a constant 1 elsewhere
+        let noisy = PixelSum {
+            color: Color::grey(2.0),
+            samples: 2,
+            lum_sq: 4.0,
+            ..Default::default()
+        };
+        let flat = PixelSum {
+            color: Color::grey(2.0),
+            samples: 2,
+            lum_sq: 2.0,
+            ..Default::default()
+        };
+        acc.add(&[noisy, flat, flat, flat, flat, flat]);
+
+        let (w, h, variance) = acc.variance(3);
+
+        assert_eq!((w, h), (2, 1));
+        let [lum, var] = variance[0];
+        assert!((lum - 1.0).abs() < 1e-4, "{lum}");
+        // the first pixel has a sample variance of 1 so its mean has variance 1/2
+        assert!((var - 0.5 / 4.0).abs() < 1e-4, "{var}");
+        assert_eq!(variance[1][1], 0.0);
+    }
+
+    #[test]
+    fn gradient_background_is_lerped_by_height() {
+        let bg = Background::Gradient {
+            top: Color::new(0.5, 0.7, 1.0),
+            bottom: Color::WHITE,
+        };
+
+        assert_eq!(bg.value(v!(1, 0, 0)), Color::new(0.75, 0.85, 1.0));
+    }
+}
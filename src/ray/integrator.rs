@@ -0,0 +1,421 @@
+//! Integrators compute the light arriving at the camera along each camera ray. The render mode
+//! of a scene picks one of the integrators here, and others can be given to a [Camera] with
+//! [Camera::with_integrator] without any changes to how the image is sampled and written.
+use super::{Background, Camera, ContactShadows, LobeLight, Ray, RenderMode, WIRE_COLOR};
+use crate::{
+    bvh::{Bvh, MAX_BVH_DEPTH},
+    counters::{self, Counter},
+    guide::{GuideVertex, PathGuide},
+    hit::{HitRecord, Interval},
+    lights::power_heuristic,
+    material::{Bounce, Material, ScatterRecord, CLAY},
+    stats::RenderStats,
+    Color, P3,
+};
+use std::{f32::consts::FRAC_1_PI, fmt};
+
+/// The scene along with the state shared by the rays traced for a single camera sample.
+pub struct Tracer<'a> {
+    pub bvh: &'a Bvh,
+    /// Nodes of the tree in view of the camera: the rest are skipped by camera rays only
+    pub visible: Option<&'a [bool]>,
+    pub guide: Option<&'a PathGuide>,
+    pub stats: Option<&'a RenderStats>,
+    pub stack: &'a mut [usize; MAX_BVH_DEPTH],
+}
+
+impl Tracer<'_> {
+    /// The closest hit along a camera ray along with its distance from the camera.
+    pub fn camera_hit(&mut self, r: &Ray) -> Option<(HitRecord, f32)> {
+        let ray_t = Interval::new(r.epsilon, f32::INFINITY);
+        counters::add(Counter::Rays, 1);
+        let hr = self.bvh.hits_visible(r, ray_t, self.stack, self.visible)?;
+        if let Some(s) = self.stats {
+            s.record_hit(&hr);
+        }
+        let dist = hr.t * r.dir.length();
+
+        Some((hr, dist))
+    }
+}
+
+/// A way of computing the light arriving at the camera along a ray.
+pub trait Integrator: fmt::Debug + Send + Sync {
+    /// The light arriving along the camera ray r, the distance to the first hit (infinite if
+    /// r escapes the scene) and the light scattered from the diffuse and specular lobes at the
+    /// first hit for writing passes.
+    fn radiance(&self, camera: &Camera, r: Ray, t: &mut Tracer<'_>) -> (Color, f32, LobeLight);
+}
+
+impl RenderMode {
+    /// The integrator used to render in this mode
+    pub fn integrator(self) -> &'static dyn Integrator {
+        match self {
+            Self::Beauty => &PathTracer { clay: false },
+            Self::Clay => &PathTracer { clay: true },
+            Self::Wireframe => &Wireframe,
+            Self::Objects => &ObjectColors,
+            Self::Toon => &ToonShading,
+        }
+    }
+}
+
+/// Path tracing using the scene materials or, for clay renders, with all non-emissive
+/// materials replaced by a neutral grey. The lights of the scene and any environment map are
+/// sampled directly from diffuse bounces (see [crate::lights]) unless paths are being guided, as
+/// the guide doesn't give the density of the directions it chooses for weighting against the
+/// light samples.
+#[derive(Debug, Clone, Copy)]
+pub struct PathTracer {
+    pub clay: bool,
+}
+
+impl Integrator for PathTracer {
+    fn radiance(&self, camera: &Camera, r: Ray, t: &mut Tracer<'_>) -> (Color, f32, LobeLight) {
+        self.trace(camera, r, t, PathState::default())
+    }
+}
+
+impl PathTracer {
+    /// The light arriving along r from the path state reached so far, and the distance to the
+    /// first hit and the light split by the lobe scattered from there if the path starts at the
+    /// camera.
+    fn trace(
+        &self,
+        cam: &Camera,
+        mut r: Ray,
+        t: &mut Tracer<'_>,
+        path: PathState,
+    ) -> (Color, f32, LobeLight) {
+        let mut incoming_light = Color::BLACK;
+        let mut rcolor = Color::WHITE;
+        let mut dist = f32::INFINITY;
+        let mut lobes = LobeLight::default();
+        // light emitted at the first hit and whether the bounce from there was specular
+        let mut first: Option<(Color, bool)> = None;
+        let PathState {
+            depth: start,
+            mut bounces,
+            mut contact,
+            split,
+            mut mis,
+        } = path;
+        // bounces recorded for the guide once the light along the whole path is known
+        let mut vertices: Vec<GuideVertex> = Vec::new();
+
+        for depth in start..cam.max_bounces {
+            let ray_t = Interval::new(r.epsilon, f32::INFINITY);
+            let visible = if depth == 0 { t.visible } else { None };
+            counters::add(Counter::Rays, 1);
+            let hr = match t.bvh.hits_visible(&r, ray_t, t.stack, visible) {
+                Some(hr) => hr,
+                None if depth == 0 => return (cam.camera_bg(&r), dist, lobes),
+                None => {
+                    // as for lights, weighted against sampling the background from the last bounce
+                    let weight = match mis {
+                        Some((_, pdf)) if cam.bg.can_sample() => {
+                            power_heuristic(pdf, cam.bg.pdf(r.dir))
+                        }
+                        _ => 1.0,
+                    };
+                    incoming_light += rcolor * cam.bg.value(r.dir) * weight;
+                    break;
+                }
+            };
+
+            if let Some(s) = t.stats {
+                s.record_hit(&hr);
+            }
+
+            if depth == 0 {
+                dist = hr.t * r.dir.length();
+                if cam.on_wire(&hr, dist) {
+                    return (WIRE_COLOR, dist, lobes);
+                }
+            }
+
+            let mat = if self.clay && !hr.mat.is_emissive() {
+                &CLAY
+            } else {
+                hr.mat
+            };
+
+            if let Some(ContactShadows { radius, strength }) = contact.take() {
+                if hr.t * r.dir.length() < radius {
+                    rcolor *= 1.0 - strength.clamp(0.0, 1.0);
+                }
+            }
+
+            let emitted_light = mat.emitted(&r, &hr);
+            // lights that could also have been sampled from the last bounce are weighted against
+            // finding them that way
+            let weight = match mis.take() {
+                Some((origin, pdf)) if t.bvh.lights().contains(hr.obj_id) => {
+                    power_heuristic(pdf, t.bvh.lights().pdf(origin, r.dir, r.epsilon))
+                }
+                _ => 1.0,
+            };
+            incoming_light += emitted_light * rcolor * weight;
+
+            let n = cam
+                .samples_mult
+                .get(hr.mat_id as usize)
+                .copied()
+                .unwrap_or(1);
+            if n > 1 && !split {
+                // average the light along n paths continuing from here, none of which split again
+                let mut sum = Color::BLACK;
+                for _ in 0..n {
+                    let Some(srec) = mat.scatter(&r, &hr) else {
+                        continue;
+                    };
+                    let Some(srec) = guided(t.guide, mat, &r, &hr, srec) else {
+                        continue;
+                    };
+                    let bounce = srec.bounce;
+                    let mut bounces = bounces;
+                    let Some(next) = cam.next_ray(srec.ray, bounce, &mut bounces) else {
+                        continue;
+                    };
+                    let path = PathState {
+                        depth: depth + 1,
+                        bounces,
+                        contact: cam.contact_after(depth, bounce),
+                        split: true,
+                        mis: None,
+                    };
+                    let mut rest = Tracer {
+                        bvh: t.bvh,
+                        visible: None,
+                        guide: t.guide,
+                        stats: t.stats,
+                        stack: t.stack,
+                    };
+                    let c = srec.attenuation * self.trace(cam, next, &mut rest, path).0;
+                    if depth == 0 {
+                        lobes.add(cam.is_specular(bounce, mat, &r, &hr), rcolor * c / n as f32);
+                    }
+                    sum += c;
+                }
+                incoming_light += rcolor * sum / n as f32;
+                break;
+            }
+
+            match mat.scatter(&r, &hr) {
+                Some(srec) => {
+                    if cfg!(debug_assertions) && !srec.attenuation.is_finite() {
+                        eprintln!(
+                            "\nnon-finite attenuation {:?} from material {} at {:?}",
+                            srec.attenuation, hr.mat_id, hr.p
+                        );
+                    }
+                    let sample_lights = t.guide.is_none()
+                        && srec.bounce == Bounce::Diffuse
+                        && mat.cosine_diffuse()
+                        && depth + 1 < cam.max_bounces
+                        && (!t.bvh.lights().is_empty() || cam.bg.can_sample());
+                    let srec = match guided(t.guide, mat, &r, &hr, srec) {
+                        Some(guided) => guided,
+                        None => break,
+                    };
+                    let bounce = srec.bounce;
+                    if depth == 0 {
+                        first = Some((incoming_light, cam.is_specular(bounce, mat, &r, &hr)));
+                    }
+                    let next = match cam.next_ray(srec.ray, bounce, &mut bounces) {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    if sample_lights {
+                        let direct =
+                            direct_light(&r, &hr, t) + direct_background(&r, &hr, t, &cam.bg);
+                        incoming_light += rcolor * srec.attenuation * direct;
+                        mis = Some((hr.p, srec.pdf));
+                    }
+                    r = next;
+                    rcolor *= srec.attenuation;
+                    if let Some(g) = t.guide {
+                        if g.is_training() && is_guided(mat, &srec) {
+                            vertices.push(GuideVertex {
+                                p: hr.p,
+                                dir: r.dir,
+                                pdf: srec.pdf,
+                                throughput: rcolor,
+                                light: incoming_light,
+                            });
+                        }
+                    }
+                    contact = cam.contact_after(depth, bounce);
+                }
+                None => break,
+            };
+
+            if (rcolor.r + rcolor.g + rcolor.b) < 0.0001 {
+                break; // early exit if we can't contribute more light from here
+            }
+        }
+
+        if let Some(g) = t.guide {
+            for v in vertices.iter() {
+                g.record(v, incoming_light);
+            }
+        }
+
+        if let Some((emitted, specular)) = first {
+            lobes.add(specular, incoming_light + emitted * -1.0);
+        }
+
+        (incoming_light, dist, lobes)
+    }
+}
+
+/// Light arriving at hr directly from a randomly chosen light, scaled by the density of a cosine
+/// distributed bounce in its direction over that of choosing it by sampling the lights, and
+/// weighted against the bounce from hr finding the same light.
+fn direct_light(r: &Ray, hr: &HitRecord, t: &mut Tracer<'_>) -> Color {
+    let lights = t.bvh.lights();
+    let Some(dir) = lights.sample(hr.p) else {
+        return Color::BLACK;
+    };
+    let cos = hr.normal.dot(&dir) / dir.length();
+    if cos <= 0.0 {
+        return Color::BLACK;
+    }
+
+    let shadow = r.spawn(hr.p, dir, hr.normal);
+    let ray_t = Interval::new(shadow.epsilon, f32::INFINITY);
+    counters::add(Counter::Rays, 1);
+    let light = match t.bvh.hits_visible(&shadow, ray_t, t.stack, None) {
+        Some(lr) if lights.contains(lr.obj_id) => lr,
+        _ => return Color::BLACK,
+    };
+    let light_pdf = lights.pdf(hr.p, dir, shadow.epsilon);
+    if light_pdf <= 0.0 {
+        return Color::BLACK;
+    }
+    let cos_pdf = cos * FRAC_1_PI;
+
+    light.mat.emitted(&shadow, &light) * (cos_pdf / light_pdf * power_heuristic(light_pdf, cos_pdf))
+}
+
+/// Light arriving at hr directly from a direction chosen by sampling the background, weighted in
+/// the same way as [direct_light].
+fn direct_background(r: &Ray, hr: &HitRecord, t: &mut Tracer<'_>, bg: &Background) -> Color {
+    let Some(dir) = bg.sample() else {
+        return Color::BLACK;
+    };
+    let cos = hr.normal.dot(&dir) / dir.length();
+    if cos <= 0.0 {
+        return Color::BLACK;
+    }
+
+    let shadow = r.spawn(hr.p, dir, hr.normal);
+    let ray_t = Interval::new(shadow.epsilon, f32::INFINITY);
+    counters::add(Counter::Rays, 1);
+    if t.bvh.hits_visible(&shadow, ray_t, t.stack, None).is_some() {
+        return Color::BLACK;
+    }
+    let bg_pdf = bg.pdf(dir);
+    if bg_pdf <= 0.0 {
+        return Color::BLACK;
+    }
+    let cos_pdf = cos * FRAC_1_PI;
+
+    bg.value(dir) * (cos_pdf / bg_pdf * power_heuristic(bg_pdf, cos_pdf))
+}
+
+/// The scattered ray with cosine distributed diffuse bounces redirected by the guide (if there
+/// is one) and given the density of the guided direction, or None if the guided direction is
+/// below the surface.
+fn guided(
+    guide: Option<&PathGuide>,
+    mat: &Material,
+    r: &Ray,
+    hr: &HitRecord,
+    srec: ScatterRecord,
+) -> Option<ScatterRecord> {
+    match guide {
+        Some(g) if is_guided(mat, &srec) => {
+            let (dir, weight, pdf) = g.scatter(hr.p, hr.normal.as_v3(), srec.ray.dir)?;
+            Some(ScatterRecord {
+                ray: r.spawn(hr.p, dir, hr.normal),
+                attenuation: srec.attenuation * weight,
+                pdf,
+                ..srec
+            })
+        }
+        _ => Some(srec),
+    }
+}
+
+/// Whether the guide can redirect a bounce: it only learns in place of cosine distributed
+/// diffuse bounces.
+fn is_guided(mat: &Material, srec: &ScatterRecord) -> bool {
+    srec.bounce == Bounce::Diffuse && mat.cosine_diffuse()
+}
+
+/// Where a path has got to, so that it can be continued along several rays when it is split.
+#[derive(Debug, Default, Clone, Copy)]
+struct PathState {
+    depth: u8,
+    bounces: [u8; 3],
+    // set when the bounce from the first hit is checked for nearby occluders
+    contact: Option<ContactShadows>,
+    // paths are split at most once so the work done for a path stays bounded
+    split: bool,
+    // where the last bounce was and the density of its direction when the lights were also
+    // sampled from there
+    mis: Option<(P3, f32)>,
+}
+
+/// Primitive edges only, drawn over flat white surfaces and background
+#[derive(Debug, Clone, Copy)]
+pub struct Wireframe;
+
+impl Integrator for Wireframe {
+    fn radiance(&self, camera: &Camera, r: Ray, t: &mut Tracer<'_>) -> (Color, f32, LobeLight) {
+        match t.camera_hit(&r) {
+            Some((hr, dist)) if camera.on_wire(&hr, dist) => {
+                (WIRE_COLOR, dist, LobeLight::default())
+            }
+            Some((_, dist)) => (Color::WHITE, dist, LobeLight::default()),
+            None => (Color::WHITE, f32::INFINITY, LobeLight::default()),
+        }
+    }
+}
+
+/// A stable random color per scene object with simple facing ratio shading over a black
+/// background
+#[derive(Debug, Clone, Copy)]
+pub struct ObjectColors;
+
+impl Integrator for ObjectColors {
+    fn radiance(&self, camera: &Camera, r: Ray, t: &mut Tracer<'_>) -> (Color, f32, LobeLight) {
+        let (color, dist) = match t.camera_hit(&r) {
+            Some((hr, dist)) if camera.on_wire(&hr, dist) => (WIRE_COLOR, dist),
+            Some((hr, dist)) => {
+                let facing = hr.normal.dot(&r.dir.unit_vector()).abs();
+                (Color::from_id(hr.obj_id) * (0.3 + 0.7 * facing), dist)
+            }
+            None => (Color::BLACK, f32::INFINITY),
+        };
+
+        (color, dist, LobeLight::default())
+    }
+}
+
+/// Flat shading in bands from a single light using the toon settings of the camera
+#[derive(Debug, Clone, Copy)]
+pub struct ToonShading;
+
+impl Integrator for ToonShading {
+    fn radiance(&self, camera: &Camera, r: Ray, t: &mut Tracer<'_>) -> (Color, f32, LobeLight) {
+        let (color, dist) = match t.camera_hit(&r) {
+            Some((hr, dist)) if camera.on_wire(&hr, dist) => (WIRE_COLOR, dist),
+            Some((hr, dist)) => (camera.toon.shade(&r, &hr, t.bvh, t.stack), dist),
+            None => (camera.camera_bg(&r), f32::INFINITY),
+        };
+
+        (color, dist, LobeLight::default())
+    }
+}
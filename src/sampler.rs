@@ -0,0 +1,537 @@
+//! Random numbers for tracing paths. By default these are independent for every sample, so the
+//! noise of a low sample count render changes completely from one animation frame to the next.
+//! Scenes can instead have every pixel follow the same sequence of random numbers rotated on
+//! the unit torus by the value of a blue noise tile at that pixel (Cranley-Patterson rotation),
+//! which spreads the error of neighbouring pixels apart and keeps the noise pattern on screen
+//! rather than flickering, where it is also easier for denoisers to remove.
+//!   Georgiev & Fajardo, "Blue-noise Dithered Sampling" (SIGGRAPH 2016 talk)
+//!   Ulichney, "The void-and-cluster method for dither array generation" (SPIE 1993)
+//!
+//! The sequence itself comes from a [Sampler]: independent random numbers, or the low
+//! discrepancy Sobol or Halton sequences whose samples fill each dimension (and, for Sobol, each
+//! pair of dimensions) far more evenly, so that pixel jitter, lens samples and the first bounces
+//! converge faster at the same sample count. Every random number drawn for a sample reads the
+//! next dimension of its point, and both low discrepancy sequences are randomized per pixel and
+//! padded past their first dimensions by reusing them with independent randomization.
+//!   Burley, "Practical Hash-based Owen Scrambling" (JCGT 2020)
+//!   Joe & Kuo, "Constructing Sobol sequences with better two-dimensional projections" (2008)
+use rand::{
+    distr::uniform::{SampleRange, SampleUniform},
+    rand_core, Rng, RngCore,
+};
+use serde::Deserialize;
+use std::{cell::Cell, fmt, sync::OnceLock};
+
+/// Side length of the blue noise tile
+const TILE: usize = 64;
+/// Standard deviation in pixels of the filter used to find voids and clusters in the tile
+const TILE_SIGMA: f32 = 1.5;
+/// Dimensions of the low discrepancy sequences before they are padded
+const DIMS: u32 = 16;
+
+/// Primitive polynomial degree s, coefficients a and initial direction numbers m of Sobol
+/// dimensions 2 to 16, from the new-joe-kuo-6.21201 table. The first dimension is the van der
+/// Corput sequence.
+const JOE_KUO: [(u32, u32, &[u32]); DIMS as usize - 1] = [
+    (1, 0, &[1]),
+    (2, 1, &[1, 3]),
+    (3, 1, &[1, 3, 1]),
+    (3, 2, &[1, 1, 1]),
+    (4, 1, &[1, 1, 3, 3]),
+    (4, 4, &[1, 3, 5, 13]),
+    (5, 2, &[1, 1, 5, 5, 17]),
+    (5, 4, &[1, 1, 5, 5, 5]),
+    (5, 7, &[1, 1, 7, 11, 19]),
+    (5, 11, &[1, 1, 5, 1, 1]),
+    (5, 13, &[1, 1, 1, 3, 11]),
+    (5, 14, &[1, 3, 5, 5, 31]),
+    (6, 1, &[1, 3, 3, 9, 7, 49]),
+    (6, 13, &[1, 1, 1, 15, 21, 21]),
+    (6, 16, &[1, 3, 1, 13, 27, 49]),
+];
+
+/// Bases of the Halton dimensions
+const PRIMES: [u32; DIMS as usize] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53];
+
+/// How the random numbers used by each pixel are chosen
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoisePattern {
+    /// Independent random numbers for every sample of every frame
+    #[default]
+    Random,
+    /// The same blue noise distributed pattern of noise in every frame
+    Locked,
+    /// A blue noise distributed pattern of noise that is shifted across the screen (and so
+    /// changes) in each frame, for temporal accumulation
+    Shifted,
+}
+
+/// A sequence of points in the unit hypercube that the random numbers of each sample are read
+/// from, one dimension for each number drawn.
+pub trait Sampler: fmt::Debug + Send + Sync {
+    /// Dimension dim of the point for the given sample, as a fraction of 2^32. Pixels using
+    /// different seeds see differently randomized versions of the sequence.
+    fn sample(&self, seed: u64, index: u64, dim: u32) -> u32;
+
+    /// Whether the points are independent random numbers, which can be drawn straight from the
+    /// thread local generator when there is no noise pattern to follow
+    fn is_independent(&self) -> bool {
+        false
+    }
+}
+
+/// The sequence used for the random numbers of each sample
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplerKind {
+    #[default]
+    Independent,
+    Sobol,
+    Halton,
+}
+
+impl SamplerKind {
+    pub fn sampler(self) -> &'static dyn Sampler {
+        match self {
+            Self::Independent => &Independent,
+            Self::Sobol => &Sobol,
+            Self::Halton => &Halton,
+        }
+    }
+}
+
+/// Independent random numbers for every dimension of every sample.
+#[derive(Debug, Clone, Copy)]
+pub struct Independent;
+
+impl Sampler for Independent {
+    fn sample(&self, seed: u64, index: u64, dim: u32) -> u32 {
+        hash(seed ^ hash(index ^ (dim as u64) << 40)) as u32
+    }
+
+    fn is_independent(&self) -> bool {
+        true
+    }
+}
+
+/// The Sobol sequence with nested uniform (Owen) scrambling, which keeps the stratification of
+/// the sequence while decorrelating pixels. Each block of [DIMS] dimensions shuffles the order
+/// of the points differently so that the blocks are independent of one another.
+#[derive(Debug, Clone, Copy)]
+pub struct Sobol;
+
+impl Sampler for Sobol {
+    fn sample(&self, seed: u64, index: u64, dim: u32) -> u32 {
+        let (block, d) = (dim / DIMS, dim % DIMS);
+        let shuffled = owen_scramble(index as u32, hash(seed ^ hash(block as u64)) as u32);
+        let v = &sobol_matrices()[d as usize];
+        let x = (0..32)
+            .filter(|k| shuffled >> k & 1 == 1)
+            .fold(0, |x, k| x ^ v[k]);
+
+        owen_scramble(x, hash(seed ^ hash(dim as u64 | 1 << 40)) as u32)
+    }
+}
+
+/// The Halton sequence with the digits of each dimension scrambled (Owen scrambling in the base
+/// of the dimension) and a random offset into the sequence for each block of [DIMS] dimensions.
+#[derive(Debug, Clone, Copy)]
+pub struct Halton;
+
+impl Sampler for Halton {
+    fn sample(&self, seed: u64, index: u64, dim: u32) -> u32 {
+        let (block, d) = (dim / DIMS, dim % DIMS);
+        let offset = hash(seed ^ hash(block as u64)) >> 44;
+        let x = scrambled_radical_inverse(
+            PRIMES[d as usize],
+            index + offset,
+            hash(seed ^ hash(dim as u64 | 1 << 40)),
+        );
+
+        (x * (1u64 << 32) as f64) as u32
+    }
+}
+
+/// The digits of index in base b mirrored about the radix point, with each digit shifted by a
+/// random amount that depends on the digits before it so that the points stay stratified.
+fn scrambled_radical_inverse(b: u32, mut index: u64, seed: u64) -> f64 {
+    let (b, mut x, mut scale, mut prefix) = (b as u64, 0.0, 1.0, seed);
+    // trailing zero digits are shifted too, down to the precision of the sample
+    while scale > 1.0 / (1u64 << 32) as f64 {
+        scale /= b as f64;
+        let digit = index % b;
+        x += ((digit + prefix % b) % b) as f64 * scale;
+        prefix = hash(prefix ^ digit);
+        index /= b;
+    }
+
+    x
+}
+
+/// The generator matrices of the Sobol dimensions as the direction number for each bit of the
+/// index.
+fn sobol_matrices() -> &'static [[u32; 32]; DIMS as usize] {
+    static MATRICES: OnceLock<[[u32; 32]; DIMS as usize]> = OnceLock::new();
+
+    MATRICES.get_or_init(|| {
+        let mut matrices = [[0; 32]; DIMS as usize];
+        for (k, v) in matrices[0].iter_mut().enumerate() {
+            *v = 1 << (31 - k);
+        }
+        for (&(s, a, m), v) in JOE_KUO.iter().zip(&mut matrices[1..]) {
+            let s = s as usize;
+            for k in 0..32 {
+                v[k] = if k < s {
+                    m[k] << (31 - k)
+                } else {
+                    let mut x = v[k - s] ^ (v[k - s] >> s);
+                    for i in 1..s {
+                        if a >> (s - 1 - i) & 1 == 1 {
+                            x ^= v[k - i];
+                        }
+                    }
+                    x
+                };
+            }
+        }
+
+        matrices
+    })
+}
+
+/// A random permutation of x that only depends on its bits from the highest down to each bit in
+/// deciding whether to flip that bit, preserving the stratification of a sequence.
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits().wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+
+    x.reverse_bits()
+}
+
+/// A sample following the sequence of its sampler, rotated for a pixel when following a blue
+/// noise pattern.
+#[derive(Debug, Clone, Copy)]
+struct Sequence {
+    sampler: &'static dyn Sampler,
+    seed: u64,
+    index: u64,
+    dim: u32,
+    tile: Option<(usize, usize)>,
+}
+
+impl Sequence {
+    fn next(&mut self) -> u32 {
+        let dim = self.dim;
+        self.dim += 1;
+        let base = self.sampler.sample(self.seed, self.index, dim);
+        let Some((x, y)) = self.tile else {
+            return base;
+        };
+
+        // each dimension reads the tile from a different offset so they are not correlated
+        let offset = hash(dim as u64);
+        let x = (x + offset as usize) % TILE;
+        let y = (y + (offset >> 32) as usize) % TILE;
+        let rotation = (blue_noise()[y * TILE + x] as u32) << (32 - TILE.ilog2() * 2);
+
+        // addition modulo 2^32 is a rotation on the torus
+        base.wrapping_add(rotation)
+    }
+}
+
+thread_local! {
+    static SEQUENCE: Cell<Option<Sequence>> = const { Cell::new(None) };
+}
+
+/// Start the given sample of pixel i, j in the given frame, after which random numbers drawn by
+/// this thread follow the sampler and noise pattern until the next sample is started.
+pub fn start_sample(
+    pattern: NoisePattern,
+    sampler: &'static dyn Sampler,
+    frame: u32,
+    i: u16,
+    j: u16,
+    index: u64,
+) {
+    let (shift, seed) = match pattern {
+        NoisePattern::Random if sampler.is_independent() => return SEQUENCE.set(None),
+        NoisePattern::Random => {
+            // every pixel of every frame follows a differently randomized sequence
+            let pixel = (j as u64) << 16 | i as u64;
+            return SEQUENCE.set(Some(Sequence {
+                sampler,
+                seed: hash(pixel ^ hash(frame as u64)),
+                index,
+                dim: 0,
+                tile: None,
+            }));
+        }
+        NoisePattern::Locked => ((0, 0), 0),
+        NoisePattern::Shifted => {
+            // R2 low discrepancy offsets keep the shifts of consecutive frames far apart
+            let f = frame as f64;
+            let offset = |a: f64| ((f * a).fract() * TILE as f64) as usize;
+            (
+                (offset(0.754_877_666_2), offset(0.569_840_290_9)),
+                hash(frame as u64),
+            )
+        }
+    };
+
+    SEQUENCE.set(Some(Sequence {
+        sampler,
+        seed,
+        index,
+        dim: 0,
+        tile: Some(((i as usize + shift.0) % TILE, (j as usize + shift.1) % TILE)),
+    }));
+}
+
+/// Return to independent random numbers.
+pub fn end_samples() {
+    SEQUENCE.set(None);
+}
+
+/// The source of random numbers for the current thread: the noise pattern of the current sample
+/// if one has been started or the thread local generator otherwise.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PathRng;
+
+impl RngCore for PathRng {
+    fn next_u32(&mut self) -> u32 {
+        match SEQUENCE.get() {
+            Some(mut seq) => {
+                let v = seq.next();
+                SEQUENCE.set(Some(seq));
+                v
+            }
+            None => rand::rng().next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next_u32() as u64) << 32 | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dst: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dst)
+    }
+}
+
+/// A random value in range for tracing paths (see [PathRng]).
+pub fn random_range<T, R>(range: R) -> T
+where
+    T: SampleUniform,
+    R: SampleRange<T>,
+{
+    PathRng.random_range(range)
+}
+
+/// SplitMix64 finalizer
+pub fn hash(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+    x ^ (x >> 31)
+}
+
+/// The rank of each pixel of a tileable blue noise tile, in row-major order.
+fn blue_noise() -> &'static [u16] {
+    static TILE_RANKS: OnceLock<Vec<u16>> = OnceLock::new();
+
+    TILE_RANKS.get_or_init(void_and_cluster)
+}
+
+/// Rank the pixels of the tile by repeatedly choosing the free pixel furthest from those already
+/// chosen (the largest void), as measured by a gaussian filter wrapping around the tile.
+fn void_and_cluster() -> Vec<u16> {
+    let n = TILE * TILE;
+    let wrap = |d: usize| d.min(TILE - d) as f32;
+    let kernel: Vec<f32> = (0..n)
+        .map(|k| {
+            let (dx, dy) = (wrap(k % TILE), wrap(k / TILE));
+            (-(dx * dx + dy * dy) / (2.0 * TILE_SIGMA * TILE_SIGMA)).exp()
+        })
+        .collect();
+
+    let mut energy = vec![0.0f32; n];
+    let mut rank = vec![u16::MAX; n];
+    for r in 0..n {
+        let p = (0..n)
+            .filter(|&p| rank[p] == u16::MAX)
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap();
+        rank[p] = r as u16;
+
+        let (px, py) = (p % TILE, p / TILE);
+        for (q, e) in energy.iter_mut().enumerate() {
+            let dx = (q % TILE + TILE - px) % TILE;
+            let dy = (q / TILE + TILE - py) % TILE;
+            *e += kernel[dy * TILE + dx];
+        }
+    }
+
+    rank
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    fn draw(pattern: NoisePattern, frame: u32, i: u16, j: u16) -> Vec<f32> {
+        draw_from(&Independent, pattern, frame, i, j)
+    }
+
+    fn draw_from(
+        sampler: &'static dyn Sampler,
+        pattern: NoisePattern,
+        frame: u32,
+        i: u16,
+        j: u16,
+    ) -> Vec<f32> {
+        start_sample(pattern, sampler, frame, i, j, 3);
+        let values = (0..4).map(|_| random_range(0.0..1.0)).collect();
+        end_samples();
+
+        values
+    }
+
+    #[test]
+    fn blue_noise_ranks_each_pixel_once_and_spreads_neighbours_apart() {
+        let ranks = blue_noise();
+        let mut sorted = ranks.to_vec();
+        sorted.sort();
+
+        let n = (TILE * TILE) as f32;
+        let diff = (0..TILE * TILE)
+            .map(|k| {
+                let right = (k / TILE) * TILE + (k + 1) % TILE;
+                (ranks[k] as f32 - ranks[right] as f32).abs() / n
+            })
+            .sum::<f32>()
+            / n;
+
+        assert_eq!(
+            sorted,
+            (0..TILE * TILE).map(|r| r as u16).collect::<Vec<_>>()
+        );
+        // white noise averages 1/3
+        assert!(diff > 0.4, "{diff}");
+    }
+
+    #[test]
+    fn locked_noise_is_the_same_in_every_frame() {
+        assert_eq!(
+            draw(NoisePattern::Locked, 1, 5, 7),
+            draw(NoisePattern::Locked, 2, 5, 7)
+        );
+        assert_ne!(
+            draw(NoisePattern::Locked, 1, 5, 7),
+            draw(NoisePattern::Locked, 1, 6, 7)
+        );
+        assert_ne!(
+            draw(NoisePattern::Shifted, 1, 5, 7),
+            draw(NoisePattern::Shifted, 2, 5, 7)
+        );
+        assert_ne!(
+            draw(NoisePattern::Random, 1, 5, 7),
+            draw(NoisePattern::Random, 1, 5, 7)
+        );
+    }
+
+    #[test]
+    fn rotated_sequences_stay_uniform() {
+        let n = 64;
+        let mean = (0..n * n)
+            .map(|k| draw(NoisePattern::Locked, 0, (k % n) as u16, (k / n) as u16)[0])
+            .sum::<f32>()
+            / (n * n) as f32;
+
+        assert!((mean - 0.5).abs() < 0.01, "{mean}");
+    }
+
+    /// Dimension dim of the first n points of the sampler as fractions of 1
+    fn coords(sampler: &dyn Sampler, seed: u64, n: u64, dim: u32) -> Vec<f64> {
+        (0..n)
+            .map(|i| sampler.sample(seed, i, dim) as f64 / (1u64 << 32) as f64)
+            .collect()
+    }
+
+    /// Whether the coordinates put a single point in each of n equal intervals
+    fn stratified(coords: &[f64], n: usize) -> bool {
+        let mut cells: Vec<usize> = coords.iter().map(|x| (x * n as f64) as usize).collect();
+        cells.sort();
+
+        cells == (0..n).collect::<Vec<_>>()
+    }
+
+    #[test_case(&Sobol, 16, 0; "sobol first dimension")]
+    #[test_case(&Sobol, 16, 7; "sobol later dimension")]
+    #[test_case(&Sobol, 16, 37; "sobol padded dimension")]
+    #[test_case(&Halton, 16, 0; "halton base 2")]
+    #[test_case(&Halton, 27, 1; "halton base 3")]
+    #[test_case(&Halton, 25, 18; "halton padded base 5")]
+    #[test]
+    fn low_discrepancy_dimensions_are_stratified(sampler: &dyn Sampler, n: u64, dim: u32) {
+        for seed in 0..4 {
+            assert!(stratified(&coords(sampler, seed, n, dim), n as usize));
+        }
+    }
+
+    #[test_case(0; "first block")]
+    #[test_case(16; "padded block")]
+    #[test]
+    fn sobol_points_are_stratified_in_the_first_pair_of_dimensions(dim: u32) {
+        for seed in 0..4 {
+            let xs = coords(&Sobol, seed, 16, dim);
+            let ys = coords(&Sobol, seed, 16, dim + 1);
+            let cells: Vec<f64> = xs
+                .iter()
+                .zip(&ys)
+                .map(|(x, y)| ((y * 4.0).floor() * 4.0 + (x * 4.0).floor()) / 16.0)
+                .collect();
+
+            assert!(stratified(&cells, 16), "{xs:?} {ys:?}");
+        }
+    }
+
+    #[test_case(&Sobol; "sobol")]
+    #[test_case(&Halton; "halton")]
+    #[test]
+    fn low_discrepancy_samples_converge_faster(sampler: &dyn Sampler) {
+        // the mean of x * y over the unit square is 1/4, while independent samples have an
+        // error of around 0.01 at this count
+        let error = |sampler: &dyn Sampler| {
+            (0..16)
+                .map(|seed| {
+                    let (xs, ys) = (coords(sampler, seed, 256, 2), coords(sampler, seed, 256, 3));
+                    let mean = xs.iter().zip(&ys).map(|(x, y)| x * y).sum::<f64>() / 256.0;
+                    (mean - 0.25).abs()
+                })
+                .sum::<f64>()
+                / 16.0
+        };
+
+        assert!(
+            error(sampler) < 0.25 * error(&Independent),
+            "{} {}",
+            error(sampler),
+            error(&Independent)
+        );
+    }
+
+    #[test]
+    fn low_discrepancy_sequences_are_randomized_for_each_pixel_and_frame() {
+        let draw = |frame, i| draw_from(&Sobol, NoisePattern::Random, frame, i, 7);
+
+        assert_eq!(draw(1, 5), draw(1, 5));
+        assert_ne!(draw(1, 5), draw(1, 6));
+        assert_ne!(draw(1, 5), draw(2, 5));
+    }
+}
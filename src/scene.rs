@@ -2,17 +2,120 @@
 //!   https://docs.blender.org/manual/en/dev/modeling/meshes/introduction.html
 //!   https://en.wikipedia.org/wiki/Wavefront_.obj_file
 use crate::{
-    bvh::Bvh,
-    hit::{cuboid, ConstantMedium, Hittable, Quad, Sphere, Triangle},
-    material::Material,
+    angle::Angle,
+    bvh::{Accel, Bvh},
+    color::WhiteBalance,
+    environment::Environment,
+    fur::Fur,
+    guide::Guiding,
+    hit::{
+        batch_by_type, cuboid, is_degenerate, ClipPlane, ConstantMedium, Hittable, HittableList,
+        MovingTriangle, Quad, Sphere, Triangle, Volume,
+    },
+    ies::IesProfile,
+    mat::M4,
+    material::{Conductor, Fuzz, LightShape, Mask, Material, Texture},
+    occlusion::OcclusionGrid,
+    output::Output,
     p,
-    ray::Camera,
-    v, Color, DEBUG_SAMPLES_PER_PIXEL, IMAGE_WIDTH, MAX_BOUNCES, P3, STEP_SIZE, V3,
+    particles::Particles,
+    ray::{Background, BounceLimits, Camera, CameraShake, ContactShadows, Projection, RenderMode},
+    sampler::{NoisePattern, SamplerKind},
+    sky::Sky,
+    stars::{Band, Starfield},
+    sun::{self, Sun},
+    temporal::Temporal,
+    texture_cache::{self, TextureSettings},
+    toon::{Outline, Toon},
+    v,
+    v3::{Quat, N3},
+    voxel::Voxels,
+    Color, DEBUG_SAMPLES_PER_PIXEL, IMAGE_WIDTH, MAX_BOUNCES, P3, STEP_SIZE, V3,
 };
-use serde::Deserialize;
-use std::{collections::HashMap, fs};
+use serde::{de::Error, Deserialize, Deserializer};
+use std::{collections::HashMap, fmt, fs, io, sync::OnceLock};
 use tobj::{load_obj, GPU_LOAD_OPTIONS};
 
+mod builder;
+
+pub use builder::{ObjectBuilder, SceneBuilder};
+
+/// Why a scene couldn't be loaded
+#[derive(Debug)]
+pub enum SceneError {
+    /// The scene file couldn't be read
+    File {
+        path: String,
+        error: io::Error,
+    },
+    /// The scene file isn't a valid scene
+    Parse {
+        path: String,
+        error: toml::de::Error,
+    },
+    /// user refers to a material that isn't defined, or has no material if it is empty
+    MissingMaterial {
+        user: String,
+        material: String,
+    },
+    /// Materials that are each built from the next, ending with the first
+    MaterialCycle(Vec<String>),
+    /// user refers to a named object that isn't defined
+    UnknownObject {
+        user: String,
+        object: String,
+    },
+    UnknownLook(String),
+    /// The named object has a transform that can't be inverted (e.g. a zero scale)
+    SingularTransform(String),
+    /// user refers to a color that isn't in the [colors] table
+    UnknownColor {
+        user: String,
+        color: String,
+    },
+    /// user is a medium using a material that has no color to give it
+    MediumColor {
+        user: String,
+        material: String,
+    },
+    /// A file the scene refers to (a mesh, particles, voxels, a texture or an IES profile)
+    /// couldn't be loaded
+    Asset {
+        path: String,
+        error: String,
+    },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File { path, error } => write!(f, "unable to read {path}: {error}"),
+            Self::Parse { path, error } => write!(f, "invalid scene {path}: {error}"),
+            Self::MissingMaterial { user, material } if material.is_empty() => {
+                write!(f, "{user} has no material")
+            }
+            Self::MissingMaterial { user, material } => {
+                write!(f, "{user}: unknown material: {material}")
+            }
+            Self::MaterialCycle(names) => {
+                write!(f, "materials form a cycle: {}", names.join(" -> "))
+            }
+            Self::UnknownObject { user, object } => {
+                write!(f, "{user}: unknown object {object:?} in medium boundary")
+            }
+            Self::UnknownLook(name) => write!(f, "unknown look: {name}"),
+            Self::SingularTransform(name) => write!(f, "{name}: transform can't be inverted"),
+            Self::UnknownColor { user, color } => write!(f, "{user}: unknown color: {color}"),
+            Self::MediumColor { user, material } => {
+                write!(f, "{user}: material {material} has no color for a medium")
+            }
+            Self::Asset { path, error } => write!(f, "unable to load {path}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
 macro_rules! pt {
     ($ps:expr, $ix:expr, $i: expr) => {{
         let idx = $ix[$i] as usize * 3;
@@ -20,11 +123,263 @@ macro_rules! pt {
     }};
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
-#[serde(untagged)]
+/// An RGB triple or a grey value. Colors may also be given as a "#rrggbb" hex string (gamma
+/// encoded) or the name of an entry in the [colors] table of the scene, which is replaced by
+/// that entry by [Scene::resolve_colors] once the scene is parsed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawColorSpec")]
 pub enum ColorSpec {
     RGB([f32; 3]),
     Grey(f32),
+    Named(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawColorSpec {
+    Rgb([f32; 3]),
+    Grey(f32),
+    Named(String),
+}
+
+impl TryFrom<RawColorSpec> for ColorSpec {
+    type Error = String;
+
+    fn try_from(raw: RawColorSpec) -> Result<Self, Self::Error> {
+        match raw {
+            RawColorSpec::Rgb(rgb) => Ok(Self::RGB(rgb)),
+            RawColorSpec::Grey(v) => Ok(Self::Grey(v)),
+            RawColorSpec::Named(s) if s.starts_with('#') => parse_hex_color(&s),
+            RawColorSpec::Named(s) => Ok(Self::Named(s)),
+        }
+    }
+}
+
+impl ColorSpec {
+    /// Replace a named color with its entry in the palette, returning the name if there is no
+    /// such entry.
+    pub fn resolve(&mut self, palette: &HashMap<String, ColorSpec>) -> Result<(), String> {
+        if let Self::Named(name) = self {
+            match palette.get(name.as_str()) {
+                Some(c) if !matches!(c, Self::Named(_)) => *self = c.clone(),
+                _ => return Err(name.clone()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A "#rrggbb" hex color, converted from gamma encoded to linear values.
+fn parse_hex_color(s: &str) -> Result<ColorSpec, String> {
+    let hex = &s[1..];
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("invalid hex color: {s}"));
+    }
+    let channel = |i: usize| match u8::from_str_radix(&hex[i..i + 2], 16) {
+        Ok(v) => Ok((v as f32 / 255.0).powi(2)),
+        Err(_) => Err(format!("invalid hex color: {s}")),
+    };
+
+    Ok(ColorSpec::RGB([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+/// A solid background color, a vertical gradient, a horizon based sky, an environment map, a
+/// physically based sky and sun or a starfield
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum BgSpec {
+    Color(ColorSpec),
+    Gradient {
+        top: ColorSpec,
+        bottom: ColorSpec,
+    },
+    Horizon {
+        zenith: ColorSpec,
+        horizon: ColorSpec,
+        ground: ColorSpec,
+        #[serde(default = "default_sharpness")]
+        sharpness: f32,
+    },
+    /// An equirectangular image, importance sampled from diffuse bounces unless sample is false
+    Environment {
+        environment: String,
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+        /// Rotation about the y axis of the center of the image, which is otherwise along -z
+        #[serde(default)]
+        rotate: Angle,
+        #[serde(default = "default_true")]
+        sample: bool,
+        /// Largest width or height to use the map at, halving larger maps until they fit
+        #[serde(default)]
+        max_size: Option<usize>,
+    },
+    /// A daylight sky with a sun disk in the given direction, unless placed by the [Sun] of the
+    /// scene
+    Sky {
+        turbidity: f32,
+        #[serde(default = "default_sun_direction")]
+        sun_direction: [f32; 3],
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+    },
+    /// Procedural stars at a density per square degree, the faintest giving brightness light
+    Stars {
+        stars: f32,
+        #[serde(default = "default_star_brightness")]
+        brightness: f32,
+        /// Exponent of the power law giving the number of stars brighter than each brightness
+        #[serde(default = "default_star_slope")]
+        slope: f32,
+        #[serde(default = "default_star_size")]
+        size: Angle,
+        #[serde(default)]
+        seed: u64,
+        #[serde(default)]
+        band: Option<BandSpec>,
+    },
+}
+
+/// A band of light across a starfield around the great circle with the given normal
+#[derive(Debug, Clone, Deserialize)]
+pub struct BandSpec {
+    #[serde(default = "default_band_normal")]
+    pub normal: [f32; 3],
+    #[serde(default = "default_band_width")]
+    pub width: Angle,
+    #[serde(default = "default_band_color")]
+    pub color: ColorSpec,
+}
+
+fn default_sun_direction() -> [f32; 3] {
+    [0.0, 1.0, 1.0]
+}
+
+fn default_star_brightness() -> f32 {
+    1e-6
+}
+
+fn default_star_slope() -> f32 {
+    1.5
+}
+
+fn default_star_size() -> Angle {
+    Angle::deg(0.02)
+}
+
+fn default_band_normal() -> [f32; 3] {
+    [0.4, 1.0, 0.2]
+}
+
+fn default_band_width() -> Angle {
+    Angle::deg(8.0)
+}
+
+fn default_band_color() -> ColorSpec {
+    ColorSpec::RGB([0.02, 0.018, 0.015])
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+fn default_sharpness() -> f32 {
+    3.0
+}
+
+fn default_scene_scale() -> f32 {
+    1.0
+}
+
+impl BgSpec {
+    fn colors_mut(&mut self) -> Vec<&mut ColorSpec> {
+        match self {
+            Self::Color(c) => vec![c],
+            Self::Gradient { top, bottom } => vec![top, bottom],
+            Self::Horizon {
+                zenith,
+                horizon,
+                ground,
+                ..
+            } => vec![zenith, horizon, ground],
+            Self::Stars {
+                band: Some(band), ..
+            } => vec![&mut band.color],
+            _ => vec![],
+        }
+    }
+}
+
+impl TryFrom<&BgSpec> for Background {
+    type Error = SceneError;
+
+    fn try_from(value: &BgSpec) -> Result<Self, Self::Error> {
+        let bg = match value {
+            BgSpec::Color(c) => Background::Solid(c.into()),
+            BgSpec::Gradient { top, bottom } => Background::Gradient {
+                top: top.into(),
+                bottom: bottom.into(),
+            },
+            BgSpec::Horizon {
+                zenith,
+                horizon,
+                ground,
+                sharpness,
+            } => Background::Horizon {
+                zenith: zenith.into(),
+                horizon: horizon.into(),
+                ground: ground.into(),
+                sharpness: *sharpness,
+            },
+            BgSpec::Environment {
+                environment,
+                intensity,
+                rotate,
+                sample,
+                max_size,
+            } => {
+                let env = Environment::load(environment, *intensity, *rotate, *sample, *max_size)
+                    .map_err(|error| SceneError::Asset {
+                    path: environment.clone(),
+                    error,
+                })?;
+                Background::Environment(Box::leak(Box::new(env)))
+            }
+            BgSpec::Sky {
+                turbidity,
+                sun_direction,
+                intensity,
+            } => {
+                let dir = V3::from(*sun_direction).unit_vector();
+                let elevation = dir.y.asin().to_degrees();
+                let size = sun::default_size();
+                let radiance = sun::radiance(elevation, sun::default_irradiance(), size);
+                let sky = Background::Sky(Sky::new(dir, *turbidity, *intensity));
+                sun::disk(sky, dir, radiance * *intensity, size)
+            }
+            BgSpec::Stars {
+                stars,
+                brightness,
+                slope,
+                size,
+                seed,
+                band,
+            } => {
+                let band = band
+                    .as_ref()
+                    .map(|b| Band::new(b.normal.into(), b.width, (&b.color).into(), *seed));
+                let stars = Starfield::new(*stars, *brightness, *slope, *size, *seed, band);
+                Background::Stars(Box::leak(Box::new(stars)))
+            }
+        };
+
+        Ok(bg)
+    }
 }
 
 impl From<&ColorSpec> for Color {
@@ -32,13 +387,60 @@ impl From<&ColorSpec> for Color {
         match *value {
             ColorSpec::RGB([r, g, b]) => Color::new(r, g, b),
             ColorSpec::Grey(v) => Color::grey(v),
+            // replaced when the scene is parsed or built
+            ColorSpec::Named(ref name) => unreachable!("unresolved color: {name}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MatSpec {
+    #[serde(flatten)]
+    pub kind: MatKind,
+    /// Number of scattered rays traced from the first hit on this material along each path,
+    /// for spending extra samples where the noise comes from
+    #[serde(default = "default_samples_mult")]
+    pub samples_mult: u8,
+    /// Label for segmentation of objects without a tag of their own
+    #[serde(default)]
+    pub tag: Option<Tag>,
+}
+
+impl From<MatKind> for MatSpec {
+    fn from(kind: MatKind) -> Self {
+        Self {
+            kind,
+            samples_mult: default_samples_mult(),
+            tag: None,
+        }
+    }
+}
+
+/// A label for the objects and materials that make up a class in segmentation images, given as
+/// either a number or a string
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Tag {
+    Id(i64),
+    Name(String),
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "{id}"),
+            Self::Name(name) => write!(f, "{name}"),
         }
     }
 }
 
+fn default_samples_mult() -> u8 {
+    1
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "lowercase", tag = "kind")]
-pub enum MatSpec {
+pub enum MatKind {
     Solid {
         color: ColorSpec,
     },
@@ -48,25 +450,78 @@ pub enum MatSpec {
         smoothness: f32,
         spec_prob: f32,
     },
+    /// A diffuse color under a clear coat that reflects more towards grazing angles
+    Plastic {
+        color: ColorSpec,
+        #[serde(default = "default_plastic_ref_index")]
+        ref_index: f32,
+        #[serde(default)]
+        roughness: f32,
+    },
     Checker {
         scale: f32,
         odd: ColorSpec,
         even: ColorSpec,
     },
     Metal {
+        /// The color of the metal, or a tint over the color of a preset
+        #[serde(default = "default_metal_color")]
         color: ColorSpec,
-        fuzz: f32,
+        fuzz: FuzzSpec,
+        #[serde(default)]
+        preset: Option<MetalPreset>,
     },
     Dielectric {
         ref_index: f32,
         #[serde(default)]
         color: Option<ColorSpec>,
+        /// Frosting of the surface in [0, 1], from clear glass at 0
+        #[serde(default)]
+        roughness: f32,
     },
     Isotropic {
         color: ColorSpec,
     },
+    Hair {
+        color: ColorSpec,
+        #[serde(default = "default_hair_roughness")]
+        roughness: f32,
+        #[serde(default = "default_hair_spec_prob")]
+        spec_prob: f32,
+    },
+    /// Toon-like reflections with a tint for each of the first few glossy bounces
+    Stylized {
+        color: ColorSpec,
+        #[serde(default)]
+        roughness: f32,
+        #[serde(default = "default_stylized_tints")]
+        tints: Vec<ColorSpec>,
+        #[serde(default = "default_stylized_reflectance")]
+        reflectance: f32,
+        #[serde(default = "default_true")]
+        fresnel: bool,
+    },
+    /// An emitter with the given radiance or, if a power is given, the given color scaled so
+    /// that each object using it emits that power whatever its surface area. An IES profile
+    /// and/or a projected gobo image shape the emission of a light pointing in direction.
     Light {
         color: ColorSpec,
+        #[serde(default)]
+        watts: Option<f32>,
+        #[serde(default)]
+        lumens: Option<f32>,
+        #[serde(default)]
+        ies: Option<String>,
+        #[serde(default)]
+        gobo: Option<String>,
+        /// Full angle that the gobo image is projected over
+        #[serde(default = "default_gobo_angle")]
+        gobo_angle: Angle,
+        #[serde(default = "default_light_direction")]
+        direction: [f32; 3],
+        /// Direction of the top of the gobo image and the zero horizontal angle of the profile
+        #[serde(default = "default_light_up")]
+        up: [f32; 3],
     },
     Noise {
         scale: f32,
@@ -74,25 +529,272 @@ pub enum MatSpec {
     Image {
         path: String,
     },
+    Planet {
+        /// Albedo texture for the lit surface
+        day: String,
+        /// Emission texture for city lights on the night side
+        #[serde(default)]
+        night: Option<String>,
+        #[serde(default = "default_night_strength")]
+        night_strength: f32,
+        /// Mask texture that is bright where the surface is a specular ocean
+        #[serde(default)]
+        ocean: Option<String>,
+        #[serde(default = "default_ocean_spec_prob")]
+        spec_prob: f32,
+        /// Direction towards the sun used to place the night side
+        sun: [f32; 3],
+    },
+    Clouds {
+        path: String,
+    },
+    /// Mix of the named materials a and b using the brightness of the mask
+    Blend {
+        a: String,
+        b: String,
+        mask: MaskSpec,
+    },
+    /// The named materials on the front and back of surfaces
+    #[serde(rename = "two_sided")]
+    TwoSided {
+        front: String,
+        back: String,
+    },
+}
+
+/// Luminous efficacy used to convert lumens to watts
+const LUMENS_PER_WATT: f32 = 683.0;
+
+/// A constant blend factor, an image path, procedural noise of the given scale or a shading
+/// input: the facing ratio raised to a power, a world space height range or the ambient
+/// occlusion from geometry within a distance.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum MaskSpec {
+    Factor(f32),
+    Image(String),
+    Noise { noise: f32 },
+    Facing { facing: f32 },
+    Height { height: [f32; 2] },
+    Occlusion { occlusion: f32 },
+}
+
+/// The image texture at path.
+fn image_texture(path: &str) -> Result<Texture, SceneError> {
+    Texture::image(path).map_err(|error| SceneError::Asset {
+        path: path.to_string(),
+        error,
+    })
+}
+
+impl TryFrom<&MaskSpec> for Mask {
+    type Error = SceneError;
+
+    fn try_from(m: &MaskSpec) -> Result<Self, SceneError> {
+        let mask = match m {
+            MaskSpec::Factor(f) => Mask::Texture(Texture::solid(Color::grey(*f))),
+            MaskSpec::Image(path) => Mask::Texture(image_texture(path)?),
+            MaskSpec::Noise { noise } => Mask::Texture(Texture::noise(*noise)),
+            MaskSpec::Facing { facing } => Mask::Facing { power: *facing },
+            MaskSpec::Height { height: [min, max] } => Mask::Height {
+                min: *min,
+                max: *max,
+            },
+            MaskSpec::Occlusion { occlusion } => Mask::occlusion(*occlusion),
+        };
+
+        Ok(mask)
+    }
+}
+
+/// A constant fuzz for metals, separate fuzz for each of the red, green and blue channels or a
+/// mask giving fuzz that varies over the surface.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum FuzzSpec {
+    Channels([f32; 3]),
+    Mask(MaskSpec),
+}
+
+impl TryFrom<&FuzzSpec> for Fuzz {
+    type Error = SceneError;
+
+    fn try_from(f: &FuzzSpec) -> Result<Self, SceneError> {
+        let fuzz = match f {
+            FuzzSpec::Channels(fs) => Fuzz::Channels(*fs),
+            FuzzSpec::Mask(MaskSpec::Factor(f)) => Fuzz::Value(*f),
+            FuzzSpec::Mask(m) => Fuzz::Mask(m.try_into()?),
+        };
+
+        Ok(fuzz)
+    }
+}
+
+fn default_night_strength() -> f32 {
+    1.0
+}
+
+fn default_ocean_spec_prob() -> f32 {
+    0.5
+}
+
+fn default_light_direction() -> [f32; 3] {
+    [0.0, -1.0, 0.0]
+}
+
+fn default_light_up() -> [f32; 3] {
+    [0.0, 0.0, -1.0]
+}
+
+fn default_stylized_tints() -> Vec<ColorSpec> {
+    vec![ColorSpec::Grey(1.0)]
+}
+
+fn default_stylized_reflectance() -> f32 {
+    0.5
+}
+
+fn default_gobo_angle() -> Angle {
+    Angle::deg(45.0)
+}
+
+fn default_hair_roughness() -> f32 {
+    0.2
+}
+
+fn default_hair_spec_prob() -> f32 {
+    0.25
+}
+
+fn default_plastic_ref_index() -> f32 {
+    1.5
+}
+
+fn default_metal_color() -> ColorSpec {
+    ColorSpec::Grey(1.0)
+}
+
+/// Metals with a measured complex index of refraction, giving the color of their reflections
+/// and how it shifts towards white at grazing angles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetalPreset {
+    Gold,
+    Copper,
+    #[serde(alias = "aluminum")]
+    Aluminium,
+}
+
+impl From<MetalPreset> for Conductor {
+    fn from(p: MetalPreset) -> Self {
+        match p {
+            MetalPreset::Gold => Conductor::GOLD,
+            MetalPreset::Copper => Conductor::COPPER,
+            MetalPreset::Aluminium => Conductor::ALUMINIUM,
+        }
+    }
 }
 
-impl MatSpec {
-    fn as_color(&self) -> Color {
+impl MatKind {
+    fn as_color(&self) -> Option<Color> {
+        match self {
+            Self::Solid { color } => Some(color.into()),
+            Self::Metal { color, .. } => Some(color.into()),
+            Self::Isotropic { color, .. } => Some(color.into()),
+            Self::Light { color, .. } => Some(color.into()),
+            _ => None,
+        }
+    }
+
+    /// The power emitted by each object using this material if it is a light given in physical
+    /// units. Power is measured as the luminance of the emitted flux in scene units, with 683
+    /// lumens per watt.
+    fn light_power(&self) -> Option<f32> {
+        match self {
+            Self::Light { watts: Some(w), .. } => Some(*w),
+            Self::Light {
+                lumens: Some(lm), ..
+            } => Some(*lm / LUMENS_PER_WATT),
+            _ => None,
+        }
+    }
+
+    /// A light of the given color radiating evenly in all directions
+    pub fn light(color: ColorSpec) -> Self {
+        Self::Light {
+            color,
+            watts: None,
+            lumens: None,
+            ies: None,
+            gobo: None,
+            gobo_angle: default_gobo_angle(),
+            direction: default_light_direction(),
+            up: default_light_up(),
+        }
+    }
+
+    /// The base color of the material, if it has one.
+    pub fn color_mut(&mut self) -> Option<&mut ColorSpec> {
+        match self {
+            Self::Solid { color }
+            | Self::Specular { color, .. }
+            | Self::Plastic { color, .. }
+            | Self::Metal { color, .. }
+            | Self::Isotropic { color }
+            | Self::Hair { color, .. }
+            | Self::Stylized { color, .. }
+            | Self::Light { color, .. } => Some(color),
+            Self::Dielectric { color, .. } => Some(color.get_or_insert(ColorSpec::Grey(1.0))),
+            _ => None,
+        }
+    }
+
+    /// Every color of the material.
+    fn colors_mut(&mut self) -> Vec<&mut ColorSpec> {
         match self {
-            Self::Solid { color } => color.into(),
-            Self::Metal { color, .. } => color.into(),
-            Self::Isotropic { color, .. } => color.into(),
-            Self::Light { color } => color.into(),
-            _ => panic!("no color associated with material"),
+            Self::Specular {
+                color, spec_color, ..
+            } => vec![color, spec_color],
+            Self::Checker { odd, even, .. } => vec![odd, even],
+            Self::Dielectric { color, .. } => color.iter_mut().collect(),
+            Self::Stylized { color, tints, .. } => {
+                std::iter::once(color).chain(tints.iter_mut()).collect()
+            }
+            _ => self.color_mut().into_iter().collect(),
+        }
+    }
+
+    /// Scale the light emitted by a light, leaving any other material unchanged.
+    pub fn scale_emission(&mut self, k: f32) {
+        if let Self::Light {
+            color,
+            watts,
+            lumens,
+            ..
+        } = self
+        {
+            match (watts, lumens) {
+                (None, None) => {
+                    let c = Color::from(&*color) * k;
+                    *color = ColorSpec::RGB([c.r, c.g, c.b]);
+                }
+                (watts, lumens) => {
+                    for power in [watts, lumens].into_iter().flatten() {
+                        *power *= k;
+                    }
+                }
+            }
         }
     }
 }
 
-impl From<&MatSpec> for Material {
-    fn from(m: &MatSpec) -> Self {
-        match m {
-            MatSpec::Solid { color } => Material::solid_color(color.into()),
-            MatSpec::Specular {
+impl TryFrom<&MatKind> for Material {
+    type Error = SceneError;
+
+    fn try_from(m: &MatKind) -> Result<Self, SceneError> {
+        let mat = match m {
+            MatKind::Solid { color } => Material::solid_color(color.into()),
+            MatKind::Specular {
                 color,
                 spec_color,
                 smoothness,
@@ -103,89 +805,571 @@ impl From<&MatSpec> for Material {
                 smoothness: *smoothness,
                 prob: *spec_prob,
             },
-            MatSpec::Checker { scale, odd, even } => {
+            MatKind::Plastic {
+                color,
+                ref_index,
+                roughness,
+            } => Material::plastic(color.into(), *ref_index, *roughness),
+            MatKind::Checker { scale, odd, even } => {
                 Material::checker(*scale, even.into(), odd.into())
             }
-            MatSpec::Metal { color, fuzz } => Material::metal(color.into(), *fuzz),
-            MatSpec::Dielectric { ref_index, color } => Material::dielectric(
+            MatKind::Metal {
+                color,
+                fuzz,
+                preset: None,
+            } => Material::metal(color.into(), Fuzz::try_from(fuzz)?),
+            MatKind::Metal {
+                color,
+                fuzz,
+                preset: Some(p),
+            } => Material::conductor((*p).into(), color.into(), Fuzz::try_from(fuzz)?),
+            MatKind::Dielectric {
+                ref_index,
+                color,
+                roughness,
+            } => Material::rough_dielectric(
                 *ref_index,
                 color.as_ref().unwrap_or(&ColorSpec::Grey(1.0)).into(),
+                *roughness,
             ),
-            MatSpec::Isotropic { color } => Material::isotropic(color.into()),
-            MatSpec::Light { color } => Material::diffuse_light(color.into()),
-            MatSpec::Noise { scale } => Material::noise(*scale),
-            MatSpec::Image { path } => Material::image(path),
-        }
+            MatKind::Isotropic { color } => Material::isotropic(color.into()),
+            MatKind::Hair {
+                color,
+                roughness,
+                spec_prob,
+            } => Material::hair(color.into(), *roughness, *spec_prob),
+            MatKind::Stylized {
+                color,
+                roughness,
+                tints,
+                reflectance,
+                fresnel,
+            } => Material::stylized(
+                color.into(),
+                *roughness,
+                tints.iter().map(Color::from).collect(),
+                *reflectance,
+                *fresnel,
+            ),
+            MatKind::Light {
+                color,
+                ies,
+                gobo,
+                gobo_angle,
+                direction: [x, y, z],
+                up: [ux, uy, uz],
+                ..
+            } if ies.is_some() || gobo.is_some() => {
+                let profile = match ies {
+                    Some(path) => {
+                        let profile = IesProfile::load(path).map_err(|e| SceneError::Asset {
+                            path: path.clone(),
+                            error: e.to_string(),
+                        })?;
+                        Some(&*Box::leak(Box::new(profile)))
+                    }
+                    None => None,
+                };
+                let gobo = match gobo {
+                    Some(path) => Some((image_texture(path)?, *gobo_angle)),
+                    None => None,
+                };
+                let shape = LightShape::new(profile, gobo, v!(*x, *y, *z), v!(*ux, *uy, *uz));
+                Material::shaped_light(color.into(), shape)
+            }
+            MatKind::Light { color, .. } => Material::diffuse_light(color.into()),
+            MatKind::Noise { scale } => Material::noise(*scale),
+            MatKind::Image { path } => {
+                Material::image(path).map_err(|error| SceneError::Asset {
+                    path: path.clone(),
+                    error,
+                })?
+            }
+            MatKind::Planet {
+                day,
+                night,
+                night_strength,
+                ocean,
+                spec_prob,
+                sun,
+            } => {
+                let layer = |path: &Option<String>, v: f32| match path {
+                    Some(p) => image_texture(p).map(|t| Some((t, v))),
+                    None => Ok(None),
+                };
+                Material::planet(
+                    image_texture(day)?,
+                    layer(night, *night_strength)?,
+                    layer(ocean, *spec_prob)?,
+                    (*sun).into(),
+                )
+            }
+            MatKind::Clouds { path } => Material::clouds(image_texture(path)?),
+            MatKind::Blend { .. } | MatKind::TwoSided { .. } => {
+                panic!("blends and two sided materials are built by build_material")
+            }
+        };
+
+        Ok(mat)
     }
 }
 
-#[derive(Debug, Default, Clone, Deserialize)]
-pub struct HitMeta {
-    #[serde(default)]
-    rotate: Option<f32>,
-    #[serde(default)]
-    translate: Option<[f32; 3]>,
-    #[serde(default)]
-    density: Option<f32>,
+/// Check that the named material isn't built from itself, following the materials it is built
+/// from that are defined.
+fn material_cycle(
+    name: &str,
+    specs: &HashMap<String, MatSpec>,
+    building: &mut Vec<String>,
+) -> Result<(), SceneError> {
+    if building.iter().any(|n| n == name) {
+        let mut cycle = building.clone();
+        cycle.push(name.to_string());
+        return Err(SceneError::MaterialCycle(cycle));
+    }
+    let parts = match specs.get(name).map(|spec| &spec.kind) {
+        Some(MatKind::Blend { a, b, .. }) => [a, b],
+        Some(MatKind::TwoSided { front, back }) => [front, back],
+        _ => return Ok(()),
+    };
+
+    building.push(name.to_string());
+    for part in parts {
+        material_cycle(part, specs, building)?;
+    }
+    building.pop();
+
+    Ok(())
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct Mesh {
-    pub path: String,
-    pub material: String,
+/// Build the named material, first building the materials that it blends between or has on
+/// either side.
+fn build_material(
+    name: &str,
+    specs: &HashMap<String, MatSpec>,
+    built: &mut HashMap<String, &'static Material>,
+    building: &mut Vec<String>,
+) -> Result<&'static Material, SceneError> {
+    if let Some(mat) = built.get(name) {
+        return Ok(mat);
+    }
+    if building.iter().any(|n| n == name) {
+        let mut cycle = building.clone();
+        cycle.push(name.to_string());
+        return Err(SceneError::MaterialCycle(cycle));
+    }
+
+    let spec = specs.get(name).ok_or_else(|| SceneError::MissingMaterial {
+        user: building.last().map_or("scene", |n| n).to_string(),
+        material: name.to_string(),
+    })?;
+    building.push(name.to_string());
+    let mat = match &spec.kind {
+        MatKind::Blend { a, b, mask } => Material::blend(
+            build_material(a, specs, built, building)?,
+            build_material(b, specs, built, building)?,
+            mask.try_into()?,
+        ),
+        MatKind::TwoSided { front, back } => Material::two_sided(
+            build_material(front, specs, built, building)?,
+            build_material(back, specs, built, building)?,
+        ),
+        kind => kind.try_into()?,
+    };
+    building.pop();
+
+    let mat: &'static Material = Box::leak(Box::new(mat));
+    built.insert(name.to_string(), mat);
+
+    Ok(mat)
+}
+
+/// Materials may declare `extends = "name"` to take any parameters they do not set themselves
+/// from another material (including its kind), which is resolved before they are parsed.
+fn deserialize_materials<'de, D>(d: D) -> Result<HashMap<String, MatSpec>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tables = HashMap::<String, toml::Table>::deserialize(d)?;
+    let mut resolved = HashMap::new();
+    for name in tables.keys() {
+        resolve_extends(name, &tables, &mut resolved, &mut Vec::new()).map_err(D::Error::custom)?;
+    }
+
+    resolved
+        .into_iter()
+        .map(
+            |(name, table)| match MatSpec::deserialize(toml::Value::Table(table)) {
+                Ok(spec) => Ok((name, spec)),
+                Err(e) => Err(D::Error::custom(format!("material {name}: {e}"))),
+            },
+        )
+        .collect()
+}
+
+/// The parameters of the named material merged over those of the material it extends.
+fn resolve_extends(
+    name: &str,
+    tables: &HashMap<String, toml::Table>,
+    resolved: &mut HashMap<String, toml::Table>,
+    chain: &mut Vec<String>,
+) -> Result<toml::Table, String> {
+    if let Some(table) = resolved.get(name) {
+        return Ok(table.clone());
+    }
+    if chain.iter().any(|n| n == name) {
+        return Err(format!(
+            "materials extend each other in a cycle: {} -> {name}",
+            chain.join(" -> ")
+        ));
+    }
+
+    let mut table = match tables.get(name) {
+        Some(table) => table.clone(),
+        None => return Err(format!("unknown material: {name}")),
+    };
+    if let Some(base) = table.remove("extends") {
+        let base = match base.as_str() {
+            Some(base) => base.to_string(),
+            None => return Err(format!("material {name}: extends must be a material name")),
+        };
+        chain.push(name.to_string());
+        let mut merged = resolve_extends(&base, tables, resolved, chain)?;
+        chain.pop();
+        merged.extend(table);
+        table = merged;
+    }
+    resolved.insert(name.to_string(), table.clone());
+
+    Ok(table)
+}
+
+/// A general transform applied as: matrix, then scale, then rotation (Euler angles applied x, y,
+/// z), then translation.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct TransformSpec {
+    #[serde(default)]
+    matrix: Option<[[f32; 4]; 4]>,
+    #[serde(default)]
+    scale: Option<ScaleSpec>,
+    #[serde(default)]
+    rotate: Option<[Angle; 3]>,
+    #[serde(default)]
+    translate: Option<[f32; 3]>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum ScaleSpec {
+    Uniform(f32),
+    Axes([f32; 3]),
+}
+
+impl TransformSpec {
+    fn as_m4(&self) -> M4 {
+        let mut m = self.matrix.map(M4::new).unwrap_or_default();
+        if let Some(s) = self.scale {
+            let s = match s {
+                ScaleSpec::Uniform(s) => V3::new(s, s, s),
+                ScaleSpec::Axes(s) => s.into(),
+            };
+            m = M4::scale(s) * m;
+        }
+        if let Some([x, y, z]) = self.rotate {
+            let q = Quat::from_euler(x.radians(), y.radians(), z.radians());
+            m = M4::from_quat(q) * m;
+        }
+        if let Some(t) = self.translate {
+            m = M4::translate(t.into()) * m;
+        }
+
+        m
+    }
+}
+
+/// Clip away geometry outside of the given axis-aligned bounds and on the side of the plane that
+/// its normal points towards, optionally capping the cut surfaces with the named material.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ClipSpec {
+    #[serde(default)]
+    x: Option<[f32; 2]>,
+    #[serde(default)]
+    y: Option<[f32; 2]>,
+    #[serde(default)]
+    z: Option<[f32; 2]>,
+    #[serde(default)]
+    plane: Option<PlaneSpec>,
+    #[serde(default)]
+    cap: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlaneSpec {
+    point: [f32; 3],
+    normal: [f32; 3],
+}
+
+impl ClipSpec {
+    fn planes(&self) -> Vec<ClipPlane> {
+        let mut planes = Vec::new();
+        for (i, bounds) in [self.x, self.y, self.z].into_iter().enumerate() {
+            if let Some([min, max]) = bounds {
+                let mut axis = [0.0; 3];
+                axis[i] = 1.0;
+                let axis: V3 = axis.into();
+                planes.push(ClipPlane::new(P3::ORIGIN + axis * min, -axis));
+                planes.push(ClipPlane::new(P3::ORIGIN + axis * max, axis));
+            }
+        }
+        if let Some(p) = &self.plane {
+            planes.push(ClipPlane::new(p.point.into(), p.normal.into()));
+        }
+
+        planes
+    }
+
+    fn apply(&self, h: Hittable, mats: &HashMap<String, &'static Material>) -> Hittable {
+        let cap = self.cap.as_ref().map(|name| mats[name]);
+
+        h.clip(self.planes(), cap)
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct HitMeta {
+    #[serde(default)]
+    name: Option<String>,
+    /// Rotation about the y axis
+    #[serde(default)]
+    rotate: Option<Angle>,
+    #[serde(default)]
+    translate: Option<[f32; 3]>,
+    #[serde(default)]
+    density: Option<f32>,
+    #[serde(default)]
+    transform: Option<TransformSpec>,
+    #[serde(default)]
+    clip: Option<ClipSpec>,
+    /// Names of objects to intersect with the boundary of a medium
+    #[serde(default)]
+    intersect: Vec<String>,
+    /// Names of objects to subtract from the boundary of a medium
+    #[serde(default)]
+    subtract: Vec<String>,
+    /// Inclusive range of animation frames in which the object is visible
+    #[serde(default)]
+    visible_frames: Option<[u32; 2]>,
+    /// Label for segmentation, in place of the tag of the material
+    #[serde(default)]
+    tag: Option<Tag>,
+}
+
+impl HitMeta {
+    /// The transform from the space of the object into the scene.
+    fn placement(&self) -> M4 {
+        let mut m = M4::IDENTITY;
+        if let Some(angle) = self.rotate {
+            m = M4::rotate(V3::new(0.0, 1.0, 0.0), angle) * m;
+        }
+        if let Some(offset) = self.translate {
+            m = M4::translate(offset.into()) * m;
+        }
+        if let Some(t) = &self.transform {
+            m = t.as_m4() * m;
+        }
+
+        m
+    }
+
+    fn volume(
+        &self,
+        h: Hittable,
+        mats: &HashMap<String, &'static Material>,
+        named: &HashMap<&str, &ObjSpec>,
+    ) -> Volume {
+        // boundaries are checked by Scene::validate before loading
+        let shape = |name: &String| named[name.as_str()].shape(mats);
+
+        let mut v = Volume::from(h);
+        for name in self.intersect.iter() {
+            v = v.intersect(shape(name));
+        }
+        for name in self.subtract.iter() {
+            v = v.subtract(shape(name));
+        }
+
+        v
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Mesh {
+    pub path: String,
+    pub material: String,
     #[serde(default)]
     pub scale: f32,
+    #[serde(default)]
+    pub fur: Option<Fur>,
+    /// Further exports of the mesh with the same topology giving its vertex positions at evenly
+    /// spaced times over the shutter interval, with the mesh at path used for the shutter open.
+    #[serde(default)]
+    pub motion: Vec<String>,
+    /// Shade with smooth vertex normals, using the normals given in the file or generating them
+    /// for files without by averaging the faces around each vertex that meet at less than this
+    /// angle. Edges between faces at a sharper angle stay hard.
+    #[serde(default)]
+    pub smoothing_angle: Option<Angle>,
     #[serde(flatten)]
     pub meta: HitMeta,
 }
 
 impl Mesh {
     fn color(&self, mats: &HashMap<String, MatSpec>) -> Color {
-        mats.get(&self.material).unwrap().as_color()
+        // the materials of media are checked by Scene::validate before loading
+        mats[&self.material].kind.as_color().unwrap()
+    }
+
+    /// Move a vertex from mesh space into the scene.
+    fn place(&self, mut v: P3, scale: f32, transform: Option<M4>) -> P3 {
+        v = v.scale(scale);
+
+        if let Some(angle) = self.meta.rotate {
+            let (sin_theta, cos_theta) = angle.sin_cos();
+
+            v = P3::new(
+                cos_theta * v.x + sin_theta * v.z,
+                v.y,
+                -sin_theta * v.x + cos_theta * v.z,
+            );
+        }
+
+        if let Some(offset) = self.meta.translate {
+            v += V3::from(offset);
+        }
+
+        if let Some(m) = transform {
+            v = m.transform_point(v);
+        }
+
+        v
     }
 
+    /// Move a normal from mesh space into the scene, with inverse the inverse of the transform.
+    fn place_normal(&self, mut n: V3, inverse: Option<M4>) -> N3 {
+        if let Some(angle) = self.meta.rotate {
+            let (sin_theta, cos_theta) = angle.sin_cos();
+
+            n = V3::new(
+                cos_theta * n.x + sin_theta * n.z,
+                n.y,
+                -sin_theta * n.x + cos_theta * n.z,
+            );
+        }
+
+        if let Some(m) = inverse {
+            n = m.transform_normal(n);
+        }
+
+        N3::new(n)
+    }
+
+    /// The normal at each corner of the faces of m to shade them with, if the mesh is smoothed.
+    fn vertex_normals(
+        &self,
+        m: &tobj::Model,
+        scale: f32,
+        transform: Option<M4>,
+    ) -> Option<Vec<[N3; 3]>> {
+        let angle = self.smoothing_angle?;
+        let (ns, ix) = (&m.mesh.normals, &m.mesh.indices);
+
+        if ns.is_empty() {
+            let ps = &m.mesh.positions;
+            let faces: Vec<[P3; 3]> = (0..ix.len() / 3)
+                .map(|i| [0, 1, 2].map(|k| self.place(pt!(ps, ix, i * 3 + k), scale, transform)))
+                .collect();
+
+            return Some(smooth_normals(&faces, angle));
+        }
+
+        let inverse = transform.and_then(|m| m.inverse());
+        let normals = (0..ix.len() / 3)
+            .map(|i| {
+                [0, 1, 2].map(|k| {
+                    let n = pt!(ns, ix, i * 3 + k) - P3::ORIGIN;
+                    self.place_normal(n, inverse)
+                })
+            })
+            .collect();
+
+        Some(normals)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn as_hittable(
         &self,
         mats: &HashMap<String, &'static Material>,
         mat_specs: &HashMap<String, MatSpec>,
+        named: &HashMap<&str, &ObjSpec>,
         as_points: bool,
         point_radius: f32,
-    ) -> Hittable {
-        let (models, _) = load_obj(&self.path, &GPU_LOAD_OPTIONS).unwrap();
-        let mat = *mats.get(&self.material).unwrap();
+        accel: &Accel,
+        scene_scale: f32,
+    ) -> Result<Hittable, SceneError> {
+        let load = |path: &String| {
+            load_obj(path, &GPU_LOAD_OPTIONS)
+                .map(|(models, _)| models)
+                .map_err(|e| SceneError::Asset {
+                    path: path.clone(),
+                    error: e.to_string(),
+                })
+        };
+        let models = load(&self.path)?;
+        let mat = mats[&self.material];
         let mut objects = Vec::with_capacity(models.iter().map(|m| m.mesh.indices.len()).sum());
         let scale = if self.scale == 0.0 { 1.0 } else { self.scale };
+        let transform = self.meta.transform.as_ref().map(|t| t.as_m4());
+        let mut tris = Vec::new();
+        let mut fur_faces = Vec::new();
+        let mut degenerate = Vec::new();
+
+        let motion: Vec<Vec<tobj::Model>> = self
+            .motion
+            .iter()
+            .map(|path| {
+                let ms = load(path)?;
+                let matches = ms.len() == models.len()
+                    && ms
+                        .iter()
+                        .zip(models.iter())
+                        .all(|(a, b)| a.mesh.indices.len() == b.mesh.indices.len());
+                if !matches {
+                    return Err(SceneError::Asset {
+                        path: path.clone(),
+                        error: "motion sample has a different topology".to_string(),
+                    });
+                }
+
+                Ok(ms)
+            })
+            .collect::<Result<_, _>>()?;
 
         eprintln!("Loading meshes from {:?}...", self.path);
-        for m in models {
+        for (n, m) in models.iter().enumerate() {
             eprintln!("  mesh name = {:?}", m.name);
             let ps = &m.mesh.positions;
             let ix = &m.mesh.indices;
+            let normals = match as_points {
+                true => None,
+                false => self.vertex_normals(m, scale, transform),
+            };
 
             for i in 0..ix.len() / 3 {
-                let mut a = pt!(ps, ix, i * 3) * scale;
-                let mut b = pt!(ps, ix, i * 3 + 1) * scale;
-                let mut c = pt!(ps, ix, i * 3 + 2) * scale;
-
-                if let Some(angle) = self.meta.rotate {
-                    let rad = angle.to_radians();
-                    let sin_theta = rad.sin();
-                    let cos_theta = rad.cos();
-
-                    for v in [&mut a, &mut b, &mut c] {
-                        *v = V3::new(
-                            cos_theta * v.x + sin_theta * v.z,
-                            v.y,
-                            -sin_theta * v.x + cos_theta * v.z,
-                        );
-                    }
-                }
+                let [a, b, c] =
+                    [0, 1, 2].map(|k| self.place(pt!(ps, ix, i * 3 + k), scale, transform));
 
-                if let Some(v) = self.meta.translate {
-                    let v: V3 = v.into();
-                    a += v;
-                    b += v;
-                    c += v;
+                if !as_points && is_degenerate(b - a, c - a) {
+                    degenerate.push(i);
+                    continue;
                 }
 
                 if as_points {
@@ -194,25 +1378,98 @@ impl Mesh {
                             .into_iter()
                             .map(|p| Hittable::from(Sphere::new(p, point_radius, mat))),
                     );
+                } else if motion.is_empty() {
+                    let tri = Triangle::new(a, b, c, mat);
+                    tris.push(match &normals {
+                        Some(ns) => tri.with_normals(ns[i]),
+                        None => tri,
+                    });
                 } else {
-                    objects.push(Triangle::new(a, b, c, mat).into());
+                    let mut keys = vec![[a, b, c]];
+                    keys.extend(motion.iter().map(|ms| {
+                        let (ps, ix) = (&ms[n].mesh.positions, &ms[n].mesh.indices);
+                        [0, 1, 2].map(|k| self.place(pt!(ps, ix, i * 3 + k), scale, transform))
+                    }));
+                    if self.fur.is_some() {
+                        fur_faces.push(Triangle::new(a, b, c, mat));
+                    }
+                    objects.push(MovingTriangle::new(keys, mat).into());
                 }
             }
 
+            if self.fur.is_some() {
+                fur_faces.extend(tris.iter().cloned());
+            }
+            objects.extend(batch_by_type(tris.drain(..).map(Hittable::from).collect()));
+
+            if !degenerate.is_empty() {
+                let first: Vec<String> = degenerate.iter().take(5).map(|i| i.to_string()).collect();
+                eprintln!(
+                    "    warning: skipped {} degenerate faces (faces {}{})",
+                    degenerate.len(),
+                    first.join(", "),
+                    if degenerate.len() > 5 { ", ..." } else { "" }
+                );
+                degenerate.clear();
+            }
             eprintln!("    n vertices  = {}", ix.len());
             eprintln!("    n hittables = {}", objects.len());
         }
 
-        let mut h = Hittable::Bvh(Bvh::new(objects));
+        if let Some(fur) = &self.fur {
+            let curves = fur.grow(&fur_faces, mats[&fur.material]);
+            eprintln!("  n fur curves  = {}", curves.len());
+            objects.extend(curves.into_iter().map(Hittable::from));
+        }
 
+        let mut h = Hittable::Bvh(Bvh::with_accel(objects, accel, scene_scale));
+
+        if let Some(clip) = &self.meta.clip {
+            h = clip.apply(h, mats);
+        }
         if let Some(density) = self.meta.density {
-            h = ConstantMedium::new(h, density, self.color(mat_specs)).into();
+            let v = self.meta.volume(h, mats, named);
+            h = ConstantMedium::new(v, density, self.color(mat_specs)).into();
         }
 
-        h
+        Ok(h)
     }
 }
 
+/// Normals at the corners of each face averaged over the faces sharing that vertex position
+/// whose normals are within angle of the face's own, weighted by their area.
+fn smooth_normals(faces: &[[P3; 3]], angle: Angle) -> Vec<[N3; 3]> {
+    let areas: Vec<V3> = faces
+        .iter()
+        .map(|[a, b, c]| (*b - *a).cross(&(*c - *a)))
+        .collect();
+    // adding zero turns -0 into 0 so that both key the same vertex
+    let key = |p: &P3| [p.x, p.y, p.z].map(|c| (c + 0.0).to_bits());
+    let mut adjacent: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+    for (i, f) in faces.iter().enumerate() {
+        for p in f.iter() {
+            adjacent.entry(key(p)).or_default().push(i);
+        }
+    }
+
+    let cos_max = angle.cos();
+    faces
+        .iter()
+        .enumerate()
+        .map(|(i, f)| {
+            let n = areas[i].unit_vector();
+            f.map(|p| {
+                let sum = adjacent[&key(&p)]
+                    .iter()
+                    .filter(|&&j| j == i || n.dot(&areas[j].unit_vector()) >= cos_max)
+                    .fold(V3::ZERO, |sum, &j| sum + areas[j]);
+
+                N3::new(sum)
+            })
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ObjSpec {
     #[serde(flatten)]
@@ -222,11 +1479,8 @@ pub struct ObjSpec {
 }
 
 impl ObjSpec {
-    fn as_hittable(
-        &self,
-        mats: &HashMap<String, &'static Material>,
-        mat_specs: &HashMap<String, MatSpec>,
-    ) -> Hittable {
+    /// The geometry of this object with any transforms and clipping applied
+    fn shape(&self, mats: &HashMap<String, &'static Material>) -> Hittable {
         let mut h = self.hittable.as_hittable(mats);
         if let Some(angle) = self.meta.rotate {
             h = h.rotate(angle);
@@ -234,8 +1488,26 @@ impl ObjSpec {
         if let Some(v) = self.meta.translate {
             h = h.translate(v.into());
         }
+        if let Some(t) = &self.meta.transform {
+            h = h.transform(t.as_m4());
+        }
+        if let Some(clip) = &self.meta.clip {
+            h = clip.apply(h, mats);
+        }
+
+        h
+    }
+
+    fn as_hittable(
+        &self,
+        mats: &HashMap<String, &'static Material>,
+        mat_specs: &HashMap<String, MatSpec>,
+        named: &HashMap<&str, &ObjSpec>,
+    ) -> Hittable {
+        let mut h = self.shape(mats);
         if let Some(density) = self.meta.density {
-            h = ConstantMedium::new(h, density, self.hittable.color(mat_specs)).into();
+            let v = self.meta.volume(h, mats, named);
+            h = ConstantMedium::new(v, density, self.hittable.color(mat_specs)).into();
         }
 
         h
@@ -267,25 +1539,68 @@ pub enum HittableSpec {
         c: [f32; 3],
         material: String,
     },
+    Planet {
+        center: [f32; 3],
+        r: f32,
+        material: String,
+        #[serde(default)]
+        clouds: Option<CloudLayer>,
+    },
+}
+
+/// A cloud sphere surrounding a planet that can be rotated independently of the surface.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CloudLayer {
+    material: String,
+    /// Height of the clouds above the surface
+    height: f32,
+    /// Rotation of the clouds about the y axis
+    #[serde(default)]
+    rotate: Angle,
 }
 
 impl HittableSpec {
-    fn color(&self, mats: &HashMap<String, MatSpec>) -> Color {
-        let mat = match self {
-            Self::Sphere { material, .. } => mats.get(material).unwrap(),
-            Self::Box { material, .. } => mats.get(material).unwrap(),
-            Self::Quad { material, .. } => mats.get(material).unwrap(),
-            Self::Triangle { material, .. } => mats.get(material).unwrap(),
-        };
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Sphere { .. } => "sphere",
+            Self::Box { .. } => "box",
+            Self::Quad { .. } => "quad",
+            Self::Triangle { .. } => "triangle",
+            Self::Planet { .. } => "planet",
+        }
+    }
+
+    /// Whether this is a flat or box primitive with no area (see [is_degenerate])
+    fn is_degenerate(&self) -> bool {
+        match self {
+            Self::Box { vert1, vert2, .. } => (0..3).any(|i| vert1[i] == vert2[i]),
+            Self::Quad { u, v, .. } => is_degenerate((*u).into(), (*v).into()),
+            Self::Triangle { a, b, c, .. } => {
+                let a = V3::from(*a);
+                is_degenerate(V3::from(*b) - a, V3::from(*c) - a)
+            }
+            Self::Sphere { .. } | Self::Planet { .. } => false,
+        }
+    }
+
+    fn material(&self) -> &str {
+        match self {
+            Self::Sphere { material, .. } => material,
+            Self::Box { material, .. } => material,
+            Self::Quad { material, .. } => material,
+            Self::Triangle { material, .. } => material,
+            Self::Planet { material, .. } => material,
+        }
+    }
 
-        mat.as_color()
+    fn color(&self, mats: &HashMap<String, MatSpec>) -> Color {
+        // the materials of media are checked by Scene::validate before loading
+        mats[self.material()].kind.as_color().unwrap()
     }
 
     fn as_hittable(&self, mats: &HashMap<String, &'static Material>) -> Hittable {
-        let mat = |material: &str| {
-            mats.get(material)
-                .unwrap_or_else(|| panic!("unknown material: {material}"))
-        };
+        // materials are checked by Scene::validate before loading
+        let mat = |material: &str| &mats[material];
 
         match self {
             Self::Sphere {
@@ -307,6 +1622,29 @@ impl HittableSpec {
             Self::Triangle { a, b, c, material } => {
                 Triangle::new((*a).into(), (*b).into(), (*c).into(), mat(material)).into()
             }
+
+            Self::Planet {
+                center,
+                r,
+                material,
+                clouds,
+            } => {
+                let center: P3 = (*center).into();
+                let surface = Sphere::new(center, *r, mat(material)).into();
+                let Some(c) = clouds else {
+                    return surface;
+                };
+
+                let clouds =
+                    Hittable::from(Sphere::new(P3::ORIGIN, r + c.height, mat(&c.material)))
+                        .rotate(c.rotate)
+                        .translate(center - P3::ORIGIN);
+                let mut l = HittableList::default();
+                l.add(surface);
+                l.add(clouds);
+
+                l.into()
+            }
         }
     }
 }
@@ -318,23 +1656,126 @@ pub struct Scene {
     #[serde(default)]
     pub samples_step_size: u16,
     pub max_bounces: u8,
+    #[serde(default)]
+    pub bounces: Option<BounceLimits>,
+    /// Multiplier for the distance tolerances used for intersections and bounding boxes
+    #[serde(default = "default_scene_scale")]
+    pub scene_scale: f32,
+    /// Tuning of the bounding volume hierarchies built over the scene
+    #[serde(default)]
+    pub accel: Accel,
     // camera
-    pub fov: f32,
+    pub fov: Angle,
     pub image_width: u16,
     pub aspect_ratio: f32,
     pub from: [f32; 3],
     pub at: [f32; 3],
     pub v_up: [f32; 3],
+    #[serde(default)]
+    pub white_balance: Option<WhiteBalance>,
+    /// Camera shake applied to from and at in each animation frame
+    #[serde(default)]
+    pub shake: Option<CameraShake>,
+    /// How random numbers are chosen for each pixel, which decides how noise changes between
+    /// animation frames
+    #[serde(default)]
+    pub noise: NoisePattern,
+    /// The sequence the random numbers of each sample are read from
+    #[serde(default)]
+    pub sampler: SamplerKind,
+    /// The animation frame being rendered (set by at_frame)
+    #[serde(skip)]
+    pub frame: u32,
+    /// Blend each frame with the previous one reprojected to the current view
+    #[serde(default)]
+    pub temporal: Option<Temporal>,
+    #[serde(default)]
+    pub projection: Projection,
+    // shading
+    #[serde(default)]
+    pub mode: RenderMode,
+    #[serde(default)]
+    pub wireframe: Option<f32>,
+    #[serde(default)]
+    pub contact_shadows: Option<ContactShadows>,
+    #[serde(default)]
+    pub toon: Toon,
+    #[serde(default)]
+    pub outline: Option<Outline>,
+    #[serde(default)]
+    pub guiding: Option<Guiding>,
     // hittables
     pub as_points: bool,
     pub point_radius: f32,
+    /// Named colors that colors elsewhere in the scene can refer to
+    #[serde(default)]
+    pub colors: HashMap<String, ColorSpec>,
+    #[serde(deserialize_with = "deserialize_materials")]
     pub materials: HashMap<String, MatSpec>,
+    /// How the images used by materials are stored
+    #[serde(default)]
+    pub textures: TextureSettings,
     #[serde(default)]
     pub meshes: Vec<Mesh>,
     #[serde(default)]
     pub objects: Vec<ObjSpec>,
+    #[serde(default)]
+    pub particles: Vec<Particles>,
+    #[serde(default)]
+    pub voxels: Vec<Voxels>,
     // light
-    pub bg: ColorSpec,
+    pub bg: BgSpec,
+    /// Sun placed in front of the background for a location, date and time
+    #[serde(default)]
+    pub sun: Option<Sun>,
+    /// Whether the camera sees the background directly or only its light on the scene
+    #[serde(default = "default_true")]
+    pub bg_visible_to_camera: bool,
+    /// Color seen by the camera in place of a hidden background. When not set the background
+    /// is transparent in output formats that support it.
+    #[serde(default)]
+    pub backplate: Option<ColorSpec>,
+    // output
+    #[serde(default)]
+    pub output: Output,
+    // looks
+    #[serde(default)]
+    pub looks: HashMap<String, Look>,
+    /// The look to render: the name of a look or "all" to render each look in turn
+    #[serde(default)]
+    pub look: Option<String>,
+    // animation
+    /// Inclusive range of frames to render as an animation
+    #[serde(default)]
+    pub frames: Option<[u32; 2]>,
+}
+
+/// Whether an object with the given visible frame range should be rendered in a frame.
+pub fn visible_in_frame(visible_frames: Option<[u32; 2]>, frame: u32) -> bool {
+    match visible_frames {
+        Some([start, end]) => (start..=end).contains(&frame),
+        None => true,
+    }
+}
+
+/// An alternative set of material assignments and object visibility for rendering different
+/// layers (e.g. beauty, clay and silhouette) from a single scene.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Look {
+    /// Replacement materials keyed by the name of the material they replace. The key "*"
+    /// replaces all other non-emissive materials.
+    #[serde(default)]
+    pub materials: HashMap<String, String>,
+    /// Names of objects to hide
+    #[serde(default)]
+    pub hide: Vec<String>,
+    /// If given, only objects with these names are shown
+    #[serde(default)]
+    pub show: Option<Vec<String>>,
+    #[serde(default)]
+    pub mode: Option<RenderMode>,
+    #[serde(default)]
+    pub bg: Option<BgSpec>,
 }
 
 impl Default for Scene {
@@ -343,35 +1784,51 @@ impl Default for Scene {
             samples_per_pixel: DEBUG_SAMPLES_PER_PIXEL,
             samples_step_size: STEP_SIZE,
             max_bounces: MAX_BOUNCES,
+            bounces: None,
+            scene_scale: 1.0,
+            accel: Accel::default(),
             image_width: IMAGE_WIDTH,
             aspect_ratio: 1.0,
-            fov: 40.0,
+            fov: Angle::deg(40.0),
             from: [1.2, 0.2, -0.85],
             at: [0.0, 0.0, 0.0],
             v_up: [0.0, 1.0, 0.0],
+            white_balance: None,
+            shake: None,
+            noise: NoisePattern::default(),
+            sampler: SamplerKind::default(),
+            frame: 0,
+            temporal: None,
+            projection: Projection::default(),
+            mode: RenderMode::default(),
+            wireframe: None,
+            contact_shadows: None,
+            toon: Toon::default(),
+            outline: None,
+            guiding: None,
             as_points: false,
             point_radius: 0.001,
+            colors: HashMap::new(),
             materials: [
                 (
                     "grey",
-                    MatSpec::Solid {
+                    MatKind::Solid {
                         color: ColorSpec::Grey(0.5),
                     },
                 ),
-                (
-                    "light",
-                    MatSpec::Light {
-                        color: ColorSpec::Grey(25.0),
-                    },
-                ),
+                ("light", MatKind::light(ColorSpec::Grey(25.0))),
             ]
             .into_iter()
-            .map(|(s, m)| (s.to_string(), m))
+            .map(|(s, m)| (s.to_string(), m.into()))
             .collect(),
+            textures: TextureSettings::default(),
             meshes: vec![Mesh {
                 path: "assets/Dragon_8K.obj".to_string(),
                 material: "grey".to_string(),
                 scale: 1.0,
+                fur: None,
+                motion: Vec::new(),
+                smoothing_angle: None,
                 meta: HitMeta::default(),
             }],
             objects: vec![ObjSpec {
@@ -382,60 +1839,1171 @@ impl Default for Scene {
                 },
                 meta: HitMeta::default(),
             }],
-            bg: ColorSpec::RGB([0.7, 0.8, 1.0]),
+            particles: Vec::new(),
+            voxels: Vec::new(),
+            bg: BgSpec::Color(ColorSpec::RGB([0.7, 0.8, 1.0])),
+            sun: None,
+            bg_visible_to_camera: true,
+            backplate: None,
+            output: Output::default(),
+            looks: HashMap::new(),
+            look: None,
+            frames: None,
         }
     }
 }
 
 impl Scene {
-    pub fn try_from_file(path: &str) -> Option<Self> {
-        let s = fs::read_to_string(path).ok()?;
+    /// Names of the scene materials indexed by material ID - 1. IDs are assigned in sorted name
+    /// order so they are stable as long as the set of materials is unchanged.
+    pub fn material_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.materials.keys().cloned().collect();
+        names.sort();
+
+        names
+    }
+
+    /// The transform from the scene into the space of each object, in object ID order. Particles
+    /// and voxels are placed in the scene as given so their space is the scene's.
+    pub fn object_spaces(&self) -> Vec<M4> {
+        let meshes = self.meshes.iter().map(|m| {
+            let s = if m.scale == 0.0 { 1.0 } else { m.scale };
+            m.meta.placement() * M4::scale(V3::new(s, s, s))
+        });
+        let objects = self.objects.iter().map(|o| o.meta.placement());
+        let others = (self.particles.iter().map(|_| M4::IDENTITY))
+            .chain(self.voxels.iter().map(|_| M4::IDENTITY));
+
+        meshes
+            .chain(objects)
+            .chain(others)
+            .map(|m| m.inverse().unwrap_or(M4::IDENTITY))
+            .collect()
+    }
+
+    /// Tags of the scene objects indexed by object ID - 1 (see [Scene::object_names]).
+    pub fn object_tags(&self) -> Vec<Option<String>> {
+        let tag = |t: &Option<Tag>| t.as_ref().map(Tag::to_string);
+
+        (self.meshes.iter().map(|m| tag(&m.meta.tag)))
+            .chain(self.objects.iter().map(|o| tag(&o.meta.tag)))
+            .chain(self.particles.iter().map(|p| tag(&p.tag)))
+            .chain(self.voxels.iter().map(|v| tag(&v.tag)))
+            .collect()
+    }
+
+    /// Tags of the scene materials indexed by material ID - 1 (see [Scene::material_names]).
+    pub fn material_tags(&self) -> Vec<Option<String>> {
+        self.material_names()
+            .iter()
+            .map(|name| self.materials[name].tag.as_ref().map(Tag::to_string))
+            .collect()
+    }
+
+    /// Add an offset to the translation of the meshes and objects with the given name, or every
+    /// mesh and object for "*", applied after any rotation and before any transform.
+    pub fn offset_objects(&mut self, name: &str, mut offset: impl FnMut() -> V3) {
+        let metas = (self.meshes.iter_mut().map(|m| &mut m.meta))
+            .chain(self.objects.iter_mut().map(|o| &mut o.meta));
+        for meta in metas {
+            if name == "*" || meta.name.as_deref() == Some(name) {
+                let d = offset();
+                let [x, y, z] = meta.translate.unwrap_or_default();
+                meta.translate = Some([x + d.x, y + d.y, z + d.z]);
+            }
+        }
+    }
+
+    /// Names of the scene objects indexed by object ID - 1. IDs are assigned in declaration
+    /// order with meshes first, then objects, particles and voxels. Unnamed objects are named
+    /// after their mesh, particle or voxel file path or kind.
+    pub fn object_names(&self) -> Vec<String> {
+        let meshes = self.meshes.iter().map(|m| match &m.meta.name {
+            Some(name) => name.clone(),
+            None => m.path.clone(),
+        });
+        let objects = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(i, o)| match &o.meta.name {
+                Some(name) => name.clone(),
+                None => format!("{}.{i}", o.hittable.kind()),
+            });
+
+        let particles = self.particles.iter().map(|p| match &p.name {
+            Some(name) => name.clone(),
+            None => p.path.clone(),
+        });
+
+        let voxels = self.voxels.iter().map(|v| match &v.name {
+            Some(name) => name.clone(),
+            None => v.path.clone(),
+        });
+
+        meshes
+            .chain(objects)
+            .chain(particles)
+            .chain(voxels)
+            .collect()
+    }
+
+    /// Metadata describing the render of this scene loaded from path, for embedding in output
+    /// images so that they can be traced back to the configuration that produced them.
+    pub fn metadata(&self, path: &str) -> Vec<(String, String)> {
+        let f = |v: [f32; 3]| format!("[{}, {}, {}]", v[0], v[1], v[2]);
+
+        [
+            (
+                "Software",
+                format!(
+                    "raymart {} ({})",
+                    env!("CARGO_PKG_VERSION"),
+                    env!("RAYMART_GIT_HASH")
+                ),
+            ),
+            ("Scene", path.to_string()),
+            ("Samples", self.samples_per_pixel.to_string()),
+            ("MaxBounces", self.max_bounces.to_string()),
+            ("Mode", format!("{:?}", self.mode)),
+            ("Sampler", format!("{:?}", self.sampler)),
+            ("From", f(self.from)),
+            ("At", f(self.at)),
+            ("Up", f(self.v_up)),
+            ("Fov", self.fov.degrees().to_string()),
+            ("Width", self.image_width.to_string()),
+            ("AspectRatio", self.aspect_ratio.to_string()),
+        ]
+        .into_iter()
+        .chain(
+            self.output
+                .lut
+                .as_ref()
+                .map(|lut| ("Lut", lut.path.clone())),
+        )
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+    }
+
+    /// This scene with the named look applied.
+    pub fn with_look(&self, name: &str) -> Result<Scene, SceneError> {
+        let look = self
+            .looks
+            .get(name)
+            .ok_or_else(|| SceneError::UnknownLook(name.to_string()))?;
+        let mut s = self.clone();
+
+        for (mat, spec) in s.materials.iter_mut() {
+            let replacement = match look.materials.get(mat) {
+                Some(r) => r,
+                None => match look.materials.get("*") {
+                    Some(r) if !matches!(spec.kind, MatKind::Light { .. }) => r,
+                    _ => continue,
+                },
+            };
+            *spec = self
+                .materials
+                .get(replacement)
+                .ok_or_else(|| SceneError::MissingMaterial {
+                    user: format!("look {name}"),
+                    material: replacement.clone(),
+                })?
+                .clone();
+        }
+
+        let visible: Vec<bool> = self
+            .object_names()
+            .iter()
+            .map(|n| {
+                !look.hide.contains(n) && look.show.as_ref().is_none_or(|show| show.contains(n))
+            })
+            .collect();
+        let mut visible = visible.into_iter();
+        s.meshes.retain(|_| visible.next().unwrap());
+        s.objects.retain(|_| visible.next().unwrap());
+        s.particles.retain(|_| visible.next().unwrap());
+        s.voxels.retain(|_| visible.next().unwrap());
+
+        if let Some(mode) = look.mode {
+            s.mode = mode;
+        }
+        if let Some(bg) = &look.bg {
+            s.bg = bg.clone();
+        }
+
+        Ok(s)
+    }
+
+    /// This scene as it appears in the given animation frame, with objects outside of their
+    /// visible frame range removed, camera shake applied and the output path suffixed with the
+    /// frame number.
+    pub fn at_frame(&self, frame: u32) -> Scene {
+        let mut s = self.clone();
+        s.frame = frame;
+        if let Some(shake) = self.shake {
+            let (d_from, d_at) = shake.offsets(frame);
+            let add = |p: [f32; 3], d: V3| [p[0] + d.x, p[1] + d.y, p[2] + d.z];
+            s.from = add(self.from, d_from);
+            s.at = add(self.at, d_at);
+        }
+        s.sun = self.sun.as_ref().map(|sun| sun.at_frame(frame));
+
+        s.meshes
+            .retain(|m| visible_in_frame(m.meta.visible_frames, frame));
+        s.objects
+            .retain(|o| visible_in_frame(o.meta.visible_frames, frame));
+        s.particles
+            .retain(|p| visible_in_frame(p.visible_frames, frame));
+        s.voxels
+            .retain(|v| visible_in_frame(v.visible_frames, frame));
 
-        Some(toml::from_str(&s).unwrap())
+        let ext = s.output.format.extension();
+        s.output.path = Some(self.output.aux_path(&format!("{frame:04}"), ext));
+
+        s
+    }
+
+    /// The scenes to render for each animation frame, or just this scene if it is not animated.
+    pub fn animation_frames(&self) -> Vec<(Option<u32>, Scene)> {
+        match self.frames {
+            Some([start, end]) => (start..=end).map(|f| (Some(f), self.at_frame(f))).collect(),
+            None => vec![(None, self.clone())],
+        }
     }
 
-    pub fn load_scene(&self) -> (Vec<Hittable>, Camera) {
+    /// The scenes to render for the selected look along with the look names. When rendering all
+    /// looks each one is written to an output path suffixed with its name.
+    pub fn selected_looks(&self) -> Result<Vec<(Option<String>, Scene)>, SceneError> {
+        match self.look.as_deref() {
+            None => Ok(vec![(None, self.clone())]),
+            Some("all") => {
+                let mut names: Vec<&String> = self.looks.keys().collect();
+                names.sort();
+
+                names
+                    .into_iter()
+                    .map(|name| {
+                        let mut s = self.with_look(name)?;
+                        let ext = s.output.format.extension();
+                        s.output.path = Some(self.output.aux_path(name, ext));
+                        Ok((Some(name.clone()), s))
+                    })
+                    .collect()
+            }
+            Some(name) => Ok(vec![(Some(name.to_string()), self.with_look(name)?)]),
+        }
+    }
+
+    pub fn try_from_file(path: &str) -> Result<Self, SceneError> {
+        let s = fs::read_to_string(path).map_err(|error| SceneError::File {
+            path: path.to_string(),
+            error,
+        })?;
+
+        Self::try_from_toml(&s).map_err(|error| SceneError::Parse {
+            path: path.to_string(),
+            error,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn from_toml(s: &str) -> Self {
+        Self::try_from_toml(s).unwrap()
+    }
+
+    /// Parse a scene, replacing colors given by name with their entries in its [colors] table.
+    pub fn try_from_toml(s: &str) -> Result<Self, toml::de::Error> {
+        let mut scene: Self = toml::from_str(s)?;
+        scene.resolve_colors().map_err(toml::de::Error::custom)?;
+
+        Ok(scene)
+    }
+
+    /// Replace each color given by the name of an entry in the [colors] table with that entry.
+    pub fn resolve_colors(&mut self) -> Result<(), SceneError> {
+        let mut users: Vec<(String, Vec<&mut ColorSpec>)> = vec![
+            ("bg".to_string(), self.bg.colors_mut()),
+            ("backplate".to_string(), self.backplate.iter_mut().collect()),
+            (
+                "outline".to_string(),
+                self.outline.iter_mut().map(|o| &mut o.color).collect(),
+            ),
+        ];
+        for (name, look) in self.looks.iter_mut() {
+            let colors = look.bg.iter_mut().flat_map(BgSpec::colors_mut).collect();
+            users.push((format!("look {name}"), colors));
+        }
+        for (name, spec) in self.materials.iter_mut() {
+            users.push((format!("material {name}"), spec.kind.colors_mut()));
+        }
+
+        for (user, colors) in users {
+            for c in colors {
+                c.resolve(&self.colors)
+                    .map_err(|color| SceneError::UnknownColor {
+                        user: user.clone(),
+                        color,
+                    })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Build a hittable using the named material. Lights given a power have their radiance
+    /// rescaled for the surface area of the hittable so that it emits that power.
+    fn with_light_power(
+        &self,
+        name: &str,
+        materials: &HashMap<String, &'static Material>,
+        build: impl Fn(&HashMap<String, &'static Material>) -> Result<Hittable, SceneError>,
+    ) -> Result<Hittable, SceneError> {
+        let h = build(materials)?;
+        let spec = &self.materials[name].kind;
+        let (Some(power), Some(color)) = (spec.light_power(), spec.as_color()) else {
+            return Ok(h);
+        };
+        let unscaled = h.emissive_power().luminance();
+        if unscaled <= 0.0 {
+            return Ok(h);
+        }
+
+        let color = color * (power / unscaled);
+        let mat = match *materials[name] {
+            Material::ShapedLight { shape, .. } => Material::shaped_light(color, shape),
+            _ => Material::diffuse_light(color),
+        };
+        let mut scaled = materials.clone();
+        scaled.insert(name.to_string(), Box::leak(Box::new(mat)));
+
+        build(&scaled)
+    }
+
+    /// Each material of the scene by name.
+    pub fn build_materials(&self) -> Result<HashMap<String, &'static Material>, SceneError> {
+        texture_cache::configure(self.textures);
+        let mut materials = HashMap::new();
+        for name in self.material_names() {
+            build_material(&name, &self.materials, &mut materials, &mut Vec::new())?;
+        }
+
+        Ok(materials)
+    }
+
+    /// Check that every material, object and look the scene refers to is defined, and that no
+    /// material is built from itself. Files the scene refers to are only checked when loading.
+    pub fn validate(&self) -> Result<(), SceneError> {
+        let known = |user: &str, material: &str| match self.materials.contains_key(material) {
+            true => Ok(()),
+            false => Err(SceneError::MissingMaterial {
+                user: user.to_string(),
+                material: material.to_string(),
+            }),
+        };
+
+        for name in self.material_names() {
+            let parts = match &self.materials[&name].kind {
+                MatKind::Blend { a, b, .. } => [a, b],
+                MatKind::TwoSided { front, back } => [front, back],
+                _ => continue,
+            };
+            for m in parts {
+                known(&format!("material {name}"), m)?;
+            }
+        }
+        // cycles are found from the specs alone so that no files are loaded
+        let mut building = Vec::new();
+        for name in self.material_names() {
+            material_cycle(&name, &self.materials, &mut building)?;
+        }
+
+        let names = self.object_names();
+        let used = self
+            .meshes
+            .iter()
+            .map(|m| (m.material.as_str(), &m.meta))
+            .chain(
+                self.objects
+                    .iter()
+                    .map(|o| (o.hittable.material(), &o.meta)),
+            );
+        for (name, (material, meta)) in names.iter().zip(used) {
+            known(name, material)?;
+            if meta
+                .transform
+                .as_ref()
+                .is_some_and(|t| t.as_m4().inverse().is_none())
+            {
+                return Err(SceneError::SingularTransform(name.clone()));
+            }
+            if let Some(cap) = meta.clip.as_ref().and_then(|c| c.cap.as_ref()) {
+                known(&format!("{name} clip cap"), cap)?;
+            }
+            let boundary = meta.intersect.iter().chain(meta.subtract.iter());
+            for other in boundary {
+                if !self
+                    .objects
+                    .iter()
+                    .any(|o| o.meta.name.as_ref() == Some(other))
+                {
+                    return Err(SceneError::UnknownObject {
+                        user: name.clone(),
+                        object: other.clone(),
+                    });
+                }
+            }
+            if meta.density.is_some() && self.materials[material].kind.as_color().is_none() {
+                return Err(SceneError::MediumColor {
+                    user: name.clone(),
+                    material: material.to_string(),
+                });
+            }
+        }
+        for (name, mesh) in names.iter().zip(self.meshes.iter()) {
+            if let Some(fur) = &mesh.fur {
+                known(&format!("{name} fur"), &fur.material)?;
+            }
+        }
+
+        let n = self.meshes.len() + self.objects.len();
+        for (name, ps) in names[n..].iter().zip(self.particles.iter()) {
+            known(name, &ps.material)?;
+        }
+        let n = n + self.particles.len();
+        for (name, vs) in names[n..].iter().zip(self.voxels.iter()) {
+            let mut palette: Vec<&String> = vs.materials.values().collect();
+            palette.sort();
+            for m in palette {
+                known(name, m)?;
+            }
+        }
+
+        let mut looks: Vec<&String> = self.looks.keys().collect();
+        looks.sort();
+        for look in looks {
+            let mut replacements: Vec<&String> = self.looks[look].materials.values().collect();
+            replacements.sort();
+            for m in replacements {
+                known(&format!("look {look}"), m)?;
+            }
+        }
+        match self.look.as_deref() {
+            Some(look) if look != "all" && !self.looks.contains_key(look) => {
+                Err(SceneError::UnknownLook(look.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The background with the sun (if there is one) in front of it, which also places the
+    /// sun of a physically based sky.
+    fn background(&self) -> Result<Background, SceneError> {
+        match (&self.bg, &self.sun) {
+            (
+                BgSpec::Sky {
+                    turbidity,
+                    intensity,
+                    ..
+                },
+                Some(sun),
+            ) => {
+                let sky = Sky::new(sun.direction(), *turbidity, *intensity);
+                Ok(sun.background(Background::Sky(sky)))
+            }
+            (bg, Some(sun)) => Ok(sun.background(bg.try_into()?)),
+            (bg, None) => bg.try_into(),
+        }
+    }
+
+    /// A tree over hittables loaded from this scene, built with its [Accel] settings and scale.
+    pub fn build_bvh(&self, hittables: Vec<Hittable>) -> Bvh {
+        Bvh::with_accel(hittables, &self.accel, self.scene_scale)
+    }
+
+    pub fn load_scene(&self) -> Result<(Vec<Hittable>, Camera), SceneError> {
+        self.validate()?;
         let mut hittables = Vec::new();
-        let materials: HashMap<String, &'static Material> = self
-            .materials
+        let materials = self.build_materials()?;
+
+        let mat_ids: HashMap<String, u32> = self
+            .material_names()
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| (name, i as u32 + 1))
+            .collect();
+        // objects without a material ID are never split
+        let samples_mult: Vec<u8> = std::iter::once(1)
+            .chain(
+                self.material_names()
+                    .iter()
+                    .map(|name| self.materials[name].samples_mult.max(1)),
+            )
+            .collect();
+
+        // Named objects can be referenced as part of the boundary of a medium
+        let named: HashMap<&str, &ObjSpec> = self
+            .objects
             .iter()
-            .map(|(k, v)| (k.clone(), Box::leak(Box::new(v.into())) as &'static _))
+            .filter_map(|obj| Some((obj.meta.name.as_deref()?, obj)))
             .collect();
 
         for mesh in self.meshes.iter() {
-            hittables.push(mesh.as_hittable(
-                &materials,
-                &self.materials,
-                self.as_points,
-                self.point_radius,
-            ));
+            let h = self.with_light_power(&mesh.material, &materials, |materials| {
+                mesh.as_hittable(
+                    materials,
+                    &self.materials,
+                    &named,
+                    self.as_points,
+                    self.point_radius,
+                    &self.accel,
+                    self.scene_scale,
+                )
+            })?;
+            let id = hittables.len() as u32 + 1;
+            hittables.push(h.with_id(id, mat_ids[&mesh.material]));
+        }
+
+        let names = self.object_names();
+        for (i, obj) in self.objects.iter().enumerate() {
+            if obj.hittable.is_degenerate() {
+                let name = &names[self.meshes.len() + i];
+                eprintln!("warning: {name} is degenerate (zero area) and will not be visible");
+            }
+            let h = self.with_light_power(obj.hittable.material(), &materials, |materials| {
+                Ok(obj.as_hittable(materials, &self.materials, &named))
+            })?;
+            let id = hittables.len() as u32 + 1;
+            hittables.push(h.with_id(id, mat_ids[obj.hittable.material()]));
         }
 
-        for obj in self.objects.clone().into_iter() {
-            hittables.push(obj.as_hittable(&materials, &self.materials));
+        let look_from = p!(self.from[0], self.from[1], self.from[2]);
+        for ps in self.particles.iter() {
+            let particles = ps
+                .as_hittables(materials[&ps.material], look_from)
+                .map_err(|e| SceneError::Asset {
+                    path: ps.path.clone(),
+                    error: e.to_string(),
+                })?;
+            eprintln!("Loaded {} particles from {:?}", particles.len(), ps.path);
+
+            let h = Hittable::Bvh(self.build_bvh(batch_by_type(particles)));
+            let id = hittables.len() as u32 + 1;
+            hittables.push(h.with_id(id, mat_ids[&ps.material]));
+        }
+
+        for vs in self.voxels.iter() {
+            let grid = vs.as_grid(&materials).map_err(|e| SceneError::Asset {
+                path: vs.path.clone(),
+                error: e.to_string(),
+            })?;
+            eprintln!("Loaded {} voxels from {:?}", grid.n_filled(), vs.path);
+
+            // palette colors are not scene materials so they share the unassigned material ID
+            let id = hittables.len() as u32 + 1;
+            hittables.push(Hittable::from(grid).with_id(id, 0));
+        }
+
+        let occlusion_masks: Vec<(f32, &OnceLock<OcclusionGrid>)> = materials
+            .values()
+            .filter_map(|m| match m {
+                Material::Blend {
+                    mask: Mask::Occlusion { distance, grid },
+                    ..
+                }
+                | Material::Metal {
+                    fuzz: Fuzz::Mask(Mask::Occlusion { distance, grid }),
+                    ..
+                } => Some((*distance, *grid)),
+                _ => None,
+            })
+            .collect();
+        if !occlusion_masks.is_empty() {
+            eprintln!("Baking ambient occlusion...");
+            let bvh = self.build_bvh(hittables.clone());
+            for (distance, grid) in occlusion_masks {
+                grid.set(OcclusionGrid::bake(&bvh, distance)).unwrap();
+            }
         }
 
         let v_up = v!(self.v_up[0], self.v_up[1], self.v_up[2]);
-        let defocus_angle = 0.0;
+        let defocus_angle = Angle::ZERO;
         let focus_dist = 10.0;
-        let look_from = p!(self.from[0], self.from[1], self.from[2]);
         let look_at = p!(self.at[0], self.at[1], self.at[2]);
 
-        let camera = Camera::new(
+        let mut camera = Camera::new(
             self.aspect_ratio,
             self.image_width,
             self.samples_per_pixel,
             self.samples_step_size,
             self.max_bounces,
-            (&self.bg).into(),
+            self.background()?,
             self.fov,
             look_from,
             look_at,
             v_up,
             defocus_angle,
             focus_dist,
+            self.mode,
+            self.wireframe,
+            self.white_balance,
+        )
+        .with_projection(self.projection)
+        .with_bounce_limits(self.bounces)
+        .with_contact_shadows(self.contact_shadows)
+        .with_toon(self.toon)
+        .with_outline(self.outline.clone())
+        .with_samples_mult(samples_mult)
+        .with_guiding(self.guiding)
+        .with_noise(self.noise, self.frame)
+        .with_sampler(self.sampler.sampler())
+        .with_temporal(self.temporal)
+        .with_passes(self.output.passes)
+        .with_scene_scale(self.scene_scale)
+        .with_overscan(self.output.overscan);
+        if let Some(path) = &self.output.backplate {
+            let (w, h) = camera.dimensions();
+            let plate = self
+                .output
+                .load_backplate(w, h)
+                .map_err(|e| SceneError::Asset {
+                    path: path.clone(),
+                    error: e.to_string(),
+                })?;
+            camera = camera.with_backplate_image(plate.unwrap_or_default());
+        } else if !self.bg_visible_to_camera {
+            camera = camera.with_hidden_bg(self.backplate.as_ref().map(Color::from));
+        }
+
+        Ok((hittables, camera))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    const SCENE: &str = r#"
+samples_per_pixel = 1
+max_bounces = 1
+image_width = 10
+aspect_ratio = 1.0
+fov = 40.0
+from = [0.0, 0.0, -1.0]
+at = [0.0, 0.0, 0.0]
+v_up = [0.0, 1.0, 0.0]
+as_points = false
+point_radius = 0.0
+bg = 0.5
+
+[materials.red]
+kind = "solid"
+color = [1.0, 0.0, 0.0]
+
+[materials.grey]
+kind = "solid"
+color = 0.5
+
+[materials.light]
+kind = "light"
+color = 10.0
+
+[[objects]]
+kind = "sphere"
+name = "ball"
+center = [0.0, 0.0, 0.0]
+r = 1.0
+material = "red"
+
+[[objects]]
+kind = "sphere"
+name = "lamp"
+center = [0.0, 5.0, 0.0]
+r = 1.0
+material = "light"
+
+[looks.clay]
+materials = { "*" = "grey" }
+
+[looks.lamp_only]
+show = ["lamp"]
+bg = 0.0
+
+[looks.no_lamp]
+hide = ["lamp"]
+"#;
+
+    #[test]
+    fn look_materials_replace_non_emissive() {
+        let scene: Scene = toml::from_str(SCENE).unwrap();
+        let s = scene.with_look("clay").unwrap();
+
+        assert!(
+            matches!(s.materials["red"].kind, MatKind::Solid { color: ColorSpec::Grey(g) } if g == 0.5)
+        );
+        assert!(matches!(s.materials["light"].kind, MatKind::Light { .. }));
+    }
+
+    #[test_case("lamp_only", &["lamp"]; "show")]
+    #[test_case("no_lamp", &["ball"]; "hide")]
+    #[test_case("clay", &["ball", "lamp"]; "unchanged")]
+    #[test]
+    fn look_visibility_works(look: &str, expected: &[&str]) {
+        let scene: Scene = toml::from_str(SCENE).unwrap();
+
+        assert_eq!(scene.with_look(look).unwrap().object_names(), expected);
+    }
+
+    #[test]
+    fn all_looks_are_written_to_separate_paths() {
+        let mut scene: Scene = toml::from_str(SCENE).unwrap();
+        scene.look = Some("all".to_string());
+
+        let paths: Vec<String> = scene
+            .selected_looks()
+            .unwrap()
+            .into_iter()
+            .map(|(_, s)| s.output.path())
+            .collect();
+
+        assert_eq!(
+            paths,
+            ["test.clay.ppm", "test.lamp_only.ppm", "test.no_lamp.ppm"]
+        );
+    }
+
+    /// An object that is only visible for part of an animation
+    const CAMEO: &str = r#"
+[[objects]]
+kind = "sphere"
+name = "cameo"
+center = [2.0, 0.0, 0.0]
+r = 0.5
+material = "grey"
+visible_frames = [2, 3]
+"#;
+
+    #[test_case(1, &["ball", "lamp"]; "before")]
+    #[test_case(2, &["ball", "lamp", "cameo"]; "first frame")]
+    #[test_case(3, &["ball", "lamp", "cameo"]; "last frame")]
+    #[test_case(4, &["ball", "lamp"]; "after")]
+    #[test]
+    fn objects_are_only_visible_in_their_frame_range(frame: u32, expected: &[&str]) {
+        let scene: Scene = toml::from_str(&format!("{SCENE}{CAMEO}")).unwrap();
+        let s = scene.at_frame(frame);
+
+        assert_eq!(s.object_names(), expected);
+        assert_eq!(s.output.path(), format!("test.{frame:04}.ppm"));
+    }
+
+    #[test]
+    fn object_spaces_undo_object_transforms() {
+        let mut scene: Scene = toml::from_str(SCENE).unwrap();
+        scene.objects[0] = toml::from_str(
+            r#"
+kind = "sphere"
+center = [1.0, 0.0, 0.0]
+r = 0.5
+material = "red"
+rotate = 90.0
+translate = [1.0, 2.0, 3.0]
+"#,
+        )
+        .unwrap();
+        let (hittables, _) = scene.load_scene().unwrap();
+        let bvh = Bvh::new(hittables);
+
+        let r = crate::Ray::new(p!(1, 2, 10), V3::new(0.0, 0.0, -1.0));
+        let ray_t = crate::hit::Interval::new(0.001, f32::INFINITY);
+        let hr = bvh
+            .hits(&r, ray_t, &mut [0; crate::bvh::MAX_BVH_DEPTH])
+            .unwrap();
+        let local = scene.object_spaces()[hr.obj_id as usize - 1].transform_point(hr.p);
+
+        assert!((local - p!(0.5, 0, 0)).length() < 1e-4, "{local:?}");
+    }
+
+    #[test]
+    fn tags_can_be_numbers_or_strings() {
+        let mut scene: Scene = toml::from_str(SCENE).unwrap();
+        let tags: HashMap<String, Tag> = toml::from_str("ball = 7\nlamp = \"lamp\"").unwrap();
+        assert_eq!(tags["ball"], Tag::Id(7));
+        scene.objects[0].meta.tag = Some(tags["ball"].clone());
+        scene.objects[1].meta.tag = Some(tags["lamp"].clone());
+        scene.materials.get_mut("red").unwrap().tag = Some(Tag::Name("red".to_string()));
+
+        let tag = |t: &str| Some(t.to_string());
+        assert_eq!(scene.object_tags(), [tag("7"), tag("lamp")]);
+        assert_eq!(scene.material_tags(), [None, None, tag("red")]);
+    }
+
+    #[test]
+    fn camera_shake_is_smooth_and_reproducible() {
+        let mut scene: Scene = toml::from_str(SCENE).unwrap();
+        scene.shake = Some(CameraShake {
+            amplitude: 0.5,
+            frequency: 0.1,
+            seed: 42,
+        });
+        let dist = |a: [f32; 3], b: [f32; 3]| (V3::from(a) - V3::from(b)).length();
+
+        let froms: Vec<[f32; 3]> = (0..20).map(|f| scene.at_frame(f).from).collect();
+
+        assert!(froms.iter().any(|&p| dist(p, scene.from) > 0.0));
+        assert!(froms
+            .iter()
+            .all(|&p| dist(p, scene.from) < 0.5 * 3f32.sqrt()));
+        assert!(
+            froms.windows(2).all(|w| dist(w[0], w[1]) < 0.2),
+            "{froms:?}"
+        );
+        assert_eq!(scene.at_frame(7).at, scene.at_frame(7).at);
+    }
+
+    #[test]
+    fn skies_have_a_sun_placed_by_direction_or_by_the_scene_sun() {
+        let bg = "bg = { turbidity = 3.0, sun_direction = [1.0, 1.0, 0.0] }";
+        let mut scene = Scene::from_toml(&SCENE.replace("bg = 0.5", bg));
+        let sun_dir = |s: &Scene| match s.background().unwrap() {
+            Background::Sun {
+                sky: Background::Sky(_),
+                dir,
+                ..
+            } => dir,
+            bg => panic!("unexpected background: {bg:?}"),
+        };
+
+        assert!((sun_dir(&scene) - v!(1, 1, 0).unit_vector()).length() < 1e-5);
+
+        let sun =
+            "location = [51.48, 0.0]\ndate = \"2024-06-21\"\ntime = \"13:00\"\nutc_offset = 1.0";
+        scene.sun = Some(toml::from_str(sun).unwrap());
+        let dir = sun_dir(&scene);
+        assert!(dir.y > 0.8 && dir.z > 0.0, "{dir:?}");
+    }
+
+    #[test_case("bg = { stars = 0.5 }", 0.0; "stars")]
+    #[test_case("bg = { stars = 0.5, band = { normal = [0.0, 1.0, 0.0] } }", 1e-3; "stars and band")]
+    #[test]
+    fn starfields_have_an_optional_band(bg: &str, min_band_light: f32) {
+        let scene = Scene::from_toml(&SCENE.replace("bg = 0.5", bg));
+        let Background::Stars(stars) = scene.background().unwrap() else {
+            panic!("expected a starfield");
+        };
+        // the band runs around the horizon
+        let light = stars.value(v!(0.3, 0.01, -1)).luminance();
+
+        assert!(light >= min_band_light && light < 1.0, "{light}");
+    }
+
+    #[test_case(30.0, v!(0, 1, 0); "hard edge")]
+    #[test_case(100.0, v!(0, 1, 1).unit_vector(); "smoothed edge")]
+    #[test]
+    fn smoothing_angle_keeps_sharper_edges_hard(angle: f32, expected: V3) {
+        let floor = [p!(0, 0, 0), p!(0, 0, 1), p!(1, 0, 0)];
+        let wall = [p!(0, 0, 0), p!(1, 0, 0), p!(0, 1, 0)];
+
+        let normals = smooth_normals(&[floor, wall], Angle::deg(angle));
+
+        let n = normals[0][0].as_v3();
+        assert!((n - expected).length() < 1e-5, "{n:?}");
+        // the corner of the floor away from the wall only has the floor around it
+        assert_eq!(normals[0][1].as_v3(), v!(0, 1, 0));
+    }
+
+    #[test_case("bg = 0.5", Background::Solid(Color::grey(0.5)); "grey")]
+    #[test_case("bg = { top = [0.5, 0.7, 1.0], bottom = 1.0 }", Background::Gradient { top: Color::new(0.5, 0.7, 1.0), bottom: Color::WHITE }; "gradient")]
+    #[test_case("bg = { zenith = 1.0, horizon = 0.5, ground = 0.0 }", Background::Horizon { zenith: Color::WHITE, horizon: Color::grey(0.5), ground: Color::BLACK, sharpness: 3.0 }; "horizon")]
+    #[test]
+    fn bg_specs_parse(bg: &str, expected: Background) {
+        let scene: Scene = toml::from_str(&SCENE.replace("bg = 0.5", bg)).unwrap();
+
+        assert_eq!(Background::try_from(&scene.bg).unwrap(), expected);
+    }
+    fn blend_specs(toml: &str) -> HashMap<String, MatSpec> {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn blends_are_built_after_their_materials() {
+        let specs = blend_specs(
+            r#"
+dirty = { kind = "blend", a = "clean", b = "rust", mask = 0.5 }
+rust = { kind = "blend", a = "red", b = "brown", mask = { noise = 4.0 } }
+clean = { kind = "metal", color = 0.9, fuzz = 0.0 }
+red = { kind = "solid", color = [0.5, 0.1, 0.0] }
+brown = { kind = "solid", color = [0.3, 0.2, 0.1] }
+"#,
+        );
+        let mut built = HashMap::new();
+
+        let dirty = build_material("dirty", &specs, &mut built, &mut Vec::new()).unwrap();
+
+        assert!(
+            matches!(dirty, Material::Blend { a, b, .. } if std::ptr::eq(*a, built["clean"]) && std::ptr::eq(*b, built["rust"]))
+        );
+        assert_eq!(built.len(), 5);
+    }
+
+    #[test]
+    fn blend_cycles_are_errors() {
+        let specs = blend_specs(
+            r#"
+a = { kind = "blend", a = "b", b = "c", mask = 0.5 }
+b = { kind = "blend", a = "a", b = "c", mask = 0.5 }
+c = { kind = "solid", color = 0.5 }
+"#,
+        );
+        let expected = "materials form a cycle: a -> b -> a";
+
+        let err = build_material("a", &specs, &mut HashMap::new(), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+
+        let mut scene = Scene::from_toml(SCENE);
+        scene.materials = specs;
+        scene.objects.clear();
+        assert_eq!(scene.validate().unwrap_err().to_string(), expected);
+    }
+
+    #[test_case("[looks.chalk]\nmaterials = { red = \"chalk\" }", "look chalk: unknown material: chalk"; "unknown look material")]
+    #[test_case("[[meshes]]\npath = \"cat.obj\"\nmaterial = \"red\"\nfur = { material = \"ginger\", density = 1.0, length = 0.1 }", "cat.obj fur: unknown material: ginger"; "unknown fur material")]
+    #[test_case("[[objects]]\nkind = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nr = 1.0\nmaterial = \"red\"\nclip = { plane = { point = [0.0, 0.0, 0.0], normal = [0.0, 1.0, 0.0] }, cap = \"gold\" }", "sphere.2 clip cap: unknown material: gold"; "unknown clip cap")]
+    #[test]
+    fn unknown_references_are_errors(extra: &str, expected: &str) {
+        let scene = Scene::from_toml(&format!("{SCENE}\n{extra}"));
+
+        assert_eq!(scene.validate().unwrap_err().to_string(), expected);
+        assert!(scene.load_scene().is_err());
+    }
+
+    #[test]
+    fn media_need_a_material_with_a_color() {
+        let scene = Scene::from_toml(&format!(
+            "{SCENE}\n[materials.glass]\nkind = \"dielectric\"\nref_index = 1.5\n[[objects]]\nkind = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nr = 1.0\nmaterial = \"glass\"\ndensity = 0.5"
+        ));
+
+        assert_eq!(
+            scene.validate().unwrap_err().to_string(),
+            "sphere.2: material glass has no color for a medium"
+        );
+        assert!(scene.load_scene().is_err());
+    }
+
+    #[test]
+    fn singular_transforms_are_errors() {
+        let scene = Scene::from_toml(&format!(
+            "{SCENE}\n[[objects]]\nkind = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nr = 1.0\nmaterial = \"grey\"\ntransform = {{ scale = [1.0, 0.0, 1.0] }}"
+        ));
+
+        assert_eq!(
+            scene.validate().unwrap_err().to_string(),
+            "sphere.2: transform can't be inverted"
+        );
+        assert!(scene.load_scene().is_err());
+    }
+
+    #[test]
+    fn unknown_looks_are_errors() {
+        let mut scene = Scene::from_toml(SCENE);
+        scene.look = Some("chalk".to_string());
+
+        assert_eq!(
+            scene.validate().unwrap_err().to_string(),
+            "unknown look: chalk"
+        );
+        assert!(scene.selected_looks().is_err());
+    }
+
+    #[test]
+    fn missing_files_are_errors() {
+        let err = Scene::try_from_file("no/such/scene.toml").unwrap_err();
+        assert!(matches!(err, SceneError::File { .. }), "{err}");
+
+        let scene = Scene::from_toml(&format!(
+            "{SCENE}\n[[meshes]]\npath = \"no/such/mesh.obj\"\nmaterial = \"red\""
+        ));
+        let err = scene.load_scene().unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("unable to load no/such/mesh.obj"),
+            "{err}"
+        );
+
+        let scene = Scene::from_toml(&format!(
+            "{SCENE}\n[materials.mask]\nkind = \"blend\"\na = \"red\"\nb = \"grey\"\nmask = \"no/such/mask.png\""
+        ));
+        let err = scene.load_scene().unwrap_err();
+        assert!(
+            matches!(&err, SceneError::Asset { path, .. } if path == "no/such/mask.png"),
+            "{err}"
+        );
+
+        let mut scene = Scene::from_toml(SCENE);
+        scene.output.backplate = Some("no/such/plate.png".to_string());
+        let err = scene.load_scene().unwrap_err();
+        assert!(
+            matches!(&err, SceneError::Asset { path, .. } if path == "no/such/plate.png"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn lights_given_a_power_emit_it_whatever_their_size() {
+        let scene = Scene::from_toml(&format!(
+            "{}\n[[objects]]\nkind = \"sphere\"\ncenter = [0.0, 5.0, 3.0]\nr = 4.0\nmaterial = \"light\"\n",
+            SCENE.replace("color = 10.0", "color = [1.0, 0.5, 0.5]\nlumens = 6830.0")
+        ));
+        let (hittables, _) = scene.load_scene().unwrap();
+
+        let powers: Vec<f32> = hittables
+            .iter()
+            .map(|h| h.emissive_power().luminance())
+            .filter(|&p| p > 0.0)
+            .collect();
+
+        assert_eq!(powers.len(), 2);
+        for p in powers {
+            assert!((p - 10.0).abs() < 1e-3, "{p}");
+        }
+    }
+
+    #[test]
+    fn colors_can_be_named_or_given_as_hex() {
+        let scene = Scene::from_toml(&format!(
+            "{}\n[colors]\nbrand_red = \"#ff0000\"\nsky = [0.1, 0.2, 0.3]\n",
+            SCENE
+                .replace("color = [1.0, 0.0, 0.0]", "color = \"brand_red\"")
+                .replace("color = 0.5", "color = \"#808080\"")
+                .replace("bg = 0.5", "bg = \"sky\"")
+        ));
+
+        let color = |name: &str| match scene.materials[name].kind {
+            MatKind::Solid {
+                color: ColorSpec::RGB(rgb),
+            } => rgb,
+            ref spec => panic!("unexpected spec: {spec:?}"),
+        };
+        let grey = (128.0f32 / 255.0).powi(2);
+
+        assert_eq!(color("red"), [1.0, 0.0, 0.0]);
+        assert_eq!(color("grey"), [grey; 3]);
+        assert!(matches!(scene.bg, BgSpec::Color(ColorSpec::RGB([_, g, _])) if g == 0.2));
+    }
+
+    #[test]
+    fn unknown_colors_are_errors() {
+        let named = Scene::try_from_toml(&SCENE.replace("color = 0.5", "color = \"nope\""));
+        let hex = ColorSpec::try_from(RawColorSpec::Named("#12345g".to_string()));
+
+        let err = named.unwrap_err().to_string();
+        assert!(err.contains("material grey: unknown color: nope"), "{err}");
+        assert_eq!(hex.unwrap_err(), "invalid hex color: #12345g");
+    }
+
+    fn library(toml: &str) -> Result<HashMap<String, MatSpec>, toml::de::Error> {
+        deserialize_materials(toml::Value::Table(toml::from_str(toml).unwrap()))
+    }
+
+    #[test]
+    fn materials_inherit_the_parameters_they_do_not_set() {
+        let specs = library(
+            r#"
+base_metal = { kind = "metal", color = 0.9, fuzz = 0.0 }
+brushed = { extends = "base_metal", fuzz = 0.3 }
+dark_brushed = { extends = "brushed", color = 0.2 }
+"#,
+        )
+        .unwrap();
+
+        match &specs["dark_brushed"].kind {
+            MatKind::Metal {
+                color: ColorSpec::Grey(c),
+                fuzz: FuzzSpec::Mask(MaskSpec::Factor(f)),
+                ..
+            } => assert_eq!((*c, *f), (0.2, 0.3)),
+            spec => panic!("unexpected spec: {spec:?}"),
+        }
+        assert!(matches!(
+            specs["base_metal"].kind,
+            MatKind::Metal {
+                fuzz: FuzzSpec::Mask(MaskSpec::Factor(f)),
+                ..
+            } if f == 0.0
+        ));
+    }
+
+    #[test]
+    fn metal_presets_use_the_measured_conductor() {
+        let specs = library(
+            r#"
+gold = { kind = "metal", preset = "gold", fuzz = 0.1 }
+foil = { kind = "metal", preset = "aluminum", color = 0.8, fuzz = 0.0 }
+"#,
+        )
+        .unwrap();
+
+        let gold = Material::try_from(&specs["gold"].kind).unwrap();
+        assert!(
+            matches!(gold, Material::Metal { albedo, conductor: Some(c), .. } if albedo == Color::WHITE && c == Conductor::GOLD)
+        );
+        let foil = Material::try_from(&specs["foil"].kind).unwrap();
+        assert!(
+            matches!(foil, Material::Metal { albedo, conductor: Some(c), .. } if albedo == Color::grey(0.8) && c == Conductor::ALUMINIUM)
+        );
+    }
+
+    #[test]
+    fn metal_fuzz_can_vary_by_channel_or_over_the_surface() {
+        let specs = library(
+            r#"
+plain = { kind = "metal", fuzz = 0.2 }
+fringed = { kind = "metal", fuzz = [0.0, 0.2, 0.4] }
+hammered = { kind = "metal", preset = "copper", fuzz = { noise = 8.0 } }
+"#,
+        )
+        .unwrap();
+        let fuzz = |name: &str| match Material::try_from(&specs[name].kind).unwrap() {
+            Material::Metal { fuzz, .. } => fuzz,
+            m => panic!("unexpected material: {m:?}"),
+        };
+
+        assert!(matches!(fuzz("plain"), Fuzz::Value(f) if f == 0.2));
+        assert!(matches!(fuzz("fringed"), Fuzz::Channels(fs) if fs == [0.0, 0.2, 0.4]));
+        assert!(matches!(fuzz("hammered"), Fuzz::Mask(Mask::Texture(_))));
+    }
+
+    #[test]
+    fn samples_mult_is_given_alongside_the_kind_of_material() {
+        let specs = library(
+            r#"
+glass = { kind = "dielectric", ref_index = 1.5, samples_mult = 4 }
+frosted = { extends = "glass", color = 1 }
+plain = { kind = "solid", color = 0.5 }
+"#,
+        )
+        .unwrap();
+
+        assert!(
+            matches!(specs["frosted"].kind, MatKind::Dielectric { ref_index, .. } if ref_index == 1.5)
+        );
+        assert_eq!(specs["frosted"].samples_mult, 4);
+        assert_eq!(specs["plain"].samples_mult, 1);
+    }
+
+    #[test]
+    fn extends_cycles_and_unknown_bases_are_errors() {
+        let cycle = library(
+            r#"
+a = { extends = "b", kind = "solid", color = 0.5 }
+b = { extends = "a" }
+"#,
         );
+        let unknown = library(r#"a = { extends = "missing", kind = "solid", color = 0.5 }"#);
 
-        (hittables, camera)
+        assert!(cycle.unwrap_err().to_string().contains("cycle"));
+        assert!(unknown
+            .unwrap_err()
+            .to_string()
+            .contains("unknown material"));
     }
 }
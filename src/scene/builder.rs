@@ -0,0 +1,346 @@
+//! Building scenes in code rather than from a TOML file. The builder fills in the same specs
+//! that scene files are parsed into, so a scene renders the same however it was built:
+//!
+//! ```rust
+//! # use raymart::scene::{ColorSpec, MatKind, SceneBuilder};
+//! # fn main() -> Result<(), raymart::scene::SceneError> {
+//! let mut b = SceneBuilder::new();
+//! b.camera([0.0, 1.0, 5.0], [0.0, 0.5, 0.0]).samples(100);
+//! b.material("glass", MatKind::Dielectric { ref_index: 1.5, color: None, roughness: 0.0 });
+//! b.material("floor", MatKind::Solid { color: ColorSpec::Grey(0.5) });
+//! b.material("lamp", MatKind::light(ColorSpec::Grey(10.0)));
+//! b.sphere(1.0).at([0.0, 1.0, 0.0]).material("glass").name("ball");
+//! b.cuboid([-5.0, -0.1, -5.0], [5.0, 0.0, 5.0]).material("floor");
+//! b.sphere(0.5).at([0.0, 4.0, 0.0]).material("lamp");
+//! let scene = b.build()?;
+//! # Ok(())
+//! # }
+//! ```
+use super::{BgSpec, HitMeta, HittableSpec, MatSpec, Mesh, ObjSpec, Scene, SceneError, Tag};
+use crate::angle::Angle;
+use std::collections::HashMap;
+
+/// A scene built up from materials and objects added in code.
+#[derive(Debug, Clone)]
+pub struct SceneBuilder {
+    scene: Scene,
+}
+
+impl Default for SceneBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SceneBuilder {
+    /// An empty scene using the default settings
+    pub fn new() -> Self {
+        Self::from_scene(Scene {
+            materials: HashMap::new(),
+            meshes: Vec::new(),
+            objects: Vec::new(),
+            ..Scene::default()
+        })
+    }
+
+    /// Add to an existing scene, e.g. one loaded from a file.
+    pub fn from_scene(scene: Scene) -> Self {
+        Self { scene }
+    }
+
+    pub fn camera(&mut self, from: [f32; 3], at: [f32; 3]) -> &mut Self {
+        self.scene.from = from;
+        self.scene.at = at;
+        self
+    }
+
+    pub fn fov(&mut self, fov: Angle) -> &mut Self {
+        self.scene.fov = fov;
+        self
+    }
+
+    pub fn image(&mut self, width: u16, aspect_ratio: f32) -> &mut Self {
+        self.scene.image_width = width;
+        self.scene.aspect_ratio = aspect_ratio;
+        self
+    }
+
+    pub fn samples(&mut self, samples_per_pixel: u16) -> &mut Self {
+        self.scene.samples_per_pixel = samples_per_pixel;
+        self
+    }
+
+    pub fn max_bounces(&mut self, max_bounces: u8) -> &mut Self {
+        self.scene.max_bounces = max_bounces;
+        self
+    }
+
+    pub fn bg(&mut self, bg: BgSpec) -> &mut Self {
+        self.scene.bg = bg;
+        self
+    }
+
+    pub fn output(&mut self, path: &str) -> &mut Self {
+        self.scene.output.path = Some(path.to_string());
+        self
+    }
+
+    /// Change any other settings of the scene.
+    pub fn settings(&mut self, f: impl FnOnce(&mut Scene)) -> &mut Self {
+        f(&mut self.scene);
+        self
+    }
+
+    /// Add a material, replacing any existing material with the same name.
+    pub fn material(&mut self, name: &str, spec: impl Into<MatSpec>) -> &mut Self {
+        self.scene.materials.insert(name.to_string(), spec.into());
+        self
+    }
+
+    /// A sphere of radius r centered on the origin until moved with [ObjectBuilder::at]
+    pub fn sphere(&mut self, r: f32) -> ObjectBuilder<'_> {
+        self.object(HittableSpec::Sphere {
+            center: [0.0; 3],
+            r,
+            material: String::new(),
+        })
+    }
+
+    /// An axis aligned box with opposite corners a and b
+    pub fn cuboid(&mut self, a: [f32; 3], b: [f32; 3]) -> ObjectBuilder<'_> {
+        self.object(HittableSpec::Box {
+            vert1: a,
+            vert2: b,
+            material: String::new(),
+        })
+    }
+
+    /// A parallelogram with a corner at q and sides u and v
+    pub fn quad(&mut self, q: [f32; 3], u: [f32; 3], v: [f32; 3]) -> ObjectBuilder<'_> {
+        self.object(HittableSpec::Quad {
+            q,
+            u,
+            v,
+            material: String::new(),
+        })
+    }
+
+    pub fn triangle(&mut self, a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> ObjectBuilder<'_> {
+        self.object(HittableSpec::Triangle {
+            a,
+            b,
+            c,
+            material: String::new(),
+        })
+    }
+
+    /// A mesh loaded from the OBJ file at path when the scene is rendered
+    pub fn mesh(&mut self, path: &str) -> ObjectBuilder<'_> {
+        self.scene.meshes.push(Mesh {
+            path: path.to_string(),
+            material: String::new(),
+            scale: 1.0,
+            fur: None,
+            motion: Vec::new(),
+            smoothing_angle: None,
+            meta: HitMeta::default(),
+        });
+        let mesh = self.scene.meshes.last_mut().unwrap();
+
+        ObjectBuilder {
+            material: &mut mesh.material,
+            meta: &mut mesh.meta,
+        }
+    }
+
+    fn object(&mut self, hittable: HittableSpec) -> ObjectBuilder<'_> {
+        self.scene.objects.push(ObjSpec {
+            hittable,
+            meta: HitMeta::default(),
+        });
+        let obj = self.scene.objects.last_mut().unwrap();
+        let material = match &mut obj.hittable {
+            HittableSpec::Sphere { material, .. }
+            | HittableSpec::Box { material, .. }
+            | HittableSpec::Quad { material, .. }
+            | HittableSpec::Triangle { material, .. }
+            | HittableSpec::Planet { material, .. } => material,
+        };
+
+        ObjectBuilder {
+            material,
+            meta: &mut obj.meta,
+        }
+    }
+
+    /// The scene, checking that every material and object referred to has been added.
+    pub fn build(&self) -> Result<Scene, SceneError> {
+        let mut scene = self.scene.clone();
+        scene.resolve_colors()?;
+        scene.validate()?;
+
+        Ok(scene)
+    }
+}
+
+/// Settings for an object just added to a [SceneBuilder].
+#[derive(Debug)]
+pub struct ObjectBuilder<'a> {
+    material: &'a mut String,
+    meta: &'a mut HitMeta,
+}
+
+impl ObjectBuilder<'_> {
+    /// The name of the scene material to use
+    pub fn material(self, name: &str) -> Self {
+        *self.material = name.to_string();
+        self
+    }
+
+    /// A name for referring to the object, e.g. in looks or medium boundaries
+    pub fn name(self, name: &str) -> Self {
+        self.meta.name = Some(name.to_string());
+        self
+    }
+
+    /// Move the object by offset, placing the origin of its own space at that point.
+    pub fn at(self, offset: [f32; 3]) -> Self {
+        self.meta.translate = Some(offset);
+        self
+    }
+
+    /// Rotate the object about the y axis before moving it.
+    pub fn rotate(self, angle: Angle) -> Self {
+        self.meta.rotate = Some(angle);
+        self
+    }
+
+    /// Fill the object with a participating medium of the given density.
+    pub fn density(self, density: f32) -> Self {
+        self.meta.density = Some(density);
+        self
+    }
+
+    /// Intersect the boundary of a medium with the named object.
+    pub fn intersect(self, name: &str) -> Self {
+        self.meta.intersect.push(name.to_string());
+        self
+    }
+
+    /// Subtract the named object from the boundary of a medium.
+    pub fn subtract(self, name: &str) -> Self {
+        self.meta.subtract.push(name.to_string());
+        self
+    }
+
+    /// Label for segmentation, in place of the tag of the material
+    pub fn tag(self, tag: Tag) -> Self {
+        self.meta.tag = Some(tag);
+        self
+    }
+
+    /// Only show the object in this inclusive range of animation frames.
+    pub fn visible_frames(self, frames: [u32; 2]) -> Self {
+        self.meta.visible_frames = Some(frames);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::{ColorSpec, MatKind};
+    use simple_test_case::test_case;
+
+    const SCENE: &str = r#"
+samples_per_pixel = 4
+max_bounces = 3
+image_width = 10
+aspect_ratio = 1.0
+fov = 40.0
+from = [0.0, 1.0, 5.0]
+at = [0.0, 0.0, 0.0]
+v_up = [0.0, 1.0, 0.0]
+as_points = false
+point_radius = 0.0
+bg = 0.5
+
+[materials.glass]
+kind = "dielectric"
+ref_index = 1.5
+
+[materials.lamp]
+kind = "light"
+color = 10.0
+
+[[objects]]
+kind = "sphere"
+name = "ball"
+center = [0.0, 0.0, 0.0]
+r = 1.0
+translate = [0.0, 1.0, 0.0]
+material = "glass"
+
+[[objects]]
+kind = "box"
+vert1 = [-1.0, 2.0, -1.0]
+vert2 = [1.0, 2.1, 1.0]
+material = "lamp"
+"#;
+
+    fn builder() -> SceneBuilder {
+        let mut b = SceneBuilder::new();
+        b.camera([0.0, 1.0, 5.0], [0.0, 0.0, 0.0])
+            .samples(4)
+            .max_bounces(3)
+            .image(10, 1.0)
+            .bg(BgSpec::Color(ColorSpec::Grey(0.5)));
+        b.material(
+            "glass",
+            MatKind::Dielectric {
+                ref_index: 1.5,
+                color: None,
+                roughness: 0.0,
+            },
+        )
+        .material("lamp", MatKind::light(ColorSpec::Grey(10.0)));
+        b.sphere(1.0)
+            .at([0.0, 1.0, 0.0])
+            .material("glass")
+            .name("ball");
+        b.cuboid([-1.0, 2.0, -1.0], [1.0, 2.1, 1.0])
+            .material("lamp");
+
+        b
+    }
+
+    #[test]
+    fn built_scenes_match_those_loaded_from_toml() {
+        let built = builder().build().unwrap();
+        let parsed = Scene::from_toml(SCENE);
+
+        assert_eq!(built.object_names(), parsed.object_names());
+        assert_eq!(built.material_names(), parsed.material_names());
+        assert_eq!(built.object_spaces(), parsed.object_spaces());
+
+        let (a, _) = built.load_scene().unwrap();
+        let (b, _) = parsed.load_scene().unwrap();
+        let summary = |hs: &[crate::hit::Hittable]| -> Vec<String> {
+            hs.iter()
+                .map(|h| format!("{:?} {:?}", h.bounding_box(), h.emissive_power()))
+                .collect()
+        };
+        assert_eq!(summary(&a), summary(&b));
+    }
+
+    #[test_case(|b: &mut SceneBuilder| { b.quad([0.0; 3], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]); }, "quad.2 has no material"; "missing material")]
+    #[test_case(|b: &mut SceneBuilder| { b.triangle([0.0; 3], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]).material("chrome"); }, "triangle.2: unknown material: chrome"; "unknown material")]
+    #[test_case(|b: &mut SceneBuilder| { b.sphere(2.0).material("glass").density(0.1).subtract("cup"); }, "sphere.2: unknown object \"cup\" in medium boundary"; "unknown boundary")]
+    #[test]
+    fn unknown_references_are_errors(add: fn(&mut SceneBuilder), expected: &str) {
+        let mut b = builder();
+        add(&mut b);
+
+        assert_eq!(b.build().unwrap_err().to_string(), expected);
+    }
+}
@@ -0,0 +1,217 @@
+//! Batched vector math over four V3s at a time using a structure of arrays layout so that each
+//! operation maps directly on to 4-wide SIMD instructions (rather than packing a single V3 into
+//! a SIMD register which leaves a lane idle and needs horizontal operations for dot products).
+use crate::V3;
+use std::ops::{Add, Mul, Neg, Sub};
+use wide::f32x4;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct V3x4 {
+    pub x: f32x4,
+    pub y: f32x4,
+    pub z: f32x4,
+}
+
+impl V3x4 {
+    pub const fn new(x: f32x4, y: f32x4, z: f32x4) -> V3x4 {
+        Self { x, y, z }
+    }
+
+    /// Copy a single vector into all four lanes.
+    pub fn splat(v: V3) -> V3x4 {
+        V3x4::new(f32x4::splat(v.x), f32x4::splat(v.y), f32x4::splat(v.z))
+    }
+
+    pub fn from_v3s(vs: [V3; 4]) -> V3x4 {
+        V3x4::new(
+            f32x4::new(vs.map(|v| v.x)),
+            f32x4::new(vs.map(|v| v.y)),
+            f32x4::new(vs.map(|v| v.z)),
+        )
+    }
+
+    /// Extract the vector stored in lane i.
+    pub fn lane(&self, i: usize) -> V3 {
+        V3::new(
+            self.x.as_array_ref()[i],
+            self.y.as_array_ref()[i],
+            self.z.as_array_ref()[i],
+        )
+    }
+
+    pub fn dot(&self, rhs: &V3x4) -> f32x4 {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
+    }
+
+    pub fn cross(&self, rhs: &V3x4) -> V3x4 {
+        V3x4::new(
+            self.y * rhs.z - self.z * rhs.y,
+            self.z * rhs.x - self.x * rhs.z,
+            self.x * rhs.y - self.y * rhs.x,
+        )
+    }
+
+    pub fn length(&self) -> f32x4 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn unit_vector(&self) -> V3x4 {
+        *self * (f32x4::ONE / self.length())
+    }
+}
+
+impl Add<V3x4> for V3x4 {
+    type Output = V3x4;
+
+    fn add(self, rhs: V3x4) -> Self::Output {
+        V3x4::new(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z)
+    }
+}
+
+impl Sub<V3x4> for V3x4 {
+    type Output = V3x4;
+
+    fn sub(self, rhs: V3x4) -> Self::Output {
+        V3x4::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
+    }
+}
+
+impl Mul<f32x4> for V3x4 {
+    type Output = V3x4;
+
+    fn mul(self, rhs: f32x4) -> Self::Output {
+        V3x4::new(self.x * rhs, self.y * rhs, self.z * rhs)
+    }
+}
+
+impl Neg for V3x4 {
+    type Output = V3x4;
+
+    fn neg(self) -> Self::Output {
+        V3x4::new(-self.x, -self.y, -self.z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hit::{batch_by_type, Hittable, Interval, Sphere, Sphere4, Triangle, Triangle4},
+        material::CLAY,
+        Ray, P3,
+    };
+
+    fn vs() -> [V3; 4] {
+        [
+            V3::new(1.0, 2.0, 3.0),
+            V3::new(-1.0, 0.5, 0.0),
+            V3::new(0.0, 0.0, 1.0),
+            V3::new(4.0, -2.0, 1.5),
+        ]
+    }
+
+    #[test]
+    fn lanes_match_scalar_ops() {
+        let (a, b) = (vs(), vs().map(|v| v.cross(&V3::new(0.3, 0.2, 0.1))));
+        let (a4, b4) = (V3x4::from_v3s(a), V3x4::from_v3s(b));
+
+        let dots = a4.dot(&b4).to_array();
+        let crosses = a4.cross(&b4);
+        let units = a4.unit_vector();
+
+        for i in 0..4 {
+            assert!((dots[i] - a[i].dot(&b[i])).abs() < 1e-5);
+            assert!((crosses.lane(i) - a[i].cross(&b[i])).length() < 1e-5);
+            assert!((units.lane(i) - a[i].unit_vector()).length() < 1e-5);
+        }
+    }
+
+    fn random_triangles(n: usize) -> Vec<Triangle> {
+        (0..n)
+            .map(|_| {
+                let a = P3::ORIGIN + V3::random(-1.0, 1.0);
+                Triangle::new(
+                    a,
+                    a + V3::random(-0.5, 0.5),
+                    a + V3::random(-0.5, 0.5),
+                    &CLAY,
+                )
+            })
+            .collect()
+    }
+
+    fn random_rays(n: usize) -> Vec<Ray> {
+        (0..n)
+            .map(|_| Ray::new(P3::ORIGIN + V3::random(-3.0, 3.0), V3::random(-1.0, 1.0)))
+            .collect()
+    }
+
+    #[test]
+    fn triangle4_matches_scalar_triangles() {
+        let tris = random_triangles(4);
+        let t4 = Triangle4::new(&tris);
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+
+        for r in random_rays(2000) {
+            let expected = tris
+                .iter()
+                .filter_map(|t| t.hits(&r, ray_t))
+                .min_by(|a, b| a.t.total_cmp(&b.t))
+                .map(|hr| hr.t);
+            let res = t4.hits(&r, ray_t).map(|hr| hr.t);
+
+            match (res, expected) {
+                (Some(a), Some(b)) => assert!((a - b).abs() < 1e-3, "{a} != {b}"),
+                (a, b) => assert_eq!(a.is_some(), b.is_some(), "{a:?} != {b:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn sphere4_matches_scalar_spheres() {
+        let spheres: Vec<Sphere> = (0..3)
+            .map(|_| Sphere::new(P3::ORIGIN + V3::random(-1.0, 1.0), 0.5, &CLAY))
+            .collect();
+        let s4 = Sphere4::new(&spheres);
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+
+        for r in random_rays(2000) {
+            let expected = spheres
+                .iter()
+                .filter_map(|s| s.hits(&r, ray_t))
+                .min_by(|a, b| a.t.total_cmp(&b.t))
+                .map(|hr| hr.t);
+            let res = s4.hits(&r, ray_t).map(|hr| hr.t);
+
+            match (res, expected) {
+                (Some(a), Some(b)) => assert!((a - b).abs() < 1e-3, "{a} != {b}"),
+                (a, b) => assert_eq!(a.is_some(), b.is_some(), "{a:?} != {b:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn batching_packs_primitives_by_type() {
+        let mut hittables: Vec<Hittable> = random_triangles(9)
+            .into_iter()
+            .map(Hittable::from)
+            .collect();
+        hittables
+            .extend((0..5).map(|i| Sphere::new(P3::new(i as f32, 0.0, 0.0), 0.5, &CLAY).into()));
+        hittables.push(Hittable::Empty);
+
+        let batched = batch_by_type(hittables);
+        let kinds: Vec<&str> = batched
+            .iter()
+            .map(|h| match h {
+                Hittable::Triangle4(_) => "tri4",
+                Hittable::Triangle(_) => "tri",
+                Hittable::Sphere4(_) => "sphere4",
+                Hittable::Sphere(_) => "sphere",
+                _ => "other",
+            })
+            .collect();
+
+        assert_eq!(kinds, ["other", "tri4", "tri4", "tri", "sphere4", "sphere"]);
+    }
+}
@@ -0,0 +1,165 @@
+//! The Preetham analytic model of the clear daylight sky, giving the light arriving from each
+//! direction for the position of the sun and the turbidity (haziness) of the atmosphere.
+//!   https://courses.cs.duke.edu/cps124/spring08/assign/07_papers/p91-preetham.pdf
+//!
+//! The model gives luminance and chromaticity (CIE Yxy) relative to their values at the zenith,
+//! which are then converted to linear sRGB. It only holds while the sun is above the horizon, so
+//! lower suns are clamped to it, and below the horizon the sky continues the horizon.
+use crate::{Color, V3};
+use std::f32::consts::PI;
+
+/// Scale from the luminance of the model in kcd/m² to scene radiance, putting the light from
+/// the sky at roughly a tenth of that of the default sun.
+const SKY_SCALE: f32 = 0.025;
+
+/// Coefficients of the Perez distribution for Y, x and y as (a, b) pairs of a * turbidity + b
+const PEREZ: [[(f32, f32); 5]; 3] = [
+    [
+        (0.1787, -1.4630),
+        (-0.3554, 0.4275),
+        (-0.0227, 5.3251),
+        (0.1206, -2.5771),
+        (-0.0670, 0.3703),
+    ],
+    [
+        (-0.0193, -0.2592),
+        (-0.0665, 0.0008),
+        (-0.0004, 0.2125),
+        (-0.0641, -0.8989),
+        (-0.0033, 0.0452),
+    ],
+    [
+        (-0.0167, -0.2608),
+        (-0.0950, 0.0092),
+        (-0.0079, 0.2102),
+        (-0.0441, -1.6537),
+        (-0.0109, 0.0529),
+    ],
+];
+
+/// Zenith chromaticity x and y as polynomials in turbidity (rows T², T, 1) and the zenith angle
+/// of the sun (columns θ³, θ², θ, 1)
+const ZENITH_X: [[f32; 4]; 3] = [
+    [0.00166, -0.00375, 0.00209, 0.0],
+    [-0.02903, 0.06377, -0.03202, 0.00394],
+    [0.11693, -0.21196, 0.06052, 0.25886],
+];
+const ZENITH_Y: [[f32; 4]; 3] = [
+    [0.00275, -0.00610, 0.00317, 0.0],
+    [-0.04214, 0.08970, -0.04153, 0.00516],
+    [0.15346, -0.26756, 0.06670, 0.26688],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sky {
+    /// Unit vector towards the sun
+    sun: V3,
+    /// Perez coefficients for Y, x and y
+    perez: [[f32; 5]; 3],
+    /// Y, x and y at the zenith divided by the Perez distribution there
+    zenith: [f32; 3],
+    intensity: f32,
+}
+
+impl Sky {
+    /// The sky for the sun in direction sun, with turbidity from 2 (very clear) to around 10
+    /// (hazy) and the light scaled by intensity.
+    pub fn new(sun: V3, turbidity: f32, intensity: f32) -> Self {
+        let mut sun = sun.unit_vector();
+        // the model breaks down once the sun has set
+        if sun.y < 0.01 {
+            let h = (sun.x * sun.x + sun.z * sun.z).sqrt().max(1e-6);
+            sun = V3::new(sun.x / h * 0.99995, 0.01, sun.z / h * 0.99995);
+        }
+        let t = turbidity.max(1.0);
+        let theta_s = sun.y.acos();
+
+        let perez = PEREZ.map(|cs| cs.map(|(a, b)| a * t + b));
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_lum = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let poly = |m: [[f32; 4]; 3]| {
+            let th = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+            let row = |r: [f32; 4]| r.iter().zip(th).map(|(c, x)| c * x).sum::<f32>();
+            t * t * row(m[0]) + t * row(m[1]) + row(m[2])
+        };
+        let zenith = [zenith_lum, poly(ZENITH_X), poly(ZENITH_Y)];
+        // normalised so that the distribution is the zenith value looking straight up
+        let zenith = [0, 1, 2].map(|i| zenith[i] / perez_f(&perez[i], 1.0, theta_s.cos()));
+
+        Self {
+            sun,
+            perez,
+            zenith,
+            intensity,
+        }
+    }
+
+    /// The light arriving from direction dir
+    pub fn value(&self, dir: V3) -> Color {
+        let dir = dir.unit_vector();
+        let cos_theta = dir.y.max(0.001);
+        let cos_gamma = dir.dot(&self.sun).clamp(-1.0, 1.0);
+        let [lum, x, y] =
+            [0, 1, 2].map(|i| self.zenith[i] * perez_f(&self.perez[i], cos_theta, cos_gamma));
+        if y <= 0.0 || lum <= 0.0 {
+            return Color::BLACK;
+        }
+
+        // Yxy to XYZ to linear sRGB
+        let (cx, cy, cz) = (x / y * lum, lum, (1.0 - x - y) / y * lum);
+        let c = Color::new(
+            3.2406 * cx - 1.5372 * cy - 0.4986 * cz,
+            -0.9689 * cx + 1.8758 * cy + 0.0415 * cz,
+            0.0557 * cx - 0.2040 * cy + 1.0570 * cz,
+        );
+
+        Color::new(c.r.max(0.0), c.g.max(0.0), c.b.max(0.0)) * (SKY_SCALE * self.intensity)
+    }
+}
+
+/// The Perez distribution for a view direction at cos_theta from the zenith and cos_gamma from
+/// the sun.
+fn perez_f(&[a, b, c, d, e]: &[f32; 5], cos_theta: f32, cos_gamma: f32) -> f32 {
+    let gamma = cos_gamma.acos();
+
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v;
+
+    #[test]
+    fn the_sky_is_blue_overhead_and_brighter_around_the_sun() {
+        let sky = Sky::new(v!(0, 1, -1), 3.0, 1.0);
+        let zenith = sky.value(v!(0, 1, 0));
+        let near_sun = sky.value(v!(0, 1, -0.9));
+        let away = sky.value(v!(0, 1, 1));
+
+        assert!(zenith.b > zenith.r, "{zenith:?}");
+        assert!(
+            near_sun.luminance() > 2.0 * away.luminance(),
+            "{near_sun:?} {away:?}"
+        );
+    }
+
+    #[test]
+    fn hazier_skies_are_whiter() {
+        let ratio = |t: f32| {
+            let c = Sky::new(v!(0, 1, -1), t, 1.0).value(v!(0, 1, 1));
+            c.b / c.r
+        };
+
+        assert!(ratio(2.0) > ratio(8.0), "{} {}", ratio(2.0), ratio(8.0));
+    }
+
+    #[test]
+    fn set_suns_and_the_ground_are_finite() {
+        let sky = Sky::new(v!(1, -0.5, 0), 3.0, 1.0);
+
+        for dir in [v!(1, 0, 0), v!(0, -1, 0), v!(-1, 0.1, 0)] {
+            assert!(sky.value(dir).is_finite(), "{:?}", sky.value(dir));
+        }
+    }
+}
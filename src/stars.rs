@@ -0,0 +1,261 @@
+//! A procedural starfield for space scenes, with an optional band of diffuse light across the sky
+//! like the Milky Way. Stars in an image fall between the pixels of a low resolution render and
+//! shimmer or vanish, so each star here is instead a small gaussian spot carrying the same total
+//! light however finely the render resolves it, and is averaged over the samples of a pixel like
+//! any other small light.
+//!
+//! The sphere is divided into a grid of cells over the faces of a cube, each holding at most one
+//! star whose position, brightness and color come from a hash of the cell, so that looking up a
+//! direction only ever checks a single cell whatever the number of stars. Brightness follows a
+//! power law, with many faint stars for every bright one, and colors are those of black bodies
+//! between 2500K and 12000K.
+use crate::{angle::Angle, color::blackbody, noise::Perlin, sampler::hash, Color, P3, V3};
+use std::f32::consts::{PI, TAU};
+
+/// Square degrees in a steradian
+const DEG2_PER_SR: f32 = (180.0 / PI) * (180.0 / PI);
+/// Brightest star relative to the faintest
+const MAX_BRIGHTNESS: f32 = 1000.0;
+/// Largest chance of a cell holding a star, for cells at the center of a face
+const MAX_CHANCE: f32 = 0.5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Starfield {
+    /// Cells along each edge of a cube face
+    cells: u32,
+    /// Chance of the cell at the center of a face holding a star, which is lower for the smaller
+    /// cells towards the corners
+    chance: f32,
+    /// Light of the faintest stars
+    brightness: f32,
+    /// Exponent of the power law giving the number of stars brighter than each brightness
+    slope: f32,
+    /// Standard deviation of the profile of a star in radians
+    sigma: f32,
+    seed: u64,
+    band: Option<Band>,
+}
+
+impl Starfield {
+    /// Stars at the given density per square degree, the faintest giving brightness light to a
+    /// surface facing them. Star sizes are limited to a small fraction of the spacing between
+    /// stars.
+    pub fn new(
+        density: f32,
+        brightness: f32,
+        slope: f32,
+        size: Angle,
+        seed: u64,
+        band: Option<Band>,
+    ) -> Self {
+        // the solid angle of a cell at the center of a face is (2 / cells)²
+        let per_sr = density.max(0.0) * DEG2_PER_SR;
+        let cells = ((4.0 * per_sr / MAX_CHANCE).sqrt().ceil() as u32).max(1);
+        let cell = 2.0 / cells as f32;
+        // the profile is cut off at 3 sigma, which is at most 9 sigma across a face at its
+        // corners, and has to fit within its cell
+        let sigma = size.radians().clamp(1e-6, cell / 20.0);
+
+        Self {
+            cells,
+            chance: per_sr * cell * cell,
+            brightness,
+            slope: slope.max(0.1),
+            sigma,
+            seed,
+            band,
+        }
+    }
+
+    /// The light arriving from direction dir
+    pub fn value(&self, dir: V3) -> Color {
+        let dir = dir.unit_vector();
+        let band = self.band.as_ref().map_or(Color::BLACK, |b| b.value(dir));
+
+        band + self.star(dir)
+    }
+
+    /// The light of the star in the cell containing dir, if there is one and dir is within it
+    fn star(&self, dir: V3) -> Color {
+        let (face, s, t) = to_face(dir);
+        let cell = |x: f32| (((x + 1.0) * 0.5 * self.cells as f32) as u32).min(self.cells - 1);
+        let Some((center, light)) = self.cell_star(face, cell(s), cell(t)) else {
+            return Color::BLACK;
+        };
+
+        let d = dir - center;
+        let (d2, var) = (d.dot(&d), self.sigma * self.sigma);
+        if d2 > 9.0 * var {
+            return Color::BLACK;
+        }
+
+        // normalised so that the light over the whole star is its brightness
+        light * ((-0.5 * d2 / var).exp() / (TAU * var))
+    }
+
+    /// The direction towards and the total light of the star in cell (cx, cy) of a face, if the
+    /// cell holds one
+    fn cell_star(&self, face: u32, cx: u32, cy: u32) -> Option<(V3, Color)> {
+        let h = hash(self.seed ^ hash((face as u64) << 48 | (cx as u64) << 24 | cy as u64));
+        let rand = |k: u64| (hash(h ^ k) >> 40) as f32 / (1u64 << 24) as f32;
+
+        // placed so that the profile stays within the cell
+        let n = self.cells as f32;
+        let margin = 9.0 * self.sigma * n / 2.0;
+        let place =
+            |c: u32, r: f32| -1.0 + 2.0 * (c as f32 + margin + r * (1.0 - 2.0 * margin)) / n;
+        let (s, t) = (place(cx, rand(1)), place(cy, rand(2)));
+        // cells cover less of the sphere towards the corners of a face
+        if rand(0) >= self.chance * (1.0 + s * s + t * t).powf(-1.5) {
+            return None;
+        }
+
+        let brightness = self.brightness
+            * rand(3)
+                .max(MAX_BRIGHTNESS.powf(-self.slope))
+                .powf(-1.0 / self.slope);
+        let color = blackbody(2500.0 + 9500.0 * rand(4));
+
+        Some((from_face(face, s, t).unit_vector(), color * brightness))
+    }
+}
+
+/// A band of diffuse light around a great circle of the sky, broken up into clumps by noise and
+/// darkened along its center by lanes of dust.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Band {
+    /// Unit normal of the plane of the band
+    normal: V3,
+    /// Standard deviation of the light across the band in radians
+    width: f32,
+    color: Color,
+    noise: Perlin,
+}
+
+impl Band {
+    pub fn new(normal: V3, width: Angle, color: Color, seed: u64) -> Self {
+        Self {
+            normal: normal.unit_vector(),
+            width: width.radians().max(1e-3),
+            color,
+            noise: Perlin::seeded(seed),
+        }
+    }
+
+    fn value(&self, dir: V3) -> Color {
+        let lat = dir.dot(&self.normal).clamp(-1.0, 1.0).asin() / self.width;
+        if lat.abs() > 4.0 {
+            return Color::BLACK;
+        }
+        let p = P3::new(dir.x, dir.y, dir.z);
+        let glow = (-0.5 * lat * lat).exp();
+        let clumps = 0.4 + self.noise.turb(p.scale(6.0), 6);
+        let lanes =
+            (-2.0 * lat * lat).exp() * (0.5 + self.noise.noise(p.scale(3.0))).clamp(0.0, 1.0);
+
+        self.color * (glow * clumps * (1.0 - 0.8 * lanes))
+    }
+}
+
+/// The face of a cube (twice the axis, plus one for the negative side) that dir points through,
+/// and the position on that face in [-1, 1].
+fn to_face(d: V3) -> (u32, f32, f32) {
+    let (ax, ay, az) = (d.x.abs(), d.y.abs(), d.z.abs());
+    if ax >= ay && ax >= az {
+        ((d.x < 0.0) as u32, d.y / ax, d.z / ax)
+    } else if ay >= az {
+        (2 + (d.y < 0.0) as u32, d.x / ay, d.z / ay)
+    } else {
+        (4 + (d.z < 0.0) as u32, d.x / az, d.y / az)
+    }
+}
+
+/// The (not normalised) direction through position s, t of a face
+fn from_face(face: u32, s: f32, t: f32) -> V3 {
+    let sign = if face.is_multiple_of(2) { 1.0 } else { -1.0 };
+    match face / 2 {
+        0 => V3::new(sign, s, t),
+        1 => V3::new(s, sign, t),
+        _ => V3::new(s, t, sign),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v;
+    use simple_test_case::test_case;
+
+    fn stars(density: f32, size: f32, band: Option<Band>) -> Starfield {
+        Starfield::new(density, 1.0, 1.5, Angle::deg(size), 7, band)
+    }
+
+    /// The direction and light of every star
+    fn every_star(field: &Starfield) -> Vec<(V3, Color)> {
+        let n = field.cells;
+        (0..6)
+            .flat_map(|face| (0..n * n).map(move |c| (face, c % n, c / n)))
+            .filter_map(|(face, cx, cy)| field.cell_star(face, cx, cy))
+            .collect()
+    }
+
+    #[test_case(0.2; "sparse")]
+    #[test_case(2.0; "dense")]
+    #[test]
+    fn stars_are_spread_evenly_at_the_given_density(density: f32) {
+        let found = every_star(&stars(density, 0.01, None));
+        let expected = density * 4.0 * PI * DEG2_PER_SR;
+        // the two polar caps above 30 degrees of latitude cover half of the sphere and include
+        // the corners of the faces around them
+        let in_caps = found.iter().filter(|(dir, _)| dir.y.abs() > 0.5).count();
+
+        let n = found.len() as f32;
+        assert!((n - expected).abs() < 0.03 * expected, "{n} != {expected}");
+        assert!((in_caps as f32 / n - 0.5).abs() < 0.02, "{in_caps} of {n}");
+    }
+
+    #[test_case(0.005; "small")]
+    #[test_case(0.02; "large")]
+    #[test]
+    fn the_light_of_a_star_does_not_depend_on_its_size(size: f32) {
+        let field = stars(0.5, size, None);
+        let (center, light) = every_star(&field)[0];
+        // integrate over a grid across the star, which is flat at this scale
+        let onb = crate::v3::Onb::new(center);
+        let (n, extent) = (200, 4.0 * field.sigma);
+        let step = 2.0 * extent / n as f32;
+        let mut total = Color::BLACK;
+        for i in 0..n {
+            for j in 0..n {
+                let (x, y) = (
+                    -extent + (i as f32 + 0.5) * step,
+                    -extent + (j as f32 + 0.5) * step,
+                );
+                total += field.value(onb.to_world(v!(x, y, 1))) * (step * step);
+            }
+        }
+
+        let (got, expected) = (total.luminance(), light.luminance());
+        assert!(
+            (got - expected).abs() < 0.03 * expected,
+            "{got} != {expected}"
+        );
+    }
+
+    #[test]
+    fn the_band_lights_the_sky_around_its_great_circle() {
+        let band = Band::new(v!(0, 1, 0), Angle::deg(10.0), Color::grey(1.0), 3);
+        let field = stars(0.0, 0.01, Some(band));
+        let light = |dirs: &[V3]| {
+            dirs.iter()
+                .map(|&d| field.value(d).luminance())
+                .sum::<f32>()
+        };
+
+        let along = [v!(1, 0.05, 0), v!(0, 0.05, -1), v!(-1, -0.05, 0.3)];
+        let away = [v!(1, 0.9, 0), v!(0, 1, 0), v!(0.2, -1, 0.1)];
+
+        assert!(light(&along) > 0.1, "{}", light(&along));
+        assert_eq!(light(&away), 0.0);
+    }
+}
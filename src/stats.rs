@@ -0,0 +1,81 @@
+//! Render statistics for diagnosing which parts of a scene dominate render time
+use crate::hit::HitRecord;
+use std::{
+    cmp::Reverse,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[derive(Debug, Default)]
+pub struct RenderStats {
+    obj_hits: Vec<AtomicU64>, // indexed by object ID
+    mat_hits: Vec<AtomicU64>, // indexed by material ID
+    paths: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl RenderStats {
+    pub fn new(n_objects: usize, n_materials: usize) -> Self {
+        // ID 0 is used for hittables that have not been assigned an ID
+        Self {
+            obj_hits: (0..=n_objects).map(|_| AtomicU64::new(0)).collect(),
+            mat_hits: (0..=n_materials).map(|_| AtomicU64::new(0)).collect(),
+            paths: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_path(&self) {
+        self.paths.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_hit(&self, hr: &HitRecord) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        if let Some(n) = self.obj_hits.get(hr.obj_id as usize) {
+            n.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(n) = self.mat_hits.get(hr.mat_id as usize) {
+            n.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Average number of surface interactions per camera path.
+    pub fn mean_depth(&self) -> f64 {
+        let paths = self.paths.load(Ordering::Relaxed).max(1);
+
+        self.hits.load(Ordering::Relaxed) as f64 / paths as f64
+    }
+
+    /// Print a summary of the hits per object and material sorted by decreasing hit count.
+    pub fn report(&self, object_names: &[String], material_names: &[String]) {
+        let total = self.hits.load(Ordering::Relaxed).max(1) as f64;
+        eprintln!(
+            "\nPaths traced:        {}",
+            self.paths.load(Ordering::Relaxed)
+        );
+        eprintln!("Mean bounces / path: {:.3}", self.mean_depth());
+
+        for (title, counts, names) in [
+            ("object", &self.obj_hits, object_names),
+            ("material", &self.mat_hits, material_names),
+        ] {
+            let mut rows: Vec<(String, u64)> = counts
+                .iter()
+                .enumerate()
+                .map(|(id, n)| {
+                    let name = match id {
+                        0 => "<unassigned>".to_string(),
+                        _ => names.get(id - 1).cloned().unwrap_or_default(),
+                    };
+                    (name, n.load(Ordering::Relaxed))
+                })
+                .filter(|(_, n)| *n > 0)
+                .collect();
+            rows.sort_by_key(|(_, n)| Reverse(*n));
+
+            eprintln!("\nRay hits per {title}:");
+            for (name, n) in rows {
+                eprintln!("  {:>6.2}% {n:>12} {name}", 100.0 * n as f64 / total);
+            }
+        }
+    }
+}
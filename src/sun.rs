@@ -0,0 +1,349 @@
+//! The position of the sun in the sky for a location, date and time so that daylight studies can
+//! be lit by a physically placed sun. The solar position follows the NOAA solar calculator and
+//! the sun is dimmed and reddened by the air mass it shines through.
+//!   https://gml.noaa.gov/grad/solcalc/calcdetails.html
+//!   https://en.wikipedia.org/wiki/Air_mass_(solar_energy)
+//!
+//! Scenes are taken to be oriented with y up, north along -z and east along +x.
+use crate::{angle::Angle, ray::Background, Color, V3};
+use serde::{de::Error, Deserialize, Deserializer};
+use std::f64::consts::PI;
+
+/// Optical depth of a clear atmosphere at zenith for the red, green and blue channels
+const OPTICAL_DEPTH: [f32; 3] = [0.1, 0.15, 0.25];
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Sun {
+    /// Latitude and longitude in degrees (north and east positive)
+    pub location: [f32; 2],
+    /// Date as YYYY-MM-DD
+    #[serde(deserialize_with = "deserialize_date")]
+    pub date: String,
+    /// Local time as HH:MM or HH:MM:SS
+    #[serde(deserialize_with = "deserialize_time")]
+    pub time: String,
+    /// Hours that local time is ahead of UTC
+    #[serde(default)]
+    pub utc_offset: f32,
+    /// Irradiance from the sun when it is directly overhead
+    #[serde(default = "default_irradiance")]
+    pub irradiance: f32,
+    /// Angular diameter of the sun disk
+    #[serde(default = "default_size")]
+    pub size: Angle,
+    /// Local times keyed by animation frame for timelapses, interpolated between the keys in
+    /// place of time
+    #[serde(default, deserialize_with = "deserialize_time_keys")]
+    pub time_keys: Vec<(u32, String)>,
+}
+
+// Dates and times are checked when the scene is parsed so that bad values are reported as
+// scene errors rather than found while rendering.
+
+fn deserialize_date<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+    let s = String::deserialize(d)?;
+    match parse_date(&s) {
+        Some(_) => Ok(s),
+        None => Err(D::Error::custom(format!(
+            "invalid sun date (expected YYYY-MM-DD): {s}"
+        ))),
+    }
+}
+
+fn check_time<E: Error>(s: &str) -> Result<(), E> {
+    match parse_time(s) {
+        Some(_) => Ok(()),
+        None => Err(E::custom(format!("invalid sun time (expected HH:MM): {s}"))),
+    }
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+    let s = String::deserialize(d)?;
+    check_time(&s)?;
+
+    Ok(s)
+}
+
+fn deserialize_time_keys<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<(u32, String)>, D::Error> {
+    let keys = Vec::<(u32, String)>::deserialize(d)?;
+    for (_, time) in keys.iter() {
+        check_time(time)?;
+    }
+
+    Ok(keys)
+}
+
+pub fn default_irradiance() -> f32 {
+    10.0
+}
+
+pub fn default_size() -> Angle {
+    Angle::deg(0.53)
+}
+
+impl Sun {
+    /// Elevation above the horizon and azimuth clockwise from north in degrees.
+    pub fn position(&self) -> (f32, f32) {
+        let [lat, lon] = self.location;
+        // checked when the scene is parsed
+        let (y, m, d) = parse_date(&self.date).expect("valid sun date");
+        let hours = parse_time(&self.time).expect("valid sun time");
+
+        solar_position(
+            lat as f64,
+            lon as f64,
+            julian_day(y, m, d) + (hours - self.utc_offset as f64) / 24.0,
+            hours * 60.0,
+            self.utc_offset as f64,
+        )
+    }
+
+    /// The sun at the time keyed for the given animation frame. Frames before the first key or
+    /// after the last are held at the time of that key.
+    pub fn at_frame(&self, frame: u32) -> Sun {
+        let mut keys: Vec<(u32, f64)> = self
+            .time_keys
+            .iter()
+            .map(|(f, time)| {
+                // checked when the scene is parsed
+                (*f, parse_time(time).expect("valid sun time key"))
+            })
+            .collect();
+        keys.sort_by_key(|&(f, _)| f);
+
+        let hours = match keys.iter().position(|&(f, _)| f > frame) {
+            _ if keys.is_empty() => return self.clone(),
+            Some(0) => keys[0].1,
+            Some(i) => {
+                let ((f0, h0), (f1, h1)) = (keys[i - 1], keys[i]);
+                let t = (frame - f0) as f64 / (f1 - f0) as f64;
+                h0 + (h1 - h0) * t
+            }
+            None => keys[keys.len() - 1].1,
+        };
+
+        let secs = (hours * 3600.0).round() as u64;
+        let time = format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+
+        Sun {
+            time,
+            ..self.clone()
+        }
+    }
+
+    /// Unit vector towards the sun.
+    pub fn direction(&self) -> V3 {
+        let (el, az) = self.position();
+        let (el, az) = (el.to_radians(), az.to_radians());
+
+        V3::new(az.sin() * el.cos(), el.sin(), -az.cos() * el.cos())
+    }
+
+    /// Radiance of the sun disk after passing through the atmosphere, black once it has set.
+    pub fn radiance(&self) -> Color {
+        radiance(self.position().0, self.irradiance, self.size)
+    }
+
+    /// The sky with the sun disk in front of it.
+    pub fn background(&self, sky: Background) -> Background {
+        disk(sky, self.direction(), self.radiance(), self.size)
+    }
+}
+
+/// Radiance of a sun disk of the given angular diameter at elevation degrees above the horizon,
+/// giving irradiance when directly overhead.
+pub fn radiance(elevation: f32, irradiance: f32, size: Angle) -> Color {
+    if elevation <= 0.0 {
+        return Color::BLACK;
+    }
+
+    // Kasten and Young air mass relative to the sun being directly overhead
+    let z = 90.0 - elevation;
+    let air_mass = 1.0 / (z.to_radians().cos() + 0.50572 * (96.07995 - z).powf(-1.6364));
+    let [r, g, b] = OPTICAL_DEPTH.map(|tau| (-tau * (air_mass - 1.0)).exp());
+    let cos_radius = (size / 2.0).cos();
+    let solid_angle = 2.0 * std::f32::consts::PI * (1.0 - cos_radius);
+
+    Color::new(r, g, b) * (irradiance / solid_angle)
+}
+
+/// A sun disk of the given angular diameter in direction dir in front of the sky.
+pub fn disk(sky: Background, dir: V3, radiance: Color, size: Angle) -> Background {
+    Background::Sun {
+        sky: Box::leak(Box::new(sky)),
+        dir: dir.unit_vector(),
+        radiance,
+        cos_radius: (size / 2.0).cos(),
+    }
+}
+
+fn parse_date(s: &str) -> Option<(i32, u32, u32)> {
+    let mut parts = s.split('-').map(|p| p.parse().ok());
+    let (y, m, d) = (parts.next()??, parts.next()??, parts.next()??);
+    let valid = parts.next().is_none() && (1..=12).contains(&m) && (1..=31).contains(&d);
+
+    valid.then_some((y as i32, m, d))
+}
+
+/// Hours since midnight.
+fn parse_time(s: &str) -> Option<f64> {
+    let parts: Vec<f64> = s
+        .split(':')
+        .map(|p| p.parse().ok())
+        .collect::<Option<_>>()?;
+
+    match parts[..] {
+        [h, m] => Some(h + m / 60.0),
+        [h, m, s] => Some(h + m / 60.0 + s / 3600.0),
+        _ => None,
+    }
+}
+
+/// Julian day at midnight UTC at the start of the given date.
+fn julian_day(y: i32, m: u32, d: u32) -> f64 {
+    let (y, m) = if m <= 2 { (y - 1, m + 12) } else { (y, m) };
+    let a = (y as f64 / 100.0).floor();
+    let b = 2.0 - a + (a / 4.0).floor();
+
+    (365.25 * (y as f64 + 4716.0)).floor() + (30.6001 * (m as f64 + 1.0)).floor() + d as f64 + b
+        - 1524.5
+}
+
+/// Elevation and azimuth in degrees of the sun for the given latitude and longitude at the
+/// julian day jd (UTC), which is local_minutes past midnight in a timezone utc_offset hours
+/// ahead of UTC.
+fn solar_position(lat: f64, lon: f64, jd: f64, local_minutes: f64, utc_offset: f64) -> (f32, f32) {
+    let (sin, cos) = (|d: f64| d.to_radians().sin(), |d: f64| d.to_radians().cos());
+    let jc = (jd - 2451545.0) / 36525.0;
+
+    let mean_long = (280.46646 + jc * (36000.76983 + jc * 0.0003032)).rem_euclid(360.0);
+    let mean_anom = 357.52911 + jc * (35999.05029 - 0.0001537 * jc);
+    let ecc = 0.016708634 - jc * (0.000042037 + 0.0000001267 * jc);
+    let center = sin(mean_anom) * (1.914602 - jc * (0.004817 + 0.000014 * jc))
+        + sin(2.0 * mean_anom) * (0.019993 - 0.000101 * jc)
+        + sin(3.0 * mean_anom) * 0.000289;
+    let omega = 125.04 - 1934.136 * jc;
+    let app_long = mean_long + center - 0.00569 - 0.00478 * sin(omega);
+    let mean_obliq =
+        23.0 + (26.0 + (21.448 - jc * (46.815 + jc * (0.00059 - jc * 0.001813))) / 60.0) / 60.0;
+    let obliq = mean_obliq + 0.00256 * cos(omega);
+    let decl = (sin(obliq) * sin(app_long)).asin();
+
+    // equation of time in minutes
+    let y = (obliq / 2.0).to_radians().tan().powi(2);
+    let eot = 4.0
+        * (y * sin(2.0 * mean_long) - 2.0 * ecc * sin(mean_anom)
+            + 4.0 * ecc * y * sin(mean_anom) * cos(2.0 * mean_long)
+            - 0.5 * y * y * sin(4.0 * mean_long)
+            - 1.25 * ecc * ecc * sin(2.0 * mean_anom))
+        .to_degrees();
+
+    let true_solar = (local_minutes + eot + 4.0 * lon - 60.0 * utc_offset).rem_euclid(1440.0);
+    let hour_angle = (true_solar / 4.0 - 180.0).to_radians();
+    let lat = lat.to_radians();
+
+    let cos_zenith = lat.sin() * decl.sin() + lat.cos() * decl.cos() * hour_angle.cos();
+    let elevation = 90.0 - cos_zenith.clamp(-1.0, 1.0).acos().to_degrees();
+    let azimuth = (hour_angle
+        .sin()
+        .atan2(hour_angle.cos() * lat.sin() - decl.tan() * lat.cos())
+        + PI)
+        .to_degrees()
+        .rem_euclid(360.0);
+
+    (elevation as f32, azimuth as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    fn sun(location: [f32; 2], date: &str, time: &str, utc_offset: f32) -> Sun {
+        Sun {
+            location,
+            date: date.to_string(),
+            time: time.to_string(),
+            utc_offset,
+            irradiance: default_irradiance(),
+            size: default_size(),
+            time_keys: Vec::new(),
+        }
+    }
+
+    #[test_case("date = \"2024-13-01\"\ntime = \"12:00\"", "invalid sun date"; "month")]
+    #[test_case("date = \"2024-06-21\"\ntime = \"noon\"", "invalid sun time"; "time")]
+    #[test_case("date = \"2024-06-21\"\ntime = \"12:00\"\ntime_keys = [[1, \"12\"]]", "invalid sun time"; "time key")]
+    #[test]
+    fn bad_dates_and_times_are_parse_errors(fields: &str, expected: &str) {
+        let err = toml::from_str::<Sun>(&format!("location = [0.0, 0.0]\n{fields}")).unwrap_err();
+
+        assert!(err.to_string().contains(expected), "{err}");
+    }
+
+    // reference values from the low precision solar coordinates of the Astronomical Almanac
+    #[test_case([51.48, 0.0], "2024-06-21", "13:00", 1.0, 61.9, 179.1; "greenwich summer")]
+    #[test_case([51.48, 0.0], "2024-12-21", "12:00", 0.0, 15.1, 180.4; "greenwich winter")]
+    #[test_case([40.71, -74.01], "2024-03-20", "09:00", -4.0, 21.8, 109.9; "new york morning")]
+    #[test_case([-33.87, 151.21], "2024-01-15", "18:00", 11.0, 24.6, 260.1; "sydney evening")]
+    #[test]
+    fn solar_position_matches_reference(
+        location: [f32; 2],
+        date: &str,
+        time: &str,
+        utc_offset: f32,
+        elevation: f32,
+        azimuth: f32,
+    ) {
+        let (el, az) = sun(location, date, time, utc_offset).position();
+
+        assert!(
+            (el - elevation).abs() < 0.5,
+            "elevation {el} != {elevation}"
+        );
+        assert!((az - azimuth).abs() < 0.5, "azimuth {az} != {azimuth}");
+    }
+
+    #[test]
+    fn low_sun_is_dimmer_and_redder() {
+        let noon = sun([0.0, 0.0], "2024-03-20", "12:07", 0.0).radiance();
+        let evening = sun([0.0, 0.0], "2024-03-20", "17:45", 0.0).radiance();
+        let night = sun([0.0, 0.0], "2024-03-20", "23:00", 0.0).radiance();
+
+        assert!(evening.luminance() < 0.5 * noon.luminance());
+        assert!(evening.r / evening.b > 2.0 * noon.r / noon.b);
+        assert_eq!(night, Color::BLACK);
+    }
+
+    #[test_case(1, "12:00:00"; "before the first key")]
+    #[test_case(10, "12:00:00"; "first key")]
+    #[test_case(15, "15:15:00"; "between keys")]
+    #[test_case(20, "18:30:00"; "last key")]
+    #[test_case(30, "18:30:00"; "after the last key")]
+    #[test]
+    fn time_keys_are_interpolated_by_frame(frame: u32, expected: &str) {
+        let mut s = sun([51.48, 0.0], "2024-06-21", "06:00", 1.0);
+        // keys can be given in any order
+        s.time_keys = vec![(20, "18:30".to_string()), (10, "12:00".to_string())];
+
+        assert_eq!(s.at_frame(frame).time, expected);
+    }
+
+    #[test]
+    fn timelapses_run_from_day_into_night() {
+        let mut s = sun([51.48, 0.0], "2024-06-21", "13:00", 1.0);
+        s.time_keys = vec![(1, "13:00".to_string()), (25, "37:00".to_string())];
+        let elevations: Vec<f32> = (1..=25).map(|f| s.at_frame(f).position().0).collect();
+
+        assert!(elevations[0] > 60.0);
+        assert!(elevations[12] < 0.0, "{elevations:?}");
+        assert!((elevations[24] - elevations[0]).abs() < 1.0);
+    }
+
+    #[test]
+    fn direction_is_south_and_up_at_northern_noon() {
+        let d = sun([51.48, 0.0], "2024-06-21", "13:00", 1.0).direction();
+
+        assert!(d.y > 0.8 && d.z > 0.0 && d.x.abs() < 0.1, "{d:?}");
+    }
+}
@@ -0,0 +1,278 @@
+//! Temporal reuse for animations: the previous frame is reprojected into the current one using
+//! the surface seen through each pixel and the motion of the camera, then blended with the
+//! current frame as an exponential moving average so that each frame needs far fewer samples.
+//! History is rejected where the surface was not visible in the previous frame (a change in
+//! object or depth) and clamped to the colors around each pixel to limit ghosting.
+//!   Karis, "High Quality Temporal Supersampling" (SIGGRAPH 2014 course)
+use crate::{ray::Camera, toon::GSample, Color};
+use serde::Deserialize;
+
+/// Settings for blending each animation frame with those before it
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Temporal {
+    /// Weight of the current frame once enough frames have been accumulated, in (0, 1]
+    #[serde(default = "default_blend")]
+    pub blend: f32,
+    /// Largest difference in distance from the previous camera, relative to that distance, for
+    /// the surface seen in the previous frame to be reused
+    #[serde(default = "default_depth_tolerance")]
+    pub depth_tolerance: f32,
+    /// Clamp the previous frame to the range of colors around each pixel of the current frame
+    #[serde(default = "default_clamp")]
+    pub clamp: bool,
+}
+
+fn default_blend() -> f32 {
+    0.2
+}
+
+fn default_depth_tolerance() -> f32 {
+    0.05
+}
+
+fn default_clamp() -> bool {
+    true
+}
+
+impl Default for Temporal {
+    fn default() -> Self {
+        Self {
+            blend: default_blend(),
+            depth_tolerance: default_depth_tolerance(),
+            clamp: default_clamp(),
+        }
+    }
+}
+
+/// The previous frame as seen by its camera, carried between the frames of an animation.
+#[derive(Debug, Clone)]
+pub struct History {
+    camera: Camera,
+    surfaces: Vec<GSample>,
+    pixels: Vec<Color>,
+    /// Number of frames accumulated into each pixel
+    frames: Vec<u16>,
+}
+
+impl History {
+    pub fn new(
+        camera: Camera,
+        surfaces: Vec<GSample>,
+        pixels: Vec<Color>,
+        frames: Vec<u16>,
+    ) -> Self {
+        Self {
+            camera,
+            surfaces,
+            pixels,
+            frames,
+        }
+    }
+}
+
+impl Temporal {
+    /// The color of each pixel in the previous frame along with the number of frames accumulated
+    /// into it, or None where the surface seen through the pixel was not visible.
+    pub fn reproject(
+        &self,
+        camera: &Camera,
+        surfaces: &[GSample],
+        history: &History,
+    ) -> Vec<Option<(Color, f32)>> {
+        let (w, h) = history.camera.dimensions();
+        let (w, h) = (w as i32, h as i32);
+        let prev_center = history.camera.center();
+        let width = camera.dimensions().0 as usize;
+
+        let reused = |k: usize, t: usize| match (surfaces[k], history.surfaces[t]) {
+            (Some((_, p, _, id)), Some((_, prev_p, _, prev_id))) => {
+                let depth = (p - prev_center).length();
+                let prev_depth = (prev_p - prev_center).length();
+                id == prev_id && (depth - prev_depth).abs() <= self.depth_tolerance * depth
+            }
+            // the background is only seen through pixels that missed the scene in both frames
+            (None, None) => true,
+            _ => false,
+        };
+
+        (0..surfaces.len())
+            .map(|k| {
+                let target = match surfaces[k] {
+                    Some((_, p, _, _)) => p,
+                    None => {
+                        let (i, j) = ((k % width) as f32, (k / width) as f32);
+                        prev_center + camera.ray_through(i, j).dir
+                    }
+                };
+                let (x, y) = history.camera.project(target)?;
+
+                // bilinear interpolation over the neighbouring pixels showing the same surface
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (mut color, mut frames, mut total) = (Color::BLACK, 0.0, 0.0);
+                for (dx, dy, weight) in [
+                    (0, 0, (1.0 - fx) * (1.0 - fy)),
+                    (1, 0, fx * (1.0 - fy)),
+                    (0, 1, (1.0 - fx) * fy),
+                    (1, 1, fx * fy),
+                ] {
+                    let (tx, ty) = (x0 as i32 + dx, y0 as i32 + dy);
+                    if tx < 0 || ty < 0 || tx >= w || ty >= h || weight <= 0.0 {
+                        continue;
+                    }
+                    let t = (ty * w + tx) as usize;
+                    if reused(k, t) {
+                        color += history.pixels[t] * weight;
+                        frames += history.frames[t] as f32 * weight;
+                        total += weight;
+                    }
+                }
+
+                (total > 1e-3).then(|| (color / total, frames / total))
+            })
+            .collect()
+    }
+
+    /// Blend the reprojected previous frame into the current one, returning the blended pixels
+    /// and the number of frames accumulated into each of them.
+    pub fn blend(
+        &self,
+        width: usize,
+        current: &[Color],
+        reprojected: Option<&[Option<(Color, f32)>]>,
+    ) -> (Vec<Color>, Vec<u16>) {
+        let height = current.len() / width.max(1);
+
+        (0..current.len())
+            .map(|k| {
+                let c = current[k];
+                let Some((mut prev, n)) = reprojected.and_then(|r| r[k]) else {
+                    return (c, 1);
+                };
+
+                if self.clamp {
+                    let (i, j) = (k % width, k / width);
+                    let (mut lo, mut hi) = (c, c);
+                    for y in j.saturating_sub(1)..=(j + 1).min(height - 1) {
+                        for x in i.saturating_sub(1)..=(i + 1).min(width - 1) {
+                            let n = current[y * width + x];
+                            lo = Color::new(lo.r.min(n.r), lo.g.min(n.g), lo.b.min(n.b));
+                            hi = Color::new(hi.r.max(n.r), hi.g.max(n.g), hi.b.max(n.b));
+                        }
+                    }
+                    prev = Color::new(
+                        prev.r.clamp(lo.r, hi.r),
+                        prev.g.clamp(lo.g, hi.g),
+                        prev.b.clamp(lo.b, hi.b),
+                    );
+                }
+
+                // a plain average until enough frames have been accumulated
+                let a = self.blend.clamp(0.0, 1.0).max(1.0 / (n + 1.0));
+                let frames = (n.round() + 1.0).min(u16::MAX as f32) as u16;
+
+                (prev * (1.0 - a) + c * a, frames)
+            })
+            .unzip()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        angle::Angle,
+        ray::{Background, RenderMode},
+        P3, V3,
+    };
+
+    fn camera(from: P3) -> Camera {
+        Camera::new(
+            1.0,
+            4,
+            1,
+            0,
+            1,
+            Background::Solid(Color::BLACK),
+            Angle::deg(90.0),
+            from,
+            from + V3::new(0.0, 0.0, -1.0),
+            V3::new(0.0, 1.0, 0.0),
+            Angle::ZERO,
+            1.0,
+            RenderMode::Beauty,
+            None,
+            None,
+        )
+    }
+
+    // A wall at z = -2 through every pixel of a 4x4 image, with each pixel showing object 1 or 2
+    fn wall(c: &Camera, split: f32) -> Vec<GSample> {
+        (0..16)
+            .map(|k| {
+                let r = c.ray_through((k % 4) as f32, (k / 4) as f32);
+                let p = r.orig + r.dir * ((-2.0 - r.orig.z) / r.dir.z);
+                Some((
+                    0.0,
+                    p,
+                    V3::new(0.0, 0.0, 1.0),
+                    if p.x < split { 1 } else { 2 },
+                ))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reprojection_follows_the_camera_and_rejects_disocclusions() {
+        let (prev, cur) = (
+            camera(P3::new(0.0, 0.0, 0.0)),
+            camera(P3::new(1.0, 0.0, 0.0)),
+        );
+        let pixels = (0..16).map(|k| Color::grey(k as f32)).collect();
+        let history = History::new(prev.clone(), wall(&prev, 1.0), pixels, vec![3; 16]);
+
+        // pixels are 1 unit apart on the wall so the image moves one pixel to the left
+        let t = Temporal::default();
+        let reprojected = t.reproject(&cur, &wall(&cur, 1.0), &history);
+        let reused = |r: Option<(Color, f32)>| r.map(|(c, n)| ((c.r * 1e3).round() / 1e3, n));
+
+        assert_eq!(reused(reprojected[4]), Some((5.0, 3.0)));
+        assert_eq!(reused(reprojected[6]), Some((7.0, 3.0)));
+        assert_eq!(reprojected[7], None, "outside the previous frame");
+
+        // the previous frame saw a different object through the pixels left of x = 2
+        let history = History::new(prev.clone(), wall(&prev, 2.0), history.pixels, vec![3; 16]);
+        let reprojected = t.reproject(&cur, &wall(&cur, 1.0), &history);
+
+        assert_eq!(reused(reprojected[4]), Some((5.0, 3.0)));
+        assert_eq!(reprojected[6], None);
+    }
+
+    #[test]
+    fn blending_averages_new_history_then_decays_it() {
+        let t = Temporal {
+            clamp: false,
+            ..Temporal::default()
+        };
+        let current = [Color::grey(1.0), Color::grey(1.0)];
+        let reprojected = [Some((Color::grey(0.0), 1.0)), Some((Color::grey(0.0), 9.0))];
+        let (pixels, frames) = t.blend(2, &current, Some(&reprojected));
+
+        assert_eq!(pixels, [Color::grey(0.5), Color::grey(0.2)]);
+        assert_eq!(frames, [2, 10]);
+
+        let (pixels, frames) = t.blend(2, &current, None);
+        assert_eq!(pixels, current);
+        assert_eq!(frames, [1, 1]);
+    }
+
+    #[test]
+    fn history_is_clamped_to_the_colors_around_each_pixel() {
+        let t = Temporal::default();
+        let current = [Color::grey(1.0), Color::grey(2.0)];
+        let reprojected = [Some((Color::grey(10.0), 100.0)), None];
+        let (pixels, _) = t.blend(2, &current, Some(&reprojected));
+
+        assert!((pixels[0].r - 1.8).abs() < 1e-5, "{:?}", pixels[0]);
+    }
+}
@@ -0,0 +1,392 @@
+//! Tiled, mip-mapped storage for image textures, shared between every material using the same
+//! image so that each image is only held in memory once.
+//!
+//! Images are split into square tiles at each level of a mip chain halving the resolution down
+//! to a single pixel, and looked up in the finest level that fits within the max_size of the
+//! [TextureSettings] so that very large images can be rendered at a lower resolution without
+//! editing them. Only that level is kept in memory.
+//!
+//! With caching enabled, the tiles of every level are written to a cache file next to the image
+//! the first time it is loaded ("atlas.png" is cached in "atlas.png.tiles"). After that, tiles
+//! are read from the cache file only when they are first looked up, so regions of the image that
+//! are never seen by the camera are never loaded. The image is decoded in full once to write the
+//! cache file, which is rewritten whenever the image changes.
+use image::{open, RgbImage};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    sync::{Mutex, OnceLock, RwLock},
+    time::UNIX_EPOCH,
+};
+
+/// Width and height of a tile in pixels
+const TILE: u32 = 64;
+/// Start of every cache file, changed whenever the layout changes
+const MAGIC: &[u8; 8] = b"RMTILES1";
+
+/// How image textures are stored, shared by every image texture of a scene.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub struct TextureSettings {
+    /// Largest width or height that images are sampled at, using a smaller level of the mip
+    /// chain for images above it
+    #[serde(default)]
+    pub max_size: Option<u32>,
+    /// Write tiled cache files next to images and read tiles from them as they are needed
+    #[serde(default)]
+    pub cache: bool,
+}
+
+static SETTINGS: RwLock<TextureSettings> = RwLock::new(TextureSettings {
+    max_size: None,
+    cache: false,
+});
+
+type Key = (String, TextureSettings);
+static CACHE: OnceLock<Mutex<HashMap<Key, &'static TiledImage>>> = OnceLock::new();
+
+/// Set how the images loaded from now on are stored.
+pub fn configure(settings: TextureSettings) {
+    *SETTINGS.write().unwrap() = settings;
+}
+
+/// The image at path stored with the current settings, loading it if no material has yet.
+pub fn load(path: &str) -> Result<&'static TiledImage, String> {
+    let settings = *SETTINGS.read().unwrap();
+    let key = (path.to_string(), settings);
+    let mut cache = CACHE.get_or_init(Default::default).lock().unwrap();
+    if let Some(img) = cache.get(&key) {
+        return Ok(img);
+    }
+
+    let img: &'static TiledImage = Box::leak(Box::new(TiledImage::load(path, settings)?));
+    cache.insert(key, img);
+
+    Ok(img)
+}
+
+/// One level of an image split into tiles, each loaded when it is first looked up.
+#[derive(Debug)]
+pub struct TiledImage {
+    width: u32,
+    height: u32,
+    tiles: Vec<OnceLock<Box<[u8]>>>,
+    /// The cache file and the offset of the level within it, for reading tiles on demand
+    file: Option<(Mutex<File>, u64)>,
+    path: String,
+}
+
+impl TiledImage {
+    /// The level of an image fitting within max_size, taken from it directly.
+    pub fn new(img: &RgbImage, max_size: Option<u32>) -> Self {
+        let mut level = img.clone();
+        while max_size.is_some_and(|m| level.width().max(level.height()) > m.max(1)) {
+            level = half_size(&level);
+        }
+
+        let tiled = Self::empty(level.width(), level.height(), None, "");
+        for (i, tile) in tiled.tiles.iter().enumerate() {
+            let data = tile_data(
+                &level,
+                i as u32 % tiled.tiles_x(),
+                i as u32 / tiled.tiles_x(),
+            );
+            tile.set(data.into_boxed_slice()).unwrap();
+        }
+
+        tiled
+    }
+
+    fn load(path: &str, settings: TextureSettings) -> Result<Self, String> {
+        if !settings.cache {
+            let img = open(path).map_err(|e| e.to_string())?.into_rgb8();
+            return Ok(Self::new(&img, settings.max_size));
+        }
+
+        let cache_path = format!("{path}.tiles");
+        let stamp = source_stamp(path)?;
+        if let Ok(tiled) = Self::from_cache(&cache_path, stamp, settings.max_size) {
+            return Ok(tiled);
+        }
+
+        let img = open(path).map_err(|e| e.to_string())?.into_rgb8();
+        match write_cache(&cache_path, stamp, &img) {
+            Ok(()) => Self::from_cache(&cache_path, stamp, settings.max_size)
+                .map_err(|e| format!("{cache_path}: {e}")),
+            Err(e) => {
+                eprintln!("warning: unable to write texture cache {cache_path}: {e}");
+                Ok(Self::new(&img, settings.max_size))
+            }
+        }
+    }
+
+    /// The level fitting within max_size of the cache file at path, if it was written for the
+    /// current version of the image.
+    fn from_cache(path: &str, stamp: [u64; 2], max_size: Option<u32>) -> io::Result<Self> {
+        let mut f = File::open(path)?;
+        let mut magic = [0; 8];
+        f.read_exact(&mut magic)?;
+        let [len, modified, n_levels] = [read_u64(&mut f)?, read_u64(&mut f)?, read_u64(&mut f)?];
+        if &magic != MAGIC || [len, modified] != stamp {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "stale cache"));
+        }
+
+        let mut levels = Vec::new();
+        for _ in 0..n_levels {
+            levels.push((read_u64(&mut f)? as u32, read_u64(&mut f)? as u32));
+        }
+        let mut offset = f.stream_position()?;
+        for &(w, h) in &levels {
+            let fits = max_size.is_none_or(|m| w.max(h) <= m.max(1));
+            if fits || (w, h) == (1, 1) {
+                return Ok(Self::empty(w, h, Some((Mutex::new(f), offset)), path));
+            }
+            offset += 3 * w as u64 * h as u64;
+        }
+
+        Err(io::Error::new(io::ErrorKind::InvalidData, "no levels"))
+    }
+
+    fn empty(width: u32, height: u32, file: Option<(Mutex<File>, u64)>, path: &str) -> Self {
+        let n = width.div_ceil(TILE) * height.div_ceil(TILE);
+
+        Self {
+            width,
+            height,
+            tiles: (0..n).map(|_| OnceLock::new()).collect(),
+            file,
+            path: path.to_string(),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn tiles_x(&self) -> u32 {
+        self.width.div_ceil(TILE)
+    }
+
+    /// Number of tiles that have been loaded so far
+    pub fn loaded_tiles(&self) -> usize {
+        self.tiles.iter().filter(|t| t.get().is_some()).count()
+    }
+
+    /// The pixel at (x, y), loading the tile it is in if needed.
+    pub fn get_pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let (x, y) = (x.min(self.width - 1), y.min(self.height - 1));
+        let (tx, ty) = (x / TILE, y / TILE);
+        let tile = self.tiles[(ty * self.tiles_x() + tx) as usize].get_or_init(|| {
+            self.read_tile(tx, ty)
+                .unwrap_or_else(|e| panic!("unable to read texture tile from {}: {e}", self.path))
+        });
+        let tw = TILE.min(self.width - tx * TILE);
+        let i = 3 * ((y % TILE) * tw + x % TILE) as usize;
+
+        [tile[i], tile[i + 1], tile[i + 2]]
+    }
+
+    fn read_tile(&self, tx: u32, ty: u32) -> io::Result<Box<[u8]>> {
+        let Some((f, offset)) = &self.file else {
+            unreachable!("tiles held in memory are set when the image is created")
+        };
+        // tiles are stored in rows, with narrower and shorter tiles along the right and bottom
+        let (tw, th) = (
+            TILE.min(self.width - tx * TILE),
+            TILE.min(self.height - ty * TILE),
+        );
+        let rows_above = 3 * (ty * TILE) as u64 * self.width as u64;
+        let left = 3 * (tx * TILE) as u64 * th as u64;
+        let mut data = vec![0; 3 * (tw * th) as usize];
+
+        let mut f = f.lock().unwrap();
+        f.seek(SeekFrom::Start(offset + rows_above + left))?;
+        f.read_exact(&mut data)?;
+
+        Ok(data.into_boxed_slice())
+    }
+}
+
+/// The length and modification time of the file at path, which cache files are checked against
+pub fn source_stamp(path: &str) -> Result<[u64; 2], String> {
+    let meta = fs::metadata(path).map_err(|e| e.to_string())?;
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+
+    Ok([meta.len(), modified])
+}
+
+/// Write the tiles of every level of the mip chain of img, finest first.
+fn write_cache(path: &str, stamp: [u64; 2], img: &RgbImage) -> io::Result<()> {
+    let mut levels = vec![img.clone()];
+    while let Some(l) = levels.last().filter(|l| l.width().max(l.height()) > 1) {
+        levels.push(half_size(l));
+    }
+
+    // written to a temporary file first so that an interrupted write never leaves a cache file
+    // that looks valid
+    let tmp = format!("{path}.tmp");
+    let mut f = BufWriter::new(File::create(&tmp)?);
+    f.write_all(MAGIC)?;
+    for n in [stamp[0], stamp[1], levels.len() as u64] {
+        f.write_all(&n.to_le_bytes())?;
+    }
+    for l in &levels {
+        f.write_all(&(l.width() as u64).to_le_bytes())?;
+        f.write_all(&(l.height() as u64).to_le_bytes())?;
+    }
+    for l in &levels {
+        for ty in 0..l.height().div_ceil(TILE) {
+            for tx in 0..l.width().div_ceil(TILE) {
+                f.write_all(&tile_data(l, tx, ty))?;
+            }
+        }
+    }
+    f.into_inner()?.sync_all()?;
+
+    fs::rename(tmp, path)
+}
+
+pub fn read_u64(f: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    f.read_exact(&mut buf)?;
+
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// The pixels of the tile at (tx, ty) in rows
+fn tile_data(img: &RgbImage, tx: u32, ty: u32) -> Vec<u8> {
+    let (x0, y0) = (tx * TILE, ty * TILE);
+    let (x1, y1) = ((x0 + TILE).min(img.width()), (y0 + TILE).min(img.height()));
+    let mut data = Vec::with_capacity(3 * ((x1 - x0) * (y1 - y0)) as usize);
+    for y in y0..y1 {
+        for x in x0..x1 {
+            data.extend_from_slice(&img.get_pixel(x, y).0);
+        }
+    }
+
+    data
+}
+
+/// The next level of the mip chain, averaging each 2x2 block of pixels (folding the last row or
+/// column into the one before for odd sizes).
+fn half_size(img: &RgbImage) -> RgbImage {
+    let (w, h) = ((img.width() / 2).max(1), (img.height() / 2).max(1));
+    let (sx, sy) = (
+        img.width() as f32 / w as f32,
+        img.height() as f32 / h as f32,
+    );
+
+    RgbImage::from_fn(w, h, |x, y| {
+        let (x0, y0) = ((x as f32 * sx) as u32, (y as f32 * sy) as u32);
+        let (x1, y1) = (
+            (((x + 1) as f32 * sx) as u32).max(x0 + 1).min(img.width()),
+            (((y + 1) as f32 * sy) as u32).max(y0 + 1).min(img.height()),
+        );
+        let mut sum = [0u32; 3];
+        for j in y0..y1 {
+            for i in x0..x1 {
+                let p = img.get_pixel(i, j).0;
+                (0..3).for_each(|c| sum[c] += p[c] as u32);
+            }
+        }
+        let n = (x1 - x0) * (y1 - y0);
+
+        image::Rgb(sum.map(|s| ((s + n / 2) / n) as u8))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    /// An image whose size isn't a multiple of the tile size, with a different value in every
+    /// channel of every pixel.
+    fn gradient() -> RgbImage {
+        RgbImage::from_fn(150, 70, |x, y| {
+            image::Rgb([x as u8, y as u8, (x + y) as u8])
+        })
+    }
+
+    #[test]
+    fn tiles_hold_every_pixel_of_the_image() {
+        let img = gradient();
+        let tiled = TiledImage::new(&img, None);
+
+        for (x, y, p) in img.enumerate_pixels() {
+            assert_eq!(tiled.get_pixel(x, y), p.0, "({x}, {y})");
+        }
+    }
+
+    #[test_case(None, (150, 70); "full size")]
+    #[test_case(Some(150), (150, 70); "fits")]
+    #[test_case(Some(100), (75, 35); "one level down")]
+    #[test_case(Some(40), (37, 17); "two levels down")]
+    #[test_case(Some(0), (1, 1); "smallest level")]
+    #[test]
+    fn the_largest_level_within_max_size_is_used(max_size: Option<u32>, size: (u32, u32)) {
+        let tiled = TiledImage::new(&gradient(), max_size);
+
+        assert_eq!((tiled.width(), tiled.height()), size);
+    }
+
+    #[test]
+    fn smaller_levels_average_the_pixels_they_cover() {
+        let img = RgbImage::from_fn(2, 2, |x, y| image::Rgb([100 * (x + y) as u8, 0, 255]));
+
+        assert_eq!(half_size(&img).get_pixel(0, 0).0, [100, 0, 255]);
+    }
+
+    #[test_case(None, "raymart_tiles_full"; "full size")]
+    #[test_case(Some(40), "raymart_tiles_small"; "smaller level")]
+    #[test]
+    fn cached_tiles_are_read_as_they_are_needed(max_size: Option<u32>, dir: &str) {
+        let dir = std::env::temp_dir().join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("gradient.png").to_string_lossy().into_owned();
+        let img = gradient();
+        img.save(&path).unwrap();
+        let _ = fs::remove_file(format!("{path}.tiles"));
+        let settings = TextureSettings {
+            max_size,
+            cache: true,
+        };
+        let expected = TiledImage::new(&img, max_size);
+
+        for _ in 0..2 {
+            // written on the first load and read back on the second
+            let tiled = TiledImage::load(&path, settings).unwrap();
+            assert!(tiled.file.is_some());
+            assert_eq!(tiled.loaded_tiles(), 0);
+            assert_eq!(tiled.get_pixel(20, 10), expected.get_pixel(20, 10));
+            assert_eq!(tiled.loaded_tiles(), 1);
+            for y in 0..tiled.height() {
+                for x in 0..tiled.width() {
+                    assert_eq!(
+                        tiled.get_pixel(x, y),
+                        expected.get_pixel(x, y),
+                        "({x}, {y})"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn images_are_shared_between_loads() {
+        let path = std::env::temp_dir().join("raymart_shared.png");
+        let path = path.to_string_lossy();
+        gradient().save(path.as_ref()).unwrap();
+
+        assert!(std::ptr::eq(load(&path).unwrap(), load(&path).unwrap()));
+    }
+}
@@ -0,0 +1,325 @@
+//! Non-photorealistic rendering for illustrative images: toon shading with quantized lambert
+//! bands and a hard edged specular highlight, and outlines drawn along depth, normal and object
+//! discontinuities in the image.
+//!   https://en.wikipedia.org/wiki/Cel_shading
+use crate::{
+    angle::Angle,
+    bvh::{Bvh, MAX_BVH_DEPTH},
+    counters::{self, Counter},
+    hit::Interval,
+    scene::ColorSpec,
+    Color, HitRecord, Ray, P3, V3,
+};
+use serde::Deserialize;
+
+/// Settings for the toon render mode
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Toon {
+    /// Direction towards the single light used for shading
+    #[serde(default = "default_light")]
+    pub light: [f32; 3],
+    /// Number of brightness levels between unlit and fully lit
+    #[serde(default = "default_bands")]
+    pub bands: u8,
+    /// Brightness of surfaces facing away from the light or in shadow
+    #[serde(default = "default_ambient")]
+    pub ambient: f32,
+    /// Cosine between the normal and the half vector above which surfaces show a highlight
+    #[serde(default)]
+    pub specular: Option<f32>,
+    #[serde(default = "default_shadows")]
+    pub shadows: bool,
+}
+
+fn default_light() -> [f32; 3] {
+    [1.0, 2.0, 1.0]
+}
+
+fn default_bands() -> u8 {
+    3
+}
+
+fn default_ambient() -> f32 {
+    0.15
+}
+
+fn default_shadows() -> bool {
+    true
+}
+
+impl Default for Toon {
+    fn default() -> Self {
+        Self {
+            light: default_light(),
+            bands: default_bands(),
+            ambient: default_ambient(),
+            specular: None,
+            shadows: default_shadows(),
+        }
+    }
+}
+
+impl Toon {
+    /// The flat shaded color of the hit seen along r.
+    pub fn shade(
+        &self,
+        r: &Ray,
+        rec: &HitRecord,
+        bvh: &Bvh,
+        stack: &mut [usize; MAX_BVH_DEPTH],
+    ) -> Color {
+        if rec.mat.is_emissive() {
+            return rec.mat.emitted(r, rec);
+        }
+
+        let light = V3::from(self.light).unit_vector();
+        let mut lit = rec.normal.dot(&light).max(0.0);
+        if self.shadows && lit > 0.0 {
+            let shadow = r.spawn(rec.p, light, rec.normal);
+            counters::add(Counter::ShadowRays, 1);
+            if bvh
+                .hits(&shadow, Interval::new(shadow.epsilon, f32::INFINITY), stack)
+                .is_some()
+            {
+                lit = 0.0;
+            }
+        }
+
+        let bands = self.bands.max(1) as f32;
+        let level = (lit * bands).ceil() / bands;
+        let albedo = rec.mat.albedo(r, rec);
+        let c = albedo * (self.ambient + (1.0 - self.ambient) * level);
+
+        match self.specular {
+            Some(cutoff) if lit > 0.0 => {
+                let half = (light - r.dir.unit_vector()).unit_vector();
+                if rec.normal.dot(&half) > cutoff {
+                    Color::WHITE
+                } else {
+                    c
+                }
+            }
+            _ => c,
+        }
+    }
+}
+
+/// Lines drawn over the image where the surface seen by neighbouring pixels changes
+/// abruptly: at silhouettes, creases and the boundaries between objects.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Outline {
+    /// Line width in pixels
+    #[serde(default = "default_width")]
+    pub width: f32,
+    #[serde(default = "default_color")]
+    pub color: ColorSpec,
+    /// Distance between neighbouring surfaces, relative to their distance from the camera,
+    /// that counts as an edge
+    #[serde(default = "default_depth")]
+    pub depth: f32,
+    /// Angle between the normals of neighbouring surfaces that counts as a crease
+    #[serde(default = "default_crease")]
+    pub crease: Angle,
+    /// Outline the boundaries between objects even when they touch
+    #[serde(default = "default_objects")]
+    pub objects: bool,
+}
+
+fn default_width() -> f32 {
+    1.0
+}
+
+fn default_color() -> ColorSpec {
+    ColorSpec::Grey(0.0)
+}
+
+fn default_depth() -> f32 {
+    0.05
+}
+
+fn default_crease() -> Angle {
+    Angle::deg(45.0)
+}
+
+fn default_objects() -> bool {
+    true
+}
+
+/// The surface seen through the center of a pixel: distance, point, normal and object ID.
+pub type GSample = Option<(f32, P3, V3, u32)>;
+
+impl Outline {
+    /// Coverage of the outline over each pixel of a w x h image given the surface seen through
+    /// the center of each pixel. Lines are drawn on the nearer side of each edge.
+    pub fn mask(&self, w: usize, h: usize, samples: &[GSample]) -> Vec<f32> {
+        let cos_crease = self.crease.cos();
+        let is_edge = |a: &GSample, b: &GSample| match (a, b) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some((da, pa, na, ida)), Some((db, pb, nb, idb))) => {
+                if da > db {
+                    // drawn from the other side
+                    return false;
+                }
+                let off_plane = na.dot(&(*pb - *pa)).abs() / da;
+                off_plane > self.depth || na.dot(nb) < cos_crease || (self.objects && ida != idb)
+            }
+        };
+
+        let edges: Vec<bool> = (0..w * h)
+            .map(|k| {
+                let (i, j) = (k % w, k / w);
+                let neighbours = [
+                    (i > 0).then(|| k - 1),
+                    (i + 1 < w).then(|| k + 1),
+                    (j > 0).then(|| k - w),
+                    (j + 1 < h).then(|| k + w),
+                ];
+                neighbours
+                    .into_iter()
+                    .flatten()
+                    .any(|n| is_edge(&samples[k], &samples[n]))
+            })
+            .collect();
+
+        // grow the edges to the line width with antialiased borders
+        let half = 0.5 * self.width.max(0.0);
+        let r = half.ceil() as isize;
+        (0..w * h)
+            .map(|k| {
+                let (i, j) = ((k % w) as isize, (k / w) as isize);
+                let mut coverage: f32 = 0.0;
+                for dj in -r..=r {
+                    for di in -r..=r {
+                        let (x, y) = (i + di, j + dj);
+                        if x < 0 || y < 0 || x >= w as isize || y >= h as isize {
+                            continue;
+                        }
+                        if edges[y as usize * w + x as usize] {
+                            let d = ((di * di + dj * dj) as f32).sqrt();
+                            coverage = coverage.max((half + 0.5 - d).clamp(0.0, 1.0));
+                        }
+                    }
+                }
+                coverage
+            })
+            .collect()
+    }
+
+    /// Draw the outline with the given coverage over the pixels, making covered pixels opaque.
+    pub fn composite(&self, pixels: &mut [Color], alpha: &mut [f32], mask: &[f32]) {
+        let color = Color::from(&self.color);
+        for ((c, a), &m) in pixels.iter_mut().zip(alpha.iter_mut()).zip(mask) {
+            *c = *c * (1.0 - m) + color * m;
+            *a = a.max(m);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hit::Quad, material::Material, p, v, v3::N3};
+    use simple_test_case::test_case;
+
+    fn outline(width: f32) -> Outline {
+        Outline {
+            width,
+            color: default_color(),
+            depth: default_depth(),
+            crease: default_crease(),
+            objects: true,
+        }
+    }
+
+    // a 6x4 image of a plane facing the camera with a nearer square over its middle
+    fn samples(square_id: u32) -> Vec<GSample> {
+        (0..24)
+            .map(|k| {
+                let (i, j) = ((k % 6) as f32, (k / 6) as f32);
+                let near = (2.0..4.0).contains(&i) && (1.0..3.0).contains(&j);
+                let (z, id) = if near { (5.0, square_id) } else { (10.0, 1) };
+                Some((z, p!(i, j, -z), v!(0, 0, 1), id))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn outlines_are_drawn_on_the_near_side_of_edges() {
+        let mask = outline(1.0).mask(6, 4, &samples(2));
+        let drawn: Vec<usize> = (0..24).filter(|&k| mask[k] > 0.5).collect();
+
+        assert_eq!(drawn, vec![8, 9, 14, 15]);
+    }
+
+    #[test]
+    fn object_boundaries_are_outlined_without_a_depth_change() {
+        let flat: Vec<GSample> = samples(2)
+            .into_iter()
+            .map(|s| s.map(|(_, p, n, id)| (10.0, P3::new(p.x, p.y, -10.0), n, id)))
+            .collect();
+
+        let with_objects = outline(1.0).mask(6, 4, &flat);
+        let without = Outline {
+            objects: false,
+            ..outline(1.0)
+        }
+        .mask(6, 4, &flat);
+
+        assert!(with_objects.iter().any(|&m| m > 0.0));
+        assert!(without.iter().all(|&m| m == 0.0));
+    }
+
+    #[test]
+    fn wide_outlines_cover_neighbouring_pixels() {
+        let thin = outline(1.0).mask(6, 4, &samples(2));
+        let wide = outline(3.0).mask(6, 4, &samples(2));
+
+        assert!(wide.iter().sum::<f32>() > thin.iter().sum::<f32>());
+        assert_eq!(wide[7], 1.0);
+    }
+
+    #[test_case(v!(0, 1, 0), 1, 1.0; "facing the light")]
+    #[test_case(v!(1, 0.2, 0), 3, 0.15 + 0.85 / 3.0; "grazing the light")]
+    #[test_case(v!(0, -1, 0), 3, 0.15; "facing away")]
+    #[test]
+    fn lambert_shading_is_quantized(normal: V3, bands: u8, expected: f32) {
+        let mat: &'static Material = Box::leak(Box::new(Material::solid_color(Color::WHITE)));
+        let toon = Toon {
+            light: [0.0, 1.0, 0.0],
+            bands,
+            ..Toon::default()
+        };
+        let r = Ray::new(P3::ORIGIN + normal.unit_vector(), -normal);
+        let rec = HitRecord::new(1.0, P3::ORIGIN, N3::new(normal), &r, mat, 0.5, 0.5);
+        let bvh = Bvh::new(vec![
+            Quad::new(p!(5, 5, 5), v!(1, 0, 0), v!(0, 0, 1), mat).into()
+        ]);
+
+        let c = toon.shade(&r, &rec, &bvh, &mut [0; MAX_BVH_DEPTH]);
+
+        assert!((c.r - expected).abs() < 1e-5, "{c:?}");
+    }
+
+    #[test]
+    fn shadowed_surfaces_are_unlit() {
+        let mat: &'static Material = Box::leak(Box::new(Material::solid_color(Color::WHITE)));
+        let r = Ray::new(p!(0, 1, 1), v!(0, -1, -1));
+        let rec = HitRecord::new(1.0, P3::ORIGIN, N3::new(v!(0, 1, 0)), &r, mat, 0.5, 0.5);
+        let bvh = Bvh::new(vec![Quad::new(
+            p!(-1, 2, -1),
+            v!(2, 0, 0),
+            v!(0, 0, 2),
+            mat,
+        )
+        .into()]);
+        let toon = Toon {
+            light: [0.0, 1.0, 0.0],
+            ..Toon::default()
+        };
+
+        let c = toon.shade(&r, &rec, &bvh, &mut [0; MAX_BVH_DEPTH]);
+
+        assert!((c.r - toon.ambient).abs() < 1e-5, "{c:?}");
+    }
+}
@@ -1,14 +1,16 @@
-//! A simple 3D vector using f32s
-use rand::random_range;
+//! Simple 3D points, vectors and unit normals using f32s.
+//!
+//! Points and vectors are distinct types so that only meaningful operations are available:
+//! the difference of two points is a vector, a point can be offset by a vector but two points
+//! can not be added together.
+use crate::sampler::random_range;
 use std::ops::{
     Add, AddAssign, Div, DivAssign, Index, IndexMut, Mul, MulAssign, Neg, Sub, SubAssign,
 };
 
 const NEAR_ZERO: f32 = 1e-8;
 
-pub type P3 = V3;
-
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
 pub struct V3 {
     pub x: f32,
     pub y: f32,
@@ -16,7 +18,7 @@ pub struct V3 {
 }
 
 impl V3 {
-    pub const ORIGIN: V3 = V3::new(0.0, 0.0, 0.0);
+    pub const ZERO: V3 = V3::new(0.0, 0.0, 0.0);
 
     pub const fn new(x: f32, y: f32, z: f32) -> V3 {
         Self { x, y, z }
@@ -49,6 +51,15 @@ impl V3 {
         }
     }
 
+    /// A random direction about the +z axis with density cos(theta) / pi over solid angle
+    pub fn random_cosine_direction() -> V3 {
+        let (r1, r2): (f32, f32) = (random_range(0.0..1.0), random_range(0.0..1.0));
+        let phi = std::f32::consts::TAU * r1;
+        let r = r2.sqrt();
+
+        V3::new(phi.cos() * r, phi.sin() * r, (1.0 - r2).sqrt())
+    }
+
     pub fn random_in_unit_disk() -> V3 {
         loop {
             let p = V3::new(random_range(-1.0..1.0), random_range(-1.0..1.0), 0.0);
@@ -58,11 +69,13 @@ impl V3 {
         }
     }
 
-    pub fn reflect(&self, normal: V3) -> V3 {
+    pub fn reflect(&self, normal: N3) -> V3 {
+        let normal = normal.as_v3();
         *self - 2.0 * self.dot(&normal) * normal
     }
 
-    pub fn refract(&self, normal: V3, etai_over_etat: f32) -> V3 {
+    pub fn refract(&self, normal: N3, etai_over_etat: f32) -> V3 {
+        let normal = normal.as_v3();
         let cos_theta = (-self.dot(&normal)).min(1.0);
         let r_out_perp = etai_over_etat * (*self + cos_theta * normal);
         let r_out_para = -(1.0 - r_out_perp.square_length()).sqrt() * normal;
@@ -161,23 +174,6 @@ impl Mul<V3> for f32 {
     }
 }
 
-// This is odd...really this should be the full product so what is this used for???
-impl Mul<V3> for V3 {
-    type Output = V3;
-
-    fn mul(self, rhs: V3) -> Self::Output {
-        Self::new(self.x * rhs.x, self.y * rhs.y, self.z * rhs.z)
-    }
-}
-
-impl MulAssign<V3> for V3 {
-    fn mul_assign(&mut self, rhs: V3) {
-        self.x *= rhs.x;
-        self.y *= rhs.y;
-        self.z *= rhs.z;
-    }
-}
-
 impl MulAssign<f32> for V3 {
     fn mul_assign(&mut self, rhs: f32) {
         self.x *= rhs;
@@ -225,3 +221,424 @@ impl IndexMut<usize> for V3 {
         }
     }
 }
+
+/// A position in 3D space.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct P3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl P3 {
+    pub const ORIGIN: P3 = P3::new(0.0, 0.0, 0.0);
+
+    pub const fn new(x: f32, y: f32, z: f32) -> P3 {
+        Self { x, y, z }
+    }
+
+    /// Scale the position of this point relative to the origin.
+    pub fn scale(&self, s: f32) -> P3 {
+        P3::new(self.x * s, self.y * s, self.z * s)
+    }
+
+    /// The component-wise minimum of two points.
+    pub fn min(&self, rhs: &P3) -> P3 {
+        P3::new(self.x.min(rhs.x), self.y.min(rhs.y), self.z.min(rhs.z))
+    }
+
+    /// The component-wise maximum of two points.
+    pub fn max(&self, rhs: &P3) -> P3 {
+        P3::new(self.x.max(rhs.x), self.y.max(rhs.y), self.z.max(rhs.z))
+    }
+}
+
+impl From<[f32; 3]> for P3 {
+    fn from(p: [f32; 3]) -> Self {
+        P3::new(p[0], p[1], p[2])
+    }
+}
+
+impl Add<V3> for P3 {
+    type Output = P3;
+
+    fn add(self, rhs: V3) -> Self::Output {
+        Self::new(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z)
+    }
+}
+
+impl AddAssign<V3> for P3 {
+    fn add_assign(&mut self, rhs: V3) {
+        self.x += rhs.x;
+        self.y += rhs.y;
+        self.z += rhs.z;
+    }
+}
+
+impl Sub<V3> for P3 {
+    type Output = P3;
+
+    fn sub(self, rhs: V3) -> Self::Output {
+        Self::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
+    }
+}
+
+impl SubAssign<V3> for P3 {
+    fn sub_assign(&mut self, rhs: V3) {
+        self.x -= rhs.x;
+        self.y -= rhs.y;
+        self.z -= rhs.z;
+    }
+}
+
+impl Sub<P3> for P3 {
+    type Output = V3;
+
+    fn sub(self, rhs: P3) -> Self::Output {
+        V3::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
+    }
+}
+
+impl Index<usize> for P3 {
+    type Output = f32;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        match index {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("p3 index out of bounds: {index}"),
+        }
+    }
+}
+
+/// A vector of unit length, used for surface normals.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct N3(V3);
+
+impl N3 {
+    /// Normalise v to unit length.
+    pub fn new(v: V3) -> N3 {
+        Self(v.unit_vector())
+    }
+
+    /// Wrap a vector that is already known to be of unit length.
+    pub const fn new_unchecked(v: V3) -> N3 {
+        Self(v)
+    }
+
+    pub const fn as_v3(&self) -> V3 {
+        self.0
+    }
+
+    pub const fn dot(&self, rhs: &V3) -> f32 {
+        self.0.dot(rhs)
+    }
+}
+
+impl Add<V3> for N3 {
+    type Output = V3;
+
+    fn add(self, rhs: V3) -> Self::Output {
+        self.0 + rhs
+    }
+}
+
+impl From<N3> for V3 {
+    fn from(n: N3) -> Self {
+        n.0
+    }
+}
+
+impl Neg for N3 {
+    type Output = N3;
+
+    fn neg(self) -> Self::Output {
+        Self(-self.0)
+    }
+}
+
+impl Mul<f32> for N3 {
+    type Output = V3;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        self.0 * rhs
+    }
+}
+
+impl Mul<N3> for f32 {
+    type Output = V3;
+
+    fn mul(self, rhs: N3) -> Self::Output {
+        rhs.0 * self
+    }
+}
+
+/// An orthonormal basis with w aligned to a given direction, used for converting between world
+/// space and a local shading frame around a surface normal.
+///   https://raytracing.github.io/books/RayTracingTheRestOfYourLife.html#orthonormalbases
+#[derive(Debug, Clone, Copy)]
+pub struct Onb {
+    pub u: V3,
+    pub v: V3,
+    pub w: V3,
+}
+
+impl Onb {
+    pub fn new(n: V3) -> Onb {
+        let w = n.unit_vector();
+        let a = if w.x.abs() > 0.9 {
+            V3::new(0.0, 1.0, 0.0)
+        } else {
+            V3::new(1.0, 0.0, 0.0)
+        };
+        let v = w.cross(&a).unit_vector();
+        let u = w.cross(&v);
+
+        Self { u, v, w }
+    }
+
+    /// Convert a vector from local (u,v,w) coordinates to world space.
+    pub fn to_world(&self, a: V3) -> V3 {
+        a.x * self.u + a.y * self.v + a.z * self.w
+    }
+
+    /// Convert a vector from world space to local (u,v,w) coordinates.
+    pub fn to_local(&self, a: V3) -> V3 {
+        V3::new(a.dot(&self.u), a.dot(&self.v), a.dot(&self.w))
+    }
+}
+
+/// A quaternion used to represent rotations in 3D. Rotation constructors always return unit
+/// quaternions.
+///   https://en.wikipedia.org/wiki/Quaternions_and_spatial_rotation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quat {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Default for Quat {
+    fn default() -> Self {
+        Quat::IDENTITY
+    }
+}
+
+impl Quat {
+    pub const IDENTITY: Quat = Quat::new(1.0, 0.0, 0.0, 0.0);
+
+    pub const fn new(w: f32, x: f32, y: f32, z: f32) -> Quat {
+        Self { w, x, y, z }
+    }
+
+    /// A rotation of angle radians around axis (which need not be normalised).
+    pub fn from_axis_angle(axis: V3, angle: f32) -> Quat {
+        let a = axis.unit_vector();
+        let (s, c) = (angle / 2.0).sin_cos();
+
+        Quat::new(c, a.x * s, a.y * s, a.z * s)
+    }
+
+    /// A rotation from Euler angles (in radians) applied in the order: x, then y, then z.
+    pub fn from_euler(x: f32, y: f32, z: f32) -> Quat {
+        let qx = Quat::from_axis_angle(V3::new(1.0, 0.0, 0.0), x);
+        let qy = Quat::from_axis_angle(V3::new(0.0, 1.0, 0.0), y);
+        let qz = Quat::from_axis_angle(V3::new(0.0, 0.0, 1.0), z);
+
+        qz * qy * qx
+    }
+
+    /// The rotation axis and angle (in radians) for this rotation. The identity rotation is
+    /// returned as a rotation of 0 around the x axis.
+    pub fn to_axis_angle(&self) -> (V3, f32) {
+        let q = self.normalize();
+        let s = (1.0 - q.w * q.w).max(0.0).sqrt();
+        if s < NEAR_ZERO {
+            return (V3::new(1.0, 0.0, 0.0), 0.0);
+        }
+
+        (
+            V3::new(q.x / s, q.y / s, q.z / s),
+            2.0 * q.w.clamp(-1.0, 1.0).acos(),
+        )
+    }
+
+    pub fn length(&self) -> f32 {
+        (self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+    }
+
+    pub fn normalize(&self) -> Quat {
+        let inv_len = 1.0 / self.length();
+
+        Quat::new(
+            self.w * inv_len,
+            self.x * inv_len,
+            self.y * inv_len,
+            self.z * inv_len,
+        )
+    }
+
+    /// The inverse rotation for a unit quaternion.
+    pub const fn conjugate(&self) -> Quat {
+        Quat::new(self.w, -self.x, -self.y, -self.z)
+    }
+
+    pub const fn dot(&self, rhs: &Quat) -> f32 {
+        self.w * rhs.w + self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
+    }
+
+    /// Rotate the vector v by this (unit) quaternion.
+    pub fn rotate(&self, v: V3) -> V3 {
+        // v' = v + 2w(q x v) + 2q x (q x v) for the vector part q of the quaternion
+        let q = V3::new(self.x, self.y, self.z);
+        let t = 2.0 * q.cross(&v);
+
+        v + self.w * t + q.cross(&t)
+    }
+
+    /// Spherical linear interpolation between two rotations for t in [0, 1].
+    pub fn slerp(&self, other: &Quat, t: f32) -> Quat {
+        let mut cos_theta = self.dot(other);
+        let mut b = *other;
+        if cos_theta < 0.0 {
+            // take the shortest path around the sphere
+            b = Quat::new(-b.w, -b.x, -b.y, -b.z);
+            cos_theta = -cos_theta;
+        }
+
+        let (ka, kb) = if cos_theta > 0.9995 {
+            (1.0 - t, t) // nearly parallel so fall back to linear interpolation
+        } else {
+            let theta = cos_theta.acos();
+            let inv_sin = 1.0 / theta.sin();
+            (
+                ((1.0 - t) * theta).sin() * inv_sin,
+                (t * theta).sin() * inv_sin,
+            )
+        };
+
+        Quat::new(
+            ka * self.w + kb * b.w,
+            ka * self.x + kb * b.x,
+            ka * self.y + kb * b.y,
+            ka * self.z + kb * b.z,
+        )
+        .normalize()
+    }
+}
+
+/// Hamilton product: (a * b) applies the rotation b followed by a.
+impl Mul<Quat> for Quat {
+    type Output = Quat;
+
+    fn mul(self, rhs: Quat) -> Self::Output {
+        Quat::new(
+            self.w * rhs.w - self.x * rhs.x - self.y * rhs.y - self.z * rhs.z,
+            self.w * rhs.x + self.x * rhs.w + self.y * rhs.z - self.z * rhs.y,
+            self.w * rhs.y - self.x * rhs.z + self.y * rhs.w + self.z * rhs.x,
+            self.w * rhs.z + self.x * rhs.y - self.y * rhs.x + self.z * rhs.w,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+    use std::f32::consts::{FRAC_PI_2, PI};
+
+    fn assert_close(a: V3, b: V3) {
+        assert!((a - b).length() < 1e-5, "{a:?} != {b:?}");
+    }
+
+    #[test_case(V3::new(0.0, 0.0, 1.0); "z")]
+    #[test_case(V3::new(1.0, 0.0, 0.0); "x")]
+    #[test_case(V3::new(1.0, 2.0, -3.0); "arbitrary")]
+    #[test]
+    fn onb_is_orthonormal(n: V3) {
+        let onb = Onb::new(n);
+
+        assert_close(onb.w, n.unit_vector());
+        for (a, b) in [(onb.u, onb.v), (onb.v, onb.w), (onb.u, onb.w)] {
+            assert!(a.dot(&b).abs() < 1e-6);
+        }
+        for a in [onb.u, onb.v, onb.w] {
+            assert!((a.length() - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn point_vector_arithmetic() {
+        let a = P3::new(1.0, 2.0, 3.0);
+        let b = P3::new(4.0, 6.0, 3.0);
+        let ab = b - a;
+
+        assert_close(ab, V3::new(3.0, 4.0, 0.0));
+        assert_eq!(a + ab, b);
+        assert_eq!(b - ab, a);
+    }
+
+    #[test]
+    fn normals_are_unit_length() {
+        let n = N3::new(V3::new(3.0, 4.0, 0.0));
+
+        assert!((n.as_v3().length() - 1.0).abs() < 1e-6);
+        assert_close(
+            V3::new(1.0, -1.0, 0.0).reflect(N3::new(V3::new(0.0, 1.0, 0.0))),
+            V3::new(1.0, 1.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn onb_round_trips() {
+        let onb = Onb::new(V3::new(0.3, -0.5, 0.8));
+        let a = V3::new(1.5, -2.0, 0.25);
+
+        assert_close(onb.to_world(onb.to_local(a)), a);
+        assert_close(onb.to_local(onb.w), V3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test_case(V3::new(0.0, 1.0, 0.0), FRAC_PI_2, V3::new(1.0, 0.0, 0.0), V3::new(0.0, 0.0, -1.0); "y quarter turn")]
+    #[test_case(V3::new(0.0, 0.0, 1.0), FRAC_PI_2, V3::new(1.0, 0.0, 0.0), V3::new(0.0, 1.0, 0.0); "z quarter turn")]
+    #[test_case(V3::new(1.0, 0.0, 0.0), PI, V3::new(0.0, 1.0, 0.0), V3::new(0.0, -1.0, 0.0); "x half turn")]
+    #[test]
+    fn quat_rotate_works(axis: V3, angle: f32, v: V3, expected: V3) {
+        let q = Quat::from_axis_angle(axis, angle);
+
+        assert_close(q.rotate(v), expected);
+        assert_close(q.conjugate().rotate(expected), v);
+    }
+
+    #[test]
+    fn quat_axis_angle_round_trips() {
+        let axis = V3::new(1.0, 2.0, 3.0).unit_vector();
+        let (a, theta) = Quat::from_axis_angle(axis, 1.2).to_axis_angle();
+
+        assert_close(a, axis);
+        assert!((theta - 1.2).abs() < 1e-5);
+    }
+
+    #[test]
+    fn quat_product_composes_rotations() {
+        let a = Quat::from_axis_angle(V3::new(0.0, 1.0, 0.0), 0.7);
+        let b = Quat::from_axis_angle(V3::new(1.0, 0.0, 0.0), -0.4);
+        let v = V3::new(0.2, 0.5, -1.0);
+
+        assert_close((a * b).rotate(v), a.rotate(b.rotate(v)));
+    }
+
+    #[test]
+    fn quat_slerp_halfway() {
+        let y = V3::new(0.0, 1.0, 0.0);
+        let a = Quat::IDENTITY;
+        let b = Quat::from_axis_angle(y, FRAC_PI_2);
+        let q = a.slerp(&b, 0.5);
+
+        assert_close(
+            q.rotate(V3::new(1.0, 0.0, 0.0)),
+            Quat::from_axis_angle(y, PI / 4.0).rotate(V3::new(1.0, 0.0, 0.0)),
+        );
+    }
+}
@@ -0,0 +1,539 @@
+//! Voxel grids rendered directly with a DDA traversal rather than as per-cube geometry so that
+//! blocky scenes with millions of voxels stay cheap to load and render.
+//!   http://www.cse.yorku.ca/~amana/research/grid.pdf
+//!
+//! Grids can be loaded from MagicaVoxel .vox files (only the first model is used and the Z-up
+//! coordinates are converted to Y-up) or from a simple run length encoded text format:
+//!
+//!   # comment
+//!   size 16 8 16               # dimensions in x, y, z
+//!   palette 1 0.8 0.2 0.1      # linear RGB color for a palette index (1-255)
+//!   data                       # followed by "count index" pairs with x varying fastest,
+//!   256 1 10 0 4 2             # then z, then y. Index 0 is empty space.
+//!
+//! Each palette index is rendered with a diffuse material of its color unless it is mapped to a
+//! named scene material.
+use crate::{
+    bvh::AABBox,
+    hit::{HitRecord, Interval},
+    material::Material,
+    scene::Tag,
+    v3::N3,
+    Color, Ray, P3, V3,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Voxels {
+    pub path: String,
+    /// Edge length of each voxel
+    #[serde(default = "default_size")]
+    pub size: f32,
+    /// Position of the minimum corner of the grid
+    #[serde(default)]
+    pub translate: [f32; 3],
+    /// Scene materials to use in place of palette colors, keyed by palette index
+    #[serde(default)]
+    pub materials: HashMap<String, String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Inclusive range of animation frames in which the grid is visible
+    #[serde(default)]
+    pub visible_frames: Option<[u32; 2]>,
+    /// Label for segmentation
+    #[serde(default)]
+    pub tag: Option<Tag>,
+}
+
+fn default_size() -> f32 {
+    1.0
+}
+
+impl Voxels {
+    /// Load the voxel data and build a grid, resolving palette indices mapped to scene materials.
+    pub fn as_grid(&self, materials: &HashMap<String, &'static Material>) -> io::Result<VoxelGrid> {
+        let data = load_voxels(&self.path)?;
+        let mats = self
+            .materials
+            .iter()
+            .map(|(ix, name)| {
+                let ix = ix
+                    .parse::<u8>()
+                    .map_err(|_| invalid(format!("invalid palette index {ix}")))?;
+                let mat = materials
+                    .get(name)
+                    .ok_or_else(|| invalid(format!("unknown material {name}")))?;
+                Ok((ix, *mat))
+            })
+            .collect::<io::Result<_>>()?;
+        let [x, y, z] = self.translate;
+
+        Ok(VoxelGrid::new(data, P3::new(x, y, z), self.size, &mats))
+    }
+}
+
+/// Raw voxel data as loaded from a file: palette indices for each cell along with the colors
+/// for each index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelData {
+    pub dims: [usize; 3],
+    pub cells: Vec<u8>,
+    pub palette: HashMap<u8, Color>,
+}
+
+impl VoxelData {
+    fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        x + self.dims[0] * (z + self.dims[2] * y)
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+/// Load voxels from path as MagicaVoxel if it has a ".vox" extension and RLE text otherwise.
+pub fn load_voxels(path: &str) -> io::Result<VoxelData> {
+    let bytes = fs::read(path)?;
+    let is_vox = Path::new(path).extension().is_some_and(|ext| ext == "vox");
+
+    if is_vox {
+        parse_vox(&bytes)
+    } else {
+        parse_rle(&String::from_utf8_lossy(&bytes))
+    }
+}
+
+pub fn parse_rle(s: &str) -> io::Result<VoxelData> {
+    let mut dims = None;
+    let mut palette = HashMap::new();
+    let mut cells = Vec::new();
+    let mut in_data = false;
+
+    for line in s.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let num = |s: &str| {
+            s.parse::<f32>()
+                .map_err(|_| invalid(format!("invalid number {s}")))
+        };
+        let int = |s: &str| {
+            s.parse::<usize>()
+                .map_err(|_| invalid(format!("invalid int {s}")))
+        };
+
+        match parts[..] {
+            [] => (),
+            _ if in_data => {
+                for pair in parts.chunks(2) {
+                    let [count, ix] = pair else {
+                        return Err(invalid("unpaired run length"));
+                    };
+                    let ix = u8::try_from(int(ix)?).map_err(|_| invalid("index out of range"))?;
+                    cells.extend(std::iter::repeat_n(ix, int(count)?));
+                }
+            }
+            ["size", x, y, z] => dims = Some([int(x)?, int(y)?, int(z)?]),
+            ["palette", ix, r, g, b] => {
+                let ix = u8::try_from(int(ix)?).map_err(|_| invalid("index out of range"))?;
+                palette.insert(ix, Color::new(num(r)?, num(g)?, num(b)?));
+            }
+            ["data"] => in_data = true,
+            _ => return Err(invalid(format!("unexpected line: {line}"))),
+        }
+    }
+
+    let dims = dims.ok_or_else(|| invalid("missing size"))?;
+    let n: usize = dims.iter().product();
+    if cells.len() != n {
+        return Err(invalid(format!(
+            "expected {n} cells but got {}",
+            cells.len()
+        )));
+    }
+
+    Ok(VoxelData {
+        dims,
+        cells,
+        palette,
+    })
+}
+
+/// Parse the first model from a MagicaVoxel file.
+///   https://github.com/ephtracy/voxel-model/blob/master/MagicaVoxel-file-format-vox.txt
+pub fn parse_vox(bytes: &[u8]) -> io::Result<VoxelData> {
+    if bytes.len() < 8 || &bytes[..4] != b"VOX " {
+        return Err(invalid("not a MagicaVoxel file"));
+    }
+
+    let read_u32 = |off: usize| -> io::Result<u32> {
+        let b = bytes
+            .get(off..off + 4)
+            .ok_or_else(|| invalid("truncated .vox file"))?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    let mut size = None;
+    let mut voxels: Option<&[u8]> = None;
+    let mut rgba: Option<&[u8]> = None;
+
+    // The MAIN chunk header is followed by all other chunks as its children, so we can walk all
+    // chunks linearly after skipping the header.
+    let mut off = 8 + 12;
+    while off + 12 <= bytes.len() {
+        let id = &bytes[off..off + 4];
+        let content_len = read_u32(off + 4)? as usize;
+        let content = bytes
+            .get(off + 12..off + 12 + content_len)
+            .ok_or_else(|| invalid("truncated .vox chunk"))?;
+
+        match id {
+            b"SIZE" if size.is_none() => {
+                size = Some([
+                    read_u32(off + 12)?,
+                    read_u32(off + 16)?,
+                    read_u32(off + 20)?,
+                ])
+            }
+            b"XYZI" if voxels.is_none() => {
+                // the voxels follow their count
+                let xyzi = content
+                    .get(4..)
+                    .ok_or_else(|| invalid("truncated XYZI chunk"))?;
+                voxels = Some(xyzi);
+            }
+            b"RGBA" => rgba = Some(content),
+            _ => (),
+        }
+
+        off += 12 + content_len; // children are walked in place
+    }
+
+    let [sx, sy, sz] = size.ok_or_else(|| invalid("missing SIZE chunk"))?;
+    let voxels = voxels.ok_or_else(|| invalid("missing XYZI chunk"))?;
+
+    // Z-up to Y-up, flipping the new z axis to preserve handedness
+    let dims = [sx as usize, sz as usize, sy as usize];
+    let mut data = VoxelData {
+        dims,
+        cells: vec![0; dims.iter().product()],
+        palette: HashMap::new(),
+    };
+
+    for v in voxels.chunks_exact(4) {
+        let (x, y, z, ix) = (v[0] as usize, v[1] as usize, v[2] as usize, v[3]);
+        if x < dims[0] && z < dims[1] && y < dims[2] {
+            let i = data.index(x, z, dims[2] - 1 - y);
+            data.cells[i] = ix;
+        }
+    }
+
+    for ix in data.cells.iter().copied().filter(|&ix| ix != 0) {
+        data.palette.entry(ix).or_insert_with(|| match rgba {
+            // palette entry i holds the color for index i + 1. Squaring approximately undoes
+            // the sRGB encoding to match the gamma 2 output transform.
+            Some(rgba) => {
+                let c = &rgba[4 * (ix as usize - 1)..];
+                let f = |b: u8| (b as f32 / 255.0).powi(2);
+                Color::new(f(c[0]), f(c[1]), f(c[2]))
+            }
+            // files without a palette use MagicaVoxel's default which we don't replicate
+            None => Color::from_id(ix as u32),
+        });
+    }
+
+    Ok(data)
+}
+
+/// A grid of voxels traversed using a 3D DDA. Each cell holds a palette index with 0 being
+/// empty space.
+#[derive(Debug, Clone)]
+pub struct VoxelGrid {
+    dims: [usize; 3],
+    origin: P3,
+    size: f32,
+    cells: Vec<u8>,
+    palette: Vec<&'static Material>,
+    pub bbox: AABBox,
+}
+
+impl VoxelGrid {
+    /// Build a grid from data, using the given materials for palette indices where provided and
+    /// diffuse materials of the palette colors otherwise.
+    pub fn new(
+        data: VoxelData,
+        origin: P3,
+        size: f32,
+        mats: &HashMap<u8, &'static Material>,
+    ) -> Self {
+        let grey: &'static Material = Box::leak(Box::new(Material::solid_color(Color::grey(0.5))));
+        let mut palette = vec![grey; 256];
+        for (&ix, &c) in data.palette.iter() {
+            palette[ix as usize] = Box::leak(Box::new(Material::solid_color(c)));
+        }
+        for (&ix, &m) in mats.iter() {
+            palette[ix as usize] = m;
+        }
+
+        let extent = V3::new(
+            data.dims[0] as f32,
+            data.dims[1] as f32,
+            data.dims[2] as f32,
+        ) * size;
+
+        Self {
+            dims: data.dims,
+            origin,
+            size,
+            cells: data.cells,
+            palette,
+            bbox: AABBox::new_from_points(origin, origin + extent),
+        }
+    }
+
+    fn cell(&self, [x, y, z]: [usize; 3]) -> u8 {
+        self.cells[x + self.dims[0] * (z + self.dims[2] * y)]
+    }
+
+    pub fn n_filled(&self) -> usize {
+        self.cells.iter().filter(|&&c| c != 0).count()
+    }
+
+    /// Total area of the exposed faces of voxels using emissive materials, along with an example
+    /// material and position for computing the emitted power.
+    pub fn emissive_faces(&self) -> Vec<(&'static Material, f32, P3)> {
+        let mut faces = Vec::new();
+        let face_area = self.size * self.size;
+
+        for y in 0..self.dims[1] {
+            for z in 0..self.dims[2] {
+                for x in 0..self.dims[0] {
+                    let c = self.cell([x, y, z]);
+                    let mat = self.palette[c as usize];
+                    if c == 0 || !mat.is_emissive() {
+                        continue;
+                    }
+
+                    let p = [x, y, z];
+                    let exposed = (0..3)
+                        .flat_map(|axis| {
+                            [p[axis] == 0, p[axis] + 1 == self.dims[axis]]
+                                .into_iter()
+                                .zip([p[axis].wrapping_sub(1), p[axis] + 1])
+                                .map(move |(edge, n)| (axis, edge, n))
+                        })
+                        .filter(|&(axis, edge, n)| {
+                            let mut q = p;
+                            q[axis] = n;
+                            edge || self.cell(q) == 0
+                        })
+                        .count();
+
+                    let pos = self.origin + V3::new(x as f32, y as f32, z as f32) * self.size;
+                    faces.push((mat, exposed as f32 * face_area, pos));
+                }
+            }
+        }
+
+        faces
+    }
+
+    pub fn hits(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        // Slab test against the grid bounds to find where the ray enters
+        let lo = self.origin;
+        let hi = P3::new(self.bbox.x.max, self.bbox.y.max, self.bbox.z.max);
+        let (mut t0, mut t1) = (ray_t.min, ray_t.max);
+        let mut axis = 0;
+        for a in 0..3 {
+            let inv = 1.0 / r.dir[a];
+            let (mut ta, mut tb) = ((lo[a] - r.orig[a]) * inv, (hi[a] - r.orig[a]) * inv);
+            if ta.is_nan() || tb.is_nan() {
+                return None; // NaN rays or rays lying in a face of the grid
+            }
+            if inv < 0.0 {
+                (ta, tb) = (tb, ta);
+            }
+            if ta > t0 {
+                t0 = ta;
+                axis = a;
+            }
+            t1 = t1.min(tb);
+            if t1 <= t0 {
+                return None;
+            }
+        }
+
+        // Set up the DDA from the entry cell
+        let entry = r.at(t0) - lo;
+        let mut cell = [0usize; 3];
+        let mut step = [0isize; 3];
+        let mut t_max = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for a in 0..3 {
+            let c = ((entry[a] / self.size) as isize).clamp(0, self.dims[a] as isize - 1);
+            cell[a] = c as usize;
+            if r.dir[a] > 0.0 {
+                step[a] = 1;
+                t_delta[a] = self.size / r.dir[a];
+                t_max[a] = t0 + ((c + 1) as f32 * self.size - entry[a]) / r.dir[a];
+            } else if r.dir[a] < 0.0 {
+                step[a] = -1;
+                t_delta[a] = -self.size / r.dir[a];
+                t_max[a] = t0 + (c as f32 * self.size - entry[a]) / r.dir[a];
+            }
+        }
+
+        let mut t = t0;
+        loop {
+            let c = self.cell(cell);
+            if c != 0 {
+                return Some(self.hit_record(r, t, axis, c));
+            }
+
+            axis = if t_max[0] < t_max[1] {
+                if t_max[0] < t_max[2] {
+                    0
+                } else {
+                    2
+                }
+            } else if t_max[1] < t_max[2] {
+                1
+            } else {
+                2
+            };
+
+            t = t_max[axis];
+            if t > t1 {
+                return None;
+            }
+            let next = cell[axis] as isize + step[axis];
+            if next < 0 || next >= self.dims[axis] as isize {
+                return None;
+            }
+            cell[axis] = next as usize;
+            t_max[axis] += t_delta[axis];
+        }
+    }
+
+    fn hit_record(&self, r: &Ray, t: f32, axis: usize, c: u8) -> HitRecord {
+        let p = r.at(t);
+        let mut n = V3::ZERO;
+        n[axis] = -r.dir[axis].signum();
+
+        // face local coordinates in [0, 1] from the two axes orthogonal to the normal
+        let local = (p - self.origin) / self.size;
+        let (ua, va) = ((axis + 1) % 3, (axis + 2) % 3);
+        let (u, v) = (local[ua].fract(), local[va].fract());
+
+        let mut hr = HitRecord::new(
+            t,
+            p,
+            N3::new_unchecked(n),
+            r,
+            self.palette[c as usize],
+            u,
+            v,
+        );
+        hr.edge_dist = self.size * u.min(1.0 - u).min(v).min(1.0 - v);
+
+        hr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{p, v};
+    use simple_test_case::test_case;
+
+    const RLE: &str = "# a 2x2x2 grid with a single filled voxel at (1, 0, 0)
+size 2 2 2
+palette 3 1.0 0.0 0.0
+data
+1 0 1 3 6 0
+";
+
+    #[test]
+    fn parse_rle_works() {
+        let data = parse_rle(RLE).unwrap();
+
+        assert_eq!(data.dims, [2, 2, 2]);
+        assert_eq!(data.cells, vec![0, 3, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(data.palette[&3], Color::new(1.0, 0.0, 0.0));
+    }
+
+    fn chunk(id: &[u8], content: Vec<u8>, children: u32) -> Vec<u8> {
+        let mut c = id.to_vec();
+        c.extend((content.len() as u32).to_le_bytes());
+        c.extend(children.to_le_bytes());
+        c.extend(content);
+        c
+    }
+
+    /// A .vox file with the given chunks as children of the MAIN chunk
+    fn vox_file(children: &[Vec<u8>]) -> Vec<u8> {
+        let children = children.concat();
+        let mut bytes = b"VOX ".to_vec();
+        bytes.extend(150u32.to_le_bytes());
+        bytes.extend(chunk(b"MAIN", vec![], children.len() as u32));
+        bytes.extend(children);
+        bytes
+    }
+
+    #[test]
+    fn parse_vox_works() {
+        let u32s = |vs: &[u32]| vs.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+
+        let size = chunk(b"SIZE", u32s(&[2, 3, 4]), 0);
+        let mut xyzi = u32s(&[1]);
+        xyzi.extend([1, 2, 3, 7]); // x, y, z, color index
+        let xyzi = chunk(b"XYZI", xyzi, 0);
+        let mut rgba = vec![0; 1024];
+        rgba[4 * 6..4 * 6 + 4].copy_from_slice(&[255, 0, 0, 255]);
+        let rgba = chunk(b"RGBA", rgba, 0);
+
+        let data = parse_vox(&vox_file(&[size, xyzi, rgba])).unwrap();
+
+        // z-up (2, 3, 4) becomes y-up (2, 4, 3) with vox (1, 2, 3) at (1, 3, 0)
+        assert_eq!(data.dims, [2, 4, 3]);
+        assert_eq!(data.cells[data.index(1, 3, 0)], 7);
+        assert_eq!(data.cells.iter().filter(|&&c| c != 0).count(), 1);
+        assert_eq!(data.palette[&7], Color::new(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn truncated_xyzi_chunks_are_errors() {
+        let size = chunk(b"SIZE", [2u32, 2, 2].map(u32::to_le_bytes).concat(), 0);
+        let xyzi = chunk(b"XYZI", vec![1, 0], 0);
+
+        let err = parse_vox(&vox_file(&[size, xyzi])).unwrap_err();
+
+        assert_eq!(err.to_string(), "truncated XYZI chunk");
+    }
+
+    #[test_case(p!(1.5, 0.5, 5), v!(0, 0, -1), Some((4.0, 2)); "straight on")]
+    #[test_case(p!(0.5, 0.5, 5), v!(0, 0, -1), None; "through an empty column")]
+    #[test_case(p!(-5, 0.5, 0.5), v!(1, 0, 0), Some((6.0, 0)); "through an empty cell first")]
+    #[test_case(p!(1.5, 0.5, 0.5), v!(1, 0, 0), Some((0.0, 0)); "starting inside")]
+    #[test]
+    fn grid_hits_work(orig: P3, dir: V3, expected: Option<(f32, usize)>) {
+        let grid = VoxelGrid::new(parse_rle(RLE).unwrap(), P3::ORIGIN, 1.0, &HashMap::new());
+        let r = Ray::new(orig, dir);
+
+        let hr = grid.hits(&r, Interval::new(0.0, f32::INFINITY));
+
+        match (hr, expected) {
+            (None, None) => (),
+            (Some(hr), Some((t, axis))) => {
+                assert!((hr.t - t).abs() < 1e-5, "t={}", hr.t);
+                assert_eq!(hr.normal.as_v3()[axis], -dir[axis].signum());
+            }
+            (hr, expected) => panic!("{hr:?} != {expected:?}"),
+        }
+    }
+}